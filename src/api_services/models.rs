@@ -1,10 +1,14 @@
+use std::collections::BTreeSet;
 use std::fmt;
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
-use surrealdb::sql::Thing;
+use serde_json::{Map, Value};
+use surrealdb::sql::{Datetime, Thing};
 use validator::Validate;
 
 use crate::database::{API_ROLE_TABLE, NAMESPACE_MEMBER_ROLE, ROLE_NAMESPACE_DELIMITER};
+use crate::pagination::default_page_size;
 
 #[derive(Debug, Serialize, Deserialize, Validate, Clone)]
 pub struct WebRequestApiService {
@@ -27,6 +31,38 @@ pub struct WebRequestApiService {
 
     #[validate(length(min = 1))]
     pub environment: String,
+
+    #[validate(range(min = 1))]
+    pub max_body_size: Option<u64>,
+
+    #[validate]
+    pub tls: Option<ServiceTlsConfig>,
+
+    #[serde(default)]
+    #[validate]
+    pub targets: Vec<UpstreamTarget>,
+
+    #[serde(default)]
+    pub load_balancing: LoadBalancingStrategy,
+
+    #[validate]
+    pub health_check: Option<HealthCheckConfig>,
+
+    #[validate]
+    pub timeouts: Option<TimeoutConfig>,
+
+    #[validate]
+    pub retry: Option<RetryConfig>,
+
+    #[validate]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    #[validate]
+    pub websocket: Option<WebSocketConfig>,
+
+    // Relay the response as it arrives, without the total request timeout (SSE, long polling)
+    #[serde(default)]
+    pub streaming: bool,
 }
 
 impl From<&WebRequestApiService> for Vec<WebApiRole> {
@@ -36,6 +72,7 @@ impl From<&WebRequestApiService> for Vec<WebApiRole> {
             value.role_namespaces.iter().for_each(|namespace| {
                 roles.push(WebApiRole {
                     id: None,
+                    revision: None,
                     namespace: namespace.clone(),
                     name: NAMESPACE_MEMBER_ROLE.into(),
                 });
@@ -49,6 +86,9 @@ impl From<&WebRequestApiService> for Vec<WebApiRole> {
 #[derive(Debug, Serialize, Deserialize, Validate, Clone)]
 pub struct WebResponseApiService {
     pub id: String,
+    // Counts the writes to the service; also sent as its ETag
+    #[serde(default)]
+    pub revision: u64,
     #[validate(length(min = 3))]
     pub api_name: String,
 
@@ -68,11 +108,39 @@ pub struct WebResponseApiService {
 
     #[validate(length(min = 1))]
     pub environment: String,
+
+    pub max_body_size: Option<u64>,
+
+    pub tls: Option<ServiceTlsConfig>,
+
+    #[serde(default)]
+    pub targets: Vec<UpstreamTarget>,
+
+    #[serde(default)]
+    pub load_balancing: LoadBalancingStrategy,
+
+    pub health_check: Option<HealthCheckConfig>,
+
+    pub timeouts: Option<TimeoutConfig>,
+
+    pub retry: Option<RetryConfig>,
+
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    pub websocket: Option<WebSocketConfig>,
+
+    #[serde(default)]
+    pub streaming: bool,
+
+    #[serde(default)]
+    pub health: Vec<WebServiceHealth>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Validate, Clone)]
 pub struct DbFullApiService {
     pub id: Thing,
+    #[serde(default)]
+    pub revision: u64,
     #[validate(length(min = 3))]
     pub api_name: String,
     #[validate(length(min = 3))]
@@ -82,7 +150,24 @@ pub struct DbFullApiService {
     pub version: String,
     #[validate(length(min = 1))]
     pub environment: String,
+    pub max_body_size: Option<u64>,
+    pub tls: Option<ServiceTlsConfig>,
+    #[serde(default)]
+    pub targets: Vec<UpstreamTarget>,
+    #[serde(default)]
+    pub load_balancing: LoadBalancingStrategy,
+    pub health_check: Option<HealthCheckConfig>,
+    pub timeouts: Option<TimeoutConfig>,
+    pub retry: Option<RetryConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    pub websocket: Option<WebSocketConfig>,
+
+    #[serde(default)]
+    pub streaming: bool,
     pub roles: Vec<DbApiRole>,
+    #[serde(default)]
+    pub health: Vec<DbServiceHealth>,
 }
 
 impl From<&DbFullApiService> for WebResponseApiService {
@@ -98,6 +183,7 @@ impl From<&DbFullApiService> for WebResponseApiService {
         }
         Self {
             id: format!("{}", other.id.id),
+            revision: other.revision,
             api_name: other.api_name.clone(),
             forward_url: other.forward_url.clone(),
             active: other.active,
@@ -105,6 +191,17 @@ impl From<&DbFullApiService> for WebResponseApiService {
             role_namespaces: namespaces,
             roles: roles.clone(),
             environment: other.environment.clone(),
+            max_body_size: other.max_body_size,
+            tls: other.tls.clone(),
+            targets: other.targets.clone(),
+            load_balancing: other.load_balancing,
+            health_check: other.health_check.clone(),
+            timeouts: other.timeouts.clone(),
+            retry: other.retry.clone(),
+            circuit_breaker: other.circuit_breaker.clone(),
+            websocket: other.websocket.clone(),
+            streaming: other.streaming,
+            health: other.health.iter().map(Into::into).collect(),
         }
     }
 }
@@ -124,11 +221,36 @@ pub struct DbApiServiceRequest {
 
     #[validate(length(min = 1))]
     pub environment: String,
+
+    pub max_body_size: Option<u64>,
+
+    pub tls: Option<ServiceTlsConfig>,
+
+    #[serde(default)]
+    pub targets: Vec<UpstreamTarget>,
+
+    #[serde(default)]
+    pub load_balancing: LoadBalancingStrategy,
+
+    pub health_check: Option<HealthCheckConfig>,
+
+    pub timeouts: Option<TimeoutConfig>,
+
+    pub retry: Option<RetryConfig>,
+
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    pub websocket: Option<WebSocketConfig>,
+
+    #[serde(default)]
+    pub streaming: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Validate, Clone)]
 pub struct DbApiServiceRecord {
     pub id: Thing,
+    #[serde(default)]
+    pub revision: u64,
     #[validate(length(min = 3))]
     pub api_name: String,
 
@@ -142,6 +264,29 @@ pub struct DbApiServiceRecord {
 
     #[validate(length(min = 1))]
     pub environment: String,
+
+    pub max_body_size: Option<u64>,
+
+    pub tls: Option<ServiceTlsConfig>,
+
+    #[serde(default)]
+    pub targets: Vec<UpstreamTarget>,
+
+    #[serde(default)]
+    pub load_balancing: LoadBalancingStrategy,
+
+    pub health_check: Option<HealthCheckConfig>,
+
+    pub timeouts: Option<TimeoutConfig>,
+
+    pub retry: Option<RetryConfig>,
+
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    pub websocket: Option<WebSocketConfig>,
+
+    #[serde(default)]
+    pub streaming: bool,
 }
 
 impl From<&WebRequestApiService> for DbApiServiceRequest {
@@ -152,6 +297,38 @@ impl From<&WebRequestApiService> for DbApiServiceRequest {
             active: value.active.clone(),
             version: value.version.clone(),
             environment: value.environment.clone(),
+            max_body_size: value.max_body_size,
+            tls: value.tls.clone(),
+            targets: value.targets.clone(),
+            load_balancing: value.load_balancing,
+            health_check: value.health_check.clone(),
+            timeouts: value.timeouts.clone(),
+            retry: value.retry.clone(),
+            circuit_breaker: value.circuit_breaker.clone(),
+            websocket: value.websocket.clone(),
+            streaming: value.streaming,
+        }
+    }
+}
+
+impl From<&DbFullApiService> for DbApiServiceRequest {
+    fn from(value: &DbFullApiService) -> Self {
+        Self {
+            api_name: value.api_name.clone(),
+            forward_url: value.forward_url.clone(),
+            active: value.active,
+            version: value.version.clone(),
+            environment: value.environment.clone(),
+            max_body_size: value.max_body_size,
+            tls: value.tls.clone(),
+            targets: value.targets.clone(),
+            load_balancing: value.load_balancing,
+            health_check: value.health_check.clone(),
+            timeouts: value.timeouts.clone(),
+            retry: value.retry.clone(),
+            circuit_breaker: value.circuit_breaker.clone(),
+            websocket: value.websocket.clone(),
+            streaming: value.streaming,
         }
     }
 }
@@ -160,12 +337,24 @@ impl From<(&DbApiServiceRecord, &Vec<DbApiRole>)> for DbFullApiService {
     fn from((service, roles): (&DbApiServiceRecord, &Vec<DbApiRole>)) -> Self {
         Self {
             id: service.id.clone(),
+            revision: service.revision,
             api_name: service.api_name.clone(),
             forward_url: service.forward_url.clone(),
             active: service.active,
             version: service.version.clone(),
             roles: roles.clone(),
             environment: service.environment.clone(),
+            max_body_size: service.max_body_size,
+            tls: service.tls.clone(),
+            targets: service.targets.clone(),
+            load_balancing: service.load_balancing,
+            health_check: service.health_check.clone(),
+            timeouts: service.timeouts.clone(),
+            retry: service.retry.clone(),
+            circuit_breaker: service.circuit_breaker.clone(),
+            websocket: service.websocket.clone(),
+            streaming: service.streaming,
+            health: Vec::new(),
         }
     }
 }
@@ -191,6 +380,34 @@ pub struct WebRequestPartialApiService {
 
     #[validate(length(min = 1))]
     pub environment: Option<String>,
+
+    #[validate(range(min = 1))]
+    pub max_body_size: Option<u64>,
+
+    #[validate]
+    pub tls: Option<ServiceTlsConfig>,
+
+    #[validate]
+    pub targets: Option<Vec<UpstreamTarget>>,
+
+    pub load_balancing: Option<LoadBalancingStrategy>,
+
+    #[validate]
+    pub health_check: Option<HealthCheckConfig>,
+
+    #[validate]
+    pub timeouts: Option<TimeoutConfig>,
+
+    #[validate]
+    pub retry: Option<RetryConfig>,
+
+    #[validate]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    #[validate]
+    pub websocket: Option<WebSocketConfig>,
+
+    pub streaming: Option<bool>,
 }
 
 impl From<&WebRequestPartialApiService> for Vec<WebApiRole> {
@@ -203,6 +420,7 @@ impl From<&WebRequestPartialApiService> for Vec<WebApiRole> {
             for namespace in namespaces {
                 all_roles.push(WebApiRole {
                     id: None,
+                    revision: None,
                     namespace: namespace.clone(),
                     name: NAMESPACE_MEMBER_ROLE.into(),
                 })
@@ -213,9 +431,205 @@ impl From<&WebRequestPartialApiService> for Vec<WebApiRole> {
     }
 }
 
+fn default_target_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq, Eq, Hash)]
+pub struct UpstreamTarget {
+    #[validate(length(min = 3))]
+    pub url: String,
+
+    #[serde(default = "default_target_weight")]
+    #[validate(range(min = 1))]
+    pub weight: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancingStrategy {
+    #[default]
+    RoundRobin,
+    WeightedRandom,
+    LeastOutstanding,
+}
+
+impl DbFullApiService {
+    /// The upstream pool for this service, falling back to `forward_url`
+    /// when no explicit targets are configured.
+    pub fn upstream_targets(&self) -> Vec<UpstreamTarget> {
+        if self.targets.is_empty() {
+            vec![UpstreamTarget {
+                url: self.forward_url.clone(),
+                weight: default_target_weight(),
+            }]
+        } else {
+            self.targets.clone()
+        }
+    }
+}
+
+fn default_health_check_interval() -> u64 {
+    30
+}
+
+fn default_health_check_timeout() -> u64 {
+    5
+}
+
+fn default_health_check_status() -> u16 {
+    200
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    // Path appended to each upstream target's URL, e.g. `/health`
+    #[validate(length(min = 1))]
+    pub path: String,
+
+    #[serde(default = "default_health_check_interval")]
+    #[validate(range(min = 1))]
+    pub interval_secs: u64,
+
+    #[serde(default = "default_health_check_timeout")]
+    #[validate(range(min = 1))]
+    pub timeout_secs: u64,
+
+    #[serde(default = "default_health_check_status")]
+    #[validate(range(min = 100, max = 599))]
+    pub expected_status: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq, Eq)]
+pub struct TimeoutConfig {
+    // Time allowed to establish the upstream connection
+    #[validate(range(min = 1))]
+    pub connect_ms: Option<u64>,
+
+    // Time allowed for the whole exchange, across retries and including the response
+    // body. Streaming services wait for response headers as long as it takes, and are
+    // cut off once their response body stays silent this long
+    #[validate(range(min = 1))]
+    pub request_ms: Option<u64>,
+}
+
+fn default_retry_backoff() -> u64 {
+    100
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq, Eq)]
+pub struct RetryConfig {
+    // Additional attempts after the first one fails
+    #[validate(range(max = 10))]
+    pub attempts: u32,
+
+    // Delay before the first retry, doubled for each following retry
+    #[serde(default = "default_retry_backoff")]
+    pub backoff_ms: u64,
+
+    // Also retry POST/PATCH requests, which may not be safe to repeat
+    #[serde(default)]
+    pub retry_non_idempotent: bool,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_cooldown() -> u64 {
+    30
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    // Consecutive failed requests that open the circuit
+    #[serde(default = "default_failure_threshold")]
+    #[validate(range(min = 1))]
+    pub failure_threshold: u32,
+
+    // Time the circuit stays open before a single trial request is let through
+    #[serde(default = "default_cooldown")]
+    #[validate(range(min = 1))]
+    pub cooldown_secs: u64,
+}
+
+fn default_websocket_idle_timeout() -> u64 {
+    300
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq, Eq)]
+pub struct WebSocketConfig {
+    // Accept `Upgrade: websocket` requests for this service. Off unless set,
+    // as when the whole section is left out
+    #[serde(default)]
+    pub enabled: bool,
+
+    // Close the connection when no frame passes in either direction for this long
+    #[serde(default = "default_websocket_idle_timeout")]
+    #[validate(range(min = 1))]
+    pub idle_timeout_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbServiceHealth {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub service: Thing,
+    pub target: String,
+    pub healthy: bool,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+    pub checked_at: Datetime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebServiceHealth {
+    pub target: String,
+    pub healthy: bool,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+    pub checked_at: Datetime,
+}
+
+impl From<&DbServiceHealth> for WebServiceHealth {
+    fn from(value: &DbServiceHealth) -> Self {
+        Self {
+            target: value.target.clone(),
+            healthy: value.healthy,
+            status_code: value.status_code,
+            error: value.error.clone(),
+            latency_ms: value.latency_ms,
+            checked_at: value.checked_at.clone(),
+        }
+    }
+}
+
+fn default_tls_verify() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq, Eq, Hash)]
+pub struct ServiceTlsConfig {
+    // Verify the upstream certificate chain and hostname
+    #[serde(default = "default_tls_verify")]
+    pub verify: bool,
+
+    // PEM bundle of additional CA certificates trusted for this upstream
+    #[validate(length(min = 1))]
+    pub ca_bundle: Option<String>,
+
+    // Server name presented to (and verified against) the upstream
+    #[validate(length(min = 1))]
+    pub sni_override: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq, Eq)]
 pub struct DbApiRole {
     pub id: Option<Thing>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
     #[validate(length(min = 3))]
     pub namespace: String,
 
@@ -226,6 +640,9 @@ pub struct DbApiRole {
 #[derive(Debug, Serialize, Deserialize, Validate, Clone)]
 pub struct WebApiRole {
     pub id: Option<String>,
+    // Only known for roles read from the database
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
 
     #[validate(length(min = 3))]
     pub namespace: String,
@@ -241,6 +658,7 @@ impl From<&WebApiRole> for DbApiRole {
                 Some(web_id) => Some(Thing::from((API_ROLE_TABLE.to_string(), web_id.clone()))),
                 _ => None,
             },
+            revision: web_record.revision,
             namespace: web_record.namespace.clone(),
             name: web_record.name.clone(),
         }
@@ -264,6 +682,7 @@ impl From<&DbApiRole> for WebApiRole {
                 Some(thing) => Some(format!("{}", thing.id)),
                 _ => None,
             },
+            revision: db_record.revision,
             namespace: db_record.namespace.clone(),
             name: db_record.name.clone(),
         }
@@ -280,9 +699,189 @@ impl fmt::Display for WebApiRole {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
-pub struct RelatedAuthorizations {
-    pub authorizations: Vec<Thing>,
+// Parses a qualified `Namespace::Name` role
+impl FromStr for WebApiRole {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(ROLE_NAMESPACE_DELIMITER) {
+            Some((namespace, name)) if !namespace.is_empty() && !name.is_empty() => Ok(Self {
+                id: None,
+                revision: None,
+                namespace: namespace.to_string(),
+                name: name.to_string(),
+            }),
+            _ => Err(format!(
+                "expected a role in the form Namespace{}Name, got [{}]",
+                ROLE_NAMESPACE_DELIMITER, value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ServiceListParams {
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default = "default_page_size")]
+    pub limit: u64,
+    // Matched case-insensitively against the API name
+    pub q: Option<String>,
+    pub active: Option<bool>,
+    pub environment: Option<String>,
+    // A `Namespace::Name` role the services must authorize
+    pub role: Option<String>,
+    pub sort: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleListParams {
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default = "default_page_size")]
+    pub limit: u64,
+    // Matched case-insensitively against the namespace and name
+    pub q: Option<String>,
+    pub namespace: Option<String>,
+    pub sort: Option<String>,
+}
+
+/// The kind of change a service revision records.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceChange {
+    Create,
+    Update,
+    Delete,
+    Rollback,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbServiceRevision {
+    pub id: Thing,
+    pub service: Thing,
+    pub action: ServiceChange,
+    // Missing for changes made from the command line
+    pub changed_by: Option<Thing>,
+    pub changed_at: Datetime,
+    // The service and its role names on either side of the change; missing
+    // before it was created and after it was deleted
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub rolled_back_to: Option<Thing>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebServiceRevision {
+    pub id: String,
+    pub service_id: String,
+    pub action: ServiceChange,
+    pub changed_by: Option<String>,
+    pub changed_at: Datetime,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub diff: Vec<FieldChange>,
+    pub rolled_back_to: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FieldChange {
+    // Nested settings are named by their path, e.g. `tls.verify`
+    pub field: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+impl From<&DbServiceRevision> for WebServiceRevision {
+    fn from(value: &DbServiceRevision) -> Self {
+        Self {
+            id: format!("{}", value.id.id),
+            service_id: format!("{}", value.service.id),
+            action: value.action,
+            changed_by: value.changed_by.as_ref().map(|user| format!("{}", user.id)),
+            changed_at: value.changed_at.clone(),
+            before: value.before.clone(),
+            after: value.after.clone(),
+            diff: snapshot_diff(value.before.as_ref(), value.after.as_ref()),
+            rolled_back_to: value
+                .rolled_back_to
+                .as_ref()
+                .map(|revision| format!("{}", revision.id)),
+        }
+    }
+}
+
+/// A service as its history records it, which can be written back.
+#[derive(Debug, Deserialize)]
+pub struct DbServiceSnapshot {
+    #[serde(flatten)]
+    pub service: DbApiServiceRequest,
+    // Qualified `Namespace::Name` roles
+    #[serde(default)]
+    pub roles: Vec<String>,
+    // The id and qualified name of each role, so a rollback finds roles renamed
+    // since; missing from revisions recorded before role ids were
+    #[serde(default)]
+    pub role_ids: Vec<(String, String)>,
+}
+
+// Bookkeeping fields that change with every write, and the role ids, whose
+// changes show in `roles`
+const UNTRACKED_FIELDS: &[&str] = &["revision", "last_modified_date", "role_ids"];
+
+// Lists the fields that differ between two snapshots of a service
+fn snapshot_diff(before: Option<&Value>, after: Option<&Value>) -> Vec<FieldChange> {
+    let nothing = Value::Object(Map::new());
+    let mut changes = Vec::new();
+    diff_values(
+        "",
+        Some(before.unwrap_or(&nothing)),
+        Some(after.unwrap_or(&nothing)),
+        &mut changes,
+    );
+    changes
+}
+
+fn diff_values(
+    path: &str,
+    before: Option<&Value>,
+    after: Option<&Value>,
+    changes: &mut Vec<FieldChange>,
+) {
+    // A null setting is the same as a missing one
+    let before = before.filter(|value| !value.is_null());
+    let after = after.filter(|value| !value.is_null());
+    match (before, after) {
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            let fields: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+            for field in fields {
+                if path.is_empty() && UNTRACKED_FIELDS.contains(&field.as_str()) {
+                    continue;
+                }
+                let field_path = if path.is_empty() {
+                    field.clone()
+                } else {
+                    format!("{}.{}", path, field)
+                };
+                diff_values(&field_path, before.get(field), after.get(field), changes);
+            }
+        }
+        _ if before == after => {}
+        _ => changes.push(FieldChange {
+            field: path.to_string(),
+            before: before.cloned(),
+            after: after.cloned(),
+        }),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevisionListParams {
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default = "default_page_size")]
+    pub limit: u64,
+    pub action: Option<ServiceChange>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Validate, Clone)]
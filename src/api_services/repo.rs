@@ -1,19 +1,33 @@
-use std::collections::{BTreeMap, HashSet};
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
-use super::models::{self, DbApiServiceRecord};
-use crate::database::{Database, API_ROLE_TABLE, API_SERVICE_TABLE, AUTHORIZATIONS_TABLE};
+use super::models;
+use crate::audit::models::{Actor, AuditAction};
+use crate::audit::repo::audit_within;
+use crate::database::{
+    Database, Transaction, API_ROLE_TABLE, API_SERVICE_TABLE, AUDIT_TABLE, AUTHORIZATIONS_TABLE,
+    ROLE_MEMBER_TABLE, ROLE_NAMESPACE_DELIMITER, SERVICE_HEALTH_TABLE, SERVICE_REVISION_TABLE,
+};
 use crate::errors::{GatewayError, Result};
+use crate::pagination::{Page, PageQuery};
+use crate::revisions::Precondition;
 use actix_web::web::Data;
 use async_trait::async_trait;
-use serde_json::{to_value, Value};
-use surrealdb::method::Patch;
-use surrealdb::sql::Thing;
+use serde::Serialize;
+use serde_json::{json, to_value, Value};
+use surrealdb::sql::{Id, Thing};
 use surrealdb::Result as dbResult;
-use surrealdb::{opt::PatchOp, sql::Datetime};
+
+// Versions that the endpoints beside `/{api_name}/{version}` would capture
+const RESERVED_VERSIONS: &[&str] = &["health", "revisions"];
 
 #[async_trait]
 pub trait ApiServiceRepository {
     async fn list_services(repo: &Data<Database>) -> Result<Vec<models::DbFullApiService>>;
+    async fn search_services(
+        repo: &Data<Database>,
+        params: &models::ServiceListParams,
+    ) -> Result<Page<models::DbFullApiService>>;
     async fn get_service_with_roles(
         repo: &Data<Database>,
         api_name: &String,
@@ -22,46 +36,36 @@ pub trait ApiServiceRepository {
     async fn add_service(
         repo: &Data<Database>,
         new_service: &models::DbApiServiceRequest,
-        roles: &Vec<models::DbApiRole>,
+        roles: &[models::WebApiRole],
+        actor: &Actor,
     ) -> Result<models::DbFullApiService>;
     async fn update_service(
         repo: &Data<Database>,
         service_id: &String,
         partial_update: &models::WebRequestPartialApiService,
+        precondition: &Precondition,
+        actor: &Actor,
+    ) -> Result<models::DbFullApiService>;
+    async fn replace_service(
+        repo: &Data<Database>,
+        service_id: &String,
+        service: &models::DbApiServiceRequest,
+        roles: &[models::WebApiRole],
+        actor: &Actor,
+    ) -> Result<models::DbFullApiService>;
+    async fn delete_service(repo: &Data<Database>, service_name: &str, actor: &Actor)
+        -> Result<()>;
+    async fn list_service_revisions(
+        repo: &Data<Database>,
+        service_id: &str,
+        params: &models::RevisionListParams,
+    ) -> Result<Page<models::DbServiceRevision>>;
+    async fn rollback_service(
+        repo: &Data<Database>,
+        service_id: &str,
+        revision_id: &str,
+        actor: &Actor,
     ) -> Result<models::DbFullApiService>;
-    async fn delete_service(repo: &Data<Database>, service_name: &str) -> Result<()>;
-}
-
-pub async fn setup_service_table_events(repo: &Database) -> std::io::Result<()> {
-    repo.define_index(
-        API_SERVICE_TABLE,
-        "serviceNamedVersionIndex",
-        vec!["api_name", "version"],
-        Some("UNIQUE"),
-    )
-    .await?;
-    repo.automate_created_date(API_SERVICE_TABLE).await?;
-    repo.automate_last_modified_date(API_SERVICE_TABLE).await?;
-
-    repo.define_index(
-        API_ROLE_TABLE,
-        "namespacedRoleNameIndex",
-        vec!["namespace", "name"],
-        Some("UNIQUE"),
-    )
-    .await?;
-    repo.automate_created_date(API_ROLE_TABLE).await?;
-    repo.automate_last_modified_date(API_ROLE_TABLE).await?;
-
-    repo.define_index(
-        AUTHORIZATIONS_TABLE,
-        "roleAuthorizationsIndex",
-        vec!["in", "out"],
-        Some("UNIQUE"),
-    )
-    .await?;
-    repo.automate_created_date(AUTHORIZATIONS_TABLE).await?;
-    Ok(())
 }
 
 #[async_trait]
@@ -69,14 +73,64 @@ impl ApiServiceRepository for Database {
     async fn list_services(repo: &Data<Database>) -> Result<Vec<models::DbFullApiService>> {
         repo.query_list::<models::DbFullApiService>(
             format!(
-                "SELECT *, <-authorizes<-role.* as roles FROM {}",
-                API_SERVICE_TABLE
+                "SELECT *, <-authorizes<-role.* as roles, \
+                (SELECT * FROM {} WHERE service = $parent.id) as health FROM {}",
+                SERVICE_HEALTH_TABLE, API_SERVICE_TABLE
             ),
             None::<String>,
         )
         .await
     }
 
+    async fn search_services(
+        repo: &Data<Database>,
+        params: &models::ServiceListParams,
+    ) -> Result<Page<models::DbFullApiService>> {
+        let mut query = PageQuery::new(
+            format!(
+                "*, <-{}<-role.* as roles, \
+                (SELECT * FROM {} WHERE service = $parent.id) as health",
+                AUTHORIZATIONS_TABLE, SERVICE_HEALTH_TABLE
+            ),
+            API_SERVICE_TABLE,
+            params.offset,
+            params.limit,
+            params.sort.as_deref(),
+            &[
+                "api_name",
+                "version",
+                "environment",
+                "created_date",
+                "last_modified_date",
+            ],
+            "api_name,version",
+        )?;
+        if let Some(search) = &params.q {
+            query = query
+                .condition("string::contains(string::lowercase(api_name), $search)")
+                .bind("search", search.to_lowercase())?;
+        }
+        if let Some(active) = params.active {
+            query = query.condition("active = $active").bind("active", active)?;
+        }
+        if let Some(environment) = &params.environment {
+            query = query
+                .condition("environment = $environment")
+                .bind("environment", environment)?;
+        }
+        if let Some(role) = &params.role {
+            let role: models::WebApiRole = role.parse().map_err(GatewayError::BadRequest)?;
+            query = query
+                .condition(format!(
+                    "array::len(<-{}<-role[WHERE namespace = $role_namespace AND name = $role_name]) > 0",
+                    AUTHORIZATIONS_TABLE
+                ))
+                .bind("role_namespace", role.namespace)?
+                .bind("role_name", role.name)?;
+        }
+        query.fetch(repo).await
+    }
+
     async fn get_service_with_roles(
         repo: &Data<Database>,
         api_name: &String,
@@ -99,13 +153,18 @@ impl ApiServiceRepository for Database {
         .into();
         let query_result: Vec<models::DbFullApiService> = repo
             .query_list(
-                "\
-            SELECT *, <-authorizes<-role.* as roles FROM type::table($table) \
+                format!(
+                    "\
+            SELECT *, <-authorizes<-role.* as roles, \
+            (SELECT * FROM {} WHERE service = $parent.id) as health \
+            FROM type::table($table) \
             WHERE active = TRUE AND \
             api_name = $api_name AND \
             version = $version \
             LIMIT 1\
             ",
+                    SERVICE_HEALTH_TABLE
+                ),
                 Some(bind_vars),
             )
             .await?;
@@ -125,136 +184,431 @@ impl ApiServiceRepository for Database {
     async fn add_service(
         repo: &Data<Database>,
         new_service: &models::DbApiServiceRequest,
-        roles: &Vec<models::DbApiRole>,
+        roles: &[models::WebApiRole],
+        actor: &Actor,
     ) -> Result<models::DbFullApiService> {
-        let query_result: Vec<models::DbApiServiceRecord> = repo
-            .db
-            .create(API_SERVICE_TABLE)
-            .content(new_service)
-            .await
-            .map_err(Into::<GatewayError>::into)?;
+        check_version(&new_service.version)?;
+        let service_db_id = Thing::from((API_SERVICE_TABLE, Id::rand()));
+        let transaction = begin_service_change(Transaction::new().bind("service", &service_db_id)?)
+            .statement("CREATE $service CONTENT $content")
+            .bind("content", new_service)?;
+        let transaction = sync_service_roles(transaction, &service_db_id, roles)?;
+        repo.commit(record_service_change(
+            transaction,
+            models::ServiceChange::Create,
+            actor,
+            None,
+        )?)
+        .await?;
 
-        let added_service = query_result.first().ok_or(GatewayError::DatabaseError(
-            "Unable to insert record.".to_string(),
-        ))?;
-
-        for role in roles {
-            if let Some(role_id) = &role.id {
-                repo.relate(role_id, &added_service.id, AUTHORIZATIONS_TABLE, None)
-                    .await?;
-            }
-        }
-        Ok((added_service, roles).into())
+        let added_service = service_record(repo, &service_db_id).await?;
+        let roles = Database::roles_for_service(repo, &service_db_id).await?;
+        Ok((&added_service, &roles).into())
     }
 
     async fn update_service(
         repo: &Data<Database>,
         service_id: &String,
         partial_update: &models::WebRequestPartialApiService,
+        precondition: &Precondition,
+        actor: &Actor,
     ) -> Result<models::DbFullApiService> {
-        // Serialize the PartialApiServiceUpdate struct to a serde_json Value
-        let service_db_id = Thing::from((API_SERVICE_TABLE.to_string(), service_id.clone()));
-        let mut new_roles: Vec<models::DbApiRole> = Vec::new();
-        for web_role in Vec::<models::WebApiRole>::from(partial_update).iter() {
-            if let Some(_) = &web_role.id {
-                new_roles.push(web_role.into());
-            } else {
-                match Database::find_role(&repo, &web_role.namespace, &web_role.name).await {
-                    Ok(role) => new_roles.push(role),
-                    Err(GatewayError::NotFound(_r, _m)) => {
-                        new_roles.push(Database::add_role(&repo, web_role).await?)
-                    }
-                    Err(err) => return Err(err),
-                }
-            }
+        if let Some(version) = &partial_update.version {
+            check_version(version)?;
         }
+        let service_db_id = Thing::from((API_SERVICE_TABLE.to_string(), service_id.clone()));
+        let existing = service_record(repo, &service_db_id).await?;
+        precondition.check(existing.revision)?;
 
-        let mut new_role_ids: HashSet<Thing> = HashSet::new();
-        for role in &new_roles {
-            new_role_ids.insert(role.id.clone().unwrap());
-        }
-        let existing_roles = Database::roles_for_service(repo, &service_db_id).await?;
-        let mut existing_role_ids: HashSet<Thing> = HashSet::new();
-        for role in existing_roles {
-            existing_role_ids.insert(role.id.unwrap());
-        }
-        for role_to_remove in existing_role_ids.difference(&new_role_ids) {
-            repo.unrelate(
-                role_to_remove,
-                &service_db_id,
-                &AUTHORIZATIONS_TABLE.to_string(),
-            )
-            .await?;
-        }
-        for role_to_add in new_role_ids.difference(&existing_role_ids) {
-            repo.relate(
-                role_to_add,
-                &service_db_id,
-                &AUTHORIZATIONS_TABLE.to_string(),
-                None,
-            )
-            .await?;
+        // Authorizations are only replaced when roles are given
+        let transaction = Transaction::new().bind("service", &service_db_id)?;
+        let mut transaction = begin_service_change(precondition.guard(transaction, "service")?);
+        if partial_update.roles.is_some() || partial_update.role_namespaces.is_some() {
+            let new_roles = Vec::<models::WebApiRole>::from(partial_update);
+            transaction = sync_service_roles(transaction, &service_db_id, &new_roles)?;
         }
 
         let update_data: Value =
-            to_value(partial_update).map_err(|e| GatewayError::MissingData(e.to_string()))?; // Handle this unwrap more gracefully in production code
-        if let Value::Object(fields) = update_data {
-            // Start constructing the update query for the specific service ID
-            let mut patch_request: Patch<
-                '_,
-                surrealdb::engine::local::Db,
-                Option<models::DbApiServiceRecord>,
-            > = repo
-                .db
-                .update((API_SERVICE_TABLE, service_id))
-                .patch(PatchOp::replace("/last_modified", Datetime::default()));
-
-            let relationship_fields: Vec<String> = vec!["roles".into(), "role_namespaces".into()];
-            // Iterate over the fields in the JSON object
-            for (key, value) in fields {
-                // Skip fields that are null or not provided in the partial update
-                if !value.is_null() && !relationship_fields.contains(&key) {
-                    // Construct the JSON Pointer string
-                    let prop_path = format!("/{}", &key);
-
-                    // Apply a patch operation for the current field
-                    patch_request = patch_request.patch(PatchOp::replace(&prop_path, value));
-                }
-            }
-
-            let patch_result = patch_request
-                .await
-                .map_err(Into::<GatewayError>::into)?
-                .ok_or(GatewayError::DatabaseError(String::from(
-                    "Empty response from Database on API Service patch update.",
-                )))?;
-            Ok((&patch_result, &new_roles).into())
-        } else {
-            Err(GatewayError::MissingData(String::from(
+            to_value(partial_update).map_err(|e| GatewayError::MissingData(e.to_string()))?;
+        let Value::Object(fields) = update_data else {
+            // The serialized update data is not an object, which shouldn't happen in correct implementations
+            return Err(GatewayError::MissingData(String::from(
                 "Didn't understand the input data",
-            ))) // The serialized update data is not an object, which shouldn't happen in correct implementations
+            )));
+        };
+        let relationship_fields: Vec<String> = vec!["roles".into(), "role_namespaces".into()];
+        // Fields that are null or not provided in the partial update are left as they are
+        let patch: Vec<Value> = fields
+            .into_iter()
+            .filter(|(key, value)| !value.is_null() && !relationship_fields.contains(key))
+            .map(|(key, value)| json!({"op": "replace", "path": format!("/{}", key), "value": value}))
+            .collect();
+        let transaction = transaction
+            .statement("UPDATE $service PATCH $patch")
+            .bind("patch", patch)?;
+        repo.commit(record_service_change(
+            transaction,
+            models::ServiceChange::Update,
+            actor,
+            None,
+        )?)
+        .await?;
+
+        let updated_service = service_record(repo, &service_db_id).await?;
+        let roles = Database::roles_for_service(repo, &service_db_id).await?;
+        Ok((&updated_service, &roles).into())
+    }
+
+    async fn replace_service(
+        repo: &Data<Database>,
+        service_id: &String,
+        service: &models::DbApiServiceRequest,
+        roles: &[models::WebApiRole],
+        actor: &Actor,
+    ) -> Result<models::DbFullApiService> {
+        check_version(&service.version)?;
+        let service_db_id = Thing::from((API_SERVICE_TABLE.to_string(), service_id.clone()));
+        service_record(repo, &service_db_id).await?;
+        // Merging unsets any optional setting the new definition leaves out
+        let transaction = begin_service_change(Transaction::new().bind("service", &service_db_id)?)
+            .statement("UPDATE $service MERGE $content")
+            .bind("content", service)?;
+        let transaction = sync_service_roles(transaction, &service_db_id, roles)?;
+        repo.commit(record_service_change(
+            transaction,
+            models::ServiceChange::Update,
+            actor,
+            None,
+        )?)
+        .await?;
+
+        let replaced = service_record(repo, &service_db_id).await?;
+        let roles = Database::roles_for_service(repo, &service_db_id).await?;
+        Ok((&replaced, &roles).into())
+    }
+
+    async fn delete_service(repo: &Data<Database>, service_id: &str, actor: &Actor) -> Result<()> {
+        let service_db_id = Thing::from((API_SERVICE_TABLE, service_id));
+        service_record(repo, &service_db_id).await?;
+        // Deleting the service also deletes its authorizations, and its health
+        // with its targets
+        let transaction = begin_service_change(Transaction::new().bind("service", &service_db_id)?)
+            .statement("DELETE $service");
+        repo.commit(record_service_change(
+            transaction,
+            models::ServiceChange::Delete,
+            actor,
+            None,
+        )?)
+        .await
+    }
+
+    async fn list_service_revisions(
+        repo: &Data<Database>,
+        service_id: &str,
+        params: &models::RevisionListParams,
+    ) -> Result<Page<models::DbServiceRevision>> {
+        let mut query = PageQuery::new(
+            "*",
+            SERVICE_REVISION_TABLE,
+            params.offset,
+            params.limit,
+            None,
+            &["changed_at"],
+            "-changed_at",
+        )?
+        .condition("service = $service")
+        .bind("service", Thing::from((API_SERVICE_TABLE, service_id)))?;
+        if let Some(action) = params.action {
+            query = query.condition("action = $action").bind("action", action)?;
+        }
+        query.fetch(repo).await
+    }
+
+    async fn rollback_service(
+        repo: &Data<Database>,
+        service_id: &str,
+        revision_id: &str,
+        actor: &Actor,
+    ) -> Result<models::DbFullApiService> {
+        let service_db_id = Thing::from((API_SERVICE_TABLE, service_id));
+        let revision: Option<models::DbServiceRevision> = repo
+            .db
+            .select((SERVICE_REVISION_TABLE, revision_id))
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        let revision = revision
+            .filter(|revision| revision.service == service_db_id)
+            .ok_or(GatewayError::NotFound(
+                String::from("Service Revision"),
+                format!(
+                    "No revision [{}] of service [{}] found.",
+                    revision_id, service_id
+                ),
+            ))?;
+        let Some(snapshot) = revision.after.clone() else {
+            return Err(GatewayError::BadRequest(format!(
+                "Revision [{}] deleted the service; roll back to an earlier revision instead",
+                revision_id
+            )));
+        };
+        let snapshot: models::DbServiceSnapshot = serde_json::from_value(snapshot)
+            .map_err(|e| GatewayError::DatabaseError(e.to_string()))?;
+
+        // Roles are found by id, so that roles renamed since keep their new
+        // name, and roles deleted since are created again
+        let mut roles: Vec<models::WebApiRole> = Vec::new();
+        for role_name in &snapshot.roles {
+            let mut role =
+                models::WebApiRole::from_str(role_name).map_err(GatewayError::DatabaseError)?;
+            role.id = snapshot
+                .role_ids
+                .iter()
+                .find(|(_, name)| name == role_name)
+                .map(|(id, _)| id.clone());
+            roles.push(role);
         }
+
+        // A deleted service is restored under its old id
+        let existing: Option<models::DbApiServiceRecord> = repo
+            .db
+            .select(&service_db_id)
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        let write = match existing {
+            Some(_) => "UPDATE $service MERGE $content",
+            None => "CREATE $service CONTENT $content",
+        };
+        let transaction = begin_service_change(Transaction::new().bind("service", &service_db_id)?)
+            .statement(write)
+            .bind("content", &snapshot.service)?;
+        let transaction = sync_service_roles(transaction, &service_db_id, &roles)?;
+        repo.commit(record_service_change(
+            transaction,
+            models::ServiceChange::Rollback,
+            actor,
+            Some(&revision.id),
+        )?)
+        .await?;
+
+        let restored = service_record(repo, &service_db_id).await?;
+        let roles = Database::roles_for_service(repo, &service_db_id).await?;
+        Ok((&restored, &roles).into())
     }
+}
+
+async fn service_record(
+    repo: &Data<Database>,
+    service_db_id: &Thing,
+) -> Result<models::DbApiServiceRecord> {
+    let record: Option<models::DbApiServiceRecord> = repo
+        .db
+        .select(service_db_id)
+        .await
+        .map_err(Into::<GatewayError>::into)?;
+    record.ok_or(GatewayError::NotFound(
+        String::from("API Service"),
+        format!("No service with id [{}] found.", service_db_id.id),
+    ))
+}
+
+fn check_version(version: &str) -> Result<()> {
+    if RESERVED_VERSIONS.contains(&version) {
+        return Err(GatewayError::BadRequest(format!(
+            "[{}] is reserved and can't be used as a version",
+            version
+        )));
+    }
+    Ok(())
+}
+
+// Adds the statements relating the service to exactly the given roles. A role
+// is found by its id when it has one that still exists, and otherwise by its
+// namespace and name, being created in the same transaction if need be.
+fn sync_service_roles(
+    transaction: Transaction,
+    service_db_id: &Thing,
+    roles: &[models::WebApiRole],
+) -> Result<Transaction> {
+    // Bound as a struct rather than JSON, which would lose the record ids
+    #[derive(Serialize)]
+    struct WantedRole<'a> {
+        role: Option<Thing>,
+        key: [&'a str; 2],
+    }
+    let wanted_roles: Vec<WantedRole> = roles
+        .iter()
+        .map(|role| WantedRole {
+            role: role
+                .id
+                .as_ref()
+                .map(|id| Thing::from((API_ROLE_TABLE, id.as_str()))),
+            key: [&role.namespace, &role.name],
+        })
+        .collect();
+    transaction
+        .statement(format!(
+            "LET $role_names = (SELECT VALUE key FROM $wanted_roles WHERE role.id = NONE);\n\
+            FOR $role_name IN $role_names {{\n\
+                IF array::len(SELECT id FROM {roles} WHERE namespace = $role_name[0] AND name = $role_name[1]) = 0 {{\n\
+                    CREATE {roles} CONTENT {{ namespace: $role_name[0], name: $role_name[1] }};\n\
+                }};\n\
+            }};\n\
+            LET $roles = array::union((SELECT VALUE role.id FROM $wanted_roles WHERE role.id != NONE), \
+            (SELECT VALUE id FROM {roles} WHERE [namespace, name] INSIDE $role_names));\n\
+            DELETE {table} WHERE out = $service AND in NOTINSIDE $roles;\n\
+            FOR $role IN $roles {{\n\
+                IF array::len(SELECT id FROM {table} WHERE in = $role AND out = $service) = 0 {{\n\
+                    RELATE $role->{table}->$service;\n\
+                }};\n\
+            }}",
+            roles = API_ROLE_TABLE,
+            table = AUTHORIZATIONS_TABLE
+        ))
+        .bind("service", service_db_id)?
+        .bind("wanted_roles", wanted_roles)
+}
+
+// The service bound to `$service` as its history records it, along with the
+// sorted names of its roles and their ids; NONE when there is no such service
+fn service_snapshot() -> String {
+    format!(
+        "(SELECT *, array::sort((SELECT VALUE namespace + '{delimiter}' + name FROM <-{table}<-{roles})) AS roles, \
+        (SELECT VALUE [meta::id(id), namespace + '{delimiter}' + name] FROM <-{table}<-{roles}) AS role_ids \
+        OMIT id FROM ONLY $service)",
+        delimiter = ROLE_NAMESPACE_DELIMITER,
+        table = AUTHORIZATIONS_TABLE,
+        roles = API_ROLE_TABLE
+    )
+}
+
+// Records a revision of the service bound to `$service`, from the snapshots
+// taken in `$service_before` and `$service_after`, and audits it
+fn revision_statement() -> String {
+    format!(
+        "LET $revision = (CREATE ONLY {} CONTENT {{ service: $service, action: $action, \
+        changed_by: $changed_by, before: $service_before, after: $service_after, \
+        rolled_back_to: $rolled_back_to }});\n\
+        CREATE {} CONTENT {{ action: $audit_action, actor: $changed_by, actor_name: $actor_name, \
+        source_ip: $source_ip, target: $service, details: {{ revision: meta::id($revision.id) }} }}",
+        SERVICE_REVISION_TABLE, AUDIT_TABLE
+    )
+}
+
+// Snapshots the service bound to `$service` ahead of the statements that change it
+fn begin_service_change(transaction: Transaction) -> Transaction {
+    transaction.statement(format!("LET $service_before = {}", service_snapshot()))
+}
 
-    async fn delete_service(repo: &Data<Database>, service_id: &str) -> Result<()> {
+// The URLs of the targets of the service bound to `$service`, which are
+// none once it has been deleted
+fn service_target_urls() -> &'static str {
+    "(IF array::len($service.targets ?? []) > 0 { $service.targets.url } \
+    ELSE IF $service.forward_url != NONE { [$service.forward_url] } ELSE { [] })"
+}
+
+// Records the changes made to the service since `begin_service_change`
+fn record_service_change(
+    transaction: Transaction,
+    action: models::ServiceChange,
+    actor: &Actor,
+    rolled_back_to: Option<&Thing>,
+) -> Result<Transaction> {
+    let transaction = transaction
+        // The health of targets the service no longer has goes with them
+        .statement(format!(
+            "DELETE {} WHERE service = $service AND target NOTINSIDE {}",
+            SERVICE_HEALTH_TABLE,
+            service_target_urls()
+        ))
+        .statement(format!("LET $service_after = {}", service_snapshot()))
+        .statement(revision_statement());
+    bind_revision(transaction, action, actor, rolled_back_to)
+}
+
+// Binds the details of the revisions `revision_statement` records
+fn bind_revision(
+    transaction: Transaction,
+    action: models::ServiceChange,
+    actor: &Actor,
+    rolled_back_to: Option<&Thing>,
+) -> Result<Transaction> {
+    let audit_action = match action {
+        models::ServiceChange::Create => AuditAction::ServiceCreated,
+        models::ServiceChange::Update => AuditAction::ServiceUpdated,
+        models::ServiceChange::Delete => AuditAction::ServiceDeleted,
+        models::ServiceChange::Rollback => AuditAction::ServiceRolledBack,
+    };
+    let entry = actor.entry(audit_action, None, Value::Null);
+    transaction
+        .bind("action", action)?
+        .bind("audit_action", entry.action)?
+        .bind("changed_by", entry.actor)?
+        .bind("actor_name", entry.actor_name)?
+        .bind("source_ip", entry.source_ip)?
+        .bind("rolled_back_to", rolled_back_to)
+}
+
+#[async_trait]
+pub trait ServiceHealthRepository {
+    async fn record_health(repo: &Data<Database>, result: &models::DbServiceHealth) -> Result<()>;
+    async fn service_health(
+        repo: &Data<Database>,
+        service_id: &String,
+    ) -> Result<Vec<models::DbServiceHealth>>;
+}
+
+#[async_trait]
+impl ServiceHealthRepository for Database {
+    async fn record_health(repo: &Data<Database>, result: &models::DbServiceHealth) -> Result<()> {
+        // One record per service target, keyed on [service, target]. A check
+        // that finishes after its target was removed is dropped
         repo.db
-            .delete((API_SERVICE_TABLE, service_id))
+            .query(format!(
+                "IF $target INSIDE {} {{ UPDATE type::thing($table, [$service, $target]) CONTENT $result }}",
+                service_target_urls()
+            ))
+            .bind(("table", SERVICE_HEALTH_TABLE))
+            .bind(("service", &result.service))
+            .bind(("target", &result.target))
+            .bind(("result", result))
             .await
-            .or_else(|err| Err(err.into()))
-            .and_then(
-                |response: Option<models::DbApiServiceRecord>| match response {
-                    Some(_) => Ok(()),
-                    None => Err(GatewayError::DatabaseError(String::from(
-                        "Unable to delete api service entry",
-                    ))),
-                },
-            )
+            .map_err(Into::<GatewayError>::into)?
+            .check()
+            .map_err(Into::<GatewayError>::into)?;
+        Ok(())
+    }
+
+    async fn service_health(
+        repo: &Data<Database>,
+        service_id: &String,
+    ) -> Result<Vec<models::DbServiceHealth>> {
+        let bind_params: BTreeMap<String, surrealdb::sql::Value> = [
+            ("table".to_string(), SERVICE_HEALTH_TABLE.into()),
+            (
+                "service_id".to_string(),
+                surrealdb::sql::Value::Thing(Thing::from((
+                    API_SERVICE_TABLE.to_string(),
+                    service_id.clone(),
+                ))),
+            ),
+        ]
+        .into();
+        repo.query_list(
+            "SELECT * FROM type::table($table) WHERE service = $service_id ORDER BY target",
+            Some(bind_params),
+        )
+        .await
     }
 }
 
 #[async_trait]
 pub trait RoleRepository {
     async fn list_roles(repo: &Data<Database>) -> Result<Vec<models::DbApiRole>>;
+    async fn search_roles(
+        repo: &Data<Database>,
+        params: &models::RoleListParams,
+    ) -> Result<Page<models::DbApiRole>>;
     async fn find_role(
         repo: &Data<Database>,
         namespace: &String,
@@ -272,12 +626,18 @@ pub trait RoleRepository {
         repo: &Data<Database>,
         new_role: &models::WebApiRole,
     ) -> Result<models::DbApiRole>;
+    async fn find_or_add_role(
+        repo: &Data<Database>,
+        role: &models::WebApiRole,
+    ) -> Result<models::DbApiRole>;
     async fn rename_role(
         repo: &Data<Database>,
         role_id: &String,
         role_update: &models::WebApiRole,
+        precondition: &Precondition,
+        actor: &Actor,
     ) -> Result<models::DbApiRole>;
-    async fn delete_role(repo: &Data<Database>, service_name: &str) -> Result<()>;
+    async fn delete_role(repo: &Data<Database>, role_id: &str, actor: &Actor) -> Result<()>;
 }
 
 #[async_trait]
@@ -289,6 +649,35 @@ impl RoleRepository for Database {
             .map_err(Into::<GatewayError>::into)
     }
 
+    async fn search_roles(
+        repo: &Data<Database>,
+        params: &models::RoleListParams,
+    ) -> Result<Page<models::DbApiRole>> {
+        let mut query = PageQuery::new(
+            "*",
+            API_ROLE_TABLE,
+            params.offset,
+            params.limit,
+            params.sort.as_deref(),
+            &["namespace", "name", "created_date", "last_modified_date"],
+            "namespace,name",
+        )?;
+        if let Some(search) = &params.q {
+            query = query
+                .condition(
+                    "(string::contains(string::lowercase(namespace), $search) \
+                    OR string::contains(string::lowercase(name), $search))",
+                )
+                .bind("search", search.to_lowercase())?;
+        }
+        if let Some(namespace) = &params.namespace {
+            query = query
+                .condition("namespace = $namespace")
+                .bind("namespace", namespace)?;
+        }
+        query.fetch(repo).await
+    }
+
     async fn find_role(
         repo: &Data<Database>,
         namespace: &String,
@@ -411,54 +800,298 @@ impl RoleRepository for Database {
         }
     }
 
+    async fn find_or_add_role(
+        repo: &Data<Database>,
+        role: &models::WebApiRole,
+    ) -> Result<models::DbApiRole> {
+        if role.id.is_some() {
+            return Ok(role.into());
+        }
+        match Database::find_role(repo, &role.namespace, &role.name).await {
+            Ok(found_role) => Ok(found_role),
+            Err(GatewayError::NotFound(_r, _m)) => Database::add_role(repo, role).await,
+            Err(err) => Err(err),
+        }
+    }
+
     async fn rename_role(
         repo: &Data<Database>,
         role_id: &String,
         role_update: &models::WebApiRole,
+        precondition: &Precondition,
+        actor: &Actor,
     ) -> Result<models::DbApiRole> {
-        let update_result: Option<models::DbApiRole> = repo
+        let role_db_id = Thing::from((API_ROLE_TABLE, role_id.as_str()));
+        // UPDATE would otherwise create a missing role
+        let existing = role_record(repo, &role_db_id).await?;
+        precondition.check(existing.revision.unwrap_or_default())?;
+
+        // Each service the role authorizes records a revision under the new
+        // name, so rolling back to an earlier one doesn't bring the old name back
+        let transaction = Transaction::new().bind("role", &role_db_id)?;
+        let transaction = precondition
+            .guard(transaction, "role")?
+            .statement(format!(
+                "LET $old_name = $role.namespace + '{delimiter}' + $role.name;\n\
+                UPDATE $role MERGE {{ namespace: $namespace, name: $name }};\n\
+                LET $new_name = $namespace + '{delimiter}' + $name;\n\
+                IF $old_name != $new_name {{\n\
+                    FOR $service IN (SELECT VALUE out FROM {table} WHERE in = $role) {{\n\
+                        LET $service_after = {snapshot};\n\
+                        LET $service_before = (SELECT *, array::sort(array::union(\
+                        array::complement(roles, [$new_name]), [$old_name])) AS roles \
+                        FROM ONLY $service_after);\n\
+                        {revision};\n\
+                    }};\n\
+                }}",
+                delimiter = ROLE_NAMESPACE_DELIMITER,
+                table = AUTHORIZATIONS_TABLE,
+                snapshot = service_snapshot(),
+                revision = revision_statement()
+            ))
+            .bind("namespace", &role_update.namespace)?
+            .bind("name", &role_update.name)?;
+        repo.commit(bind_revision(
+            transaction,
+            models::ServiceChange::Update,
+            actor,
+            None,
+        )?)
+        .await?;
+        role_record(repo, &role_db_id).await
+    }
+
+    async fn delete_role(repo: &Data<Database>, role_id: &str, actor: &Actor) -> Result<()> {
+        let role_db_id = Thing::from((API_ROLE_TABLE, role_id));
+        let role = role_record(repo, &role_db_id).await?;
+        // Deleting the role revokes it from its members
+        let members: Vec<Thing> = repo
             .db
-            .update((API_ROLE_TABLE, role_id))
-            .patch(PatchOp::replace("/last_modified", Datetime::default()))
-            .patch(PatchOp::replace("/namespace", &role_update.namespace))
-            .patch(PatchOp::replace("/name", &role_update.name))
+            .query(format!(
+                "SELECT VALUE in FROM {} WHERE out = $role",
+                ROLE_MEMBER_TABLE
+            ))
+            .bind(("role", &role_db_id))
+            .await
+            .map_err(Into::<GatewayError>::into)?
+            .take(0)
+            .map_err(Into::<GatewayError>::into)?;
+        let revocations = members
+            .into_iter()
+            .map(|member| {
+                actor.entry(
+                    AuditAction::RoleRevoked,
+                    Some(member),
+                    json!({ "role": role.to_string() }),
+                )
+            })
+            .collect();
+        // Each service the role authorized records a revision without it
+        let transaction = Transaction::new()
+            .statement(format!(
+                "FOR $service IN (SELECT VALUE out FROM {table} WHERE in = $role) {{\n\
+                    LET $service_before = {snapshot};\n\
+                    DELETE {table} WHERE in = $role AND out = $service;\n\
+                    LET $service_after = {snapshot};\n\
+                    {revision};\n\
+                }}",
+                table = AUTHORIZATIONS_TABLE,
+                snapshot = service_snapshot(),
+                revision = revision_statement()
+            ))
+            .statement("DELETE $role")
+            .bind("role", &role_db_id)?;
+        let transaction = audit_within(transaction, revocations)?;
+        repo.commit(bind_revision(
+            transaction,
+            models::ServiceChange::Update,
+            actor,
+            None,
+        )?)
+        .await
+    }
+}
+
+async fn role_record(repo: &Data<Database>, role_db_id: &Thing) -> Result<models::DbApiRole> {
+    let record: Option<models::DbApiRole> = repo
+        .db
+        .select(role_db_id)
+        .await
+        .map_err(Into::<GatewayError>::into)?;
+    record.ok_or(GatewayError::NotFound(
+        String::from("Role"),
+        format!("No role with id [{}] found.", role_db_id.id),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::{count, database, fail_creating};
+
+    fn service_request(roles: serde_json::Value) -> models::WebRequestApiService {
+        serde_json::from_value(json!({
+            "api_name": "orders",
+            "version": "v1",
+            "forward_url": "http://localhost:9000",
+            "active": true,
+            "environment": "dev",
+            "role_namespaces": [],
+            "roles": roles,
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn add_service_leaves_nothing_behind_when_it_fails() {
+        let repo = database().await;
+        fail_creating(&repo, SERVICE_REVISION_TABLE).await;
+
+        let request = service_request(json!([{ "namespace": "Shop", "name": "Reader" }]));
+        let roles = Vec::<models::WebApiRole>::from(&request);
+        let added =
+            Database::add_service(&repo, &(&request).into(), &roles, &Actor::default()).await;
+
+        assert!(added.is_err());
+        assert_eq!(count(&repo, API_SERVICE_TABLE).await, 0);
+        assert_eq!(count(&repo, API_ROLE_TABLE).await, 0);
+        assert_eq!(count(&repo, AUTHORIZATIONS_TABLE).await, 0);
+        assert_eq!(count(&repo, AUDIT_TABLE).await, 0);
+    }
+
+    #[actix_web::test]
+    async fn update_service_leaves_nothing_behind_when_it_fails() {
+        let repo = database().await;
+        let request = service_request(json!([{ "namespace": "Shop", "name": "Reader" }]));
+        let roles = Vec::<models::WebApiRole>::from(&request);
+        let added = Database::add_service(&repo, &(&request).into(), &roles, &Actor::default())
             .await
-            .map_err(GatewayError::from)?;
+            .unwrap();
+        fail_creating(&repo, SERVICE_REVISION_TABLE).await;
 
-        update_result.ok_or(GatewayError::DatabaseError(format!(
-            "Unable to update role {}",
-            role_id
-        )))
+        let update: models::WebRequestPartialApiService = serde_json::from_value(json!({
+            "environment": "prod",
+            "roles": [{ "namespace": "Shop", "name": "Writer" }],
+        }))
+        .unwrap();
+        let updated = Database::update_service(
+            &repo,
+            &added.id.id.to_string(),
+            &update,
+            &Precondition::Any,
+            &Actor::default(),
+        )
+        .await;
+
+        assert!(updated.is_err());
+        let service = service_record(&repo, &added.id).await.unwrap();
+        assert_eq!(service.environment, "dev");
+        assert_eq!(service.revision, added.revision);
+        let roles = Database::roles_for_service(&repo, &added.id).await.unwrap();
+        assert_eq!(
+            roles.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            ["Shop::Reader"]
+        );
+        assert!(Database::find_role(&repo, &"Shop".into(), &"Writer".into())
+            .await
+            .is_err());
+        assert_eq!(count(&repo, SERVICE_REVISION_TABLE).await, 1);
     }
 
-    async fn delete_role(repo: &Data<Database>, role_id: &str) -> Result<()> {
-        let auth_results: Vec<models::RelatedAuthorizations> = repo
-            .query_list(
-                "SELECT ->authorizes.id as authorizations FROM $role_id",
-                Some((
-                    "role_id".to_string(),
-                    surrealdb::sql::Value::Thing((API_ROLE_TABLE, role_id).into()),
-                )),
-            )
-            .await?;
-        for role in auth_results {
-            for role_auth in role.authorizations {
-                let _: Option<DbApiServiceRecord> = repo
-                    .db
-                    .delete(role_auth)
-                    .await
-                    .map_err(Into::<GatewayError>::into)?;
+    #[actix_web::test]
+    async fn rollback_keeps_the_name_a_role_was_renamed_to() {
+        let repo = database().await;
+        let request = service_request(json!([{ "namespace": "Shop", "name": "Reader" }]));
+        let roles = Vec::<models::WebApiRole>::from(&request);
+        let added = Database::add_service(&repo, &(&request).into(), &roles, &Actor::default())
+            .await
+            .unwrap();
+        let reader = Database::find_role(&repo, &"Shop".into(), &"Reader".into())
+            .await
+            .unwrap();
+        let role_id = reader.id.unwrap().id.to_string();
+        let renamed: models::WebApiRole =
+            serde_json::from_value(json!({ "id": null, "namespace": "Shop", "name": "Viewer" }))
+                .unwrap();
+        Database::rename_role(
+            &repo,
+            &role_id,
+            &renamed,
+            &Precondition::Any,
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+
+        let service_id = added.id.id.to_string();
+        let revisions = Database::list_service_revisions(
+            &repo,
+            &service_id,
+            &serde_json::from_value(json!({})).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(revisions.items.len(), 2);
+        let created = revisions
+            .items
+            .iter()
+            .find(|revision| revision.action == models::ServiceChange::Create)
+            .unwrap();
+        let rolled_back = Database::rollback_service(
+            &repo,
+            &service_id,
+            &created.id.id.to_string(),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            rolled_back
+                .roles
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["Shop::Viewer"]
+        );
+        assert!(Database::find_role(&repo, &"Shop".into(), &"Reader".into())
+            .await
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn health_keeps_the_latest_check_of_each_target() {
+        let repo = database().await;
+        let request = service_request(json!([]));
+        let added = Database::add_service(&repo, &(&request).into(), &[], &Actor::default())
+            .await
+            .unwrap();
+        let check = |healthy: bool, status_code: Option<u16>, error: Option<&str>| {
+            models::DbServiceHealth {
+                id: None,
+                service: added.id.clone(),
+                target: request.forward_url.clone(),
+                healthy,
+                status_code,
+                error: error.map(String::from),
+                latency_ms: 12,
+                checked_at: surrealdb::sql::Datetime::default(),
             }
-        }
-        repo.db
-            .delete((API_ROLE_TABLE, role_id))
+        };
+
+        Database::record_health(&repo, &check(false, None, Some("Connection refused")))
             .await
-            .or_else(|err| Err(GatewayError::DatabaseError(err.to_string())))
-            .and_then(|response: Option<models::DbApiRole>| match response {
-                Some(_) => Ok(()),
-                None => Err(GatewayError::DatabaseError(String::from(
-                    "Unable to delete api service entry",
-                ))),
-            })
+            .unwrap();
+        Database::record_health(&repo, &check(true, Some(200), None))
+            .await
+            .unwrap();
+
+        let health = Database::service_health(&repo, &added.id.id.to_string())
+            .await
+            .unwrap();
+        assert_eq!(health.len(), 1);
+        assert!(health[0].healthy);
+        assert_eq!(health[0].status_code, Some(200));
+        assert_eq!(health[0].error, None);
     }
 }
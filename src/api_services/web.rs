@@ -1,18 +1,22 @@
 use actix_web::{
     delete, get, patch, post, put,
-    web::{scope, to, Data, Json, Path, ServiceConfig},
+    web::{scope, to, Data, Json, Path, Query, ServiceConfig},
     HttpRequest, HttpResponse,
 };
 use serde::Deserialize;
 
+use crate::audit::models::Actor;
+use crate::auth::web::validate_jwt;
 use crate::database::Database;
 use crate::errors::{unknown_resource_error, Result};
-use crate::{auth::web::validate_jwt, errors::GatewayError};
+use crate::pagination::page_response;
+use crate::revisions::{etag, tagged_response, Precondition};
 
 use super::models::{
-    DbApiRole, WebApiRole, WebRequestApiService, WebRequestPartialApiService, WebResponseApiService,
+    RevisionListParams, RoleListParams, ServiceListParams, WebApiRole, WebRequestApiService,
+    WebRequestPartialApiService, WebResponseApiService, WebServiceHealth, WebServiceRevision,
 };
-use super::repo::{ApiServiceRepository, RoleRepository};
+use super::repo::{ApiServiceRepository, RoleRepository, ServiceHealthRepository};
 
 // Intermediate function to configure services
 pub fn service_setup(cfg: &mut ServiceConfig) {
@@ -22,6 +26,11 @@ pub fn service_setup(cfg: &mut ServiceConfig) {
             .service(add_service)
             .service(patch_service)
             // .service(http::update_service)
+            // Register health and revisions before the named version lookup
+            // So that they don't get captured as a version
+            .service(service_health)
+            .service(list_service_revisions)
+            .service(rollback_service)
             .service(get_service_by_name_and_version)
             .service(delete_service)
             .default_service(to(unknown_resource_error)),
@@ -39,15 +48,15 @@ pub fn service_setup(cfg: &mut ServiceConfig) {
 #[get("/")]
 async fn list_services(
     req: HttpRequest,
+    params: Query<ServiceListParams>,
     repo: Data<Database>,
-) -> Result<Json<Vec<WebResponseApiService>>> {
+) -> Result<HttpResponse> {
     validate_jwt(&req, Some(&vec!["Gateway::Admin", "Gateway::BasicMember"]))?;
-    let api_services = Database::list_services(&repo).await?;
-    let api_services: Vec<WebResponseApiService> = api_services
-        .iter()
-        .map(|service| WebResponseApiService::from(service))
-        .collect();
-    Ok(Json(api_services))
+    let api_services = Database::search_services(&repo, &params).await?;
+    Ok(page_response(
+        &req,
+        api_services.map(|service| WebResponseApiService::from(&service)),
+    ))
 }
 
 #[derive(Deserialize)]
@@ -61,13 +70,17 @@ async fn get_role_by_namespace_and_name(
     req: HttpRequest,
     path_params: Path<ApiRoleQualifiedNamePath>,
     repo: Data<Database>,
-) -> Result<Json<WebApiRole>> {
+) -> Result<HttpResponse> {
     validate_jwt(&req, Some(&vec!["Gateway::Admin", "Gateway::BasicMember"]))?;
     let parsed_path = path_params.into_inner();
     let namespace = parsed_path.namespace;
     let name = parsed_path.name;
     let found_role = Database::find_role(&repo, &namespace, &name).await?;
-    Ok(Json(WebApiRole::from(&found_role)))
+    Ok(tagged_response(
+        &req,
+        found_role.revision.unwrap_or_default(),
+        &WebApiRole::from(&found_role),
+    ))
 }
 
 #[derive(Deserialize)]
@@ -81,13 +94,17 @@ async fn get_service_by_name_and_version(
     req: HttpRequest,
     path_params: Path<ApiServiceNamedVersionPath>,
     repo: Data<Database>,
-) -> Result<Json<WebResponseApiService>> {
+) -> Result<HttpResponse> {
     validate_jwt(&req, Some(&vec!["Gateway::Admin", "Gateway::BasicMember"]))?;
     let parsed_path = path_params.into_inner();
     let api_name = parsed_path.api_name;
     let version = parsed_path.version;
     let found_service = Database::get_service_with_roles(&repo, &api_name, &version).await?;
-    Ok(Json(WebResponseApiService::from(&found_service)))
+    Ok(tagged_response(
+        &req,
+        found_service.revision,
+        &WebResponseApiService::from(&found_service),
+    ))
 }
 
 #[post("/")]
@@ -95,30 +112,21 @@ async fn add_service(
     req: HttpRequest,
     service: Json<WebRequestApiService>,
     repo: Data<Database>,
-) -> Result<Json<WebResponseApiService>> {
-    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
     let service_to_add = service.into_inner();
     let roles: Vec<WebApiRole> = Vec::<WebApiRole>::from(&service_to_add);
-    let mut db_roles: Vec<DbApiRole> = Vec::new();
-    for role in roles.iter() {
-        match &role.id {
-            Some(_) => db_roles.push(role.into()),
-            None => match Database::find_role(&repo, &role.namespace, &role.name).await {
-                Ok(role) => db_roles.push(role),
-
-                Err(GatewayError::NotFound(_t, _m)) => {
-                    db_roles.push(Database::add_role(&repo, &role).await?.into())
-                }
-                Err(other_error) => {
-                    return Err(other_error);
-                }
-            },
-        };
-    }
-
-    let created_service =
-        Database::add_service(&repo, &(&service_to_add).into(), &db_roles).await?;
-    Ok(Json(WebResponseApiService::from(&created_service)))
+
+    let created_service = Database::add_service(
+        &repo,
+        &(&service_to_add).into(),
+        &roles,
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(created_service.revision))
+        .json(WebResponseApiService::from(&created_service)))
 }
 
 #[derive(Deserialize)]
@@ -132,12 +140,33 @@ async fn patch_service(
     path_params: Path<ApiServiceIdPath>,
     service: Json<WebRequestPartialApiService>,
     repo: Data<Database>,
-) -> Result<Json<WebResponseApiService>> {
-    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let precondition = Precondition::from_request(&req)?;
     let service_id = path_params.into_inner().service_id;
-    let patched_service =
-        Database::update_service(&repo, &service_id, &service.into_inner().into()).await?;
-    Ok(Json(WebResponseApiService::from(&patched_service)))
+    let patched_service = Database::update_service(
+        &repo,
+        &service_id,
+        &service.into_inner().into(),
+        &precondition,
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(patched_service.revision))
+        .json(WebResponseApiService::from(&patched_service)))
+}
+
+#[get("/{service_id}/health")]
+async fn service_health(
+    req: HttpRequest,
+    path_params: Path<ApiServiceIdPath>,
+    repo: Data<Database>,
+) -> Result<Json<Vec<WebServiceHealth>>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin", "Gateway::BasicMember"]))?;
+    let service_id = path_params.into_inner().service_id;
+    let health = Database::service_health(&repo, &service_id).await?;
+    Ok(Json(health.iter().map(Into::into).collect()))
 }
 
 #[delete("/{service_id}")]
@@ -146,22 +175,75 @@ async fn delete_service(
     path_params: Path<ApiServiceIdPath>,
     repo: Data<Database>,
 ) -> Result<HttpResponse> {
-    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
     let service_id = path_params.into_inner().service_id;
-    Database::delete_service(&repo, service_id.as_str()).await?;
+    Database::delete_service(
+        &repo,
+        service_id.as_str(),
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
     Ok(HttpResponse::NoContent().finish())
 }
 
+#[get("/{service_id}/revisions")]
+async fn list_service_revisions(
+    req: HttpRequest,
+    path_params: Path<ApiServiceIdPath>,
+    params: Query<RevisionListParams>,
+    repo: Data<Database>,
+) -> Result<HttpResponse> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let service_id = path_params.into_inner().service_id;
+    let revisions = Database::list_service_revisions(&repo, &service_id, &params).await?;
+    Ok(page_response(
+        &req,
+        revisions.map(|revision| WebServiceRevision::from(&revision)),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ServiceRevisionPath {
+    pub service_id: String,
+    pub revision_id: String,
+}
+
+#[post("/{service_id}/revisions/{revision_id}/rollback")]
+async fn rollback_service(
+    req: HttpRequest,
+    path_params: Path<ServiceRevisionPath>,
+    repo: Data<Database>,
+) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let path = path_params.into_inner();
+    let restored_service = Database::rollback_service(
+        &repo,
+        &path.service_id,
+        &path.revision_id,
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(restored_service.revision))
+        .json(WebResponseApiService::from(&restored_service)))
+}
+
 /**
  * Role management
  */
 
 #[get("/")]
-async fn list_roles(req: HttpRequest, repo: Data<Database>) -> Result<Json<Vec<WebApiRole>>> {
+async fn list_roles(
+    req: HttpRequest,
+    params: Query<RoleListParams>,
+    repo: Data<Database>,
+) -> Result<HttpResponse> {
     validate_jwt(&req, Some(&vec!["Gateway::Admin", "Gateway::BasicMember"]))?;
-    let api_roles = Database::list_roles(&repo).await?;
-    let api_roles = api_roles.iter().map(Into::into).collect();
-    Ok(Json(api_roles))
+    let api_roles = Database::search_roles(&repo, &params).await?;
+    Ok(page_response(
+        &req,
+        api_roles.map(|role| WebApiRole::from(&role)),
+    ))
 }
 
 #[post("/")]
@@ -169,10 +251,12 @@ async fn add_role(
     req: HttpRequest,
     role: Json<WebApiRole>,
     repo: Data<Database>,
-) -> Result<Json<WebApiRole>> {
+) -> Result<HttpResponse> {
     validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
     let created_role = Database::add_role(&repo, &role.into_inner()).await?;
-    Ok(Json(WebApiRole::from(&created_role)))
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(created_role.revision.unwrap_or_default()))
+        .json(WebApiRole::from(&created_role)))
 }
 
 #[derive(Deserialize)]
@@ -186,11 +270,21 @@ async fn rename_role(
     path_params: Path<ApiRoleIdPath>,
     role: Json<WebApiRole>,
     repo: Data<Database>,
-) -> Result<Json<WebApiRole>> {
-    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let precondition = Precondition::from_request(&req)?;
     let role_id = path_params.into_inner().role_id;
-    let updated_role = Database::rename_role(&repo, &role_id, &role.into_inner()).await?;
-    Ok(Json(WebApiRole::from(&updated_role)))
+    let updated_role = Database::rename_role(
+        &repo,
+        &role_id,
+        &role.into_inner(),
+        &precondition,
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(updated_role.revision.unwrap_or_default()))
+        .json(WebApiRole::from(&updated_role)))
 }
 
 #[delete("/{role_id}")]
@@ -199,8 +293,8 @@ async fn delete_role(
     path_params: Path<ApiRoleIdPath>,
     repo: Data<Database>,
 ) -> Result<HttpResponse> {
-    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
     let role_id = path_params.into_inner().role_id;
-    Database::delete_role(&repo, role_id.as_str()).await?;
+    Database::delete_role(&repo, role_id.as_str(), &Actor::from_claims(&req, &claims)).await?;
     Ok(HttpResponse::NoContent().finish())
 }
@@ -0,0 +1,158 @@
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surrealdb::sql::{Datetime, Thing};
+
+use crate::auth::models::GatewayUserClaims;
+use crate::database::USER_TABLE;
+use crate::pagination::default_page_size;
+
+/// Who is making a change, and from where. Neither is known for changes made
+/// from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Actor {
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    pub source_ip: Option<String>,
+}
+
+impl Actor {
+    /// The signed in user making the request.
+    pub fn from_claims(req: &HttpRequest, claims: &GatewayUserClaims) -> Self {
+        Self {
+            user_id: Some(claims.sub_id.clone()),
+            username: Some(claims.sub.clone()),
+            source_ip: source_ip(req),
+        }
+    }
+
+    /// A request made without signing in, such as a login.
+    pub fn anonymous(req: &HttpRequest) -> Self {
+        Self {
+            user_id: None,
+            username: None,
+            source_ip: source_ip(req),
+        }
+    }
+
+    /// An audit entry for an action taken by this actor.
+    pub fn entry(
+        &self,
+        action: AuditAction,
+        target: Option<Thing>,
+        details: Value,
+    ) -> DbAuditEntryRequest {
+        DbAuditEntryRequest {
+            action,
+            actor: self
+                .user_id
+                .as_ref()
+                .map(|user_id| Thing::from((USER_TABLE, user_id.as_str()))),
+            actor_name: self.username.clone(),
+            source_ip: self.source_ip.clone(),
+            target,
+            details,
+        }
+    }
+}
+
+// The connecting address, which unlike forwarding headers can't be forged
+fn source_ip(req: &HttpRequest) -> Option<String> {
+    req.peer_addr().map(|address| address.ip().to_string())
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    LoginSucceeded,
+    LoginFailed,
+    PasswordResetRequested,
+    PasswordResetUsed,
+    PasswordChanged,
+    SessionRevoked,
+    RefreshTokenReused,
+    SigningKeyRotated,
+    AuthorizationCodeReused,
+    OidcClientCreated,
+    OidcClientUpdated,
+    OidcClientDeleted,
+    FederatedIdentityLinked,
+    UserCreated,
+    UserUpdated,
+    RoleGranted,
+    RoleRevoked,
+    ServiceCreated,
+    ServiceUpdated,
+    ServiceDeleted,
+    ServiceRolledBack,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DbAuditEntryRequest {
+    pub action: AuditAction,
+    pub actor: Option<Thing>,
+    pub actor_name: Option<String>,
+    pub source_ip: Option<String>,
+    // The user or service acted on
+    pub target: Option<Thing>,
+    pub details: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbAuditEntry {
+    pub id: Thing,
+    pub at: Datetime,
+    pub action: AuditAction,
+    pub actor: Option<Thing>,
+    pub actor_name: Option<String>,
+    pub source_ip: Option<String>,
+    pub target: Option<Thing>,
+    pub details: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebAuditEntry {
+    pub id: String,
+    pub at: Datetime,
+    pub action: AuditAction,
+    // The acting user's id
+    pub actor: Option<String>,
+    pub actor_name: Option<String>,
+    pub source_ip: Option<String>,
+    // The record acted on, as `table:id`
+    pub target: Option<String>,
+    pub details: Value,
+}
+
+impl From<&DbAuditEntry> for WebAuditEntry {
+    fn from(value: &DbAuditEntry) -> Self {
+        Self {
+            id: format!("{}", value.id.id),
+            at: value.at.clone(),
+            action: value.action,
+            actor: value.actor.as_ref().map(|actor| format!("{}", actor.id)),
+            actor_name: value.actor_name.clone(),
+            source_ip: value.source_ip.clone(),
+            target: value.target.as_ref().map(ToString::to_string),
+            details: value.details.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditListParams {
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default = "default_page_size")]
+    pub limit: u64,
+    pub action: Option<AuditAction>,
+    // The acting user's id
+    pub actor: Option<String>,
+    // The record acted on, as `table:id`
+    pub target: Option<String>,
+    pub source_ip: Option<String>,
+    // RFC 3339 timestamps bounding when the entries were written
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub sort: Option<String>,
+}
@@ -0,0 +1,188 @@
+use std::str::FromStr;
+
+use actix_web::web::Data;
+use async_trait::async_trait;
+use surrealdb::sql::{Datetime, Thing};
+
+use super::models::{AuditListParams, DbAuditEntry, DbAuditEntryRequest};
+use crate::database::{Database, Transaction, AUDIT_TABLE, USER_TABLE};
+use crate::errors::{GatewayError, Result};
+use crate::pagination::{Page, PageQuery, MAX_PAGE_SIZE};
+
+#[async_trait]
+pub trait AuditRepository {
+    async fn add_audit_entry(repo: &Data<Database>, entry: &DbAuditEntryRequest) -> Result<()>;
+    async fn search_audit(
+        repo: &Data<Database>,
+        params: &AuditListParams,
+    ) -> Result<Page<DbAuditEntry>>;
+    async fn export_audit_page(
+        repo: &Data<Database>,
+        params: &AuditListParams,
+        after: Option<&DbAuditEntry>,
+    ) -> Result<Vec<DbAuditEntry>>;
+}
+
+#[async_trait]
+impl AuditRepository for Database {
+    async fn add_audit_entry(repo: &Data<Database>, entry: &DbAuditEntryRequest) -> Result<()> {
+        let _: Vec<DbAuditEntry> = repo
+            .db
+            .create(AUDIT_TABLE)
+            .content(entry)
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        Ok(())
+    }
+
+    async fn search_audit(
+        repo: &Data<Database>,
+        params: &AuditListParams,
+    ) -> Result<Page<DbAuditEntry>> {
+        let query = PageQuery::new(
+            "*",
+            AUDIT_TABLE,
+            params.offset,
+            params.limit,
+            params.sort.as_deref(),
+            &["at", "action"],
+            "-at",
+        )?;
+        filter_audit(query, params)?.fetch(repo).await
+    }
+
+    // The matching entries following `after`, oldest first. Pages are found
+    // by the last entry read rather than by offset, so that reading every one
+    // doesn't go through the log again for each page
+    async fn export_audit_page(
+        repo: &Data<Database>,
+        params: &AuditListParams,
+        after: Option<&DbAuditEntry>,
+    ) -> Result<Vec<DbAuditEntry>> {
+        let mut query = PageQuery::new("*", AUDIT_TABLE, 0, MAX_PAGE_SIZE, None, &["at"], "at")?;
+        if let Some(after) = after {
+            query = query
+                .condition("(at > $after_at OR (at = $after_at AND id > $after_id))")
+                .bind("after_at", &after.at)?
+                .bind("after_id", &after.id)?;
+        }
+        filter_audit(query, params)?.fetch_items(repo).await
+    }
+}
+
+/// Writes the entry, logging rather than returning a failure, since the
+/// action it describes has already happened.
+pub async fn record(repo: &Data<Database>, entry: DbAuditEntryRequest) {
+    if let Err(e) = Database::add_audit_entry(repo, &entry).await {
+        log::error!(
+            "Unable to record {:?} in the audit log: {}",
+            entry.action,
+            e
+        );
+    }
+}
+
+/// Adds a statement writing the entries, so that they are only kept if the
+/// rest of the transaction is.
+pub fn audit_within(
+    transaction: Transaction,
+    entries: Vec<DbAuditEntryRequest>,
+) -> Result<Transaction> {
+    if entries.is_empty() {
+        return Ok(transaction);
+    }
+    transaction
+        .statement(format!(
+            "FOR $audit_entry IN $audit_entries {{ CREATE {} CONTENT $audit_entry; }}",
+            AUDIT_TABLE
+        ))
+        .bind("audit_entries", entries)
+}
+
+fn filter_audit(mut query: PageQuery, params: &AuditListParams) -> Result<PageQuery> {
+    if let Some(action) = params.action {
+        query = query.condition("action = $action").bind("action", action)?;
+    }
+    if let Some(actor) = &params.actor {
+        query = query
+            .condition("actor = $actor")
+            .bind("actor", Thing::from((USER_TABLE, actor.as_str())))?;
+    }
+    if let Some(target) = &params.target {
+        let target = surrealdb::sql::thing(target).map_err(|_| {
+            GatewayError::BadRequest(format!(
+                "target must be a record id such as service:abc, not [{}]",
+                target
+            ))
+        })?;
+        query = query.condition("target = $target").bind("target", target)?;
+    }
+    if let Some(source_ip) = &params.source_ip {
+        query = query
+            .condition("source_ip = $source_ip")
+            .bind("source_ip", source_ip)?;
+    }
+    if let Some(since) = &params.since {
+        query = query
+            .condition("at >= $since")
+            .bind("since", parse_timestamp("since", since)?)?;
+    }
+    if let Some(until) = &params.until {
+        query = query
+            .condition("at < $until")
+            .bind("until", parse_timestamp("until", until)?)?;
+    }
+    Ok(query)
+}
+
+fn parse_timestamp(parameter: &str, value: &str) -> Result<Datetime> {
+    Datetime::from_str(value).map_err(|_| {
+        GatewayError::BadRequest(format!(
+            "{} must be an RFC 3339 timestamp, such as 2024-05-01T00:00:00Z, not [{}]",
+            parameter, value
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::database;
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn export_pages_keep_entries_sharing_a_timestamp() {
+        let repo = database().await;
+        let entries = MAX_PAGE_SIZE as usize + 20;
+        repo.db
+            .query(format!(
+                "FOR $i IN $entries {{ CREATE {} SET at = $at, action = 'login_failed'; }}",
+                AUDIT_TABLE
+            ))
+            .bind(("entries", vec![0; entries]))
+            .bind(("at", Datetime::default()))
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+        let params: AuditListParams = serde_json::from_value(json!({})).unwrap();
+
+        let first = Database::export_audit_page(&repo, &params, None)
+            .await
+            .unwrap();
+        let second = Database::export_audit_page(&repo, &params, first.last())
+            .await
+            .unwrap();
+
+        assert_eq!(first.len() as u64, MAX_PAGE_SIZE);
+        assert_eq!(second.len(), 20);
+        let mut ids: Vec<String> = first
+            .iter()
+            .chain(&second)
+            .map(|entry| entry.id.to_string())
+            .collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), entries);
+    }
+}
@@ -0,0 +1,81 @@
+use actix_web::{
+    get,
+    http::header,
+    web::{scope, to, Bytes, Data, Query, ServiceConfig},
+    HttpRequest, HttpResponse,
+};
+use futures::stream::try_unfold;
+
+use super::models::{AuditListParams, WebAuditEntry};
+use super::repo::AuditRepository;
+use crate::auth::web::validate_jwt;
+use crate::database::Database;
+use crate::errors::{unknown_resource_error, GatewayError, Result};
+use crate::pagination::{page_response, MAX_PAGE_SIZE};
+
+// Intermediate function to configure services
+pub fn service_setup(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/cfg/v1/audit")
+            .service(list_audit)
+            .service(export_audit)
+            .default_service(to(unknown_resource_error)),
+    );
+}
+
+#[get("/")]
+async fn list_audit(
+    req: HttpRequest,
+    params: Query<AuditListParams>,
+    repo: Data<Database>,
+) -> Result<HttpResponse> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let entries = Database::search_audit(&repo, &params).await?;
+    Ok(page_response(
+        &req,
+        entries.map(|entry| WebAuditEntry::from(&entry)),
+    ))
+}
+
+// Every matching entry as JSON lines, oldest first, sent a page at a time
+#[get("/export")]
+async fn export_audit(
+    req: HttpRequest,
+    params: Query<AuditListParams>,
+    repo: Data<Database>,
+) -> Result<HttpResponse> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    // Read ahead of the response, so a bad filter still gets an error status
+    let first_page = Database::export_audit_page(&repo, &params, None).await?;
+
+    let pages = try_unfold(
+        Some((repo, params.into_inner(), first_page)),
+        |state| async move {
+            let Some((repo, params, entries)) = state else {
+                return Ok(None);
+            };
+            let mut body = String::new();
+            for entry in &entries {
+                let line = serde_json::to_string(&WebAuditEntry::from(entry))
+                    .map_err(|e| GatewayError::SystemError(e.to_string()))?;
+                body.push_str(&line);
+                body.push('\n');
+            }
+            // A short page is the last one
+            let next = if (entries.len() as u64) < MAX_PAGE_SIZE {
+                None
+            } else {
+                let next_page = Database::export_audit_page(&repo, &params, entries.last()).await?;
+                Some((repo, params, next_page))
+            };
+            Ok::<_, GatewayError>(Some((Bytes::from(body), next)))
+        },
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"audit.jsonl\"",
+        ))
+        .streaming(pages))
+}
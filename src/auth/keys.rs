@@ -0,0 +1,333 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::StreamExt;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+    RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::{Id as KeyType, PKey, Private};
+use openssl::rsa::Rsa;
+use serde::Serialize;
+use surrealdb::sql::{Id, Thing};
+
+use super::models::{DbSigningKey, DbSigningKeyRequest, SigningAlgorithm};
+use super::repo::SigningKeyRepository;
+use crate::audit::models::Actor;
+use crate::database::{Database, SIGNING_KEY_TABLE};
+use crate::errors::{GatewayError, Result};
+
+// Delay before re-subscribing when a live query stream ends unexpectedly
+const LIVE_QUERY_RETRY_DELAY: Duration = Duration::from_secs(5);
+// Size of generated RSA keys, and the smallest accepted on import
+const RSA_KEY_BITS: u32 = 2048;
+// Bytes in each P-256 coordinate
+const P256_COORDINATE_LENGTH: i32 = 32;
+
+struct LoadedKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    public_key: Jwk,
+    retired: bool,
+}
+
+/// The keys access tokens are signed and verified with, cached from the
+/// database. Tokens name the key that signed them in their `kid` header.
+pub struct SigningKeys {
+    // Newest first
+    keys: RwLock<Vec<LoadedKey>>,
+}
+
+impl SigningKeys {
+    pub fn new() -> Self {
+        SigningKeys {
+            keys: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Reload the active key, and the keys retired within `retention_secs`,
+    /// from the database.
+    pub async fn refresh(&self, repo: &Data<Database>, retention_secs: u64) -> Result<()> {
+        let keys = Database::list_signing_keys(repo, retention_secs)
+            .await?
+            .iter()
+            .map(load_key)
+            .collect::<Result<Vec<LoadedKey>>>()?;
+        log::debug!("Loaded {} signing keys", keys.len());
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// Signs the claims with the active key.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let keys = self.keys.read().unwrap();
+        let key = keys
+            .iter()
+            .find(|key| !key.retired)
+            .ok_or(GatewayError::TokenEncodeError(String::from(
+                "There is no active signing key",
+            )))?;
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+        encode(&header, claims, &key.encoding_key)
+            .map_err(|e| GatewayError::TokenEncodeError(e.to_string()))
+    }
+
+    /// The algorithm and key that verify the token, found by its `kid`. The
+    /// algorithm is the key's own, whatever the token's header claims.
+    pub fn verifying_key(&self, token: &str) -> Result<(Algorithm, DecodingKey)> {
+        let header =
+            decode_header(token).map_err(|e| GatewayError::TokenDecodeError(e.to_string()))?;
+        let kid = header
+            .kid
+            .ok_or(GatewayError::TokenDecodeError(String::from(
+                "The token does not name its signing key",
+            )))?;
+        let keys = self.keys.read().unwrap();
+        let key = keys
+            .iter()
+            .find(|key| key.kid == kid)
+            .ok_or(GatewayError::TokenDecodeError(format!(
+                "Unknown signing key [{}]",
+                kid
+            )))?;
+        Ok((key.algorithm, key.decoding_key.clone()))
+    }
+
+    /// The public keys, for services verifying tokens themselves.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .keys
+                .read()
+                .unwrap()
+                .iter()
+                .map(|key| key.public_key.clone())
+                .collect(),
+        }
+    }
+}
+
+fn load_key(key: &DbSigningKey) -> Result<LoadedKey> {
+    let kid = format!("{}", key.id.id);
+    let invalid_key =
+        |e: String| GatewayError::SystemError(format!("Signing key [{}] is invalid: {}", kid, e));
+    let public_key: Jwk =
+        serde_json::from_value(key.public_key.clone()).map_err(|e| invalid_key(e.to_string()))?;
+    let private_key = key.private_key.as_bytes();
+    let encoding_key = match key.algorithm {
+        SigningAlgorithm::Rs256 | SigningAlgorithm::Rs512 => EncodingKey::from_rsa_pem(private_key),
+        SigningAlgorithm::Es256 => EncodingKey::from_ec_pem(private_key),
+        SigningAlgorithm::EdDsa => EncodingKey::from_ed_pem(private_key),
+    }
+    .map_err(|e| invalid_key(e.to_string()))?;
+    let decoding_key =
+        DecodingKey::from_jwk(&public_key).map_err(|e| invalid_key(e.to_string()))?;
+    Ok(LoadedKey {
+        algorithm: key.algorithm.algorithm(),
+        encoding_key,
+        decoding_key,
+        public_key,
+        retired: key.retired_at.is_some(),
+        kid,
+    })
+}
+
+/// Generates a key for the algorithm and makes it the active signing key.
+/// The previous key is retired, but verifies the tokens it signed for
+/// another `retention_secs`.
+pub async fn rotate_signing_key(
+    repo: &Data<Database>,
+    algorithm: SigningAlgorithm,
+    retention_secs: u64,
+    actor: &Actor,
+) -> Result<DbSigningKey> {
+    let private_key = match algorithm {
+        SigningAlgorithm::Rs256 | SigningAlgorithm::Rs512 => {
+            Rsa::generate(RSA_KEY_BITS).and_then(PKey::from_rsa)
+        }
+        SigningAlgorithm::Es256 => EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+            .and_then(|group| EcKey::generate(&group))
+            .and_then(PKey::from_ec_key),
+        SigningAlgorithm::EdDsa => PKey::generate_ed25519(),
+    }
+    .map_err(key_error)?;
+    let (key_id, key) = signing_key_request(algorithm, &private_key)?;
+    Database::add_signing_key(repo, &key_id, key, retention_secs, actor).await
+}
+
+/// Makes an existing PEM-formatted private key the active signing key, such
+/// as one that upstream services already trust.
+pub async fn import_signing_key(
+    repo: &Data<Database>,
+    algorithm: SigningAlgorithm,
+    private_key_pem: &[u8],
+    retention_secs: u64,
+    actor: &Actor,
+) -> Result<DbSigningKey> {
+    let private_key = PKey::private_key_from_pem(private_key_pem)
+        .map_err(|e| GatewayError::BadRequest(format!("Invalid private key: {}", e)))?;
+    let (key_id, key) = signing_key_request(algorithm, &private_key)?;
+    Database::add_signing_key(repo, &key_id, key, retention_secs, actor).await
+}
+
+/// Generates a signing key when there is no active one, as on first start.
+pub async fn ensure_signing_key(
+    repo: &Data<Database>,
+    algorithm: SigningAlgorithm,
+    retention_secs: u64,
+) -> Result<()> {
+    let keys = Database::list_signing_keys(repo, retention_secs).await?;
+    if keys.iter().all(|key| key.retired_at.is_some()) {
+        let key = rotate_signing_key(repo, algorithm, retention_secs, &Actor::default()).await?;
+        log::info!("Generated {} signing key [{}]", algorithm, key.id.id);
+    }
+    Ok(())
+}
+
+// Checks the key suits the algorithm, and describes its public half as a JWK
+fn signing_key_request(
+    algorithm: SigningAlgorithm,
+    private_key: &PKey<Private>,
+) -> Result<(Thing, DbSigningKeyRequest)> {
+    let wrong_key =
+        || GatewayError::BadRequest(format!("The private key cannot sign {} tokens", algorithm));
+    let parameters = match (algorithm, private_key.id()) {
+        (SigningAlgorithm::Rs256 | SigningAlgorithm::Rs512, KeyType::RSA) => {
+            let rsa = private_key.rsa().map_err(key_error)?;
+            if rsa.size() * 8 < RSA_KEY_BITS {
+                return Err(GatewayError::BadRequest(format!(
+                    "RSA signing keys must have at least {} bits",
+                    RSA_KEY_BITS
+                )));
+            }
+            AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+                e: URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+            })
+        }
+        (SigningAlgorithm::Es256, KeyType::EC) => {
+            let ec_key = private_key.ec_key().map_err(key_error)?;
+            if ec_key.group().curve_name() != Some(Nid::X9_62_PRIME256V1) {
+                return Err(wrong_key());
+            }
+            let (x, y) = p256_coordinates(&ec_key).map_err(key_error)?;
+            AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x: URL_SAFE_NO_PAD.encode(x),
+                y: URL_SAFE_NO_PAD.encode(y),
+            })
+        }
+        (SigningAlgorithm::EdDsa, KeyType::ED25519) => {
+            AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(private_key.raw_public_key().map_err(key_error)?),
+            })
+        }
+        _ => return Err(wrong_key()),
+    };
+
+    let key_id = Thing::from((SIGNING_KEY_TABLE, Id::rand()));
+    let public_key = Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(algorithm.key_algorithm()),
+            key_id: Some(format!("{}", key_id.id)),
+            ..Default::default()
+        },
+        algorithm: parameters,
+    };
+    let private_key = private_key.private_key_to_pem_pkcs8().map_err(key_error)?;
+    Ok((
+        key_id,
+        DbSigningKeyRequest {
+            algorithm,
+            private_key: String::from_utf8_lossy(&private_key).into_owned(),
+            public_key: serde_json::to_value(&public_key)
+                .map_err(|e| GatewayError::SystemError(e.to_string()))?,
+        },
+    ))
+}
+
+fn p256_coordinates(
+    ec_key: &EcKey<Private>,
+) -> std::result::Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let mut context = BigNumContext::new()?;
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    ec_key
+        .public_key()
+        .affine_coordinates(ec_key.group(), &mut x, &mut y, &mut context)?;
+    Ok((
+        x.to_vec_padded(P256_COORDINATE_LENGTH)?,
+        y.to_vec_padded(P256_COORDINATE_LENGTH)?,
+    ))
+}
+
+fn key_error(e: ErrorStack) -> GatewayError {
+    GatewayError::SystemError(format!("Unable to prepare the signing key: {}", e))
+}
+
+/// Keeps the signing keys current by reloading them whenever a `LIVE SELECT`
+/// on the signing key table reports a change, such as a rotation made by
+/// another gateway.
+pub fn spawn_key_sync(keys: Data<SigningKeys>, repo: Data<Database>, retention_secs: u64) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = watch_signing_keys(&keys, &repo, retention_secs).await {
+                log::error!("Signing key live query failed: {}", e);
+            }
+            tokio::time::sleep(LIVE_QUERY_RETRY_DELAY).await;
+        }
+    });
+}
+
+async fn watch_signing_keys(
+    keys: &Data<SigningKeys>,
+    repo: &Data<Database>,
+    retention_secs: u64,
+) -> Result<()> {
+    let mut reconnected = repo.reconnected();
+    let mut notifications = repo
+        .db
+        .select::<Vec<serde_json::Value>>(SIGNING_KEY_TABLE)
+        .live()
+        .await
+        .map_err(GatewayError::from)?;
+    // Catch any change made between the last refresh and the subscription
+    keys.refresh(repo, retention_secs).await?;
+
+    loop {
+        tokio::select! {
+            notification = notifications.next() => match notification {
+                Some(Ok(notification)) => {
+                    log::debug!("Signing key change detected: {:?}", notification.action);
+                    if let Err(e) = keys.refresh(repo, retention_secs).await {
+                        log::error!("Unable to refresh the signing keys: {}", e);
+                    }
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+            // The live query ended with the old connection
+            _ = reconnected.changed() => {
+                log::info!("Restarting the signing key live query after reconnecting");
+                return Ok(());
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+pub mod keys;
 pub mod models;
 pub mod repo;
+pub mod revocation;
 pub mod web;
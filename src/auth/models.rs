@@ -1,5 +1,8 @@
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use clap::ValueEnum;
+use jsonwebtoken::jwk::KeyAlgorithm;
+use jsonwebtoken::Algorithm;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use surrealdb::sql::{Datetime, Thing};
 use validator::Validate;
 // Define a struct to represent your user record
@@ -21,6 +24,16 @@ pub struct GatewayUserClaims {
     pub iat: u64,
     // Not before (datetime-formatted string)
     pub nbf: u64,
+    // Session the token was issued for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    // Token ID, which the token is revoked by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    // Space-separated OpenID Connect scopes, for tokens issued to a client
+    // application
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -29,12 +42,213 @@ pub struct GatewayLoginCredentials {
     pub password: String,
 }
 
+/// The tokens handed out at login and on every refresh.
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    // Seconds until the access token expires
+    pub expires_in: u64,
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshForm {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionEnd {
+    Logout,
+    // By the user, or an admin
+    Revoked,
+    // A spent refresh token was presented again
+    TokenReuse,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbSession {
+    pub id: Thing,
+    pub user: Thing,
+    pub user_agent: Option<String>,
+    pub source_ip: Option<String>,
+    pub created_at: Datetime,
+    pub last_used_at: Datetime,
+    pub expires_at: Datetime,
+    pub revoked_at: Option<Datetime>,
+    pub revoked_reason: Option<SessionEnd>,
+    // The client application the session was started for, and the scopes it
+    // was granted
+    #[serde(default)]
+    pub client: Option<Thing>,
+    #[serde(default)]
+    pub scope: Option<Vec<String>>,
+}
+
+/// An access token refused before it expires; the record id is its `jti`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbRevokedToken {
+    pub id: Thing,
+    pub user: Thing,
+    pub revoked_at: Datetime,
+    pub expires_at: Datetime,
+}
+
+/// A user whose access tokens issued before `tokens_valid_after` are refused.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbTokenWatermark {
+    pub id: Thing,
+    pub tokens_valid_after: Datetime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbRefreshToken {
+    pub id: Thing,
+    pub session: Thing,
+    pub token_hash: String,
+    pub created_at: Datetime,
+    pub used_at: Option<Datetime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebSession {
+    pub id: String,
+    // The client's User-Agent when it signed in
+    pub user_agent: Option<String>,
+    // Where the session was last used from
+    pub source_ip: Option<String>,
+    pub created_at: Datetime,
+    pub last_used_at: Datetime,
+    pub expires_at: Datetime,
+    // The client application signed in to, if not the gateway itself
+    pub client: Option<String>,
+    // Whether this is the session making the request
+    pub current: bool,
+}
+
+impl WebSession {
+    pub fn new(session: &DbSession, current_session: Option<&str>) -> Self {
+        let id = format!("{}", session.id.id);
+        Self {
+            current: current_session == Some(id.as_str()),
+            id,
+            user_agent: session.user_agent.clone(),
+            source_ip: session.source_ip.clone(),
+            created_at: session.created_at.clone(),
+            last_used_at: session.last_used_at.clone(),
+            expires_at: session.expires_at.clone(),
+            client: session
+                .client
+                .as_ref()
+                .map(|client| format!("{}", client.id)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SessionIdParams {
+    pub session_id: String,
+}
+
 #[derive(Clone)]
 pub struct JwtConfig {
-    pub decoding_key: DecodingKey,
-    pub encoding_key: EncodingKey,
     pub issuer: String,
-    pub algorithm: Algorithm,
+    // Algorithm of newly generated signing keys
+    pub algorithm: SigningAlgorithm,
+    pub token_lifetime_secs: u64,
+    pub refresh_token_lifetime_secs: u64,
+}
+
+/// Algorithms access tokens can be signed with.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SigningAlgorithm {
+    #[serde(rename = "RS256")]
+    #[value(name = "RS256")]
+    Rs256,
+    #[default]
+    #[serde(rename = "RS512")]
+    #[value(name = "RS512")]
+    Rs512,
+    #[serde(rename = "ES256")]
+    #[value(name = "ES256")]
+    Es256,
+    #[serde(rename = "EdDSA")]
+    #[value(name = "EdDSA")]
+    EdDsa,
+}
+
+impl SigningAlgorithm {
+    pub fn algorithm(self) -> Algorithm {
+        match self {
+            SigningAlgorithm::Rs256 => Algorithm::RS256,
+            SigningAlgorithm::Rs512 => Algorithm::RS512,
+            SigningAlgorithm::Es256 => Algorithm::ES256,
+            SigningAlgorithm::EdDsa => Algorithm::EdDSA,
+        }
+    }
+
+    pub fn key_algorithm(self) -> KeyAlgorithm {
+        match self {
+            SigningAlgorithm::Rs256 => KeyAlgorithm::RS256,
+            SigningAlgorithm::Rs512 => KeyAlgorithm::RS512,
+            SigningAlgorithm::Es256 => KeyAlgorithm::ES256,
+            SigningAlgorithm::EdDsa => KeyAlgorithm::EdDSA,
+        }
+    }
+}
+
+impl std::fmt::Display for SigningAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.algorithm())
+    }
+}
+
+/// A key access tokens are signed with; the record id is the `kid` header of
+/// the tokens it signs.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbSigningKey {
+    pub id: Thing,
+    pub algorithm: SigningAlgorithm,
+    // PEM-formatted PKCS 8 private key
+    pub private_key: String,
+    // JWK, as published
+    pub public_key: Value,
+    pub created_at: Datetime,
+    // Retired keys sign nothing new, but still verify the tokens they signed
+    pub retired_at: Option<Datetime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DbSigningKeyRequest {
+    pub algorithm: SigningAlgorithm,
+    pub private_key: String,
+    pub public_key: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebSigningKey {
+    pub id: String,
+    pub algorithm: SigningAlgorithm,
+    pub created_at: Datetime,
+    pub retired_at: Option<Datetime>,
+}
+
+impl From<&DbSigningKey> for WebSigningKey {
+    fn from(value: &DbSigningKey) -> Self {
+        Self {
+            id: format!("{}", value.id.id),
+            algorithm: value.algorithm,
+            created_at: value.created_at.clone(),
+            retired_at: value.retired_at.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RotateKeyForm {
+    // Defaults to `auth.jwt_algorithm`
+    pub algorithm: Option<SigningAlgorithm>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
@@ -3,15 +3,30 @@ use std::time;
 
 use actix_web::web::Data;
 use async_trait::async_trait;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use surrealdb::opt::PatchOp;
-use surrealdb::sql::{Datetime, Id, Strand};
+use surrealdb::sql::{Datetime, Duration, Id, Strand, Thing};
 
-use super::models::PasswordResetRequest;
-use crate::database::{Database, PASSWORD_RESET_TABLE, ROLE_MEMBER_TABLE, USER_TABLE};
+use super::models::{
+    DbRefreshToken, DbRevokedToken, DbSession, DbSigningKey, DbSigningKeyRequest, DbTokenWatermark,
+    GatewayUserClaims, PasswordResetRequest, SessionEnd,
+};
+use crate::audit::models::{Actor, AuditAction};
+use crate::audit::repo::audit_within;
+use crate::database::{
+    Database, Transaction, PASSWORD_RESET_TABLE, REFRESH_TOKEN_TABLE, REVOKED_TOKEN_TABLE,
+    ROLE_MEMBER_TABLE, SESSION_TABLE, SIGNING_KEY_TABLE, USER_TABLE,
+};
 use crate::errors::{GatewayError, Result};
 use crate::users::models::{DbGatewayUserRecord, DbGatewayUserResponse};
 
-const REQUEST_LIFETIME: u64 = 24 * 60 * 60;
+// Characters in a refresh token, which is about 285 bits of randomness
+const REFRESH_TOKEN_LENGTH: usize = 48;
+// Thrown when a refresh token turns out to be used by the time it is redeemed
+const REFRESH_TOKEN_REUSED: &str = "The refresh token was already used";
 
 #[async_trait]
 pub trait UserAuthRepository {
@@ -23,14 +38,24 @@ pub trait UserAuthRepository {
 
     async fn set_last_login(repo: &Data<Database>, user_id: &String) -> Result<()>;
 
-    async fn request_password_reset(repo: &Data<Database>, username: &String) -> Result<()>;
+    async fn request_password_reset(
+        repo: &Data<Database>,
+        username: &String,
+        lifetime_secs: u64,
+    ) -> Result<PasswordResetRequest>;
+
+    async fn create_password_reset(
+        repo: &Data<Database>,
+        user_id: &String,
+        lifetime_secs: u64,
+    ) -> Result<PasswordResetRequest>;
 
     async fn set_user_password_with_reset_token(
         repo: &Data<Database>,
         reset_token: &String,
         username: &String,
         new_password: &String,
-    ) -> Result<()>;
+    ) -> Result<DbGatewayUserRecord>;
 
     async fn set_user_password(
         repo: &Data<Database>,
@@ -39,13 +64,6 @@ pub trait UserAuthRepository {
     ) -> Result<()>;
 }
 
-pub async fn setup_reset_request_table(repo: &Database) -> std::io::Result<()> {
-    repo.automate_created_date(PASSWORD_RESET_TABLE).await?;
-    repo.automate_last_modified_date(PASSWORD_RESET_TABLE)
-        .await?;
-    Ok(())
-}
-
 #[async_trait]
 impl UserAuthRepository for Database {
     async fn authenticate_user(
@@ -74,6 +92,7 @@ impl UserAuthRepository for Database {
                 "\
                 SELECT *, ->{}->role.* as roles FROM type::table($userTable) \
                 WHERE username = $username \
+                AND disabled != true \
                 AND password_hash IS NOT NONE
                 AND crypto::argon2::compare(password_hash, $password)\
             ",
@@ -105,12 +124,6 @@ impl UserAuthRepository for Database {
         user_id: &String,
         new_password: &String,
     ) -> Result<()> {
-        log::debug!(
-            "Hashing password [ {} ] for user [ {} ]",
-            new_password,
-            user_id
-        );
-
         let pass_hash: Option<String> = repo
             .db
             .query("RETURN crypto::argon2::generate($password)")
@@ -124,13 +137,13 @@ impl UserAuthRepository for Database {
             "Unable to hash password",
         )))?;
 
-        log::debug!("New Password Hash: [ {} ] ", pass_hash);
         let now = Datetime::default();
         let _: DbGatewayUserRecord = repo
             .db
             .update((USER_TABLE, user_id))
             .patch(PatchOp::replace("/password_hash", &pass_hash))
             .patch(PatchOp::replace("/password_reset_at", &now))
+            .patch(PatchOp::replace("/tokens_valid_after", &now))
             .patch(PatchOp::replace("/last_modified_date", &now))
             .await
             .map_err(Into::<GatewayError>::into)?
@@ -149,7 +162,7 @@ impl UserAuthRepository for Database {
         reset_token: &String,
         username: &String,
         new_password: &String,
-    ) -> Result<()> {
+    ) -> Result<DbGatewayUserRecord> {
         let reset_request: PasswordResetRequest = repo
             .db
             .select((PASSWORD_RESET_TABLE, reset_token))
@@ -190,18 +203,29 @@ impl UserAuthRepository for Database {
             ));
         }
 
-        Database::set_user_password(repo, &reset_request.user_id, new_password).await?;
-        repo.db
-            .update::<Option<PasswordResetRequest>>((PASSWORD_RESET_TABLE, reset_token))
-            .patch(PatchOp::replace("/used", true))
-            .patch(PatchOp::replace("/last_modified", Datetime::default()))
-            .await
-            .map_err(Into::<GatewayError>::into)?;
+        // The token is only spent if the password is changed
+        let transaction = Transaction::new()
+            .statement(
+                "UPDATE $user SET password_hash = crypto::argon2::generate($password), \
+                password_reset_at = time::now(), tokens_valid_after = time::now()",
+            )
+            .statement("UPDATE $reset SET used = true, last_modified = time::now()")
+            .bind("user", &user.id)?
+            .bind("password", new_password)?
+            .bind(
+                "reset",
+                Thing::from((PASSWORD_RESET_TABLE, reset_token.as_str())),
+            )?;
+        repo.commit(transaction).await?;
 
-        Ok(())
+        Ok(user)
     }
 
-    async fn request_password_reset(repo: &Data<Database>, username: &String) -> Result<()> {
+    async fn request_password_reset(
+        repo: &Data<Database>,
+        username: &String,
+        lifetime_secs: u64,
+    ) -> Result<PasswordResetRequest> {
         let bind_data: std::collections::BTreeMap<String, surrealdb::sql::Value> = [
             ("username".into(), username.clone().into()),
             ("table".into(), USER_TABLE.into()),
@@ -224,39 +248,562 @@ impl UserAuthRepository for Database {
             String::from("User"),
             String::from("Could not find user to request password reset"),
         ))?;
-        let now = time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .map_err(|e| GatewayError::SystemError(e.to_string()))?
-            .as_secs();
         if let Id::String(user_id) = found_user.id.id {
-            let response: Vec<PasswordResetRequest> = repo
-                .db
-                .create(PASSWORD_RESET_TABLE)
-                .content(PasswordResetRequest {
-                    id: None,
-                    expires_at: now + REQUEST_LIFETIME,
-                    user_id: user_id.clone(),
-                    used: false,
-                    last_modified: Datetime::default(),
-                })
-                .await
-                .map_err(Into::<GatewayError>::into)?;
-
-            let reset_request = response.get(0).ok_or(GatewayError::DatabaseError(
-                "Unable to create Password Reset Request".to_string(),
-            ))?;
-
+            let reset_request =
+                Database::create_password_reset(repo, &user_id, lifetime_secs).await?;
             log::debug!(
                 "New password reset {} created for user {}",
                 reset_request.id.as_ref().unwrap().id.to_string(),
                 user_id.clone()
             );
+            Ok(reset_request)
         } else {
-            return Err(GatewayError::DatabaseError(
+            Err(GatewayError::DatabaseError(
                 "Unknown User ID Format for selected User".to_string(),
+            ))
+        }
+    }
+
+    async fn create_password_reset(
+        repo: &Data<Database>,
+        user_id: &String,
+        lifetime_secs: u64,
+    ) -> Result<PasswordResetRequest> {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_err(|e| GatewayError::SystemError(e.to_string()))?
+            .as_secs();
+        let response: Vec<PasswordResetRequest> = repo
+            .db
+            .create(PASSWORD_RESET_TABLE)
+            .content(PasswordResetRequest {
+                id: None,
+                expires_at: now + lifetime_secs,
+                user_id: user_id.clone(),
+                used: false,
+                last_modified: Datetime::default(),
+            })
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+
+        response
+            .into_iter()
+            .next()
+            .ok_or(GatewayError::DatabaseError(
+                "Unable to create Password Reset Request".to_string(),
+            ))
+    }
+}
+
+#[async_trait]
+pub trait SessionRepository {
+    async fn start_session(
+        repo: &Data<Database>,
+        user_id: &Thing,
+        user_agent: Option<String>,
+        source_ip: Option<String>,
+        lifetime_secs: u64,
+    ) -> Result<(DbSession, String)>;
+
+    async fn refresh_session(
+        repo: &Data<Database>,
+        refresh_token: &str,
+        client: Option<&Thing>,
+        actor: &Actor,
+        lifetime_secs: u64,
+    ) -> Result<(DbSession, String)>;
+
+    async fn list_sessions(repo: &Data<Database>, user_id: &str) -> Result<Vec<DbSession>>;
+
+    async fn revoke_session(
+        repo: &Data<Database>,
+        user_id: &str,
+        session_id: &str,
+        reason: SessionEnd,
+        actor: &Actor,
+    ) -> Result<()>;
+
+    async fn revoke_user_sessions(
+        repo: &Data<Database>,
+        user_id: &str,
+        actor: &Actor,
+    ) -> Result<()>;
+}
+
+#[async_trait]
+impl SessionRepository for Database {
+    async fn start_session(
+        repo: &Data<Database>,
+        user_id: &Thing,
+        user_agent: Option<String>,
+        source_ip: Option<String>,
+        lifetime_secs: u64,
+    ) -> Result<(DbSession, String)> {
+        let session_id = Thing::from((SESSION_TABLE, Id::rand()));
+        let transaction = Transaction::new()
+            .statement(
+                "CREATE $session_id CONTENT { user: $user, user_agent: $user_agent, \
+                source_ip: $source_ip, expires_at: time::now() + $lifetime }",
+            )
+            .bind("session_id", &session_id)?
+            .bind("user", user_id)?
+            .bind("user_agent", user_agent)?
+            .bind("source_ip", source_ip)?
+            .bind("lifetime", Duration::from_secs(lifetime_secs))?;
+        let (transaction, refresh_token) = add_refresh_token(transaction)?;
+        repo.commit(transaction).await?;
+
+        Ok((session_record(repo, &session_id).await?, refresh_token))
+    }
+
+    // The session must have been started for `client`, or for the gateway
+    // itself when there is none
+    async fn refresh_session(
+        repo: &Data<Database>,
+        refresh_token: &str,
+        client: Option<&Thing>,
+        actor: &Actor,
+        lifetime_secs: u64,
+    ) -> Result<(DbSession, String)> {
+        let token: Option<DbRefreshToken> = repo
+            .db
+            .query(format!(
+                "SELECT * FROM {} WHERE token_hash = $token_hash LIMIT 1",
+                REFRESH_TOKEN_TABLE
+            ))
+            .bind(("token_hash", hash_refresh_token(refresh_token)))
+            .await
+            .map_err(Into::<GatewayError>::into)?
+            .take(0)
+            .map_err(Into::<GatewayError>::into)?;
+        let token = token.ok_or(GatewayError::Unauthorized(String::from(
+            "Invalid refresh token",
+        )))?;
+        let session = session_record(repo, &token.session).await?;
+        if session.revoked_at.is_some() || session.expires_at.0 <= chrono::Utc::now() {
+            return Err(GatewayError::Unauthorized(String::from(
+                "The session has ended; sign in again",
+            )));
+        }
+        if session.client.as_ref() != client {
+            return Err(GatewayError::Unauthorized(String::from(
+                "The refresh token was issued to another client",
+            )));
+        }
+
+        if token.used_at.is_some() {
+            return Err(revoke_reused_session(repo, &session, actor).await);
+        }
+
+        let user: Option<DbGatewayUserRecord> = repo
+            .db
+            .select(&session.user)
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        if user.is_none_or(|user| user.disabled) {
+            return Err(GatewayError::Unauthorized(String::from(
+                "The account has been disabled",
+            )));
+        }
+
+        // Of two requests racing to use the token, only the first commits
+        let transaction = Transaction::new()
+            .statement(format!(
+                "IF (SELECT VALUE used_at FROM ONLY $refresh_token) != NONE {{ THROW '{}' }}",
+                REFRESH_TOKEN_REUSED
+            ))
+            .statement("UPDATE $refresh_token SET used_at = time::now()")
+            .statement(
+                "UPDATE $session_id SET last_used_at = time::now(), source_ip = $source_ip, \
+                expires_at = time::now() + $lifetime",
+            )
+            .bind("refresh_token", &token.id)?
+            .bind("session_id", &session.id)?
+            .bind("source_ip", &actor.source_ip)?
+            .bind("lifetime", Duration::from_secs(lifetime_secs))?;
+        let (transaction, refresh_token) = add_refresh_token(transaction)?;
+        match repo.commit(transaction).await {
+            Err(GatewayError::Conflict(message)) if message == REFRESH_TOKEN_REUSED => {
+                return Err(revoke_reused_session(repo, &session, actor).await);
+            }
+            result => result?,
+        }
+
+        Ok((session_record(repo, &session.id).await?, refresh_token))
+    }
+
+    async fn list_sessions(repo: &Data<Database>, user_id: &str) -> Result<Vec<DbSession>> {
+        repo.db
+            .query(format!(
+                "SELECT * FROM {} WHERE user = $user AND revoked_at = NONE \
+                AND expires_at > time::now() ORDER BY last_used_at DESC",
+                SESSION_TABLE
+            ))
+            .bind(("user", Thing::from((USER_TABLE, user_id))))
+            .await
+            .map_err(Into::<GatewayError>::into)?
+            .take(0)
+            .map_err(Into::<GatewayError>::into)
+    }
+
+    async fn revoke_session(
+        repo: &Data<Database>,
+        user_id: &str,
+        session_id: &str,
+        reason: SessionEnd,
+        actor: &Actor,
+    ) -> Result<()> {
+        let user_id = Thing::from((USER_TABLE, user_id));
+        let session: Option<DbSession> = repo
+            .db
+            .select((SESSION_TABLE, session_id))
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        // Other users' sessions are reported as missing
+        let session = session
+            .filter(|session| session.user == user_id && session.revoked_at.is_none())
+            .ok_or(GatewayError::NotFound(
+                String::from("Session"),
+                format!("No active session [{}] found.", session_id),
+            ))?;
+
+        let transaction = end_sessions(
+            Transaction::new().bind("session_id", &session.id)?,
+            "id = $session_id",
+            reason,
+        )?;
+        let entry = actor.entry(
+            AuditAction::SessionRevoked,
+            Some(user_id),
+            json!({ "session": session_id, "reason": reason }),
+        );
+        repo.commit(audit_within(transaction, vec![entry])?).await
+    }
+
+    async fn revoke_user_sessions(
+        repo: &Data<Database>,
+        user_id: &str,
+        actor: &Actor,
+    ) -> Result<()> {
+        let user_id = Thing::from((USER_TABLE, user_id));
+        let user: Option<DbGatewayUserRecord> = repo
+            .db
+            .select(&user_id)
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        if user.is_none() {
+            return Err(GatewayError::NotFound(
+                String::from("User"),
+                format!("Could not find a user with id {}", user_id),
             ));
         }
 
-        Ok(())
+        let transaction = end_sessions(
+            Transaction::new().bind("user", &user_id)?,
+            "user = $user",
+            SessionEnd::Revoked,
+        )?;
+        let transaction = invalidate_tokens_within(transaction);
+        let entry = actor.entry(
+            AuditAction::SessionRevoked,
+            Some(user_id),
+            json!({ "session": "all", "reason": SessionEnd::Revoked }),
+        );
+        repo.commit(audit_within(transaction, vec![entry])?).await
+    }
+}
+
+#[async_trait]
+pub trait TokenRevocationRepository {
+    async fn revoke_token(repo: &Data<Database>, claims: &GatewayUserClaims) -> Result<()>;
+    async fn list_revoked_tokens(repo: &Data<Database>) -> Result<Vec<DbRevokedToken>>;
+    async fn list_revoked_sessions(repo: &Data<Database>) -> Result<Vec<Thing>>;
+    async fn list_token_watermarks(repo: &Data<Database>) -> Result<Vec<DbTokenWatermark>>;
+}
+
+#[async_trait]
+impl TokenRevocationRepository for Database {
+    async fn revoke_token(repo: &Data<Database>, claims: &GatewayUserClaims) -> Result<()> {
+        // Only tokens issued before the `jti` claim lack one, and they have expired
+        let Some(jti) = &claims.jti else {
+            return Ok(());
+        };
+        let expires_at = chrono::DateTime::from_timestamp(claims.exp as i64, 0).ok_or(
+            GatewayError::BadRequest(format!("Invalid token expiry [{}]", claims.exp)),
+        )?;
+        let transaction = Transaction::new()
+            // Tokens that have expired since they were revoked are refused anyway
+            .statement(format!(
+                "DELETE {} WHERE expires_at < time::now()",
+                REVOKED_TOKEN_TABLE
+            ))
+            .statement("UPDATE $revoked_token CONTENT { user: $user, expires_at: $expires_at }")
+            .bind(
+                "revoked_token",
+                Thing::from((REVOKED_TOKEN_TABLE, jti.as_str())),
+            )?
+            .bind("user", Thing::from((USER_TABLE, claims.sub_id.as_str())))?
+            .bind("expires_at", Datetime::from(expires_at))?;
+        repo.commit(transaction).await
+    }
+
+    async fn list_revoked_tokens(repo: &Data<Database>) -> Result<Vec<DbRevokedToken>> {
+        repo.query_list(
+            format!(
+                "SELECT * FROM {} WHERE expires_at > time::now()",
+                REVOKED_TOKEN_TABLE
+            ),
+            None::<String>,
+        )
+        .await
+    }
+
+    // Sessions that ended early, while tokens issued to them may still be live.
+    // Expired sessions can't be refreshed, so their last tokens have run out
+    async fn list_revoked_sessions(repo: &Data<Database>) -> Result<Vec<Thing>> {
+        repo.query_list(
+            format!(
+                "SELECT VALUE id FROM {} WHERE revoked_at != NONE AND expires_at > time::now()",
+                SESSION_TABLE
+            ),
+            None::<String>,
+        )
+        .await
+    }
+
+    async fn list_token_watermarks(repo: &Data<Database>) -> Result<Vec<DbTokenWatermark>> {
+        repo.query_list(
+            format!(
+                "SELECT id, tokens_valid_after FROM {} WHERE tokens_valid_after != NONE",
+                USER_TABLE
+            ),
+            None::<String>,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+pub trait SigningKeyRepository {
+    async fn list_signing_keys(
+        repo: &Data<Database>,
+        retention_secs: u64,
+    ) -> Result<Vec<DbSigningKey>>;
+    async fn add_signing_key(
+        repo: &Data<Database>,
+        key_id: &Thing,
+        key: DbSigningKeyRequest,
+        retention_secs: u64,
+        actor: &Actor,
+    ) -> Result<DbSigningKey>;
+}
+
+#[async_trait]
+impl SigningKeyRepository for Database {
+    // The active key and the keys retired within `retention_secs`, newest first
+    async fn list_signing_keys(
+        repo: &Data<Database>,
+        retention_secs: u64,
+    ) -> Result<Vec<DbSigningKey>> {
+        repo.query_list(
+            format!(
+                "SELECT * FROM {} WHERE retired_at = NONE \
+                OR retired_at > time::now() - $retention ORDER BY created_at DESC",
+                SIGNING_KEY_TABLE
+            ),
+            Some(("retention", Duration::from_secs(retention_secs))),
+        )
+        .await
+    }
+
+    async fn add_signing_key(
+        repo: &Data<Database>,
+        key_id: &Thing,
+        key: DbSigningKeyRequest,
+        retention_secs: u64,
+        actor: &Actor,
+    ) -> Result<DbSigningKey> {
+        let entry = actor.entry(
+            AuditAction::SigningKeyRotated,
+            Some(key_id.clone()),
+            json!({ "algorithm": key.algorithm }),
+        );
+        // The previous key is retired, and keys retired long enough ago that
+        // their tokens have expired are dropped
+        let transaction = Transaction::new()
+            .statement(format!(
+                "DELETE {table} WHERE retired_at != NONE AND retired_at < time::now() - $retention;\n\
+                UPDATE {table} SET retired_at = time::now() WHERE retired_at = NONE;",
+                table = SIGNING_KEY_TABLE
+            ))
+            .statement("CREATE $signing_key CONTENT $key")
+            .bind("retention", Duration::from_secs(retention_secs))?
+            .bind("signing_key", key_id)?
+            .bind("key", key)?;
+        repo.commit(audit_within(transaction, vec![entry])?).await?;
+
+        let key: Option<DbSigningKey> = repo
+            .db
+            .select(key_id)
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        key.ok_or(GatewayError::DatabaseError(String::from(
+            "Unable to create the signing key",
+        )))
+    }
+}
+
+/// Adds a statement refusing every access token issued so far to the user
+/// bound to `$user`.
+pub fn invalidate_tokens_within(transaction: Transaction) -> Transaction {
+    transaction.statement("UPDATE $user SET tokens_valid_after = time::now()")
+}
+
+pub async fn session_record(repo: &Data<Database>, session_id: &Thing) -> Result<DbSession> {
+    let session: Option<DbSession> = repo
+        .db
+        .select(session_id)
+        .await
+        .map_err(Into::<GatewayError>::into)?;
+    session.ok_or(GatewayError::NotFound(
+        String::from("Session"),
+        format!("No session [{}] found.", session_id.id),
+    ))
+}
+
+/// Adds a new refresh token for the session bound to `$session_id`, returning
+/// the token; only its hash is stored.
+pub fn add_refresh_token(transaction: Transaction) -> Result<(Transaction, String)> {
+    let refresh_token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(REFRESH_TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    let transaction = transaction
+        .statement(format!(
+            "CREATE {} CONTENT {{ session: $session_id, token_hash: $token_hash }}",
+            REFRESH_TOKEN_TABLE
+        ))
+        .bind("token_hash", hash_refresh_token(&refresh_token))?;
+    Ok((transaction, refresh_token))
+}
+
+// Revokes the session of a refresh token presented a second time, returning
+// the error to answer with. Whoever presented the token first may not have
+// been the client, so neither can be trusted with the session any more
+async fn revoke_reused_session(
+    repo: &Data<Database>,
+    session: &DbSession,
+    actor: &Actor,
+) -> GatewayError {
+    let revoked = async {
+        let transaction = end_sessions(
+            Transaction::new().bind("session_id", &session.id)?,
+            "id = $session_id",
+            SessionEnd::TokenReuse,
+        )?;
+        let entry = actor.entry(
+            AuditAction::RefreshTokenReused,
+            Some(session.user.clone()),
+            json!({ "session": format!("{}", session.id.id) }),
+        );
+        repo.commit(audit_within(transaction, vec![entry])?).await
+    };
+    match revoked.await {
+        Ok(()) => GatewayError::Unauthorized(String::from(
+            "The refresh token was already used, so its session has been revoked",
+        )),
+        Err(e) => e,
+    }
+}
+
+fn hash_refresh_token(refresh_token: &str) -> String {
+    format!("{:x}", Sha256::digest(refresh_token.as_bytes()))
+}
+
+/// Ends the sessions matching the condition that haven't already ended.
+pub fn end_sessions(
+    transaction: Transaction,
+    condition: &str,
+    reason: SessionEnd,
+) -> Result<Transaction> {
+    transaction
+        .statement(format!(
+            "UPDATE {} SET revoked_at = time::now(), revoked_reason = $reason \
+            WHERE {} AND revoked_at = NONE",
+            SESSION_TABLE, condition
+        ))
+        .bind("reason", reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::database;
+    use crate::users::models::{DbGatewayUserRequest, InitialCredential};
+    use crate::users::repo::UserRepository;
+
+    #[actix_web::test]
+    async fn racing_refreshes_revoke_the_session() {
+        let repo = database().await;
+        let (user, _) = Database::register_user(
+            &repo,
+            DbGatewayUserRequest {
+                username: "alice".into(),
+            },
+            vec![],
+            InitialCredential::PasswordReset(3600),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let (session, refresh_token) = Database::start_session(&repo, &user.id, None, None, 3600)
+            .await
+            .unwrap();
+
+        let actor = Actor::default();
+        let (first, second) = futures::join!(
+            Database::refresh_session(&repo, &refresh_token, None, &actor, 3600),
+            Database::refresh_session(&repo, &refresh_token, None, &actor, 3600),
+        );
+
+        assert_eq!([&first, &second].iter().filter(|r| r.is_ok()).count(), 1);
+        let ended = session_record(&repo, &session.id).await.unwrap();
+        assert_eq!(ended.revoked_reason, Some(SessionEnd::TokenReuse));
+    }
+
+    #[actix_web::test]
+    async fn used_refresh_token_revokes_the_session() {
+        let repo = database().await;
+        let (user, _) = Database::register_user(
+            &repo,
+            DbGatewayUserRequest {
+                username: "alice".into(),
+            },
+            vec![],
+            InitialCredential::PasswordReset(3600),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let (session, refresh_token) = Database::start_session(&repo, &user.id, None, None, 3600)
+            .await
+            .unwrap();
+        let (_, rotated) =
+            Database::refresh_session(&repo, &refresh_token, None, &Actor::default(), 3600)
+                .await
+                .unwrap();
+
+        let reused =
+            Database::refresh_session(&repo, &refresh_token, None, &Actor::default(), 3600).await;
+
+        assert!(matches!(reused, Err(GatewayError::Unauthorized(_))));
+        let ended = session_record(&repo, &session.id).await.unwrap();
+        assert_eq!(ended.revoked_reason, Some(SessionEnd::TokenReuse));
+        assert!(
+            Database::refresh_session(&repo, &rotated, None, &Actor::default(), 3600)
+                .await
+                .is_err()
+        );
     }
 }
@@ -0,0 +1,328 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use futures_util::stream::{select_all, StreamExt};
+use serde::Deserialize;
+use surrealdb::sql::{Datetime, Thing, Value};
+use surrealdb::Action;
+
+use super::models::GatewayUserClaims;
+use super::repo::TokenRevocationRepository;
+use crate::database::{Database, REVOKED_TOKEN_TABLE, SESSION_TABLE, USER_TABLE};
+use crate::errors::{GatewayError, Result};
+
+// Delay before re-subscribing when a live query stream ends unexpectedly
+const LIVE_QUERY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// The part of a user record the watermarks are kept from
+#[derive(Deserialize)]
+struct UserWatermark {
+    id: Thing,
+    tokens_valid_after: Option<Datetime>,
+}
+
+// The part of a session record telling whether it ended early
+#[derive(Deserialize)]
+struct EndedSession {
+    id: Thing,
+    revoked_at: Option<Datetime>,
+}
+
+/// Access tokens refused before they expire, cached from the database so
+/// that checking a token doesn't need a query.
+pub struct RevocationList {
+    // Expiry of each revoked token, keyed on its `jti`
+    revoked: RwLock<HashMap<String, u64>>,
+    // Sessions ended early, whose tokens are refused by their `sid`
+    revoked_sessions: RwLock<HashSet<String>>,
+    // Earliest issue time accepted for each user's tokens, keyed on user id
+    watermarks: RwLock<HashMap<String, u64>>,
+}
+
+impl RevocationList {
+    pub fn new() -> Self {
+        RevocationList {
+            revoked: RwLock::new(HashMap::new()),
+            revoked_sessions: RwLock::new(HashSet::new()),
+            watermarks: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Reload every revoked token and session, and per-user watermark, from
+    /// the database.
+    pub async fn refresh(&self, repo: &Data<Database>) -> Result<()> {
+        let revoked: HashMap<String, u64> = Database::list_revoked_tokens(repo)
+            .await?
+            .into_iter()
+            .map(|token| {
+                (
+                    format!("{}", token.id.id),
+                    token.expires_at.0.timestamp() as u64,
+                )
+            })
+            .collect();
+        let revoked_sessions: HashSet<String> = Database::list_revoked_sessions(repo)
+            .await?
+            .into_iter()
+            .map(|session| format!("{}", session.id))
+            .collect();
+        let watermarks: HashMap<String, u64> = Database::list_token_watermarks(repo)
+            .await?
+            .into_iter()
+            .map(|user| {
+                (
+                    format!("{}", user.id.id),
+                    user.tokens_valid_after.0.timestamp() as u64,
+                )
+            })
+            .collect();
+        log::debug!(
+            "Loaded {} revoked tokens, {} revoked sessions and {} token watermarks",
+            revoked.len(),
+            revoked_sessions.len(),
+            watermarks.len()
+        );
+        *self.revoked.write().unwrap() = revoked;
+        *self.revoked_sessions.write().unwrap() = revoked_sessions;
+        *self.watermarks.write().unwrap() = watermarks;
+        Ok(())
+    }
+
+    /// Refuses a token that was revoked, issued to a session that has ended,
+    /// or issued before its user's watermark.
+    pub fn check(&self, claims: &GatewayUserClaims) -> Result<()> {
+        if let Some(jti) = &claims.jti {
+            if self.revoked.read().unwrap().contains_key(jti) {
+                return Err(GatewayError::Unauthorized(String::from(
+                    "The token has been revoked",
+                )));
+            }
+        }
+        if let Some(sid) = &claims.sid {
+            if self.revoked_sessions.read().unwrap().contains(sid) {
+                return Err(GatewayError::Unauthorized(String::from(
+                    "The token's session has ended; sign in again",
+                )));
+            }
+        }
+        // Watermarks are kept to the second, like `iat`, so a token issued in
+        // the same second is still accepted
+        if let Some(valid_after) = self.watermarks.read().unwrap().get(&claims.sub_id) {
+            if claims.iat < *valid_after {
+                return Err(GatewayError::Unauthorized(String::from(
+                    "The token was issued before the account changed; sign in again",
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Whether a change to a user record moved their watermark away from the
+    // cached one; changes that can't be read are assumed to have
+    fn watermark_changed(&self, action: Action, user: &Value) -> bool {
+        let Ok(user) = surrealdb::sql::from_value::<UserWatermark>(user.clone()) else {
+            return true;
+        };
+        let valid_after = match action {
+            Action::Delete => None,
+            _ => user
+                .tokens_valid_after
+                .map(|valid_after| valid_after.0.timestamp() as u64),
+        };
+        let cached = self
+            .watermarks
+            .read()
+            .unwrap()
+            .get(&format!("{}", user.id.id))
+            .copied();
+        cached != valid_after
+    }
+
+    // Whether a change to a session ended it since the list was loaded;
+    // changes that can't be read are assumed to have
+    fn session_ended(&self, action: Action, session: &Value) -> bool {
+        let Ok(session) = surrealdb::sql::from_value::<EndedSession>(session.clone()) else {
+            return true;
+        };
+        action != Action::Delete
+            && session.revoked_at.is_some()
+            && !self
+                .revoked_sessions
+                .read()
+                .unwrap()
+                .contains(&format!("{}", session.id.id))
+    }
+}
+
+/// Keeps the revocation list current by reloading it whenever a `LIVE SELECT`
+/// reports a revoked token, an ended session, or a user whose watermark moved.
+pub fn spawn_revocation_sync(list: Data<RevocationList>, repo: Data<Database>) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = watch_revocations(&list, &repo).await {
+                log::error!("Token revocation live query failed: {}", e);
+            }
+            tokio::time::sleep(LIVE_QUERY_RETRY_DELAY).await;
+        }
+    });
+}
+
+async fn watch_revocations(list: &Data<RevocationList>, repo: &Data<Database>) -> Result<()> {
+    let mut reconnected = repo.reconnected();
+    let mut streams = Vec::new();
+    for watched_table in [REVOKED_TOKEN_TABLE, SESSION_TABLE, USER_TABLE] {
+        let stream = repo
+            .db
+            .select::<Vec<Value>>(watched_table)
+            .live()
+            .await
+            .map_err(GatewayError::from)?;
+        streams.push(stream.map(move |notification| (watched_table, notification)));
+    }
+    // Catch any change made between the last refresh and the subscription
+    list.refresh(repo).await?;
+
+    let mut notifications = select_all(streams);
+    loop {
+        tokio::select! {
+            notification = notifications.next() => match notification {
+                // Most writes to a user, such as each login, leave the watermark be
+                Some((USER_TABLE, Ok(notification))) if !list.watermark_changed(notification.action, &notification.data) => {}
+                // As do most writes to a session, such as each refresh
+                Some((SESSION_TABLE, Ok(notification))) if !list.session_ended(notification.action, &notification.data) => {}
+                Some((_, Ok(notification))) => {
+                    log::debug!("Token revocation change detected: {:?}", notification.action);
+                    if let Err(e) = list.refresh(repo).await {
+                        log::error!("Unable to refresh the token revocation list: {}", e);
+                    }
+                }
+                Some((_, Err(e))) => return Err(e.into()),
+                None => return Ok(()),
+            },
+            // The live queries ended with the old connection
+            _ = reconnected.changed() => {
+                log::info!("Restarting token revocation live queries after reconnecting");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::audit::models::Actor;
+    use crate::auth::models::SessionEnd;
+    use crate::auth::repo::SessionRepository;
+    use crate::database::testing::database;
+    use crate::users::models::{DbGatewayUserRequest, InitialCredential};
+    use crate::users::repo::UserRepository;
+
+    fn user(tokens_valid_after: Option<&str>) -> Value {
+        let watermark = tokens_valid_after
+            .map(|valid_after| format!(", tokens_valid_after: d'{}'", valid_after))
+            .unwrap_or_default();
+        surrealdb::sql::value(&format!(
+            "{{ id: {}:alice, username: 'alice', last_login: d'2024-05-01T00:00:00Z'{} }}",
+            USER_TABLE, watermark
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_watermark_moves_count_as_changes() {
+        let list = RevocationList::new();
+        assert!(!list.watermark_changed(Action::Update, &user(None)));
+        assert!(list.watermark_changed(Action::Update, &user(Some("2024-05-01T00:00:00Z"))));
+
+        list.watermarks
+            .write()
+            .unwrap()
+            .insert(String::from("alice"), 1714521600);
+        assert!(!list.watermark_changed(Action::Update, &user(Some("2024-05-01T00:00:00Z"))));
+        assert!(list.watermark_changed(Action::Update, &user(Some("2024-05-02T00:00:00Z"))));
+        assert!(list.watermark_changed(Action::Delete, &user(Some("2024-05-01T00:00:00Z"))));
+    }
+
+    #[actix_web::test]
+    async fn ended_sessions_refuse_their_tokens() {
+        let repo = database().await;
+        let (user, _) = Database::register_user(
+            &repo,
+            DbGatewayUserRequest {
+                username: "alice".into(),
+            },
+            vec![],
+            InitialCredential::PasswordReset(3600),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let mut sessions = Vec::new();
+        for _ in 0..2 {
+            let (session, _) = Database::start_session(&repo, &user.id, None, None, 3600)
+                .await
+                .unwrap();
+            sessions.push(format!("{}", session.id.id));
+        }
+        let user_id = format!("{}", user.id.id);
+        Database::revoke_session(
+            &repo,
+            &user_id,
+            &sessions[0],
+            SessionEnd::Logout,
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let list = RevocationList::new();
+        list.refresh(&repo).await.unwrap();
+
+        let now = chrono::Utc::now().timestamp();
+        let claims = |sid: &String| -> GatewayUserClaims {
+            serde_json::from_value(json!({
+                "iss": "gateway",
+                "sub": "alice",
+                "sub_id": user_id,
+                "aud": [],
+                "exp": now + 60,
+                "iat": now,
+                "nbf": now,
+                "sid": sid,
+                "jti": "token",
+            }))
+            .unwrap()
+        };
+        assert!(matches!(
+            list.check(&claims(&sessions[0])),
+            Err(GatewayError::Unauthorized(_))
+        ));
+        assert!(list.check(&claims(&sessions[1])).is_ok());
+    }
+
+    #[test]
+    fn only_ending_a_session_counts_as_a_change() {
+        let session = |revoked_at: &str| {
+            surrealdb::sql::value(&format!(
+                "{{ id: {}:one, last_used_at: d'2024-05-01T00:00:00Z'{} }}",
+                SESSION_TABLE, revoked_at
+            ))
+            .unwrap()
+        };
+        let list = RevocationList::new();
+        let ended = session(", revoked_at: d'2024-05-01T00:00:00Z'");
+        assert!(!list.session_ended(Action::Update, &session("")));
+        assert!(list.session_ended(Action::Update, &ended));
+
+        list.revoked_sessions
+            .write()
+            .unwrap()
+            .insert(String::from("one"));
+        assert!(!list.session_ended(Action::Update, &ended));
+        assert!(!list.session_ended(Action::Delete, &ended));
+    }
+}
@@ -1,32 +1,53 @@
 use actix_web::http::header;
 use actix_web::{
-    patch, post,
+    delete, get, patch, post,
     web::{scope, to, Data, Json, Path, ServiceConfig},
     HttpRequest, HttpResponse,
 };
-use jsonwebtoken::{decode, encode, Header, Validation};
+use jsonwebtoken::{decode, Validation};
 use serde_json::json;
 use std::time::SystemTime;
+use surrealdb::sql::{Id, Thing};
 
+use super::keys::{self, SigningKeys};
 use super::models::{
-    GatewayLoginCredentials, GatewayUserClaims, JwtConfig, PasswordForm, RequestIdParams, UserForm,
+    DbSession, GatewayLoginCredentials, GatewayUserClaims, JwtConfig, PasswordForm, RefreshForm,
+    RequestIdParams, RotateKeyForm, SessionEnd, SessionIdParams, TokenResponse, UserForm,
+    WebSession, WebSigningKey,
 };
-use super::repo::UserAuthRepository;
-use crate::database::Database;
+use super::repo::{
+    SessionRepository, SigningKeyRepository, TokenRevocationRepository, UserAuthRepository,
+};
+use super::revocation::RevocationList;
+use crate::audit;
+use crate::audit::models::{Actor, AuditAction};
+use crate::config::GatewayConfig;
+use crate::database::{Database, USER_TABLE};
 use crate::errors::{unknown_resource_error, GatewayError, Result};
-
-const GATEWAY_JWT_ISSUER: &str = "apigateway.local";
+use crate::users::models::DbGatewayUserResponse;
+use crate::users::repo::UserRepository;
 
 // Intermediate function to configure services
 pub fn service_setup(cfg: &mut ServiceConfig) {
     cfg.service(
         scope("/auth/v1")
             .service(authenticate_user)
+            .service(refresh_access_token)
+            .service(logout)
+            .service(list_sessions)
+            .service(revoke_session)
             .service(set_password)
             .service(request_password_reset)
             .service(reset_password)
             .default_service(to(unknown_resource_error)),
-    );
+    )
+    .service(
+        scope("/cfg/v1/signing-keys")
+            .service(list_signing_keys)
+            .service(rotate_signing_key)
+            .default_service(to(unknown_resource_error)),
+    )
+    .service(jwks);
 }
 
 #[post("/login")]
@@ -34,36 +55,193 @@ async fn authenticate_user(
     req: HttpRequest,
     repo: Data<Database>,
     credential_form: Json<GatewayLoginCredentials>,
-) -> Result<String> {
+) -> Result<Json<TokenResponse>> {
     let credentials = credential_form.into_inner();
-    let user =
-        Database::authenticate_user(&repo, &credentials.username, &credentials.password).await?;
+    let authenticated =
+        Database::authenticate_user(&repo, &credentials.username, &credentials.password).await;
+    if let Err(GatewayError::InvalidUsernameOrPassword(_)) = &authenticated {
+        audit::repo::record(
+            &repo,
+            Actor::anonymous(&req).entry(
+                AuditAction::LoginFailed,
+                None,
+                json!({ "username": credentials.username }),
+            ),
+        )
+        .await;
+    }
+    let user = authenticated?;
+    Ok(Json(sign_in(&req, &repo, user, None).await?))
+}
+
+/// Starts a session for a user who proved who they are, through `provider`
+/// when it isn't their password, and issues its first tokens.
+pub async fn sign_in(
+    req: &HttpRequest,
+    repo: &Data<Database>,
+    user: DbGatewayUserResponse,
+    provider: Option<&str>,
+) -> Result<TokenResponse> {
     let user_id = format!("{}", user.id.id);
+    let config: &Data<JwtConfig> = req.app_data().unwrap();
+    let signing_keys: &Data<SigningKeys> = req.app_data().unwrap();
+    let actor = Actor {
+        user_id: Some(user_id.clone()),
+        username: Some(user.username.clone()),
+        ..Actor::anonymous(req)
+    };
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let (session, refresh_token) = Database::start_session(
+        repo,
+        &user.id,
+        user_agent,
+        actor.source_ip.clone(),
+        config.refresh_token_lifetime_secs,
+    )
+    .await?;
+    let tokens = issue_tokens(config, signing_keys, &user, &session, refresh_token)?;
+
+    Database::set_last_login(repo, &user_id).await?;
+    let mut details = json!({ "session": format!("{}", session.id.id) });
+    if let Some(provider) = provider {
+        details["provider"] = json!(provider);
+    }
+    audit::repo::record(
+        repo,
+        actor.entry(AuditAction::LoginSucceeded, Some(user.id), details),
+    )
+    .await;
+    Ok(tokens)
+}
+
+#[post("/token/refresh")]
+async fn refresh_access_token(
+    req: HttpRequest,
+    repo: Data<Database>,
+    refresh_form: Json<RefreshForm>,
+) -> Result<Json<TokenResponse>> {
+    let config: &Data<JwtConfig> = &req.app_data().unwrap();
+    let signing_keys: &Data<SigningKeys> = req.app_data().unwrap();
+    let (session, refresh_token) = Database::refresh_session(
+        &repo,
+        &refresh_form.refresh_token,
+        None,
+        &Actor::anonymous(&req),
+        config.refresh_token_lifetime_secs,
+    )
+    .await?;
+    // The new access token carries the roles the user holds now
+    let user = Database::user_detail(&repo, &format!("{}", session.user.id)).await?;
+    Ok(Json(issue_tokens(
+        config,
+        signing_keys,
+        &user,
+        &session,
+        refresh_token,
+    )?))
+}
+
+#[post("/logout")]
+async fn logout(req: HttpRequest, repo: Data<Database>) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, None)?;
+    if let Some(session_id) = &claims.sid {
+        let revoked = Database::revoke_session(
+            &repo,
+            &claims.sub_id,
+            session_id,
+            SessionEnd::Logout,
+            &Actor::from_claims(&req, &claims),
+        )
+        .await;
+        match revoked {
+            // The session may already have been revoked elsewhere
+            Ok(()) | Err(GatewayError::NotFound(_, _)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    // The access token is refused from now on, rather than when it expires
+    Database::revoke_token(&repo, &claims).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/sessions")]
+async fn list_sessions(req: HttpRequest, repo: Data<Database>) -> Result<Json<Vec<WebSession>>> {
+    let claims = validate_jwt(&req, None)?;
+    let sessions = Database::list_sessions(&repo, &claims.sub_id).await?;
+    Ok(Json(
+        sessions
+            .iter()
+            .map(|session| WebSession::new(session, claims.sid.as_deref()))
+            .collect(),
+    ))
+}
+
+#[delete("/sessions/{session_id}")]
+async fn revoke_session(
+    req: HttpRequest,
+    repo: Data<Database>,
+    path_params: Path<SessionIdParams>,
+) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, None)?;
+    Database::revoke_session(
+        &repo,
+        &claims.sub_id,
+        &path_params.session_id,
+        SessionEnd::Revoked,
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Signs an access token for the user's current roles, to go with the
+// session's new refresh token
+fn issue_tokens(
+    config: &JwtConfig,
+    signing_keys: &SigningKeys,
+    user: &DbGatewayUserResponse,
+    session: &DbSession,
+    refresh_token: String,
+) -> Result<TokenResponse> {
+    let roles = user.roles.iter().map(|role| format!("{}", role)).collect();
+    let claims = access_token_claims(config, user, session, roles);
+    Ok(TokenResponse {
+        access_token: signing_keys.sign(&claims)?,
+        token_type: String::from("Bearer"),
+        expires_in: config.token_lifetime_secs,
+        refresh_token,
+    })
+}
+
+/// Claims of an access token for the user, issued for the session and
+/// granting the given roles.
+pub fn access_token_claims(
+    config: &JwtConfig,
+    user: &DbGatewayUserResponse,
+    session: &DbSession,
+    roles: Vec<String>,
+) -> GatewayUserClaims {
     let now_ts = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs();
-    let duration = 24 * 60 * 60;
-    let claims = GatewayUserClaims {
+    GatewayUserClaims {
         // Issuer (us/apigateway.local)
-        iss: String::from(GATEWAY_JWT_ISSUER),
+        iss: config.issuer.clone(),
         sub: user.username.clone(),
-        sub_id: user_id.clone(),
-        aud: user.roles.iter().map(|role| format!("{}", role)).collect(),
-        exp: now_ts + duration,
+        sub_id: format!("{}", user.id.id),
+        aud: roles,
+        exp: now_ts + config.token_lifetime_secs,
         iat: now_ts,
         nbf: now_ts,
-    };
-    let config: &Data<JwtConfig> = &req.app_data().unwrap();
-    let token = encode(
-        &Header::new(config.algorithm),
-        &claims,
-        &config.encoding_key,
-    )
-    .map_err(|e| GatewayError::TokenEncodeError(e.to_string()))?;
-
-    Database::set_last_login(&repo, &user_id).await?;
-    Ok(token)
+        sid: Some(format!("{}", session.id.id)),
+        jti: Some(format!("{}", Id::rand())),
+        scope: session.scope.as_ref().map(|scope| scope.join(" ")),
+    }
 }
 
 #[patch("/set-password")]
@@ -73,7 +251,7 @@ async fn set_password(
     password_form: Json<PasswordForm>,
 ) -> Result<HttpResponse> {
     let auth_claims = validate_jwt(&req, None)?;
-    let user_id = validate_jwt(&req, None)?.sub_id;
+    let user_id = auth_claims.sub_id.clone();
     let request_form = password_form.into_inner();
     Database::authenticate_user(&repo, &auth_claims.sub, &request_form.old_password)
         .await
@@ -84,38 +262,70 @@ async fn set_password(
             other => other,
         })?;
     Database::set_user_password(&repo, &user_id, &request_form.password).await?;
+    audit::repo::record(
+        &repo,
+        Actor::from_claims(&req, &auth_claims).entry(
+            AuditAction::PasswordChanged,
+            Some(Thing::from((USER_TABLE, user_id.as_str()))),
+            json!({}),
+        ),
+    )
+    .await;
     Ok(HttpResponse::NoContent().finish())
 }
 
 #[post("/request-password-reset")]
 async fn request_password_reset(
+    req: HttpRequest,
     repo: Data<Database>,
+    config: Data<GatewayConfig>,
     user_form: Json<UserForm>,
 ) -> Result<HttpResponse> {
     let username = user_form.into_inner().username;
-    let request_result = Database::request_password_reset(&repo, &username).await;
+    let request_result = Database::request_password_reset(
+        &repo,
+        &username,
+        config.auth.password_reset_lifetime_secs,
+    )
+    .await;
     match request_result {
         Err(e) => log::debug!("{}", e),
-        _ => ()
+        Ok(reset_request) => {
+            audit::repo::record(
+                &repo,
+                Actor::anonymous(&req).entry(
+                    AuditAction::PasswordResetRequested,
+                    Some(Thing::from((USER_TABLE, reset_request.user_id.as_str()))),
+                    json!({ "username": username }),
+                ),
+            )
+            .await
+        }
     };
     Ok(HttpResponse::Created().json(json!({"success": true, "message": format!("If a user exists with the username {}, they will receive a message to reset their password through the appropriate channel.", &username)})))
 }
 
 #[patch("/reset-password/{request_id}")]
 async fn reset_password(
+    req: HttpRequest,
     repo: Data<Database>,
     credential_form: Json<GatewayLoginCredentials>,
     path_params: Path<RequestIdParams>,
 ) -> Result<HttpResponse> {
     let credentials = credential_form.into_inner();
     let request_id = path_params.into_inner().request_id;
-    Database::set_user_password_with_reset_token(
+    let user = Database::set_user_password_with_reset_token(
         &repo,
         &request_id,
         &credentials.username,
         &credentials.password,
     )
     .await?;
+    audit::repo::record(
+        &repo,
+        Actor::anonymous(&req).entry(AuditAction::PasswordResetUsed, Some(user.id), json!({})),
+    )
+    .await;
     Ok(HttpResponse::NoContent().finish())
 }
 
@@ -136,23 +346,25 @@ pub fn validate_jwt(req: &HttpRequest, scopes: Option<&Vec<&str>>) -> Result<Gat
         "Missing 'Bearer' token from 'Authorization' header".to_string(),
     ))?;
 
-    let mut validation = Validation::new(jwt_config.algorithm);
+    let (algorithm, decoding_key) = verifying_key(req, token)?;
+    let mut validation = Validation::new(algorithm);
     if let Some(audience) = scopes {
         validation.set_audience(audience);
     } else {
         validation.validate_aud = false;
     }
     validation.set_issuer(&[jwt_config.issuer.as_str()]);
-    decode::<GatewayUserClaims>(token, &jwt_config.decoding_key, &validation)
+    let claims = decode::<GatewayUserClaims>(token, &decoding_key, &validation)
         .and_then(|token_data| Ok(token_data.claims))
-        .map_err(|e| GatewayError::TokenDecodeError(e.to_string()))
+        .map_err(|e| GatewayError::TokenDecodeError(e.to_string()))?;
+    check_revocation(req, &claims)?;
+    Ok(claims)
 }
 
 pub fn validate_jwt_prefix(
     req: &HttpRequest,
     scope_prefixes: &Vec<&str>,
 ) -> Result<GatewayUserClaims> {
-    let jwt_config = req.app_data::<Data<JwtConfig>>().unwrap();
     let token = match req.headers().get(header::AUTHORIZATION) {
         Some(auth_header) => {
             let auth_header = auth_header.to_str().unwrap_or("");
@@ -168,9 +380,10 @@ pub fn validate_jwt_prefix(
         "Missing 'Bearer' token from 'Authorization' header".to_string(),
     ))?;
 
-    let mut validation = Validation::new(jwt_config.algorithm);
+    let (algorithm, decoding_key) = verifying_key(req, token)?;
+    let mut validation = Validation::new(algorithm);
     validation.validate_aud = false;
-    let claims = decode::<GatewayUserClaims>(token, &jwt_config.decoding_key, &validation)
+    let claims = decode::<GatewayUserClaims>(token, &decoding_key, &validation)
         .and_then(|token_data| Ok(token_data.claims))
         .map_err(|e| GatewayError::TokenDecodeError(e.to_string()))?;
 
@@ -183,5 +396,70 @@ pub fn validate_jwt_prefix(
             "User does not have the requisite role".into(),
         ));
     }
+    check_revocation(req, &claims)?;
     Ok(claims)
 }
+
+// A validly signed token may still have been revoked since it was issued
+fn check_revocation(req: &HttpRequest, claims: &GatewayUserClaims) -> Result<()> {
+    req.app_data::<Data<RevocationList>>()
+        .unwrap()
+        .check(claims)
+}
+
+// The key named by the token's `kid`, which must be one the gateway holds
+fn verifying_key(
+    req: &HttpRequest,
+    token: &str,
+) -> Result<(jsonwebtoken::Algorithm, jsonwebtoken::DecodingKey)> {
+    req.app_data::<Data<SigningKeys>>()
+        .unwrap()
+        .verifying_key(token)
+}
+
+// The public signing keys, so services can verify access tokens themselves
+#[get("/.well-known/jwks.json")]
+async fn jwks(signing_keys: Data<SigningKeys>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .json(signing_keys.jwks())
+}
+
+#[get("/")]
+async fn list_signing_keys(
+    req: HttpRequest,
+    repo: Data<Database>,
+    config: Data<JwtConfig>,
+) -> Result<Json<Vec<WebSigningKey>>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let keys = Database::list_signing_keys(&repo, config.token_lifetime_secs).await?;
+    Ok(Json(keys.iter().map(WebSigningKey::from).collect()))
+}
+
+// Retires the active key in favour of a new one. Tokens it signed still
+// verify until they expire.
+#[post("/rotate")]
+async fn rotate_signing_key(
+    req: HttpRequest,
+    repo: Data<Database>,
+    config: Data<JwtConfig>,
+    signing_keys: Data<SigningKeys>,
+    rotate_form: Option<Json<RotateKeyForm>>,
+) -> Result<Json<WebSigningKey>> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let algorithm = rotate_form
+        .and_then(|form| form.algorithm)
+        .unwrap_or(config.algorithm);
+    let key = keys::rotate_signing_key(
+        &repo,
+        algorithm,
+        config.token_lifetime_secs,
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    // Sign with the new key straight away, rather than after the live query
+    signing_keys
+        .refresh(&repo, config.token_lifetime_secs)
+        .await?;
+    Ok(Json(WebSigningKey::from(&key)))
+}
@@ -0,0 +1,91 @@
+use actix_web::web::Data;
+use clap::builder::NonEmptyStringValueParser;
+use clap::Args;
+use validator::Validate;
+
+use super::users::register_with_credential;
+use crate::api_services::models::{DbApiRole, WebApiRole};
+use crate::api_services::repo::RoleRepository;
+use crate::config::GatewayConfig;
+use crate::database::Database;
+use crate::errors::{GatewayError, Result};
+use crate::users::models::WebGatewayUserRequest;
+use crate::users::repo::UserRepository;
+
+const ADMIN_ROLE_NAMESPACE: &str = "Gateway";
+const ADMIN_ROLE_NAME: &str = "Admin";
+
+#[derive(Debug, Args)]
+pub struct BootstrapAdminArgs {
+    /// Username for the new admin
+    #[arg(long)]
+    pub username: String,
+
+    /// Initial password; when omitted, a one-time password reset link is printed instead
+    #[arg(
+        long,
+        env = "API_DIRECTORY_ADMIN_PASSWORD",
+        hide_env_values = true,
+        value_parser = NonEmptyStringValueParser::new()
+    )]
+    pub password: Option<String>,
+}
+
+/// Creates the `Gateway::Admin` role (if missing) and a first user holding it.
+/// Does nothing when any user already holds the role, so it is safe to run on
+/// every deployment.
+pub async fn bootstrap_admin(
+    args: BootstrapAdminArgs,
+    config: &GatewayConfig,
+    repo: &Data<Database>,
+) -> Result<()> {
+    let existing_admins: Vec<String> = Database::list_users(repo)
+        .await?
+        .into_iter()
+        .filter(|user| user.roles.iter().any(is_admin_role))
+        .map(|user| user.username)
+        .collect();
+    if !existing_admins.is_empty() {
+        println!(
+            "An admin user already exists ({}); nothing to do.",
+            existing_admins.join(", ")
+        );
+        return Ok(());
+    }
+
+    let user_request = WebGatewayUserRequest {
+        username: args.username,
+        roles: Vec::new(),
+    };
+    user_request
+        .validate()
+        .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+
+    let admin_role = Database::find_or_add_role(
+        repo,
+        &WebApiRole {
+            id: None,
+            revision: None,
+            namespace: ADMIN_ROLE_NAMESPACE.to_string(),
+            name: ADMIN_ROLE_NAME.to_string(),
+        },
+    )
+    .await?;
+    let (admin, password_reset_url) =
+        register_with_credential(repo, config, &user_request, vec![admin_role], args.password)
+            .await?;
+
+    println!("Created admin user [{}].", admin.username);
+    if let Some(url) = password_reset_url {
+        println!(
+            "Set its password within {} minutes at {}",
+            config.auth.password_reset_lifetime_secs / 60,
+            url
+        );
+    }
+    Ok(())
+}
+
+fn is_admin_role(role: &DbApiRole) -> bool {
+    role.namespace == ADMIN_ROLE_NAMESPACE && role.name == ADMIN_ROLE_NAME
+}
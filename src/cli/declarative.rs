@@ -0,0 +1,143 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use actix_web::web::Data;
+use clap::{Args, Subcommand};
+
+use super::{print_json, print_table, OutputArgs, OutputFormat};
+use crate::audit::models::Actor;
+use crate::database::Database;
+use crate::declarative::models::{ConfigDocument, ConfigPlan, DocumentFormat};
+use crate::declarative::sync;
+use crate::errors::{GatewayError, Result};
+
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Write every role and service as a JSON or YAML document
+    Export {
+        /// Also export each user's role memberships
+        #[arg(long)]
+        include_members: bool,
+        /// File to write instead of stdout
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Document format; defaults to YAML for `.yaml`/`.yml` files and JSON otherwise
+        #[arg(long, value_enum)]
+        format: Option<DocumentFormat>,
+    },
+    /// Make the database match a JSON or YAML document, printing the changes made.
+    /// The changes are applied one by one, so a failure leaves the earlier ones in
+    /// place; applying the document again carries out the rest.
+    Apply {
+        /// JSON or YAML file to read, or `-` for stdin
+        #[arg(long)]
+        file: PathBuf,
+        /// Document format; defaults to YAML for `.yaml`/`.yml` files and JSON otherwise
+        #[arg(long, value_enum)]
+        format: Option<DocumentFormat>,
+        /// Only print the changes that would be made
+        #[arg(long)]
+        dry_run: bool,
+        /// Delete roles, services and memberships missing from the document
+        #[arg(long)]
+        prune: bool,
+    },
+}
+
+pub async fn run(args: ConfigArgs, repo: &Data<Database>) -> Result<()> {
+    match args.command {
+        ConfigCommand::Export {
+            include_members,
+            file,
+            format,
+        } => {
+            let document = sync::export(repo, include_members).await?;
+            match file {
+                Some(file) => {
+                    let format = format.unwrap_or_else(|| DocumentFormat::from_path(&file));
+                    std::fs::write(&file, document.render(format)?).map_err(|e| {
+                        GatewayError::SystemError(format!(
+                            "Unable to write {}: {}",
+                            file.display(),
+                            e
+                        ))
+                    })
+                }
+                None => {
+                    print!("{}", document.render(format.unwrap_or_default())?);
+                    Ok(())
+                }
+            }
+        }
+        ConfigCommand::Apply {
+            file,
+            format,
+            dry_run,
+            prune,
+        } => {
+            let format = format.unwrap_or_else(|| DocumentFormat::from_path(&file));
+            let document = read_document(&file, format)?;
+            let plan = sync::apply(repo, &document, prune, dry_run, &Actor::default()).await?;
+            print_plan(args.output.output, &plan)
+        }
+    }
+}
+
+fn read_document(file: &Path, format: DocumentFormat) -> Result<ConfigDocument> {
+    let mut contents = String::new();
+    let read = if file == Path::new("-") {
+        std::io::stdin().read_to_string(&mut contents)
+    } else {
+        std::fs::File::open(file).and_then(|mut f| f.read_to_string(&mut contents))
+    };
+    read.map_err(|e| {
+        GatewayError::BadRequest(format!("Unable to read {}: {}", file.display(), e))
+    })?;
+    ConfigDocument::parse(contents.as_bytes(), format).map_err(|e| match e {
+        GatewayError::BadRequest(message) => {
+            GatewayError::BadRequest(format!("{}: {}", file.display(), message))
+        }
+        e => e,
+    })
+}
+
+fn print_plan(output: OutputFormat, plan: &ConfigPlan) -> Result<()> {
+    match output {
+        OutputFormat::Json => print_json(plan),
+        OutputFormat::Table => {
+            if plan.changes.is_empty() {
+                println!("No changes; the database already matches the document.");
+                return Ok(());
+            }
+            let rows: Vec<Vec<String>> = plan
+                .changes
+                .iter()
+                .map(|change| {
+                    vec![
+                        format!("{:?}", change.action).to_lowercase(),
+                        format!("{:?}", change.kind).to_lowercase(),
+                        change.name.clone(),
+                        change.fields.join(", "),
+                    ]
+                })
+                .collect();
+            print_table(&["ACTION", "KIND", "NAME", "FIELDS"], &rows);
+            println!();
+            if plan.applied {
+                println!("Applied {} changes.", plan.changes.len());
+            } else {
+                println!("Dry run: {} changes not applied.", plan.changes.len());
+            }
+            Ok(())
+        }
+    }
+}
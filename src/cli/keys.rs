@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use actix_web::web::Data;
+use clap::{Args, Subcommand};
+
+use super::{print_json, print_table, OutputArgs, OutputFormat};
+use crate::audit::models::Actor;
+use crate::auth::keys;
+use crate::auth::models::{DbSigningKey, SigningAlgorithm, WebSigningKey};
+use crate::auth::repo::SigningKeyRepository;
+use crate::config::GatewayConfig;
+use crate::database::Database;
+use crate::errors::{GatewayError, Result};
+
+#[derive(Debug, Args)]
+pub struct KeysArgs {
+    #[command(subcommand)]
+    pub command: KeysCommand,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeysCommand {
+    /// List the active signing key and the retired keys that still verify tokens
+    List,
+    /// Generate a new signing key and retire the active one
+    Rotate {
+        /// Algorithm of the new key; defaults to `auth.jwt_algorithm`
+        #[arg(long, value_enum)]
+        algorithm: Option<SigningAlgorithm>,
+    },
+    /// Make a PEM-formatted private key the signing key and retire the active one
+    Import {
+        /// Private key file to read
+        #[arg(long)]
+        file: PathBuf,
+        /// Algorithm the key signs with
+        #[arg(long, value_enum)]
+        algorithm: SigningAlgorithm,
+    },
+}
+
+pub async fn run(args: KeysArgs, config: &GatewayConfig, repo: &Data<Database>) -> Result<()> {
+    let output = args.output.output;
+    // Retired keys verify tokens for as long as the tokens they signed last
+    let retention_secs = config.auth.token_lifetime_secs;
+    match args.command {
+        KeysCommand::List => {
+            let keys = Database::list_signing_keys(repo, retention_secs).await?;
+            print_keys(output, &keys)
+        }
+        KeysCommand::Rotate { algorithm } => {
+            let algorithm = algorithm.unwrap_or(config.auth.jwt_algorithm);
+            let key = keys::rotate_signing_key(repo, algorithm, retention_secs, &Actor::default())
+                .await?;
+            print_keys(output, &[key])
+        }
+        KeysCommand::Import { file, algorithm } => {
+            let pem = std::fs::read(&file).map_err(|e| {
+                GatewayError::BadRequest(format!("Unable to read {}: {}", file.display(), e))
+            })?;
+            let key =
+                keys::import_signing_key(repo, algorithm, &pem, retention_secs, &Actor::default())
+                    .await?;
+            print_keys(output, &[key])
+        }
+    }
+}
+
+fn print_keys(output: OutputFormat, keys: &[DbSigningKey]) -> Result<()> {
+    let keys: Vec<WebSigningKey> = keys.iter().map(Into::into).collect();
+    match output {
+        OutputFormat::Json => print_json(&keys),
+        OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = keys
+                .iter()
+                .map(|key| {
+                    vec![
+                        key.id.clone(),
+                        key.algorithm.to_string(),
+                        key.created_at.0.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        key.retired_at
+                            .as_ref()
+                            .map(|retired_at| retired_at.0.format("%Y-%m-%d %H:%M:%S").to_string())
+                            .unwrap_or_default(),
+                    ]
+                })
+                .collect();
+            print_table(&["ID", "ALGORITHM", "CREATED AT", "RETIRED AT"], &rows);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use actix_web::web::Data;
+use clap::{Args, Subcommand};
+
+use super::{print_json, print_table, OutputArgs, OutputFormat};
+use crate::database::Database;
+use crate::errors::Result;
+use crate::migrations::{self, MigrationStatus};
+
+#[derive(Debug, Args)]
+pub struct MigrateArgs {
+    #[command(subcommand)]
+    pub command: MigrateCommand,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateCommand {
+    /// List every migration and whether it has been applied
+    Status,
+    /// Apply every pending migration; the gateway also does this on startup
+    Up,
+}
+
+pub async fn run(args: MigrateArgs, repo: &Data<Database>) -> Result<()> {
+    let output = args.output.output;
+    match args.command {
+        MigrateCommand::Status => {
+            let statuses = migrations::status(repo).await?;
+            print_statuses(output, &statuses)
+        }
+        MigrateCommand::Up => {
+            let applied = migrations::migrate(repo).await?;
+            let statuses: Vec<MigrationStatus> = migrations::status(repo)
+                .await?
+                .into_iter()
+                .filter(|status| applied.contains(&status.version))
+                .collect();
+            if output == OutputFormat::Table && statuses.is_empty() {
+                println!("The database schema is up to date.");
+                return Ok(());
+            }
+            print_statuses(output, &statuses)
+        }
+    }
+}
+
+fn print_statuses(output: OutputFormat, statuses: &[MigrationStatus]) -> Result<()> {
+    match output {
+        OutputFormat::Json => print_json(statuses),
+        OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = statuses
+                .iter()
+                .map(|status| {
+                    vec![
+                        status.version.to_string(),
+                        status.name.clone(),
+                        status.state.to_string(),
+                        status
+                            .applied_at
+                            .as_ref()
+                            .map(|applied_at| applied_at.0.format("%Y-%m-%d %H:%M:%S").to_string())
+                            .unwrap_or_default(),
+                    ]
+                })
+                .collect();
+            print_table(&["VERSION", "NAME", "STATE", "APPLIED AT"], &rows);
+            Ok(())
+        }
+    }
+}
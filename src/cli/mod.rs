@@ -0,0 +1,115 @@
+use actix_web::web::Data;
+use clap::{Args, Subcommand, ValueEnum};
+use serde::Serialize;
+
+use crate::api_services::models::WebApiRole;
+use crate::config::GatewayConfig;
+use crate::database::Database;
+use crate::errors::{GatewayError, Result};
+
+pub mod bootstrap;
+pub mod declarative;
+pub mod keys;
+pub mod migrate;
+pub mod roles;
+pub mod services;
+pub mod users;
+
+// Exit statuses; clap and configuration errors exit with 2
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_NOT_FOUND: i32 = 3;
+pub const EXIT_INVALID_INPUT: i32 = 4;
+
+/// Maintenance tasks run against the configured database instead of
+/// starting the gateway.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Create the first `Gateway::Admin` user, unless an admin already exists
+    BootstrapAdmin(bootstrap::BootstrapAdminArgs),
+    /// Manage gateway users
+    Users(users::UsersArgs),
+    /// Manage API roles
+    Roles(roles::RolesArgs),
+    /// Manage API services
+    Services(services::ServicesArgs),
+    /// Export or apply the declarative role and service configuration
+    Config(declarative::ConfigArgs),
+    /// Show or apply database schema migrations
+    Migrate(migrate::MigrateArgs),
+    /// Manage the keys access tokens are signed with
+    Keys(keys::KeysArgs),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    /// How records are printed
+    #[arg(long, value_enum, default_value_t, global = true)]
+    pub output: OutputFormat,
+}
+
+/// Runs a subcommand to completion, reporting any error on stderr, and
+/// returns the process exit status.
+pub async fn run(command: Command, config: &GatewayConfig, repo: &Data<Database>) -> i32 {
+    let result = match command {
+        Command::BootstrapAdmin(args) => bootstrap::bootstrap_admin(args, config, repo).await,
+        Command::Users(args) => users::run(args, config, repo).await,
+        Command::Roles(args) => roles::run(args, repo).await,
+        Command::Services(args) => services::run(args, repo).await,
+        Command::Config(args) => declarative::run(args, repo).await,
+        Command::Migrate(args) => migrate::run(args, repo).await,
+        Command::Keys(args) => keys::run(args, config, repo).await,
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit_code(&e)
+        }
+    }
+}
+
+fn exit_code(error: &GatewayError) -> i32 {
+    match error {
+        GatewayError::NotFound(_, _) => EXIT_NOT_FOUND,
+        GatewayError::BadRequest(_) | GatewayError::MissingData(_) => EXIT_INVALID_INPUT,
+        _ => EXIT_FAILURE,
+    }
+}
+
+// Parses a `Namespace::Name` role argument
+pub fn parse_role(value: &str) -> std::result::Result<WebApiRole, String> {
+    value.parse()
+}
+
+pub fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+    let json = serde_json::to_string_pretty(value)
+        .map_err(|e| GatewayError::SystemError(e.to_string()))?;
+    println!("{}", json);
+    Ok(())
+}
+
+// Prints rows under a header line, padding each column to its widest cell
+pub fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let header_row: Vec<String> = headers.iter().map(|header| header.to_string()).collect();
+    for row in std::iter::once(&header_row).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
@@ -0,0 +1,93 @@
+use actix_web::web::Data;
+use clap::{Args, Subcommand};
+use validator::Validate;
+
+use super::{parse_role, print_json, print_table, OutputArgs, OutputFormat};
+use crate::api_services::models::{DbApiRole, WebApiRole};
+use crate::api_services::repo::RoleRepository;
+use crate::audit::models::Actor;
+use crate::database::Database;
+use crate::errors::{GatewayError, Result};
+use crate::revisions::Precondition;
+
+#[derive(Debug, Args)]
+pub struct RolesArgs {
+    #[command(subcommand)]
+    pub command: RolesCommand,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RolesCommand {
+    /// List every role
+    List,
+    /// Create a role
+    Create {
+        /// Role to create, as `Namespace::Name`
+        #[arg(value_parser = parse_role)]
+        role: WebApiRole,
+    },
+    /// Change a role's namespace and name
+    Rename {
+        role_id: String,
+        /// New role, as `Namespace::Name`
+        #[arg(value_parser = parse_role)]
+        role: WebApiRole,
+    },
+    /// Delete a role along with its service authorizations
+    Delete { role_id: String },
+}
+
+pub async fn run(args: RolesArgs, repo: &Data<Database>) -> Result<()> {
+    let output = args.output.output;
+    match args.command {
+        RolesCommand::List => {
+            let roles = Database::list_roles(repo).await?;
+            print_roles(output, &roles)
+        }
+        RolesCommand::Create { role } => {
+            role.validate()
+                .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+            let created_role = Database::add_role(repo, &role).await?;
+            print_roles(output, &[created_role])
+        }
+        RolesCommand::Rename { role_id, role } => {
+            role.validate()
+                .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+            let updated_role =
+                Database::rename_role(repo, &role_id, &role, &Precondition::Any, &Actor::default())
+                    .await?;
+            print_roles(output, &[updated_role])
+        }
+        RolesCommand::Delete { role_id } => {
+            Database::delete_role(repo, role_id.as_str(), &Actor::default()).await?;
+            if output == OutputFormat::Table {
+                println!("Deleted role [{}].", role_id);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn print_roles(output: OutputFormat, roles: &[DbApiRole]) -> Result<()> {
+    let roles: Vec<WebApiRole> = roles.iter().map(Into::into).collect();
+    match output {
+        OutputFormat::Json => print_json(&roles),
+        OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = roles
+                .iter()
+                .map(|role| {
+                    vec![
+                        role.id.clone().unwrap_or_default(),
+                        role.namespace.clone(),
+                        role.name.clone(),
+                    ]
+                })
+                .collect();
+            print_table(&["ID", "NAMESPACE", "NAME"], &rows);
+            Ok(())
+        }
+    }
+}
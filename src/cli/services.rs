@@ -0,0 +1,145 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use actix_web::web::Data;
+use clap::{Args, Subcommand};
+use serde::de::DeserializeOwned;
+use validator::Validate;
+
+use super::{print_json, print_table, OutputArgs, OutputFormat};
+use crate::api_services::models::{
+    DbFullApiService, WebApiRole, WebRequestApiService, WebRequestPartialApiService,
+    WebResponseApiService,
+};
+use crate::api_services::repo::ApiServiceRepository;
+use crate::audit::models::Actor;
+use crate::database::Database;
+use crate::errors::{GatewayError, Result};
+use crate::revisions::Precondition;
+
+#[derive(Debug, Args)]
+pub struct ServicesArgs {
+    #[command(subcommand)]
+    pub command: ServicesCommand,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ServicesCommand {
+    /// List every service
+    List,
+    /// Register a service from a JSON definition (the body accepted by `POST /cfg/v1/api-services/`)
+    Add {
+        /// JSON file to read, or `-` for stdin
+        #[arg(long)]
+        file: PathBuf,
+    },
+    /// Update some of a service's fields from a partial JSON definition
+    Patch {
+        service_id: String,
+        /// JSON file to read, or `-` for stdin
+        #[arg(long)]
+        file: PathBuf,
+    },
+    /// Delete a service
+    Delete { service_id: String },
+}
+
+pub async fn run(args: ServicesArgs, repo: &Data<Database>) -> Result<()> {
+    let output = args.output.output;
+    match args.command {
+        ServicesCommand::List => {
+            let services = Database::list_services(repo).await?;
+            print_services(output, &services)
+        }
+        ServicesCommand::Add { file } => {
+            let service: WebRequestApiService = read_json(&file)?;
+            service
+                .validate()
+                .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+            let roles = Vec::<WebApiRole>::from(&service);
+            let created_service =
+                Database::add_service(repo, &(&service).into(), &roles, &Actor::default()).await?;
+            print_services(output, &[created_service])
+        }
+        ServicesCommand::Patch { service_id, file } => {
+            let partial_update: WebRequestPartialApiService = read_json(&file)?;
+            partial_update
+                .validate()
+                .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+            let patched_service = Database::update_service(
+                repo,
+                &service_id,
+                &partial_update,
+                &Precondition::Any,
+                &Actor::default(),
+            )
+            .await?;
+            print_services(output, &[patched_service])
+        }
+        ServicesCommand::Delete { service_id } => {
+            Database::delete_service(repo, service_id.as_str(), &Actor::default()).await?;
+            if output == OutputFormat::Table {
+                println!("Deleted service [{}].", service_id);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn read_json<T: DeserializeOwned>(file: &Path) -> Result<T> {
+    let mut contents = String::new();
+    let read = if file == Path::new("-") {
+        std::io::stdin().read_to_string(&mut contents)
+    } else {
+        std::fs::File::open(file).and_then(|mut f| f.read_to_string(&mut contents))
+    };
+    read.map_err(|e| {
+        GatewayError::BadRequest(format!("Unable to read {}: {}", file.display(), e))
+    })?;
+    serde_json::from_str(&contents)
+        .map_err(|e| GatewayError::BadRequest(format!("Invalid JSON in {}: {}", file.display(), e)))
+}
+
+fn print_services(output: OutputFormat, services: &[DbFullApiService]) -> Result<()> {
+    let services: Vec<WebResponseApiService> = services.iter().map(Into::into).collect();
+    match output {
+        OutputFormat::Json => print_json(&services),
+        OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = services
+                .iter()
+                .map(|service| {
+                    vec![
+                        service.id.clone(),
+                        service.api_name.clone(),
+                        service.version.clone(),
+                        service.environment.clone(),
+                        service.active.to_string(),
+                        service.forward_url.clone(),
+                        service
+                            .roles
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                    ]
+                })
+                .collect();
+            print_table(
+                &[
+                    "ID",
+                    "NAME",
+                    "VERSION",
+                    "ENVIRONMENT",
+                    "ACTIVE",
+                    "FORWARD URL",
+                    "ROLES",
+                ],
+                &rows,
+            );
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use actix_web::web::Data;
+use clap::builder::NonEmptyStringValueParser;
+use clap::{Args, Subcommand};
+use serde::Serialize;
+use validator::Validate;
+
+use super::{parse_role, print_json, print_table, OutputArgs, OutputFormat};
+use crate::api_services::models::{DbApiRole, WebApiRole};
+use crate::api_services::repo::RoleRepository;
+use crate::audit::models::Actor;
+use crate::config::GatewayConfig;
+use crate::database::Database;
+use crate::errors::{GatewayError, Result};
+use crate::revisions::Precondition;
+use crate::users::models::{
+    DbGatewayUserResponse, DbPartialGatewayUserUpdate, InitialCredential, WebGatewayUserRequest,
+    WebGatewayUserResponse,
+};
+use crate::users::repo::UserRepository;
+
+#[derive(Debug, Args)]
+pub struct UsersArgs {
+    #[command(subcommand)]
+    pub command: UsersCommand,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UsersCommand {
+    /// List every user with their roles
+    List,
+    /// Register a user
+    Create {
+        #[arg(long)]
+        username: String,
+        /// Role to grant, as `Namespace::Name`; repeat for several roles
+        #[arg(long = "role", value_parser = parse_role)]
+        roles: Vec<WebApiRole>,
+        /// Initial password; when omitted, a one-time password reset link is printed instead
+        #[arg(
+            long,
+            env = "API_DIRECTORY_USER_PASSWORD",
+            hide_env_values = true,
+            value_parser = NonEmptyStringValueParser::new()
+        )]
+        password: Option<String>,
+    },
+    /// Stop a user from logging in
+    Disable { user_id: String },
+    /// Allow a disabled user to log in again
+    Enable { user_id: String },
+    /// Replace a user's roles
+    SetRoles {
+        user_id: String,
+        /// Role to grant, as `Namespace::Name`; repeat for several roles, or omit to remove them all
+        #[arg(long = "role", value_parser = parse_role)]
+        roles: Vec<WebApiRole>,
+    },
+}
+
+#[derive(Serialize)]
+struct CreatedUser {
+    #[serde(flatten)]
+    user: WebGatewayUserResponse,
+    password_reset_url: Option<String>,
+}
+
+pub async fn run(args: UsersArgs, config: &GatewayConfig, repo: &Data<Database>) -> Result<()> {
+    let output = args.output.output;
+    match args.command {
+        UsersCommand::List => {
+            let users = Database::list_users(repo).await?;
+            print_users(output, &users)
+        }
+        UsersCommand::Create {
+            username,
+            roles,
+            password,
+        } => {
+            let user_request = WebGatewayUserRequest { username, roles };
+            user_request
+                .validate()
+                .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+            let roles = find_roles(repo, &user_request.roles).await?;
+            let (user, password_reset_url) =
+                register_with_credential(repo, config, &user_request, roles, password).await?;
+            match output {
+                OutputFormat::Json => print_json(&CreatedUser {
+                    user: (&user).into(),
+                    password_reset_url,
+                }),
+                OutputFormat::Table => {
+                    print_users(output, &[user])?;
+                    if let Some(url) = password_reset_url {
+                        println!();
+                        println!(
+                            "Set the password within {} minutes at {}",
+                            config.auth.password_reset_lifetime_secs / 60,
+                            url
+                        );
+                    }
+                    Ok(())
+                }
+            }
+        }
+        UsersCommand::Disable { user_id } => set_disabled(repo, output, &user_id, true).await,
+        UsersCommand::Enable { user_id } => set_disabled(repo, output, &user_id, false).await,
+        UsersCommand::SetRoles { user_id, roles } => {
+            let roles = find_roles(repo, &roles).await?;
+            let user_update = DbPartialGatewayUserUpdate {
+                username: None,
+                disabled: None,
+            };
+            let user = Database::update_user(
+                repo,
+                &user_id,
+                user_update,
+                Some(roles),
+                &Precondition::Any,
+                &Actor::default(),
+            )
+            .await?;
+            print_users(output, &[user])
+        }
+    }
+}
+
+/// Registers a user with the given password, or with a password reset
+/// request when none is given, returning the link that completes it.
+pub async fn register_with_credential(
+    repo: &Data<Database>,
+    config: &GatewayConfig,
+    user_request: &WebGatewayUserRequest,
+    roles: Vec<DbApiRole>,
+    password: Option<String>,
+) -> Result<(DbGatewayUserResponse, Option<String>)> {
+    let credential = match password {
+        Some(password) => InitialCredential::Password(password),
+        None => InitialCredential::PasswordReset(config.auth.password_reset_lifetime_secs),
+    };
+    let (user, password_reset) = Database::register_user(
+        repo,
+        user_request.into(),
+        roles,
+        credential,
+        &Actor::default(),
+    )
+    .await?;
+    let password_reset_url = password_reset.map(|password_reset| {
+        let token = password_reset
+            .id
+            .map(|id| id.id.to_string())
+            .unwrap_or_default();
+        format!(
+            "{}/reset-password/{}",
+            config.server.public_url.trim_end_matches('/'),
+            token
+        )
+    });
+    Ok((user, password_reset_url))
+}
+
+// Roles must already exist to be granted to a user
+async fn find_roles(repo: &Data<Database>, roles: &[WebApiRole]) -> Result<Vec<DbApiRole>> {
+    let mut found_roles = Vec::new();
+    for role in roles {
+        found_roles.push(Database::find_role(repo, &role.namespace, &role.name).await?);
+    }
+    Ok(found_roles)
+}
+
+async fn set_disabled(
+    repo: &Data<Database>,
+    output: OutputFormat,
+    user_id: &String,
+    disabled: bool,
+) -> Result<()> {
+    let user_update = DbPartialGatewayUserUpdate {
+        username: None,
+        disabled: Some(disabled),
+    };
+    let user = Database::update_user(
+        repo,
+        user_id,
+        user_update,
+        None,
+        &Precondition::Any,
+        &Actor::default(),
+    )
+    .await?;
+    print_users(output, &[user])
+}
+
+fn print_users(output: OutputFormat, users: &[DbGatewayUserResponse]) -> Result<()> {
+    let users: Vec<WebGatewayUserResponse> = users.iter().map(Into::into).collect();
+    match output {
+        OutputFormat::Json => print_json(&users),
+        OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = users
+                .iter()
+                .map(|user| {
+                    vec![
+                        user.id.clone(),
+                        user.username.clone(),
+                        user.roles
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                        String::from(if user.disabled { "disabled" } else { "active" }),
+                        user.last_login
+                            .as_ref()
+                            .map(|last_login| last_login.0.format("%Y-%m-%d %H:%M:%S").to_string())
+                            .unwrap_or_else(|| String::from("never")),
+                    ]
+                })
+                .collect();
+            print_table(&["ID", "USERNAME", "ROLES", "STATUS", "LAST LOGIN"], &rows);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use std::fmt::Write as _;
+use std::io::{self, Write as _};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::{Args, Parser, ValueEnum};
+use serde::Deserialize;
+
+use crate::api_services::models::WebApiRole;
+use crate::auth::models::SigningAlgorithm;
+use crate::cli::Command;
+
+// Read when no `--config` is given; the gateway runs on defaults if it doesn't exist
+const DEFAULT_CONFIG_FILE: &str = "api-directory.toml";
+
+/// Gateway settings, layered as: built-in defaults, then the TOML config
+/// file, then `API_DIRECTORY_*` environment variables, then CLI flags.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct GatewayConfig {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub database: DatabaseConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Socket addresses the gateway binds to
+    pub listen: Vec<String>,
+    // Directory holding the built web UI
+    pub ui_dir: PathBuf,
+    // Base URL clients reach the gateway at, which is also its OpenID Connect
+    // issuer
+    pub public_url: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![String::from("127.0.2.1:443")],
+            ui_dir: PathBuf::from("./www"),
+            public_url: String::from("https://apigateway.local"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // PEM-formatted X.509 certificate chain
+    pub certificate: PathBuf,
+    // PEM-formatted PKCS 8 private key
+    pub private_key: PathBuf,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            certificate: PathBuf::from(".ssl.dev/snakeoil.pem"),
+            private_key: PathBuf::from(".ssl.dev/snakeoil.key"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub issuer: String,
+    // Algorithm of the signing keys generated for access tokens; the keys
+    // themselves are kept in the database
+    pub jwt_algorithm: SigningAlgorithm,
+    // Access tokens are short lived, and renewed with a refresh token
+    pub token_lifetime_secs: u64,
+    // A session ends when it goes unused for this long
+    pub refresh_token_lifetime_secs: u64,
+    pub password_reset_lifetime_secs: u64,
+    // External OpenID Connect providers users may sign in with
+    pub identity_providers: Vec<IdentityProviderConfig>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            issuer: String::from("apigateway.local"),
+            jwt_algorithm: SigningAlgorithm::Rs512,
+            token_lifetime_secs: 15 * 60,
+            refresh_token_lifetime_secs: 30 * 24 * 60 * 60,
+            password_reset_lifetime_secs: 24 * 60 * 60,
+            identity_providers: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityProviderConfig {
+    // Names the provider in URLs, e.g. `/auth/v1/federated/<name>/login`
+    pub name: String,
+    // Shown to users choosing how to sign in; defaults to the name
+    pub display_name: Option<String>,
+    // Where the provider's discovery document is found, and the `iss` its ID
+    // tokens must carry
+    pub issuer: String,
+    pub client_id: String,
+    // Left out for providers that register the gateway as a public client
+    pub client_secret: Option<String>,
+    #[serde(default = "default_provider_scopes")]
+    pub scopes: Vec<String>,
+    // ID token claim holding the username of provisioned users
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    // ID token claim listing the user's groups, for the role rules
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+    // Whether users signing in for the first time get a gateway account
+    #[serde(default = "default_true")]
+    pub provision_users: bool,
+    // Whether a first sign in is linked to an existing account with the same
+    // username, trusting the provider to vouch for it
+    #[serde(default)]
+    pub link_existing_users: bool,
+    // When there are any, they decide the user's roles at every sign in
+    #[serde(default)]
+    pub role_rules: Vec<RoleRuleConfig>,
+}
+
+impl IdentityProviderConfig {
+    pub fn display_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Grants `roles` to users the provider places in `group`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RoleRuleConfig {
+    pub group: String,
+    // Roles as `Namespace::Name`
+    pub roles: Vec<String>,
+}
+
+fn default_provider_scopes() -> Vec<String> {
+    vec![String::from("openid"), String::from("profile")]
+}
+
+fn default_username_claim() -> String {
+    String::from("preferred_username")
+}
+
+fn default_groups_claim() -> String {
+    String::from("groups")
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// Engines `database.url` may name; a URL without a scheme is a speedb path
+const DATABASE_SCHEMES: &[&str] = &["speedb", "mem", "ws", "wss", "http", "https"];
+// Engines without live queries, which only the command line subcommands can use
+const STATELESS_DATABASE_SCHEMES: &[&str] = &["http", "https"];
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DatabaseAuthLevel {
+    #[default]
+    Root,
+    Namespace,
+    Database,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    // `speedb://<path>`, `mem://`, or a remote server at `ws(s)://` or `http(s)://`
+    #[serde(alias = "path")]
+    pub url: String,
+    pub namespace: String,
+    pub name: String,
+    // Credentials for a remote server; no sign in happens without a username
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Whether the user is defined on the server, the namespace or the database
+    pub auth_level: DatabaseAuthLevel,
+    // How long startup waits for the database before giving up
+    pub connect_timeout_secs: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: String::from("speedb://temp.speedb"),
+            namespace: String::from("api_directory"),
+            name: String::from("services"),
+            username: None,
+            password: None,
+            auth_level: DatabaseAuthLevel::Root,
+            connect_timeout_secs: 10,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// The connection URL, reading a bare path (as the old `path` setting
+    /// held) as a speedb database.
+    pub fn endpoint(&self) -> String {
+        if self.url.contains("://") {
+            self.url.clone()
+        } else {
+            format!("speedb://{}", self.url)
+        }
+    }
+
+    /// Whether the engine supports live queries, which the running gateway
+    /// needs to follow changes to routes, rate limits, revoked sessions and
+    /// signing keys. SurrealDB's HTTP engine doesn't.
+    pub fn supports_live_queries(&self) -> bool {
+        let endpoint = self.endpoint();
+        let scheme = endpoint.split("://").next().unwrap_or_default();
+        !STATELESS_DATABASE_SCHEMES.contains(&scheme)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // Default filter, e.g. `info` or `api_directory=debug`; `RUST_LOG` still takes precedence
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: String::from("info"),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        GatewayConfig {
+            server: ServerConfig::default(),
+            tls: TlsConfig::default(),
+            auth: AuthConfig::default(),
+            database: DatabaseConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about = "API Gateway")]
+pub struct Cli {
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+
+    // Runs the gateway when no subcommand is given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Settings that can be overridden per run. Each flag can also be
+/// given as an environment variable; flags win over the environment.
+#[derive(Debug, Args)]
+pub struct ConfigOverrides {
+    /// Path to the TOML configuration file
+    #[arg(long, env = "API_DIRECTORY_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on; repeat (or comma-separate) for several listeners
+    #[arg(
+        long,
+        env = "API_DIRECTORY_LISTEN",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub listen: Vec<String>,
+
+    #[arg(long, env = "API_DIRECTORY_UI_DIR", global = true)]
+    pub ui_dir: Option<PathBuf>,
+
+    #[arg(long, env = "API_DIRECTORY_PUBLIC_URL", global = true)]
+    pub public_url: Option<String>,
+
+    #[arg(long, env = "API_DIRECTORY_TLS_CERTIFICATE", global = true)]
+    pub tls_certificate: Option<PathBuf>,
+
+    #[arg(long, env = "API_DIRECTORY_TLS_PRIVATE_KEY", global = true)]
+    pub tls_private_key: Option<PathBuf>,
+
+    #[arg(long, env = "API_DIRECTORY_JWT_ISSUER", global = true)]
+    pub jwt_issuer: Option<String>,
+
+    #[arg(long, value_enum, env = "API_DIRECTORY_JWT_ALGORITHM", global = true)]
+    pub jwt_algorithm: Option<SigningAlgorithm>,
+
+    #[arg(long, env = "API_DIRECTORY_TOKEN_LIFETIME_SECS", global = true)]
+    pub token_lifetime_secs: Option<u64>,
+
+    #[arg(long, env = "API_DIRECTORY_REFRESH_TOKEN_LIFETIME_SECS", global = true)]
+    pub refresh_token_lifetime_secs: Option<u64>,
+
+    #[arg(
+        long,
+        env = "API_DIRECTORY_PASSWORD_RESET_LIFETIME_SECS",
+        global = true
+    )]
+    pub password_reset_lifetime_secs: Option<u64>,
+
+    /// Database connection URL, e.g. `speedb://temp.speedb`, `mem://` or `ws://db:8000`
+    #[arg(long, alias = "db-path", env = "API_DIRECTORY_DB_URL", global = true)]
+    pub db_url: Option<String>,
+
+    #[arg(long, env = "API_DIRECTORY_DB_NAMESPACE", global = true)]
+    pub db_namespace: Option<String>,
+
+    #[arg(long, env = "API_DIRECTORY_DB_NAME", global = true)]
+    pub db_name: Option<String>,
+
+    #[arg(long, env = "API_DIRECTORY_DB_USERNAME", global = true)]
+    pub db_username: Option<String>,
+
+    #[arg(
+        long,
+        env = "API_DIRECTORY_DB_PASSWORD",
+        hide_env_values = true,
+        global = true
+    )]
+    pub db_password: Option<String>,
+
+    #[arg(long, value_enum, env = "API_DIRECTORY_DB_AUTH_LEVEL", global = true)]
+    pub db_auth_level: Option<DatabaseAuthLevel>,
+
+    #[arg(long, env = "API_DIRECTORY_DB_CONNECT_TIMEOUT_SECS", global = true)]
+    pub db_connect_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "API_DIRECTORY_LOG_LEVEL", global = true)]
+    pub log_level: Option<String>,
+
+    #[arg(long, value_enum, env = "API_DIRECTORY_LOG_FORMAT", global = true)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl GatewayConfig {
+    /// Builds the configuration for this run and checks it, reporting every
+    /// problem found rather than stopping at the first.
+    pub fn load(overrides: &ConfigOverrides) -> io::Result<Self> {
+        let mut config = match &overrides.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+        config.apply(overrides);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Unable to read config file {}: {}", path.display(), e),
+            )
+        })?;
+        toml::from_str(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid config file {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    fn apply(&mut self, overrides: &ConfigOverrides) {
+        if !overrides.listen.is_empty() {
+            self.server.listen = overrides.listen.clone();
+        }
+        if let Some(ui_dir) = &overrides.ui_dir {
+            self.server.ui_dir = ui_dir.clone();
+        }
+        if let Some(public_url) = &overrides.public_url {
+            self.server.public_url = public_url.clone();
+        }
+        if let Some(certificate) = &overrides.tls_certificate {
+            self.tls.certificate = certificate.clone();
+        }
+        if let Some(private_key) = &overrides.tls_private_key {
+            self.tls.private_key = private_key.clone();
+        }
+        if let Some(issuer) = &overrides.jwt_issuer {
+            self.auth.issuer = issuer.clone();
+        }
+        if let Some(algorithm) = overrides.jwt_algorithm {
+            self.auth.jwt_algorithm = algorithm;
+        }
+        if let Some(lifetime) = overrides.token_lifetime_secs {
+            self.auth.token_lifetime_secs = lifetime;
+        }
+        if let Some(lifetime) = overrides.refresh_token_lifetime_secs {
+            self.auth.refresh_token_lifetime_secs = lifetime;
+        }
+        if let Some(lifetime) = overrides.password_reset_lifetime_secs {
+            self.auth.password_reset_lifetime_secs = lifetime;
+        }
+        if let Some(url) = &overrides.db_url {
+            self.database.url = url.clone();
+        }
+        if let Some(namespace) = &overrides.db_namespace {
+            self.database.namespace = namespace.clone();
+        }
+        if let Some(name) = &overrides.db_name {
+            self.database.name = name.clone();
+        }
+        if let Some(username) = &overrides.db_username {
+            self.database.username = Some(username.clone());
+        }
+        if let Some(password) = &overrides.db_password {
+            self.database.password = Some(password.clone());
+        }
+        if let Some(auth_level) = overrides.db_auth_level {
+            self.database.auth_level = auth_level;
+        }
+        if let Some(timeout) = overrides.db_connect_timeout_secs {
+            self.database.connect_timeout_secs = timeout;
+        }
+        if let Some(level) = &overrides.log_level {
+            self.log.level = level.clone();
+        }
+        if let Some(format) = overrides.log_format {
+            self.log.format = format;
+        }
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        let mut problems: Vec<String> = Vec::new();
+
+        if self.server.listen.is_empty() {
+            problems.push("server.listen: at least one listen address is required".into());
+        }
+        for address in &self.server.listen {
+            if SocketAddr::from_str(address).is_err() {
+                problems.push(format!(
+                    "server.listen: [{}] is not a valid socket address (expected e.g. 127.0.0.1:443)",
+                    address
+                ));
+            }
+        }
+        match reqwest::Url::parse(&self.server.public_url) {
+            Ok(url)
+                if ["https", "http"].contains(&url.scheme())
+                    && url.query().is_none()
+                    && url.fragment().is_none() => {}
+            _ => problems.push(format!(
+                "server.public_url: [{}] is not an http(s) URL without a query (expected e.g. https://apigateway.local)",
+                self.server.public_url
+            )),
+        }
+        if self.auth.issuer.trim().is_empty() {
+            problems.push("auth.issuer: must not be empty".into());
+        }
+        if self.auth.token_lifetime_secs == 0 {
+            problems.push("auth.token_lifetime_secs: must be greater than zero".into());
+        }
+        if self.auth.refresh_token_lifetime_secs <= self.auth.token_lifetime_secs {
+            problems.push(
+                "auth.refresh_token_lifetime_secs: must be longer than auth.token_lifetime_secs"
+                    .into(),
+            );
+        }
+        if self.auth.password_reset_lifetime_secs == 0 {
+            problems.push("auth.password_reset_lifetime_secs: must be greater than zero".into());
+        }
+        for (index, provider) in self.auth.identity_providers.iter().enumerate() {
+            provider_problems(&mut problems, provider);
+            if self.auth.identity_providers[..index]
+                .iter()
+                .any(|other| other.name == provider.name)
+            {
+                problems.push(format!(
+                    "auth.identity_providers: [{}] is named more than once",
+                    provider.name
+                ));
+            }
+        }
+        for (setting, value) in [
+            ("database.url", &self.database.url),
+            ("database.namespace", &self.database.namespace),
+            ("database.name", &self.database.name),
+        ] {
+            if value.trim().is_empty() {
+                problems.push(format!("{}: must not be empty", setting));
+            }
+        }
+        let endpoint = self.database.endpoint();
+        let scheme = endpoint.split("://").next().unwrap_or_default();
+        if !DATABASE_SCHEMES.contains(&scheme) {
+            problems.push(format!(
+                "database.url: [{}] is not a supported engine (expected one of {})",
+                scheme,
+                DATABASE_SCHEMES.join(", ")
+            ));
+        }
+        if self.database.username.is_some() != self.database.password.is_some() {
+            problems.push("database.username and database.password must be given together".into());
+        }
+        if self.database.connect_timeout_secs == 0 {
+            problems.push("database.connect_timeout_secs: must be greater than zero".into());
+        }
+        if self.log.level.trim().is_empty() {
+            problems.push("log.level: must not be empty".into());
+        }
+        // Directives are `level` or `module=level`; a bare word may also be a module name
+        for directive in self.log.level.split(',') {
+            if let Some((_, level)) = directive.split_once('=') {
+                if log::LevelFilter::from_str(level.trim()).is_err() {
+                    problems.push(format!(
+                        "log.level: [{}] is not a valid level (expected off, error, warn, info, debug or trace)",
+                        level.trim()
+                    ));
+                }
+            }
+        }
+
+        report_problems(problems)
+    }
+
+    /// Checks the settings only serving relies on.
+    pub fn validate_serve(&self) -> io::Result<()> {
+        let mut problems: Vec<String> = Vec::new();
+
+        for (setting, path) in [
+            ("tls.certificate", &self.tls.certificate),
+            ("tls.private_key", &self.tls.private_key),
+        ] {
+            if !path.is_file() {
+                problems.push(format!(
+                    "{}: file {} does not exist",
+                    setting,
+                    path.display()
+                ));
+            }
+        }
+        if !self.database.supports_live_queries() {
+            problems.push(format!(
+                "database.url: [{}] can't be served from, since the http engine doesn't support live queries (use ws:// or wss://)",
+                self.database.url
+            ));
+        }
+
+        report_problems(problems)
+    }
+
+    /// The identity provider with the given name.
+    pub fn identity_provider(&self, name: &str) -> Option<&IdentityProviderConfig> {
+        self.auth
+            .identity_providers
+            .iter()
+            .find(|provider| provider.name == name)
+    }
+
+    /// Initializes the global logger from the log settings.
+    pub fn init_logging(&self) {
+        let mut builder = env_logger::Builder::from_env(
+            env_logger::Env::default().default_filter_or(self.log.level.as_str()),
+        );
+        if self.log.format == LogFormat::Json {
+            builder.format(|buf, record| {
+                writeln!(
+                    buf,
+                    "{}",
+                    serde_json::json!({
+                        "timestamp": chrono::Utc::now().to_rfc3339(),
+                        "level": record.level().to_string(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                    })
+                )
+            });
+        }
+        builder.init();
+    }
+}
+
+// Fails with every problem found, if there are any
+fn report_problems(problems: Vec<String>) -> io::Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let mut message = String::from("Invalid configuration:");
+    for problem in problems {
+        let _ = write!(message, "\n  - {}", problem);
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+fn provider_problems(problems: &mut Vec<String>, provider: &IdentityProviderConfig) {
+    let setting = format!("auth.identity_providers.{}", provider.name);
+    if provider.name.is_empty()
+        || !provider
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        problems.push(format!(
+            "{}: the name must be letters, digits, - or _",
+            setting
+        ));
+    }
+    // Plain http is only accepted for a provider on the same machine
+    match reqwest::Url::parse(&provider.issuer) {
+        Ok(url)
+            if url.scheme() == "https"
+                || (url.scheme() == "http"
+                    && matches!(url.host_str(), Some("localhost") | Some("127.0.0.1"))) => {}
+        _ => problems.push(format!(
+            "{}.issuer: [{}] is not an https URL",
+            setting, provider.issuer
+        )),
+    }
+    if provider.client_id.trim().is_empty() {
+        problems.push(format!("{}.client_id: must not be empty", setting));
+    }
+    if !provider.scopes.iter().any(|scope| scope == "openid") {
+        problems.push(format!("{}.scopes: must include openid", setting));
+    }
+    for rule in &provider.role_rules {
+        for role in &rule.roles {
+            if let Err(e) = role.parse::<WebApiRole>() {
+                problems.push(format!("{}.role_rules: {}", setting, e));
+            }
+        }
+    }
+}
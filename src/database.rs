@@ -1,53 +1,149 @@
 use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
 
+use actix_web::web::Data;
 use serde;
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
-use std::io;
-use surrealdb::engine::local::{Db, SpeeDb};
-use surrealdb::sql::{Strand, Thing, Value};
+use serde::Serialize;
+use surrealdb::engine::any::{self, Any};
+use surrealdb::error::Db as DbError;
+use surrealdb::opt::auth;
+use surrealdb::sql::Value;
 use surrealdb::{self, opt};
+use tokio::sync::watch;
 
+use crate::config::{DatabaseAuthLevel, DatabaseConfig};
 use crate::errors::GatewayError;
+use crate::revisions::PRECONDITION_FAILED;
 
 pub const USER_TABLE: &str = "gateway_user";
 pub const PASSWORD_RESET_TABLE: &str = "password_reset_request";
+pub const SESSION_TABLE: &str = "session";
+pub const REFRESH_TOKEN_TABLE: &str = "refresh_token";
+pub const REVOKED_TOKEN_TABLE: &str = "revoked_token";
+pub const SIGNING_KEY_TABLE: &str = "signing_key";
+pub const OIDC_CLIENT_TABLE: &str = "oidc_client";
+pub const AUTHORIZATION_CODE_TABLE: &str = "authorization_code";
+pub const FEDERATED_IDENTITY_TABLE: &str = "federated_identity";
+pub const FEDERATED_LOGIN_TABLE: &str = "federated_login";
 pub const API_SERVICE_TABLE: &str = "service";
 pub const API_ROLE_TABLE: &str = "role";
 pub const ROLE_MEMBER_TABLE: &str = "memberOf";
 pub const AUTHORIZATIONS_TABLE: &str = "authorizes";
+pub const SERVICE_HEALTH_TABLE: &str = "service_health";
+pub const SERVICE_REVISION_TABLE: &str = "service_revision";
+pub const AUDIT_TABLE: &str = "audit_log";
+pub const RATE_LIMIT_TABLE: &str = "rate_limit";
+pub const QUOTA_TABLE: &str = "quota";
+pub const QUOTA_USAGE_TABLE: &str = "quota_usage";
+pub const MIGRATIONS_TABLE: &str = "_migrations";
 pub const NAMESPACE_MEMBER_ROLE: &str = "__ROLE_NAMESPACE_MEMBER__";
 pub const ROLE_NAMESPACE_DELIMITER: &str = "::";
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Relationship {
-    pub id: Thing,
+// How often the connection monitor pings the database
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
-    #[serde(rename = "in")]
-    pub _in: Thing,
+/// Statements sent to the database together and run as one transaction, so
+/// that they either all take effect or none do.
+#[derive(Default)]
+pub struct Transaction {
+    statements: Vec<String>,
+    params: BTreeMap<String, Value>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a statement, or several separated by `;`.
+    pub fn statement(mut self, statement: impl std::fmt::Display) -> Self {
+        let statement = statement.to_string();
+        self.statements
+            .push(statement.trim_end().trim_end_matches(';').to_string());
+        self
+    }
 
-    pub out: Thing,
+    /// Binds `$name` for every statement in the transaction.
+    pub fn bind(mut self, name: &str, value: impl Serialize) -> Result<Self, GatewayError> {
+        let value = surrealdb::sql::to_value(value)
+            .map_err(|e| GatewayError::DatabaseError(e.to_string()))?;
+        self.params.insert(name.to_string(), value);
+        Ok(self)
+    }
 }
 
 #[derive(Clone)]
 pub struct Database {
-    pub db: surrealdb::Surreal<Db>,
+    pub db: surrealdb::Surreal<Any>,
     pub namespace: String,
     pub name: String,
+    // Bumped each time the connection comes back after being lost
+    reconnects: Arc<watch::Sender<u64>>,
 }
 
 impl Database {
-    pub async fn init(connection: &str, namespace: &str, name: &str) -> surrealdb::Result<Self> {
-        let db = surrealdb::Surreal::new::<SpeeDb>(connection).await?;
-        db.use_ns(namespace).use_db(name).await?;
+    /// Connects to the database at `config.url` with whichever engine its
+    /// scheme names, signs in when credentials are configured and selects the
+    /// namespace and database. Fails if the database doesn't answer within
+    /// `config.connect_timeout_secs`.
+    pub async fn init(config: &DatabaseConfig) -> Result<Self, GatewayError> {
+        let timeout = Duration::from_secs(config.connect_timeout_secs);
+        let db = tokio::time::timeout(timeout, Self::connect(config))
+            .await
+            .map_err(|_| {
+                GatewayError::DatabaseError(format!(
+                    "No response from {} within {} seconds",
+                    config.url, config.connect_timeout_secs
+                ))
+            })??;
+        let (reconnects, _) = watch::channel(0);
 
         Ok(Database {
             db,
-            namespace: String::from(namespace),
-            name: String::from(name),
+            namespace: config.namespace.clone(),
+            name: config.name.clone(),
+            reconnects: Arc::new(reconnects),
         })
     }
 
+    async fn connect(config: &DatabaseConfig) -> Result<surrealdb::Surreal<Any>, GatewayError> {
+        let db = any::connect(config.endpoint()).await?;
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            match config.auth_level {
+                DatabaseAuthLevel::Root => db.signin(auth::Root { username, password }).await,
+                DatabaseAuthLevel::Namespace => {
+                    db.signin(auth::Namespace {
+                        namespace: &config.namespace,
+                        username,
+                        password,
+                    })
+                    .await
+                }
+                DatabaseAuthLevel::Database => {
+                    db.signin(auth::Database {
+                        namespace: &config.namespace,
+                        database: &config.name,
+                        username,
+                        password,
+                    })
+                    .await
+                }
+            }?;
+        }
+        db.use_ns(&config.namespace).use_db(&config.name).await?;
+        db.health().await?;
+        Ok(db)
+    }
+
+    /// Notifies the receiver whenever the connection is restored after an
+    /// outage. Live queries don't survive the outage, so their owners use
+    /// this to start them again.
+    pub fn reconnected(&self) -> watch::Receiver<u64> {
+        self.reconnects.subscribe()
+    }
+
     // pub async fn get_record<T>(self: &Self, record_id: &Thing) -> Result<T, GatewayError>
     // where T: DeserializeOwned
     // {
@@ -58,181 +154,63 @@ impl Database {
     //     .ok_or(GatewayError::NotFound(record_id.tb.clone(), format!("{}", &record_id.id)))
     // }
 
-    pub async fn define_index(
-        self: &Self,
-        table: &str,
-        index_name: &str,
-        columns: Vec<&str>,
-        options: Option<&str>,
-    ) -> io::Result<()> {
-        log::debug!("Querying table info for [{}]", table);
-        let mut response = self
-            .db
-            .query(format!("INFO FOR TABLE {}", table))
-            .await
-            .map_err(|e| io::Error::other(e))?;
-        // Grotesque generic data structure for Table info...
-        let results: Vec<BTreeMap<String, BTreeMap<String, String>>> =
-            response.take(0).map_err(|e| io::Error::other(e))?;
-        if let Some(events) = results
-            .get(0)
-            .ok_or(io::Error::other(format!(
-                "No INFO result for table [{}]",
-                table
-            )))?
-            .get(&String::from("indexes"))
-        {
-            log::debug!("Looking for index [{}] on table [{}]", index_name, table);
-            let index_definition = format!(
-                "DEFINE INDEX {} ON {} FIELDS {} {}",
-                index_name,
-                table,
-                columns.join(", "),
-                options.or(Some("")).unwrap()
-            );
-            if let Some(existing_index) = events.get(&String::from(index_name)) {
-                if existing_index.eq(&index_definition) {
-                    log::info!(
-                        "Index [{}] already present on table [{}]",
-                        index_name,
-                        table
-                    );
-                    return Ok(());
-                }
-                log::warn!("Overwriting index [{}] on table [{}]", index_name, table);
-                log::warn!("Previous Index def: \n{}", existing_index);
-                log::warn!("New Index: \n{}", index_definition);
-            } else {
-                log::warn!("Creating index [{}] on table [{}]", index_name, table);
-            }
-            self.db
-                .query(index_definition)
-                .await
-                .map_err(|e| io::Error::other(e))?;
-        }
+    /// Runs the transaction, returning the error of the statement that made it
+    /// fail, if any. A `THROW` fails it with a [`GatewayError::Conflict`]
+    /// carrying the thrown message.
+    pub async fn commit(self: &Self, transaction: Transaction) -> Result<(), GatewayError> {
+        self.run_transaction(transaction).await.map(|_| ())
+    }
 
-        Ok(())
+    /// Runs the transaction like [`Database::commit`], returning the value of
+    /// its last statement.
+    pub async fn commit_returning<T>(
+        &self,
+        transaction: Transaction,
+    ) -> Result<Option<T>, GatewayError>
+    where
+        T: DeserializeOwned,
+    {
+        let mut response = self.run_transaction(transaction).await?;
+        let last = response.num_statements().saturating_sub(1);
+        response.take(last).map_err(Into::<GatewayError>::into)
     }
 
-    pub async fn ensure_event_present(
-        self: &Self,
-        table: &str,
-        event_name: &str,
-        event_condition: &str,
-        event_action: &str,
-    ) -> std::io::Result<()> {
-        log::debug!("Querying table info for [{}]", table);
+    async fn run_transaction(
+        &self,
+        transaction: Transaction,
+    ) -> Result<surrealdb::Response, GatewayError> {
+        let query = format!(
+            "BEGIN TRANSACTION;\n{};\nCOMMIT TRANSACTION;",
+            transaction.statements.join(";\n")
+        );
         let mut response = self
             .db
-            .query(format!("INFO FOR TABLE {}", table))
+            .query(query)
+            .bind(transaction.params)
             .await
-            .map_err(|e| io::Error::other(e))?;
-
-        // Grotesque generic data structure for Table info...
-        let results: Vec<BTreeMap<String, BTreeMap<String, String>>> =
-            response.take(0).map_err(|e| io::Error::other(e))?;
+            .map_err(Into::<GatewayError>::into)?;
 
-        if let Some(events) = results
-            .get(0)
-            .ok_or(io::Error::other(format!(
-                "No INFO result for table [{}]",
-                table
-            )))?
-            .get(&String::from("events"))
-        {
-            log::debug!("Looking for event [{}] on table [{}]", event_name, table);
-            let event_definition = format!(
-                "DEFINE EVENT {} ON {} WHEN {} THEN ({})",
-                event_name, table, event_condition, event_action
-            );
-            if let Some(existing_event) = events.get(&String::from(event_name)) {
-                if existing_event.eq(&event_definition) {
-                    log::info!(
-                        "Event [{}] already present on table [{}]",
-                        event_name,
-                        table
-                    );
-                    return Ok(());
-                }
-                log::warn!("Previous Event def: \n{}", existing_event);
-                log::warn!("New Event: \n{}", event_definition);
+        // Every other statement fails with the transaction
+        let mut errors: Vec<(usize, surrealdb::Error)> =
+            response.take_errors().into_iter().collect();
+        errors.sort_by_key(|(index, _)| *index);
+        let cause = errors
+            .iter()
+            .position(|(_, e)| !matches!(e, surrealdb::Error::Db(DbError::QueryNotExecuted)))
+            .unwrap_or(0);
+        match errors.into_iter().nth(cause) {
+            Some((_, surrealdb::Error::Db(DbError::Thrown(message))))
+                if message == PRECONDITION_FAILED =>
+            {
+                Err(GatewayError::PreconditionFailed(message))
             }
-            log::warn!("Creating event [{}] on table [{}]", event_name, table);
-            self.db
-                .query(event_definition)
-                .await
-                .map_err(|e| io::Error::other(e))?;
-        } else {
-            return Err(io::Error::other(format!(
-                "Unable to fetch events for table {}",
-                table
-            )));
-        }
-
-        Ok(())
-    }
-
-    pub async fn automate_created_date(self: &Self, table: &str) -> io::Result<()> {
-        self.ensure_event_present(
-            table,
-            "record_create",
-            "$event = 'CREATE'",
-            format!("UPDATE {} SET created_date = time::now(), last_modified_date = time::now() WHERE id = $after.id", table).as_str(),
-        ).await
-    }
-
-    pub async fn automate_last_modified_date(self: &Self, table: &str) -> io::Result<()> {
-        self.ensure_event_present(
-            table,
-            "record_update",
-            "$event = 'UPDATE' AND $after.last_modified_date == NONE OR ($before.last_modified_date == $after.last_modified_date)",
-            format!("UPDATE {} SET last_modified_date = time::now() WHERE id = $after.id", table).as_str(),
-        )
-        .await
-    }
-
-    pub async fn relate(
-        self: &Self,
-        from: &Thing,
-        to: &Thing,
-        relationship_table: impl std::fmt::Display,
-        content: Option<BTreeMap<String, Value>>,
-    ) -> Result<Relationship, GatewayError> {
-        let mut query: String = format!("RELATE $from->{}->$to", relationship_table);
-        let mut params: BTreeMap<String, Value> = [
-            ("from".into(), Value::Thing(from.clone())),
-            ("to".into(), Value::Thing(to.clone())),
-        ]
-        .into();
-        if let Some(data) = content {
-            query = format!("{} CONTENT $data", query);
-            params.insert("data".into(), Value::Object(data.into()));
+            // Other conditions the transaction checks are for its caller to handle
+            Some((_, surrealdb::Error::Db(DbError::Thrown(message)))) => {
+                Err(GatewayError::Conflict(message))
+            }
+            Some((_, e)) => Err(e.into()),
+            None => Ok(response),
         }
-        let query_result: Option<Relationship> = self.query_record(query, Some(params)).await?;
-        query_result.ok_or(GatewayError::DatabaseError(
-            "Could not create relationship".to_string(),
-        ))
-    }
-
-    pub async fn unrelate(
-        self: &Self,
-        from: &Thing,
-        to: &Thing,
-        rel: &String,
-    ) -> Result<(), GatewayError> {
-        let query = "DELETE type::table($rel) WHERE in=$from and out=$to";
-        let params: BTreeMap<String, Value> = [
-            ("from".into(), Value::Thing(from.clone())),
-            ("to".into(), Value::Thing(to.clone())),
-            ("rel".into(), Value::Strand(Strand::from(rel.clone()))),
-        ]
-        .into();
-        self.db
-            .query(query)
-            .bind(params)
-            .await
-            .map_err(Into::<GatewayError>::into)?;
-        Ok(())
     }
 
     pub async fn query_record<T>(
@@ -272,3 +250,75 @@ impl Database {
         Ok(records)
     }
 }
+
+/// Pings the database in the background, logging when the connection is lost
+/// and when it comes back. Remote engines reconnect by themselves; this tells
+/// the owners of live queries when that has happened.
+pub fn spawn_connection_monitor(repo: Data<Database>) {
+    tokio::spawn(async move {
+        let mut connected = true;
+        loop {
+            tokio::time::sleep(CONNECTION_CHECK_INTERVAL).await;
+            let error =
+                match tokio::time::timeout(CONNECTION_CHECK_INTERVAL, repo.db.health()).await {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(_) => Some(String::from("no response to health check")),
+                };
+            match (&error, connected) {
+                (Some(e), true) => log::warn!("Database connection lost: {}", e),
+                (None, false) => {
+                    log::info!("Database connection restored");
+                    repo.reconnects.send_modify(|count| *count += 1);
+                }
+                _ => {}
+            }
+            connected = error.is_none();
+        }
+    });
+}
+
+#[cfg(test)]
+pub mod testing {
+    use actix_web::web::Data;
+
+    use super::Database;
+    use crate::config::DatabaseConfig;
+
+    /// A migrated in-memory database of its own.
+    pub async fn database() -> Data<Database> {
+        let config = DatabaseConfig {
+            url: String::from("mem://"),
+            ..DatabaseConfig::default()
+        };
+        let repo = Database::init(&config).await.unwrap();
+        crate::migrations::migrate(&repo).await.unwrap();
+        Data::new(repo)
+    }
+
+    /// Makes every later record created in `table` fail, along with the
+    /// transaction creating it.
+    pub async fn fail_creating(repo: &Database, table: &str) {
+        repo.db
+            .query(format!(
+                "DEFINE EVENT forced_failure ON {} WHEN $event = 'CREATE' THEN {{ THROW 'Forced failure' }}",
+                table
+            ))
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+    }
+
+    /// The number of records in `table`.
+    pub async fn count(repo: &Database, table: &str) -> usize {
+        let count: Option<usize> = repo
+            .db
+            .query(format!("RETURN array::len(SELECT VALUE id FROM {})", table))
+            .await
+            .unwrap()
+            .take(0)
+            .unwrap();
+        count.unwrap_or_default()
+    }
+}
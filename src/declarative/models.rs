@@ -0,0 +1,182 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::api_services::models::{DbApiServiceRequest, WebApiRole, WebRequestApiService};
+use crate::errors::{GatewayError, Result};
+
+/// Declarative description of the directory's roles and services, meant to
+/// be kept in version control. Exports are sorted, so exporting the same
+/// state always produces the same document.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigDocument {
+    // Qualified `Namespace::Name` roles
+    #[serde(default)]
+    pub roles: Vec<String>,
+
+    #[serde(default)]
+    pub services: Vec<ServiceSpec>,
+
+    // Role memberships are only managed when this section is present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub members: Option<Vec<MemberSpec>>,
+}
+
+impl ConfigDocument {
+    pub fn parse(contents: &[u8], format: DocumentFormat) -> Result<Self> {
+        match format {
+            DocumentFormat::Json => serde_json::from_slice(contents).map_err(|e| e.to_string()),
+            DocumentFormat::Yaml => serde_yaml::from_slice(contents).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| GatewayError::BadRequest(format!("Invalid config document: {}", e)))
+    }
+
+    pub fn render(&self, format: DocumentFormat) -> Result<String> {
+        match format {
+            DocumentFormat::Json => serde_json::to_string_pretty(self)
+                .map(|json| json + "\n")
+                .map_err(|e| e.to_string()),
+            DocumentFormat::Yaml => serde_yaml::to_string(self).map_err(|e| e.to_string()),
+        }
+        .map_err(GatewayError::SystemError)
+    }
+}
+
+/// The formats a config document can be written in.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+impl DocumentFormat {
+    // YAML for `.yaml` and `.yml` files, JSON for anything else
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => DocumentFormat::Yaml,
+            _ => DocumentFormat::Json,
+        }
+    }
+
+    // YAML for the YAML media types, JSON for anything else
+    pub fn from_content_type(content_type: &str) -> Self {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if ["application/yaml", "application/x-yaml", "text/yaml"]
+            .iter()
+            .any(|yaml| mime.eq_ignore_ascii_case(yaml))
+        {
+            DocumentFormat::Yaml
+        } else {
+            DocumentFormat::Json
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            DocumentFormat::Json => "application/json",
+            DocumentFormat::Yaml => "application/yaml",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServiceSpec {
+    #[serde(flatten)]
+    pub service: DbApiServiceRequest,
+
+    #[serde(default)]
+    pub role_namespaces: Vec<String>,
+
+    // Qualified `Namespace::Name` roles authorized to call the service
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+impl ServiceSpec {
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.service.api_name, self.service.version)
+    }
+
+    // The equivalent web request, validated like one posted to the API
+    pub fn to_request(&self) -> Result<WebRequestApiService> {
+        let roles = parse_roles(&self.roles)?;
+        let service = &self.service;
+        let request = WebRequestApiService {
+            api_name: service.api_name.clone(),
+            version: service.version.clone(),
+            forward_url: service.forward_url.clone(),
+            active: service.active,
+            role_namespaces: self.role_namespaces.clone(),
+            roles,
+            environment: service.environment.clone(),
+            max_body_size: service.max_body_size,
+            tls: service.tls.clone(),
+            targets: service.targets.clone(),
+            load_balancing: service.load_balancing,
+            health_check: service.health_check.clone(),
+            timeouts: service.timeouts.clone(),
+            retry: service.retry.clone(),
+            circuit_breaker: service.circuit_breaker.clone(),
+            websocket: service.websocket.clone(),
+            streaming: service.streaming,
+        };
+        request
+            .validate()
+            .map_err(|e| GatewayError::BadRequest(format!("Service {}: {}", self.key(), e)))?;
+        Ok(request)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MemberSpec {
+    pub username: String,
+
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+pub fn parse_roles(roles: &[String]) -> Result<Vec<WebApiRole>> {
+    roles
+        .iter()
+        .map(|role| role.parse().map_err(GatewayError::BadRequest))
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    Create,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceKind {
+    Role,
+    Service,
+    Membership,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlannedChange {
+    pub action: ChangeAction,
+    pub kind: ResourceKind,
+    // Qualified role, `api_name/version` or username
+    pub name: String,
+    // Settings that differ, for updates
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigPlan {
+    pub changes: Vec<PlannedChange>,
+    pub applied: bool,
+}
@@ -0,0 +1,528 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use actix_web::web::Data;
+use serde_json::Value;
+
+use super::models::{
+    parse_roles, ChangeAction, ConfigDocument, ConfigPlan, MemberSpec, PlannedChange, ResourceKind,
+    ServiceSpec,
+};
+use crate::api_services::models::{
+    DbApiRole, DbApiServiceRequest, DbFullApiService, WebApiRole, WebRequestApiService,
+};
+use crate::api_services::repo::{ApiServiceRepository, RoleRepository};
+use crate::audit::models::Actor;
+use crate::database::{Database, NAMESPACE_MEMBER_ROLE};
+use crate::errors::{GatewayError, Result};
+use crate::revisions::Precondition;
+use crate::users::models::DbPartialGatewayUserUpdate;
+use crate::users::repo::UserRepository;
+
+// A planned change along with what it takes to carry it out
+struct Step {
+    change: PlannedChange,
+    operation: Operation,
+}
+
+enum Operation {
+    CreateRole(WebApiRole),
+    CreateService(WebRequestApiService),
+    ReplaceService(String, WebRequestApiService),
+    SetMemberships(String, Vec<WebApiRole>),
+    DeleteService(String),
+    DeleteRole(String),
+}
+
+/// Builds a document describing every role and service, and the role
+/// memberships of every user when `include_members` is set.
+pub async fn export(repo: &Data<Database>, include_members: bool) -> Result<ConfigDocument> {
+    let roles: BTreeSet<String> = Database::list_roles(repo)
+        .await?
+        .iter()
+        .filter(|role| role.name != NAMESPACE_MEMBER_ROLE)
+        .map(ToString::to_string)
+        .collect();
+
+    let mut services: Vec<ServiceSpec> = Database::list_services(repo)
+        .await?
+        .iter()
+        .map(service_spec)
+        .collect();
+    services.sort_by_key(ServiceSpec::key);
+
+    let members = if include_members {
+        let mut members: Vec<MemberSpec> = Database::list_users(repo)
+            .await?
+            .iter()
+            .map(|user| MemberSpec {
+                username: user.username.clone(),
+                roles: sorted(user.roles.iter().map(ToString::to_string)),
+            })
+            .collect();
+        members.sort_by(|a, b| a.username.cmp(&b.username));
+        Some(members)
+    } else {
+        None
+    };
+
+    Ok(ConfigDocument {
+        roles: roles.into_iter().collect(),
+        services,
+        members,
+    })
+}
+
+/// Compares the document with the database and, unless `dry_run` is set,
+/// makes the database match it. Records missing from the document are only
+/// deleted with `prune`.
+///
+/// Each change is committed on its own, so when one fails the changes before
+/// it stay applied. Applying the same document again plans only the rest.
+pub async fn apply(
+    repo: &Data<Database>,
+    desired: &ConfigDocument,
+    prune: bool,
+    dry_run: bool,
+    actor: &Actor,
+) -> Result<ConfigPlan> {
+    let steps = plan(repo, desired, prune).await?;
+    if !dry_run {
+        for (applied, step) in steps.iter().enumerate() {
+            log::info!(
+                "Applying {:?} {:?} {}",
+                step.change.action,
+                step.change.kind,
+                step.change.name
+            );
+            if let Err(e) = execute(repo, &step.operation, actor).await {
+                log::error!(
+                    "Applying {:?} {:?} {} failed; the {} changes before it remain applied",
+                    step.change.action,
+                    step.change.kind,
+                    step.change.name,
+                    applied
+                );
+                return Err(e);
+            }
+        }
+    }
+    Ok(ConfigPlan {
+        changes: steps.into_iter().map(|step| step.change).collect(),
+        applied: !dry_run,
+    })
+}
+
+async fn plan(repo: &Data<Database>, desired: &ConfigDocument, prune: bool) -> Result<Vec<Step>> {
+    let mut steps: Vec<Step> = Vec::new();
+
+    // Every role the document names or refers to
+    let mut desired_roles: BTreeSet<String> = BTreeSet::new();
+    for role in parse_roles(&desired.roles)? {
+        desired_roles.insert(role.to_string());
+    }
+    let mut desired_services: BTreeMap<String, (&ServiceSpec, WebRequestApiService)> =
+        BTreeMap::new();
+    for spec in &desired.services {
+        let request = spec.to_request()?;
+        for role in &request.roles {
+            desired_roles.insert(role.to_string());
+        }
+        if desired_services
+            .insert(spec.key(), (spec, request))
+            .is_some()
+        {
+            return Err(GatewayError::BadRequest(format!(
+                "Service {} is defined more than once",
+                spec.key()
+            )));
+        }
+    }
+    if let Some(members) = &desired.members {
+        for member in members {
+            for role in parse_roles(&member.roles)? {
+                desired_roles.insert(role.to_string());
+            }
+        }
+    }
+    let desired_namespaces: BTreeSet<&String> = desired_services
+        .values()
+        .flat_map(|(_, request)| &request.role_namespaces)
+        .collect();
+
+    let current_roles: BTreeMap<String, DbApiRole> = Database::list_roles(repo)
+        .await?
+        .into_iter()
+        .map(|role| (role.to_string(), role))
+        .collect();
+    for role in desired_roles
+        .iter()
+        .filter(|r| !current_roles.contains_key(*r))
+    {
+        steps.push(Step {
+            change: change(ChangeAction::Create, ResourceKind::Role, role),
+            operation: Operation::CreateRole(role.parse().map_err(GatewayError::BadRequest)?),
+        });
+    }
+
+    let current_services: BTreeMap<String, DbFullApiService> = Database::list_services(repo)
+        .await?
+        .into_iter()
+        .map(|service| (format!("{}/{}", service.api_name, service.version), service))
+        .collect();
+    for (key, (spec, request)) in &desired_services {
+        match current_services.get(key) {
+            None => steps.push(Step {
+                change: change(ChangeAction::Create, ResourceKind::Service, key),
+                operation: Operation::CreateService(request.clone()),
+            }),
+            Some(current) => {
+                let fields = changed_fields(&service_spec(current), spec)?;
+                if !fields.is_empty() {
+                    steps.push(Step {
+                        change: PlannedChange {
+                            fields,
+                            ..change(ChangeAction::Update, ResourceKind::Service, key)
+                        },
+                        operation: Operation::ReplaceService(
+                            current.id.id.to_string(),
+                            request.clone(),
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(members) = &desired.members {
+        let users = Database::list_users(repo).await?;
+        let desired_members: BTreeMap<&String, &MemberSpec> = members
+            .iter()
+            .map(|member| (&member.username, member))
+            .collect();
+        for username in desired_members.keys() {
+            if !users.iter().any(|user| &&user.username == username) {
+                return Err(GatewayError::BadRequest(format!(
+                    "User [{}] does not exist; users must be registered before their roles can be managed",
+                    username
+                )));
+            }
+        }
+        for user in &users {
+            let current: Vec<String> = sorted(user.roles.iter().map(ToString::to_string));
+            let wanted: Vec<String> = match desired_members.get(&user.username) {
+                Some(member) => sorted(parse_roles(&member.roles)?.iter().map(ToString::to_string)),
+                None if prune => Vec::new(),
+                None => continue,
+            };
+            if current != wanted {
+                steps.push(Step {
+                    change: change(
+                        ChangeAction::Update,
+                        ResourceKind::Membership,
+                        &user.username,
+                    ),
+                    operation: Operation::SetMemberships(
+                        user.id.id.to_string(),
+                        parse_roles(&wanted)?,
+                    ),
+                });
+            }
+        }
+    }
+
+    if prune {
+        for (key, service) in current_services
+            .iter()
+            .filter(|(key, _)| !desired_services.contains_key(*key))
+        {
+            steps.push(Step {
+                change: change(ChangeAction::Delete, ResourceKind::Service, key),
+                operation: Operation::DeleteService(service.id.id.to_string()),
+            });
+        }
+        // Namespace member roles are kept while a service still grants their namespace
+        for (name, role) in current_roles.iter().filter(|(name, role)| {
+            let granted_namespace =
+                role.name == NAMESPACE_MEMBER_ROLE && desired_namespaces.contains(&role.namespace);
+            !desired_roles.contains(*name) && !granted_namespace
+        }) {
+            if let Some(role_id) = &role.id {
+                steps.push(Step {
+                    change: change(ChangeAction::Delete, ResourceKind::Role, name),
+                    operation: Operation::DeleteRole(role_id.id.to_string()),
+                });
+            }
+        }
+    }
+    Ok(steps)
+}
+
+async fn execute(repo: &Data<Database>, operation: &Operation, actor: &Actor) -> Result<()> {
+    match operation {
+        Operation::CreateRole(role) => {
+            Database::add_role(repo, role).await?;
+        }
+        Operation::CreateService(request) => {
+            let roles = Vec::<WebApiRole>::from(request);
+            Database::add_service(repo, &request.into(), &roles, actor).await?;
+        }
+        Operation::ReplaceService(service_id, request) => {
+            let roles = Vec::<WebApiRole>::from(request);
+            Database::replace_service(repo, service_id, &request.into(), &roles, actor).await?;
+        }
+        Operation::SetMemberships(user_id, roles) => {
+            let user_update = DbPartialGatewayUserUpdate {
+                username: None,
+                disabled: None,
+            };
+            let roles = roles.iter().map(Into::into).collect();
+            Database::update_user(
+                repo,
+                user_id,
+                user_update,
+                Some(roles),
+                &Precondition::Any,
+                actor,
+            )
+            .await?;
+        }
+        Operation::DeleteService(service_id) => {
+            Database::delete_service(repo, service_id, actor).await?;
+        }
+        Operation::DeleteRole(role_id) => {
+            Database::delete_role(repo, role_id, actor).await?;
+        }
+    }
+    Ok(())
+}
+
+fn service_spec(service: &DbFullApiService) -> ServiceSpec {
+    let mut role_namespaces = Vec::new();
+    let mut roles = Vec::new();
+    for role in &service.roles {
+        if role.name == NAMESPACE_MEMBER_ROLE {
+            role_namespaces.push(role.namespace.clone());
+        } else {
+            roles.push(role.to_string());
+        }
+    }
+    role_namespaces.sort();
+    roles.sort();
+    ServiceSpec {
+        service: DbApiServiceRequest::from(service),
+        role_namespaces,
+        roles,
+    }
+}
+
+// Top-level settings whose values differ between the two definitions
+fn changed_fields(current: &ServiceSpec, desired: &ServiceSpec) -> Result<Vec<String>> {
+    let to_object = |spec: &ServiceSpec| -> Result<serde_json::Map<String, Value>> {
+        let mut spec = spec.clone();
+        spec.role_namespaces.sort();
+        spec.roles.sort();
+        match serde_json::to_value(spec) {
+            Ok(Value::Object(fields)) => Ok(fields),
+            _ => Err(GatewayError::SystemError(
+                "Unable to compare service definitions".to_string(),
+            )),
+        }
+    };
+    let current = to_object(current)?;
+    let desired = to_object(desired)?;
+    let keys: BTreeSet<&String> = current.keys().chain(desired.keys()).collect();
+    Ok(keys
+        .into_iter()
+        .filter(|key| current.get(*key) != desired.get(*key))
+        .cloned()
+        .collect())
+}
+
+fn change(action: ChangeAction, kind: ResourceKind, name: &str) -> PlannedChange {
+    PlannedChange {
+        action,
+        kind,
+        name: name.to_string(),
+        fields: Vec::new(),
+    }
+}
+
+fn sorted(values: impl Iterator<Item = String>) -> Vec<String> {
+    let mut values: Vec<String> = values.collect();
+    values.sort();
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::database::testing::database;
+    use crate::declarative::models::DocumentFormat;
+    use crate::users::models::{DbGatewayUserRequest, InitialCredential};
+
+    fn service(api_name: &str, forward_url: &str) -> Value {
+        json!({
+            "api_name": api_name,
+            "forward_url": forward_url,
+            "active": true,
+            "version": "v1",
+            "environment": "test",
+            "role_namespaces": ["Shop"],
+            "roles": ["Shop::Writer"],
+        })
+    }
+
+    fn document(document: Value) -> ConfigDocument {
+        ConfigDocument::parse(document.to_string().as_bytes(), DocumentFormat::Json).unwrap()
+    }
+
+    async fn planned(repo: &Data<Database>, desired: &ConfigDocument, prune: bool) -> Vec<String> {
+        plan(repo, desired, prune)
+            .await
+            .unwrap()
+            .iter()
+            .map(|step| {
+                format!(
+                    "{:?} {:?} {} {}",
+                    step.change.action,
+                    step.change.kind,
+                    step.change.name,
+                    step.change.fields.join(",")
+                )
+                .trim_end()
+                .to_string()
+            })
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn plans_creating_what_the_document_adds() {
+        let repo = database().await;
+        let desired = document(json!({
+            "roles": ["Shop::Reader"],
+            "services": [service("shop", "https://shop.example")],
+        }));
+
+        assert_eq!(
+            planned(&repo, &desired, false).await,
+            vec![
+                "Create Role Shop::Reader",
+                "Create Role Shop::Writer",
+                "Create Service shop/v1",
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn plans_updating_only_the_changed_settings() {
+        let repo = database().await;
+        let desired = document(json!({ "services": [service("shop", "https://shop.example")] }));
+        apply(&repo, &desired, false, false, &Actor::default())
+            .await
+            .unwrap();
+        assert!(planned(&repo, &desired, false).await.is_empty());
+
+        let mut moved = service("shop", "https://shop.example/v2");
+        moved["roles"] = json!(["Shop::Writer", "Shop::Reader"]);
+        let desired = document(json!({ "services": [moved] }));
+
+        assert_eq!(
+            planned(&repo, &desired, false).await,
+            vec![
+                "Create Role Shop::Reader",
+                "Update Service shop/v1 forward_url,roles",
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn plans_deletions_only_when_pruning() {
+        let repo = database().await;
+        let current = document(json!({
+            "roles": ["Other::Role"],
+            "services": [
+                service("shop", "https://shop.example"),
+                service("stock", "https://stock.example"),
+            ],
+        }));
+        apply(&repo, &current, false, false, &Actor::default())
+            .await
+            .unwrap();
+        let desired = document(json!({ "services": [service("shop", "https://shop.example")] }));
+
+        assert!(planned(&repo, &desired, false).await.is_empty());
+        // The Shop namespace's member role stays while shop still grants it
+        assert_eq!(
+            planned(&repo, &desired, true).await,
+            vec!["Delete Service stock/v1", "Delete Role Other::Role"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn plans_membership_changes_for_listed_users() {
+        let repo = database().await;
+        let current = document(json!({ "roles": ["Shop::Reader", "Shop::Writer"] }));
+        apply(&repo, &current, false, false, &Actor::default())
+            .await
+            .unwrap();
+        let reader = Database::find_role(&repo, &String::from("Shop"), &String::from("Reader"))
+            .await
+            .unwrap();
+        for username in ["alice", "bobby"] {
+            Database::register_user(
+                &repo,
+                DbGatewayUserRequest {
+                    username: username.to_string(),
+                },
+                vec![reader.clone()],
+                InitialCredential::External,
+                &Actor::default(),
+            )
+            .await
+            .unwrap();
+        }
+        let desired = document(json!({
+            "roles": ["Shop::Reader", "Shop::Writer"],
+            "members": [{ "username": "alice", "roles": ["Shop::Writer"] }],
+        }));
+
+        assert_eq!(
+            planned(&repo, &desired, false).await,
+            vec!["Update Membership alice"]
+        );
+        let mut pruned = planned(&repo, &desired, true).await;
+        pruned.sort();
+        assert_eq!(
+            pruned,
+            vec!["Update Membership alice", "Update Membership bobby"]
+        );
+
+        let unknown = document(json!({ "members": [{ "username": "carol", "roles": [] }] }));
+        assert!(matches!(
+            plan(&repo, &unknown, false).await,
+            Err(GatewayError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn role_order_is_not_a_change() {
+        let spec = |roles: Value| {
+            let mut service = service("shop", "https://shop.example");
+            service["roles"] = roles;
+            serde_json::from_value::<ServiceSpec>(service).unwrap()
+        };
+
+        let current = spec(json!(["Shop::Reader", "Shop::Writer"]));
+        assert!(
+            changed_fields(&current, &spec(json!(["Shop::Writer", "Shop::Reader"])))
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            changed_fields(&current, &spec(json!(["Shop::Reader"]))).unwrap(),
+            vec!["roles"]
+        );
+    }
+}
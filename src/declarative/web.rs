@@ -0,0 +1,81 @@
+use actix_web::{
+    get,
+    http::header,
+    post,
+    web::{scope, to, Bytes, Data, Json, Query, ServiceConfig},
+    HttpRequest, HttpResponse,
+};
+use serde::Deserialize;
+
+use super::models::{ConfigDocument, ConfigPlan, DocumentFormat};
+use super::sync;
+use crate::audit::models::Actor;
+use crate::auth::web::validate_jwt;
+use crate::database::Database;
+use crate::errors::{unknown_resource_error, Result};
+
+// Intermediate function to configure services
+pub fn service_setup(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/cfg/v1/config")
+            .service(export_config)
+            .service(apply_config)
+            .default_service(to(unknown_resource_error)),
+    );
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    #[serde(default)]
+    pub include_members: bool,
+    #[serde(default)]
+    pub format: DocumentFormat,
+}
+
+#[get("/")]
+async fn export_config(
+    req: HttpRequest,
+    params: Query<ExportParams>,
+    repo: Data<Database>,
+) -> Result<HttpResponse> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let document = sync::export(&repo, params.include_members).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(params.format.content_type())
+        .body(document.render(params.format)?))
+}
+
+#[derive(Deserialize)]
+struct ApplyParams {
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub prune: bool,
+}
+
+#[post("/")]
+async fn apply_config(
+    req: HttpRequest,
+    params: Query<ApplyParams>,
+    body: Bytes,
+    repo: Data<Database>,
+) -> Result<Json<ConfigPlan>> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    // YAML documents are sent as `application/yaml`, anything else is read as JSON
+    let format = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(DocumentFormat::from_content_type)
+        .unwrap_or_default();
+    let document = ConfigDocument::parse(&body, format)?;
+    let plan = sync::apply(
+        &repo,
+        &document,
+        params.prune,
+        params.dry_run,
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(Json(plan))
+}
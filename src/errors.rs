@@ -1,4 +1,7 @@
-use actix_web::{http::StatusCode, HttpResponse, Responder, ResponseError};
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, Responder, ResponseError,
+};
 
 use serde_json::json;
 use thiserror::Error;
@@ -50,12 +53,57 @@ pub enum GatewayError {
 
     #[error("Bad Request: {0}")]
     BadRequest(String),
+
+    #[error("Precondition Failed: {0}")]
+    PreconditionFailed(String),
+
+    // A check made within a transaction failed, usually because of a
+    // concurrent change
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    /**
+     * Forwarding Errors
+     */
+
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Bad Gateway: {0}")]
+    UpstreamError(String),
+
+    #[error("Service Unavailable: {0}")]
+    ServiceUnavailable(String),
+
+    #[error("Bad Gateway: {0}")]
+    UpstreamConnectionError(String),
+
+    #[error("Gateway Timeout: {0}")]
+    UpstreamTimeout(String),
+
+    #[error("Too Many Requests: {message}")]
+    RateLimited {
+        message: String,
+        limit: u64,
+        // Seconds until the request would be accepted
+        retry_after: u64,
+    },
 }
 
 impl ResponseError for GatewayError {
     fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::build(self.status_code())
-            .json(json!({"success": false, "error": self.to_string()}))
+        let mut response = HttpResponse::build(self.status_code());
+        if let GatewayError::RateLimited {
+            limit, retry_after, ..
+        } = self
+        {
+            response
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .insert_header(("RateLimit-Limit", limit.to_string()))
+                .insert_header(("RateLimit-Remaining", "0"))
+                .insert_header(("RateLimit-Reset", retry_after.to_string()));
+        }
+        response.json(json!({"success": false, "error": self.to_string()}))
     }
 
     fn status_code(&self) -> StatusCode {
@@ -64,7 +112,15 @@ impl ResponseError for GatewayError {
             GatewayError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
             GatewayError::MissingData(_) => StatusCode::BAD_REQUEST,
             GatewayError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            GatewayError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            GatewayError::Conflict(_) => StatusCode::CONFLICT,
             GatewayError::InvalidUsernameOrPassword(_) => StatusCode::UNAUTHORIZED,
+            GatewayError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            GatewayError::UpstreamError(_) => StatusCode::BAD_GATEWAY,
+            GatewayError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            GatewayError::UpstreamConnectionError(_) => StatusCode::BAD_GATEWAY,
+            GatewayError::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            GatewayError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+
+/// A provider users may sign in with, as offered to the web UI.
+#[derive(Debug, Serialize)]
+pub struct WebIdentityProvider {
+    pub name: String,
+    pub display_name: String,
+    // Where to send the user's browser to sign in
+    pub login_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProviderPath {
+    pub provider: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    // Path on the gateway to come back to, `/` by default
+    pub return_to: Option<String>,
+}
+
+/// What the provider sends the user back to the gateway with.
+#[derive(Debug, Deserialize)]
+pub struct CallbackParams {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginCodeForm {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DbFederatedLoginRequest {
+    pub provider: String,
+    pub state_hash: String,
+    // SHA-256 of the secret in the cookie of the browser that started it
+    pub browser_hash: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub return_to: String,
+    pub expires_at: Datetime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbFederatedLogin {
+    pub id: Thing,
+    pub provider: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub return_to: String,
+    pub user: Option<Thing>,
+    pub created_at: Datetime,
+    pub expires_at: Datetime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbFederatedIdentity {
+    pub id: Thing,
+    pub provider: String,
+    pub subject: String,
+    pub user: Thing,
+    pub created_at: Datetime,
+    pub last_login: Option<Datetime>,
+    #[serde(default)]
+    pub roles: Vec<Thing>,
+}
+
+/// The parts of a provider's discovery document the gateway uses.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProviderTokenResponse {
+    pub id_token: String,
+}
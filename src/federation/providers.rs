@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use super::models::{ProviderMetadata, ProviderTokenResponse};
+use crate::config::IdentityProviderConfig;
+use crate::errors::{GatewayError, Result};
+
+// How long a provider's discovery document and keys are used before fetching
+// them again
+const METADATA_LIFETIME: Duration = Duration::from_secs(60 * 60);
+// A token signed with a key the gateway doesn't know fetches the keys again,
+// at most this often
+const JWKS_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Symmetric algorithms are refused, since their key would be the client secret
+const ACCEPTED_ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Clone)]
+struct CachedProvider {
+    metadata: ProviderMetadata,
+    jwks: JwkSet,
+    fetched_at: Instant,
+}
+
+/// Talks to the external identity providers users sign in with, keeping
+/// each one's discovery document and signing keys.
+pub struct IdentityProviders {
+    client: Client,
+    // By provider name
+    cache: RwLock<HashMap<String, CachedProvider>>,
+}
+
+impl IdentityProviders {
+    pub fn new() -> Result<Self> {
+        Ok(IdentityProviders {
+            client: Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .map_err(|e| GatewayError::SystemError(e.to_string()))?,
+            cache: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// The provider's discovery document, fetched on first use.
+    pub async fn metadata(&self, provider: &IdentityProviderConfig) -> Result<ProviderMetadata> {
+        Ok(self.provider(provider, METADATA_LIFETIME).await?.metadata)
+    }
+
+    /// Exchanges the code the provider sent the user back with for an ID token.
+    pub async fn exchange_code(
+        &self,
+        provider: &IdentityProviderConfig,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<String> {
+        let metadata = self.metadata(provider).await?;
+        let mut request = self.client.post(&metadata.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("code_verifier", code_verifier),
+            ("client_id", provider.client_id.as_str()),
+        ]);
+        if let Some(client_secret) = &provider.client_secret {
+            request = request.basic_auth(&provider.client_id, Some(client_secret));
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| provider_error(provider, e))?;
+        let tokens: ProviderTokenResponse = read_json(provider, response).await?;
+        Ok(tokens.id_token)
+    }
+
+    /// Checks the ID token's signature against the provider's keys, and that
+    /// it was issued by the provider to the gateway for this sign in. Returns
+    /// its claims.
+    pub async fn validate_id_token(
+        &self,
+        provider: &IdentityProviderConfig,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<Map<String, Value>> {
+        let invalid = |e: String| {
+            GatewayError::Unauthorized(format!("Invalid ID token from the provider: {}", e))
+        };
+        let header = decode_header(id_token).map_err(|e| invalid(e.to_string()))?;
+        if !ACCEPTED_ALGORITHMS.contains(&header.alg) {
+            return Err(invalid(format!(
+                "{:?} signatures are not accepted",
+                header.alg
+            )));
+        }
+        let mut cached = self.provider(provider, METADATA_LIFETIME).await?;
+        let mut key = find_key(&cached.jwks, header.kid.as_deref());
+        // The provider may have rotated its keys since they were fetched
+        if key.is_none() {
+            cached = self.provider(provider, JWKS_REFETCH_INTERVAL).await?;
+            key = find_key(&cached.jwks, header.kid.as_deref());
+        }
+        let key = key.ok_or_else(|| invalid(String::from("signed with an unknown key")))??;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&cached.metadata.issuer]);
+        validation.set_audience(&[&provider.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = decode::<Map<String, Value>>(id_token, &key, &validation)
+            .map_err(|e| invalid(e.to_string()))?
+            .claims;
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(invalid(String::from("the nonce does not match")));
+        }
+        Ok(claims)
+    }
+
+    // The cached provider, fetched again once older than `max_age`
+    async fn provider(
+        &self,
+        provider: &IdentityProviderConfig,
+        max_age: Duration,
+    ) -> Result<CachedProvider> {
+        if let Some(cached) = self
+            .cache
+            .read()
+            .unwrap()
+            .get(&provider.name)
+            .filter(|cached| cached.fetched_at.elapsed() < max_age)
+        {
+            return Ok(cached.clone());
+        }
+
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            provider.issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = self.get_json(provider, &discovery_url).await?;
+        if metadata.issuer != provider.issuer {
+            return Err(GatewayError::UpstreamError(format!(
+                "Identity provider [{}] names its issuer [{}] rather than [{}]",
+                provider.name, metadata.issuer, provider.issuer
+            )));
+        }
+        let jwks: JwkSet = self.get_json(provider, &metadata.jwks_uri).await?;
+        log::debug!(
+            "Loaded {} signing keys of identity provider [{}]",
+            jwks.keys.len(),
+            provider.name
+        );
+        let cached = CachedProvider {
+            metadata,
+            jwks,
+            fetched_at: Instant::now(),
+        };
+        self.cache
+            .write()
+            .unwrap()
+            .insert(provider.name.clone(), cached.clone());
+        Ok(cached)
+    }
+
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        provider: &IdentityProviderConfig,
+        url: &str,
+    ) -> Result<T> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| provider_error(provider, e))?;
+        read_json(provider, response).await
+    }
+}
+
+// The key named by `kid`, or the only key when the token names none
+fn find_key(jwks: &JwkSet, kid: Option<&str>) -> Option<Result<DecodingKey>> {
+    let jwk = match kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }?;
+    Some(DecodingKey::from_jwk(jwk).map_err(|e| {
+        GatewayError::UpstreamError(format!("The provider published an invalid key: {}", e))
+    }))
+}
+
+async fn read_json<T: DeserializeOwned>(
+    provider: &IdentityProviderConfig,
+    response: reqwest::Response,
+) -> Result<T> {
+    let status = response.status();
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| provider_error(provider, e))?;
+    if !status.is_success() {
+        return Err(GatewayError::UpstreamError(format!(
+            "Identity provider [{}] answered {}: {}",
+            provider.name,
+            status,
+            String::from_utf8_lossy(&body)
+        )));
+    }
+    serde_json::from_slice(&body).map_err(|e| {
+        GatewayError::UpstreamError(format!(
+            "Identity provider [{}] sent an unexpected response: {}",
+            provider.name, e
+        ))
+    })
+}
+
+fn provider_error(provider: &IdentityProviderConfig, error: reqwest::Error) -> GatewayError {
+    GatewayError::UpstreamError(format!(
+        "Unable to reach identity provider [{}]: {}",
+        provider.name, error
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use actix_web::web::{self, Data};
+    use actix_web::{App, HttpResponse, HttpServer};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+    use crate::audit::models::Actor;
+    use crate::auth::keys::{rotate_signing_key, SigningKeys};
+    use crate::auth::models::SigningAlgorithm;
+    use crate::database::testing::database;
+    use crate::database::Database;
+
+    const CLIENT_ID: &str = "gateway";
+    const NONCE: &str = "nonce-1";
+
+    // An identity provider serving its discovery document and keys, which
+    // counts how often its keys are fetched
+    struct MockProvider {
+        config: IdentityProviderConfig,
+        repo: Data<Database>,
+        keys: Data<SigningKeys>,
+        jwks_fetches: Data<AtomicUsize>,
+    }
+
+    impl MockProvider {
+        async fn start() -> MockProvider {
+            let repo = database().await;
+            let keys = Data::new(SigningKeys::new());
+            let jwks_fetches = Data::new(AtomicUsize::new(0));
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let provider = MockProvider {
+                config: IdentityProviderConfig {
+                    name: String::from("corp"),
+                    display_name: None,
+                    issuer: issuer.clone(),
+                    client_id: String::from(CLIENT_ID),
+                    client_secret: None,
+                    scopes: vec![String::from("openid")],
+                    username_claim: String::from("preferred_username"),
+                    groups_claim: String::from("groups"),
+                    provision_users: true,
+                    link_existing_users: false,
+                    role_rules: Vec::new(),
+                },
+                repo,
+                keys: keys.clone(),
+                jwks_fetches: jwks_fetches.clone(),
+            };
+            provider.rotate_keys().await;
+
+            let issuer = Data::new(issuer);
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(issuer.clone())
+                    .app_data(keys.clone())
+                    .app_data(jwks_fetches.clone())
+                    .route(
+                        "/.well-known/openid-configuration",
+                        web::get().to(discovery),
+                    )
+                    .route("/jwks", web::get().to(jwks))
+            })
+            .workers(1)
+            .listen(listener)
+            .unwrap()
+            .run();
+            actix_web::rt::spawn(server);
+            provider
+        }
+
+        async fn rotate_keys(&self) {
+            rotate_signing_key(&self.repo, SigningAlgorithm::EdDsa, 3600, &Actor::default())
+                .await
+                .unwrap();
+            self.keys.refresh(&self.repo, 3600).await.unwrap();
+        }
+
+        fn id_token(&self, changes: Value) -> String {
+            let mut claims = json!({
+                "iss": self.config.issuer,
+                "aud": CLIENT_ID,
+                "sub": "alice-at-corp",
+                "exp": chrono::Utc::now().timestamp() + 300,
+                "nonce": NONCE,
+            });
+            claims
+                .as_object_mut()
+                .unwrap()
+                .extend(changes.as_object().unwrap().clone());
+            self.keys.sign(&claims).unwrap()
+        }
+
+        fn jwks_fetches(&self) -> usize {
+            self.jwks_fetches.load(Ordering::SeqCst)
+        }
+    }
+
+    async fn discovery(issuer: Data<String>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "issuer": issuer.as_str(),
+            "authorization_endpoint": format!("{}/authorize", issuer.as_str()),
+            "token_endpoint": format!("{}/token", issuer.as_str()),
+            "jwks_uri": format!("{}/jwks", issuer.as_str()),
+        }))
+    }
+
+    async fn jwks(keys: Data<SigningKeys>, jwks_fetches: Data<AtomicUsize>) -> HttpResponse {
+        jwks_fetches.fetch_add(1, Ordering::SeqCst);
+        HttpResponse::Ok().json(keys.jwks())
+    }
+
+    #[actix_web::test]
+    async fn accepts_tokens_issued_to_the_gateway_for_this_sign_in() {
+        let provider = MockProvider::start().await;
+        let providers = IdentityProviders::new().unwrap();
+
+        let claims = providers
+            .validate_id_token(&provider.config, &provider.id_token(json!({})), NONCE)
+            .await
+            .unwrap();
+
+        assert_eq!(claims["sub"], "alice-at-corp");
+    }
+
+    #[actix_web::test]
+    async fn rejects_tokens_for_another_issuer_audience_or_sign_in() {
+        let provider = MockProvider::start().await;
+        let providers = IdentityProviders::new().unwrap();
+
+        for changes in [
+            json!({ "iss": "https://elsewhere.example" }),
+            json!({ "aud": "another-client" }),
+            json!({ "nonce": "nonce-2" }),
+            json!({ "exp": chrono::Utc::now().timestamp() - 300 }),
+        ] {
+            let id_token = provider.id_token(changes);
+            assert!(matches!(
+                providers
+                    .validate_id_token(&provider.config, &id_token, NONCE)
+                    .await,
+                Err(GatewayError::Unauthorized(_))
+            ));
+        }
+    }
+
+    #[actix_web::test]
+    async fn refuses_symmetric_signatures() {
+        let provider = MockProvider::start().await;
+        let providers = IdentityProviders::new().unwrap();
+        let id_token = encode(
+            &Header::new(Algorithm::HS256),
+            &json!({ "iss": provider.config.issuer, "aud": CLIENT_ID, "sub": "alice-at-corp" }),
+            &EncodingKey::from_secret(b"client-secret"),
+        )
+        .unwrap();
+
+        let validated = providers
+            .validate_id_token(&provider.config, &id_token, NONCE)
+            .await;
+
+        assert!(matches!(validated, Err(GatewayError::Unauthorized(_))));
+        assert_eq!(provider.jwks_fetches(), 0);
+    }
+
+    #[actix_web::test]
+    async fn fetches_the_keys_again_for_an_unknown_key() {
+        let provider = MockProvider::start().await;
+        let providers = IdentityProviders::new().unwrap();
+        providers
+            .validate_id_token(&provider.config, &provider.id_token(json!({})), NONCE)
+            .await
+            .unwrap();
+        provider.rotate_keys().await;
+        let id_token = provider.id_token(json!({}));
+
+        // Not while the keys were only just fetched
+        assert!(providers
+            .validate_id_token(&provider.config, &id_token, NONCE)
+            .await
+            .is_err());
+        assert_eq!(provider.jwks_fetches(), 1);
+
+        if let Some(cached) = providers.cache.write().unwrap().get_mut("corp") {
+            cached.fetched_at -= JWKS_REFETCH_INTERVAL;
+        }
+        assert!(providers
+            .validate_id_token(&provider.config, &id_token, NONCE)
+            .await
+            .is_ok());
+        assert_eq!(provider.jwks_fetches(), 2);
+    }
+}
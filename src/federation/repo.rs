@@ -0,0 +1,323 @@
+use actix_web::web::Data;
+use async_trait::async_trait;
+use serde_json::json;
+use surrealdb::sql::{Duration, Thing};
+
+use super::models::{DbFederatedIdentity, DbFederatedLogin, DbFederatedLoginRequest};
+use crate::audit::models::{Actor, AuditAction};
+use crate::audit::repo::audit_within;
+use crate::database::{
+    Database, Transaction, FEDERATED_IDENTITY_TABLE, FEDERATED_LOGIN_TABLE, USER_TABLE,
+};
+use crate::errors::{GatewayError, Result};
+use crate::oidc::repo::hash_secret;
+
+#[async_trait]
+pub trait FederatedLoginRepository {
+    async fn start_federated_login(
+        repo: &Data<Database>,
+        login: DbFederatedLoginRequest,
+    ) -> Result<()>;
+    async fn take_federated_login(
+        repo: &Data<Database>,
+        state: &str,
+        browser: &str,
+    ) -> Result<DbFederatedLogin>;
+    async fn complete_federated_login(
+        repo: &Data<Database>,
+        login: &DbFederatedLogin,
+        identity: &DbFederatedIdentity,
+        granted_roles: Vec<Thing>,
+        code: &str,
+        lifetime_secs: u64,
+    ) -> Result<()>;
+    async fn redeem_login_code(
+        repo: &Data<Database>,
+        code: &str,
+        browser: &str,
+    ) -> Result<DbFederatedLogin>;
+}
+
+#[async_trait]
+impl FederatedLoginRepository for Database {
+    async fn start_federated_login(
+        repo: &Data<Database>,
+        login: DbFederatedLoginRequest,
+    ) -> Result<()> {
+        // Abandoned sign ins are dropped as new ones start
+        let transaction = Transaction::new()
+            .statement(format!(
+                "DELETE {table} WHERE expires_at < time::now();\n\
+                CREATE {table} CONTENT $login;",
+                table = FEDERATED_LOGIN_TABLE
+            ))
+            .bind("login", login)?;
+        repo.commit(transaction).await
+    }
+
+    // Finds the sign in by the state the provider sent back, which then can't
+    // be used again. Only the browser that started it can take it
+    async fn take_federated_login(
+        repo: &Data<Database>,
+        state: &str,
+        browser: &str,
+    ) -> Result<DbFederatedLogin> {
+        let logins: Vec<DbFederatedLogin> = repo
+            .query_list(
+                format!(
+                    "UPDATE {} SET state_hash = NONE \
+                    WHERE state_hash = $state_hash AND browser_hash = $browser_hash \
+                    AND expires_at > time::now() \
+                    RETURN BEFORE",
+                    FEDERATED_LOGIN_TABLE
+                ),
+                Some(json!({
+                    "state_hash": hash_secret(state),
+                    "browser_hash": hash_secret(browser),
+                })),
+            )
+            .await?;
+        logins
+            .into_iter()
+            .next()
+            .ok_or(GatewayError::Unauthorized(String::from(
+                "The sign in has expired, was already completed or was started in another browser",
+            )))
+    }
+
+    // Hands the signed in user to whoever presents `code` within the
+    // lifetime, and remembers the roles the provider granted them
+    async fn complete_federated_login(
+        repo: &Data<Database>,
+        login: &DbFederatedLogin,
+        identity: &DbFederatedIdentity,
+        granted_roles: Vec<Thing>,
+        code: &str,
+        lifetime_secs: u64,
+    ) -> Result<()> {
+        let transaction = Transaction::new()
+            .statement(
+                "UPDATE $login SET code_hash = $code_hash, user = $user, \
+                expires_at = time::now() + $lifetime",
+            )
+            .statement("UPDATE $identity SET last_login = time::now(), roles = $granted_roles")
+            .bind("login", &login.id)?
+            .bind("code_hash", hash_secret(code))?
+            .bind("user", &identity.user)?
+            .bind("lifetime", Duration::from_secs(lifetime_secs))?
+            .bind("identity", &identity.id)?
+            .bind("granted_roles", granted_roles)?;
+        repo.commit(transaction).await
+    }
+
+    async fn redeem_login_code(
+        repo: &Data<Database>,
+        code: &str,
+        browser: &str,
+    ) -> Result<DbFederatedLogin> {
+        let logins: Vec<DbFederatedLogin> = repo
+            .query_list(
+                format!(
+                    "DELETE {} WHERE code_hash = $code_hash AND browser_hash = $browser_hash \
+                    AND expires_at > time::now() \
+                    RETURN BEFORE",
+                    FEDERATED_LOGIN_TABLE
+                ),
+                Some(json!({
+                    "code_hash": hash_secret(code),
+                    "browser_hash": hash_secret(browser),
+                })),
+            )
+            .await?;
+        logins
+            .into_iter()
+            .next()
+            .filter(|login| login.user.is_some())
+            .ok_or(GatewayError::Unauthorized(String::from(
+                "Invalid or expired login code",
+            )))
+    }
+}
+
+#[async_trait]
+pub trait FederatedIdentityRepository {
+    async fn find_federated_identity(
+        repo: &Data<Database>,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<DbFederatedIdentity>>;
+    async fn find_user_by_username(repo: &Data<Database>, username: &str) -> Result<Option<Thing>>;
+    async fn link_federated_identity(
+        repo: &Data<Database>,
+        provider: &str,
+        subject: &str,
+        user: &Thing,
+        provisioned: bool,
+        actor: &Actor,
+    ) -> Result<DbFederatedIdentity>;
+}
+
+#[async_trait]
+impl FederatedIdentityRepository for Database {
+    async fn find_federated_identity(
+        repo: &Data<Database>,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<DbFederatedIdentity>> {
+        repo.query_record(
+            format!(
+                "SELECT * FROM {} WHERE provider = $provider AND subject = $subject LIMIT 1",
+                FEDERATED_IDENTITY_TABLE
+            ),
+            Some(json!({ "provider": provider, "subject": subject })),
+        )
+        .await
+    }
+
+    async fn find_user_by_username(repo: &Data<Database>, username: &str) -> Result<Option<Thing>> {
+        let users: Vec<Thing> = repo
+            .query_list(
+                format!(
+                    "SELECT VALUE id FROM {} WHERE username = $username LIMIT 1",
+                    USER_TABLE
+                ),
+                Some(("username", username)),
+            )
+            .await?;
+        Ok(users.into_iter().next())
+    }
+
+    async fn link_federated_identity(
+        repo: &Data<Database>,
+        provider: &str,
+        subject: &str,
+        user: &Thing,
+        provisioned: bool,
+        actor: &Actor,
+    ) -> Result<DbFederatedIdentity> {
+        let entry = actor.entry(
+            AuditAction::FederatedIdentityLinked,
+            Some(user.clone()),
+            json!({ "provider": provider, "subject": subject, "provisioned": provisioned }),
+        );
+        let transaction = Transaction::new()
+            .statement(format!(
+                "CREATE {} CONTENT {{ provider: $provider, subject: $subject, user: $user }}",
+                FEDERATED_IDENTITY_TABLE
+            ))
+            .bind("provider", provider)?
+            .bind("subject", subject)?
+            .bind("user", user)?;
+        repo.commit(audit_within(transaction, vec![entry])?).await?;
+        Database::find_federated_identity(repo, provider, subject)
+            .await?
+            .ok_or(GatewayError::DatabaseError(String::from(
+                "Failed to fetch the linked identity",
+            )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::Datetime;
+
+    use super::*;
+    use crate::database::testing::database;
+    use crate::users::models::{DbGatewayUserRequest, InitialCredential};
+    use crate::users::repo::UserRepository;
+
+    async fn start_login(repo: &Data<Database>, state: &str, lifetime_secs: i64) {
+        Database::start_federated_login(
+            repo,
+            DbFederatedLoginRequest {
+                provider: String::from("corp"),
+                state_hash: hash_secret(state),
+                browser_hash: hash_secret("browser"),
+                nonce: String::from("nonce"),
+                code_verifier: String::from("verifier"),
+                return_to: String::from("/"),
+                expires_at: Datetime::from(
+                    chrono::Utc::now() + chrono::Duration::seconds(lifetime_secs),
+                ),
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn state_completes_one_sign_in() {
+        let repo = database().await;
+        start_login(&repo, "state-1", 600).await;
+        start_login(&repo, "state-2", -1).await;
+
+        // Not from another browser
+        assert!(matches!(
+            Database::take_federated_login(&repo, "state-1", "other-browser").await,
+            Err(GatewayError::Unauthorized(_))
+        ));
+        let login = Database::take_federated_login(&repo, "state-1", "browser")
+            .await
+            .unwrap();
+
+        assert_eq!(login.provider, "corp");
+        for state in ["state-1", "state-2", "state-3"] {
+            assert!(matches!(
+                Database::take_federated_login(&repo, state, "browser").await,
+                Err(GatewayError::Unauthorized(_))
+            ));
+        }
+    }
+
+    #[actix_web::test]
+    async fn login_code_is_redeemed_once() {
+        let repo = database().await;
+        let (user, _) = Database::register_user(
+            &repo,
+            DbGatewayUserRequest {
+                username: String::from("alice"),
+            },
+            Vec::new(),
+            InitialCredential::External,
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let identity = Database::link_federated_identity(
+            &repo,
+            "corp",
+            "alice-at-corp",
+            &user.id,
+            true,
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        start_login(&repo, "state", 600).await;
+        let login = Database::take_federated_login(&repo, "state", "browser")
+            .await
+            .unwrap();
+        // Not before the user has been found
+        assert!(Database::redeem_login_code(&repo, "code", "browser")
+            .await
+            .is_err());
+        Database::complete_federated_login(&repo, &login, &identity, Vec::new(), "code", 60)
+            .await
+            .unwrap();
+        // Nor from another browser
+        assert!(matches!(
+            Database::redeem_login_code(&repo, "code", "other-browser").await,
+            Err(GatewayError::Unauthorized(_))
+        ));
+
+        let redeemed = Database::redeem_login_code(&repo, "code", "browser")
+            .await
+            .unwrap();
+
+        assert_eq!(redeemed.user, Some(user.id));
+        assert!(matches!(
+            Database::redeem_login_code(&repo, "code", "browser").await,
+            Err(GatewayError::Unauthorized(_))
+        ));
+    }
+}
@@ -0,0 +1,660 @@
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::{
+    get, post,
+    web::{scope, to, Data, Json, Path, Query, ServiceConfig},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use reqwest::Url;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use surrealdb::sql::{Datetime, Thing};
+
+use super::models::{
+    CallbackParams, DbFederatedIdentity, DbFederatedLogin, DbFederatedLoginRequest, LoginCodeForm,
+    LoginParams, ProviderPath, WebIdentityProvider,
+};
+use super::providers::IdentityProviders;
+use super::repo::{FederatedIdentityRepository, FederatedLoginRepository};
+use crate::api_services::models::{DbApiRole, WebApiRole};
+use crate::api_services::repo::RoleRepository;
+use crate::audit;
+use crate::audit::models::{Actor, AuditAction};
+use crate::auth::models::TokenResponse;
+use crate::auth::web::sign_in;
+use crate::config::{GatewayConfig, IdentityProviderConfig};
+use crate::database::Database;
+use crate::errors::{unknown_resource_error, GatewayError, Result};
+use crate::oidc::page::error_page;
+use crate::oidc::repo::{generate_secret, hash_secret};
+use crate::revisions::Precondition;
+use crate::users::models::{
+    DbGatewayUserRequest, DbGatewayUserResponse, DbPartialGatewayUserUpdate, InitialCredential,
+};
+use crate::users::repo::UserRepository;
+
+// Time the user has to sign in at the provider
+const LOGIN_LIFETIME_SECS: i64 = 10 * 60;
+// The web UI exchanges the login code as soon as it receives it
+const LOGIN_CODE_LIFETIME_SECS: u64 = 60;
+// Holds a secret tying a sign in to the browser that started it, so nobody
+// can have a victim's browser complete a sign in to the attacker's account
+const LOGIN_COOKIE: &str = "federated_login";
+
+// Intermediate function to configure services
+pub fn service_setup(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/auth/v1/federated")
+            .service(list_providers)
+            .service(exchange_login_code)
+            .service(start_login)
+            .service(callback)
+            .default_service(to(unknown_resource_error)),
+    );
+}
+
+#[get("/")]
+async fn list_providers(config: Data<GatewayConfig>) -> Json<Vec<WebIdentityProvider>> {
+    Json(
+        config
+            .auth
+            .identity_providers
+            .iter()
+            .map(|provider| WebIdentityProvider {
+                name: provider.name.clone(),
+                display_name: provider.display_name().to_string(),
+                login_url: format!("/auth/v1/federated/{}/login", provider.name),
+            })
+            .collect(),
+    )
+}
+
+// Sends the user's browser to the provider to sign in
+#[get("/{provider}/login")]
+async fn start_login(
+    req: HttpRequest,
+    repo: Data<Database>,
+    config: Data<GatewayConfig>,
+    providers: Data<IdentityProviders>,
+    path_params: Path<ProviderPath>,
+    params: Query<LoginParams>,
+) -> Result<HttpResponse> {
+    let provider = identity_provider(&config, &path_params.provider)?;
+    // Only paths on the gateway, so the login code can't be sent elsewhere
+    let return_to = params.into_inner().return_to.unwrap_or(String::from("/"));
+    if !return_to.starts_with('/') || return_to.starts_with("//") || return_to.contains('\\') {
+        return Err(GatewayError::BadRequest(String::from(
+            "return_to must be a path on the gateway",
+        )));
+    }
+    let metadata = providers.metadata(provider).await?;
+
+    let state = generate_secret();
+    // A browser keeps its secret across the sign ins it starts
+    let browser = login_browser(&req).unwrap_or_else(|_| generate_secret());
+    let nonce = generate_secret();
+    let code_verifier = generate_secret();
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+    Database::start_federated_login(
+        &repo,
+        DbFederatedLoginRequest {
+            provider: provider.name.clone(),
+            state_hash: hash_secret(&state),
+            browser_hash: hash_secret(&browser),
+            nonce: nonce.clone(),
+            code_verifier,
+            return_to,
+            expires_at: Datetime::from(
+                chrono::Utc::now() + chrono::Duration::seconds(LOGIN_LIFETIME_SECS),
+            ),
+        },
+    )
+    .await?;
+
+    let mut location = Url::parse(&metadata.authorization_endpoint).map_err(|e| {
+        GatewayError::UpstreamError(format!(
+            "Identity provider [{}] has an invalid authorization endpoint: {}",
+            provider.name, e
+        ))
+    })?;
+    location
+        .query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &callback_url(&config, provider))
+        .append_pair("scope", &provider.scopes.join(" "))
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256");
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, location.as_str()))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .cookie(login_cookie(browser))
+        .finish())
+}
+
+// Where the provider sends the user back to. Once signed in, the user is
+// sent on to their `return_to` path with a `login_code` for the web UI to
+// exchange for tokens; the tokens themselves never appear in a URL
+#[get("/{provider}/callback")]
+async fn callback(
+    req: HttpRequest,
+    repo: Data<Database>,
+    config: Data<GatewayConfig>,
+    providers: Data<IdentityProviders>,
+    path_params: Path<ProviderPath>,
+    params: Query<CallbackParams>,
+) -> HttpResponse {
+    let provider_name = path_params.into_inner().provider;
+    match complete_login(&req, &repo, &config, &providers, &provider_name, &params).await {
+        Ok((return_to, code)) => {
+            let mut location = return_to;
+            location.query_pairs_mut().append_pair("login_code", &code);
+            HttpResponse::SeeOther()
+                .insert_header((header::LOCATION, location.as_str()))
+                .insert_header((header::CACHE_CONTROL, "no-store"))
+                .finish()
+        }
+        Err(e) => {
+            audit::repo::record(
+                &repo,
+                Actor::anonymous(&req).entry(
+                    AuditAction::LoginFailed,
+                    None,
+                    json!({ "provider": provider_name, "reason": e.to_string() }),
+                ),
+            )
+            .await;
+            if e.status_code().is_server_error() && !matches!(e, GatewayError::UpstreamError(_)) {
+                log::error!(
+                    "Unable to complete a sign in through [{}]: {}",
+                    provider_name,
+                    e
+                );
+                error_page("The sign in could not be completed. Please try again later.")
+            } else {
+                error_page(&e.to_string())
+            }
+        }
+    }
+}
+
+// Checks the provider's answer, finds or provisions the user it vouches for,
+// and returns where to send them with their login code
+async fn complete_login(
+    req: &HttpRequest,
+    repo: &Data<Database>,
+    config: &GatewayConfig,
+    providers: &IdentityProviders,
+    provider_name: &str,
+    params: &CallbackParams,
+) -> Result<(Url, String)> {
+    let provider = identity_provider(config, provider_name)?;
+    let browser = login_browser(req)?;
+    let login = Database::take_federated_login(
+        repo,
+        params
+            .state
+            .as_deref()
+            .ok_or(GatewayError::BadRequest(String::from(
+                "The provider did not return the sign in's state",
+            )))?,
+        &browser,
+    )
+    .await?;
+    if login.provider != provider.name {
+        return Err(GatewayError::Unauthorized(String::from(
+            "The sign in was started with another provider",
+        )));
+    }
+    if let Some(error) = &params.error {
+        return Err(GatewayError::Unauthorized(format!(
+            "The identity provider refused the sign in: {}",
+            params.error_description.as_ref().unwrap_or(error)
+        )));
+    }
+    let code = params
+        .code
+        .as_deref()
+        .ok_or(GatewayError::BadRequest(String::from(
+            "The provider did not return an authorization code",
+        )))?;
+
+    let id_token = providers
+        .exchange_code(
+            provider,
+            code,
+            &callback_url(config, provider),
+            &login.code_verifier,
+        )
+        .await?;
+    let claims = providers
+        .validate_id_token(provider, &id_token, &login.nonce)
+        .await?;
+    let subject = claims
+        .get("sub")
+        .and_then(Value::as_str)
+        .ok_or(GatewayError::Unauthorized(String::from(
+            "The ID token has no subject",
+        )))?;
+
+    let identity = find_or_provision_user(req, repo, provider, &claims, subject).await?;
+    let user = Database::user_detail(repo, &format!("{}", identity.user.id)).await?;
+    if user.disabled {
+        return Err(GatewayError::Unauthorized(String::from(
+            "The account has been disabled",
+        )));
+    }
+    let granted_roles = sync_roles(req, repo, provider, &claims, &user, &identity).await?;
+
+    let code = generate_secret();
+    Database::complete_federated_login(
+        repo,
+        &login,
+        &identity,
+        granted_roles,
+        &code,
+        LOGIN_CODE_LIFETIME_SECS,
+    )
+    .await?;
+    Ok((return_url(config, &login)?, code))
+}
+
+// The identity the provider vouches for, linked on its first sign in to a
+// new account or, when the provider is trusted to, to the account with the
+// same username
+async fn find_or_provision_user(
+    req: &HttpRequest,
+    repo: &Data<Database>,
+    provider: &IdentityProviderConfig,
+    claims: &Map<String, Value>,
+    subject: &str,
+) -> Result<DbFederatedIdentity> {
+    if let Some(identity) = Database::find_federated_identity(repo, &provider.name, subject).await?
+    {
+        return Ok(identity);
+    }
+    let username = claims
+        .get(&provider.username_claim)
+        .and_then(Value::as_str)
+        .ok_or(GatewayError::Unauthorized(format!(
+            "The ID token has no {} claim to name the account after",
+            provider.username_claim
+        )))?;
+    let actor = Actor::anonymous(req);
+    let (user_id, provisioned) = match Database::find_user_by_username(repo, username).await? {
+        Some(user_id) if provider.link_existing_users => (user_id, false),
+        Some(_) => {
+            return Err(GatewayError::Unauthorized(format!(
+                "An account named [{}] already exists, and is not linked to this provider",
+                username
+            )))
+        }
+        None if provider.provision_users => {
+            let (user, _) = Database::register_user(
+                repo,
+                DbGatewayUserRequest {
+                    username: username.to_string(),
+                },
+                Vec::new(),
+                InitialCredential::External,
+                &actor,
+            )
+            .await?;
+            (user.id, true)
+        }
+        None => {
+            return Err(GatewayError::Unauthorized(format!(
+                "There is no account for [{}]",
+                username
+            )))
+        }
+    };
+    Database::link_federated_identity(repo, &provider.name, subject, &user_id, provisioned, &actor)
+        .await
+}
+
+// Grants the roles the provider's rules give the user's groups, and takes
+// away those it granted before that they no longer give. Roles granted in
+// the gateway itself are left alone. Returns the roles the rules gave.
+async fn sync_roles(
+    req: &HttpRequest,
+    repo: &Data<Database>,
+    provider: &IdentityProviderConfig,
+    claims: &Map<String, Value>,
+    user: &DbGatewayUserResponse,
+    identity: &DbFederatedIdentity,
+) -> Result<Vec<Thing>> {
+    let mut granted: Vec<DbApiRole> = Vec::new();
+    for role in rule_roles(provider, claims) {
+        // Role names were checked when the configuration was loaded
+        let role: WebApiRole = role.parse().map_err(GatewayError::BadRequest)?;
+        match Database::find_role(repo, &role.namespace, &role.name).await {
+            Ok(role) if !granted.iter().any(|found| found.id == role.id) => granted.push(role),
+            Ok(_) => {}
+            Err(GatewayError::NotFound(_, _)) => log::warn!(
+                "Identity provider [{}] maps onto role {}, which does not exist",
+                provider.name,
+                role
+            ),
+            Err(e) => return Err(e),
+        }
+    }
+    let granted_ids: Vec<Thing> = granted.iter().filter_map(|role| role.id.clone()).collect();
+
+    let mut roles: Vec<DbApiRole> = user
+        .roles
+        .iter()
+        .filter(|role| {
+            role.id
+                .as_ref()
+                .is_some_and(|id| granted_ids.contains(id) || !identity.roles.contains(id))
+        })
+        .cloned()
+        .collect();
+    let held = |role: &DbApiRole| user.roles.iter().any(|held| held.id == role.id);
+    let unchanged = roles.len() == user.roles.len() && granted.iter().all(held);
+    if !unchanged {
+        roles.extend(granted.into_iter().filter(|role| !held(role)));
+        Database::update_user(
+            repo,
+            &format!("{}", user.id.id),
+            DbPartialGatewayUserUpdate {
+                username: None,
+                disabled: None,
+            },
+            Some(roles),
+            &Precondition::Any,
+            &Actor::anonymous(req),
+        )
+        .await?;
+    }
+    Ok(granted_ids)
+}
+
+// The roles the provider's rules give the groups the claims place the user in
+fn rule_roles<'a>(
+    provider: &'a IdentityProviderConfig,
+    claims: &Map<String, Value>,
+) -> Vec<&'a str> {
+    // A single group may be given as a string
+    let groups: Vec<&str> = match claims.get(&provider.groups_claim) {
+        Some(Value::Array(groups)) => groups.iter().filter_map(Value::as_str).collect(),
+        Some(Value::String(group)) => vec![group.as_str()],
+        _ => Vec::new(),
+    };
+    provider
+        .role_rules
+        .iter()
+        .filter(|rule| groups.contains(&rule.group.as_str()))
+        .flat_map(|rule| rule.roles.iter().map(String::as_str))
+        .collect()
+}
+
+// Called by the web UI with the code from its `login_code` parameter
+#[post("/token")]
+async fn exchange_login_code(
+    req: HttpRequest,
+    repo: Data<Database>,
+    form: Json<LoginCodeForm>,
+) -> Result<Json<TokenResponse>> {
+    let browser = login_browser(&req)?;
+    let login = Database::redeem_login_code(&repo, &form.code, &browser).await?;
+    // Codes are only handed out once a user has been found
+    let user_id = login.user.unwrap();
+    let user = Database::user_detail(&repo, &format!("{}", user_id.id)).await?;
+    if user.disabled {
+        return Err(GatewayError::Unauthorized(String::from(
+            "The account has been disabled",
+        )));
+    }
+    Ok(Json(
+        sign_in(&req, &repo, user, Some(login.provider.as_str())).await?,
+    ))
+}
+
+// The secret in the browser's sign in cookie
+fn login_browser(req: &HttpRequest) -> Result<String> {
+    req.cookie(LOGIN_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .ok_or(GatewayError::Unauthorized(String::from(
+            "The sign in was not started in this browser",
+        )))
+}
+
+fn login_cookie(browser: String) -> Cookie<'static> {
+    Cookie::build(LOGIN_COOKIE, browser)
+        .path("/auth/v1/federated")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(
+            LOGIN_LIFETIME_SECS + LOGIN_CODE_LIFETIME_SECS as i64,
+        ))
+        .finish()
+}
+
+fn identity_provider<'a>(
+    config: &'a GatewayConfig,
+    name: &str,
+) -> Result<&'a IdentityProviderConfig> {
+    config.identity_provider(name).ok_or(GatewayError::NotFound(
+        String::from("Identity Provider"),
+        format!("No identity provider named [{}] is configured.", name),
+    ))
+}
+
+// Registered with the provider as the gateway's redirect URI
+fn callback_url(config: &GatewayConfig, provider: &IdentityProviderConfig) -> String {
+    format!(
+        "{}/auth/v1/federated/{}/callback",
+        config.server.public_url.trim_end_matches('/'),
+        provider.name
+    )
+}
+
+fn return_url(config: &GatewayConfig, login: &DbFederatedLogin) -> Result<Url> {
+    let url = format!(
+        "{}{}",
+        config.server.public_url.trim_end_matches('/'),
+        login.return_to
+    );
+    Url::parse(&url).map_err(|e| GatewayError::BadRequest(format!("Invalid return_to: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::database::testing::{count, database};
+    use crate::database::{FEDERATED_IDENTITY_TABLE, USER_TABLE};
+
+    fn provider(settings: Value) -> IdentityProviderConfig {
+        let mut config = json!({
+            "name": "corp",
+            "issuer": "https://login.corp.example",
+            "client_id": "gateway",
+            "role_rules": [
+                { "group": "readers", "roles": ["Shop::Reader"] },
+                { "group": "editors", "roles": ["Shop::Reader", "Shop::Writer"] },
+            ],
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(settings.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn claims(claims: Value) -> Map<String, Value> {
+        claims.as_object().unwrap().clone()
+    }
+
+    async fn add_role(repo: &Data<Database>, name: &str) -> DbApiRole {
+        let role = WebApiRole {
+            id: None,
+            revision: None,
+            namespace: String::from("Shop"),
+            name: String::from(name),
+        };
+        Database::find_or_add_role(repo, &role).await.unwrap()
+    }
+
+    async fn add_user(repo: &Data<Database>, username: &str, roles: Vec<DbApiRole>) -> Thing {
+        let (user, _) = Database::register_user(
+            repo,
+            DbGatewayUserRequest {
+                username: String::from(username),
+            },
+            roles,
+            InitialCredential::External,
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        user.id
+    }
+
+    #[test]
+    fn rules_grant_the_roles_of_the_user_groups() {
+        let provider = provider(json!({}));
+
+        assert_eq!(
+            rule_roles(
+                &provider,
+                &claims(json!({ "groups": ["readers", "others"] }))
+            ),
+            vec!["Shop::Reader"]
+        );
+        assert_eq!(
+            rule_roles(&provider, &claims(json!({ "groups": "editors" }))),
+            vec!["Shop::Reader", "Shop::Writer"]
+        );
+        assert!(rule_roles(&provider, &claims(json!({ "groups": ["others"] }))).is_empty());
+        assert!(rule_roles(&provider, &claims(json!({ "roles": ["readers"] }))).is_empty());
+    }
+
+    #[test]
+    fn rules_read_the_configured_groups_claim() {
+        let provider = provider(json!({ "groups_claim": "roles" }));
+
+        assert_eq!(
+            rule_roles(&provider, &claims(json!({ "roles": ["readers"] }))),
+            vec!["Shop::Reader"]
+        );
+        assert!(rule_roles(&provider, &claims(json!({ "groups": ["readers"] }))).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn first_sign_in_provisions_an_account() {
+        let repo = database().await;
+        let req = TestRequest::default().to_http_request();
+        let provider = provider(json!({}));
+        let claims = claims(json!({ "preferred_username": "alice" }));
+
+        let identity = find_or_provision_user(&req, &repo, &provider, &claims, "alice-at-corp")
+            .await
+            .unwrap();
+        let again = find_or_provision_user(&req, &repo, &provider, &claims, "alice-at-corp")
+            .await
+            .unwrap();
+
+        assert_eq!(again.id, identity.id);
+        assert_eq!(count(&repo, USER_TABLE).await, 1);
+        let user = Database::user_detail(&repo, &format!("{}", identity.user.id))
+            .await
+            .unwrap();
+        assert_eq!(user.username, "alice");
+    }
+
+    #[actix_web::test]
+    async fn first_sign_in_links_an_existing_account_only_when_trusted() {
+        let repo = database().await;
+        let req = TestRequest::default().to_http_request();
+        let user = add_user(&repo, "alice", Vec::new()).await;
+        let claims = claims(json!({ "preferred_username": "alice" }));
+
+        let untrusted = provider(json!({}));
+        let refused =
+            find_or_provision_user(&req, &repo, &untrusted, &claims, "alice-at-corp").await;
+        assert!(matches!(refused, Err(GatewayError::Unauthorized(_))));
+        assert_eq!(count(&repo, FEDERATED_IDENTITY_TABLE).await, 0);
+
+        let trusted = provider(json!({ "link_existing_users": true }));
+        let identity = find_or_provision_user(&req, &repo, &trusted, &claims, "alice-at-corp")
+            .await
+            .unwrap();
+        assert_eq!(identity.user, user);
+        assert_eq!(count(&repo, USER_TABLE).await, 1);
+    }
+
+    #[actix_web::test]
+    async fn first_sign_in_needs_an_account_unless_provisioning() {
+        let repo = database().await;
+        let req = TestRequest::default().to_http_request();
+        let provider = provider(json!({ "provision_users": false }));
+
+        for claims in [
+            claims(json!({ "preferred_username": "alice" })),
+            claims(json!({ "email": "alice@corp.example" })),
+        ] {
+            let refused =
+                find_or_provision_user(&req, &repo, &provider, &claims, "alice-at-corp").await;
+            assert!(matches!(refused, Err(GatewayError::Unauthorized(_))));
+        }
+        assert_eq!(count(&repo, USER_TABLE).await, 0);
+    }
+
+    #[actix_web::test]
+    async fn sign_in_replaces_only_the_roles_the_provider_granted() {
+        let repo = database().await;
+        let req = TestRequest::default().to_http_request();
+        let provider = provider(json!({
+            "role_rules": [{ "group": "readers", "roles": ["Shop::Reader", "Shop::Missing"] }],
+        }));
+        let reader = add_role(&repo, "Reader").await;
+        let writer = add_role(&repo, "Writer").await;
+        let admin = add_role(&repo, "Admin").await;
+        // Admin was granted in the gateway, Writer by an earlier sign in
+        let user_id = add_user(&repo, "alice", vec![admin.clone(), writer.clone()]).await;
+        let mut identity = Database::link_federated_identity(
+            &repo,
+            "corp",
+            "alice-at-corp",
+            &user_id,
+            false,
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        identity.roles = vec![writer.id.clone().unwrap()];
+        let user = Database::user_detail(&repo, &format!("{}", user_id.id))
+            .await
+            .unwrap();
+
+        let granted = sync_roles(
+            &req,
+            &repo,
+            &provider,
+            &claims(json!({ "groups": ["readers"] })),
+            &user,
+            &identity,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(granted, vec![reader.id.clone().unwrap()]);
+        let user = Database::user_detail(&repo, &format!("{}", user_id.id))
+            .await
+            .unwrap();
+        let mut roles: Vec<Option<Thing>> = user.roles.into_iter().map(|role| role.id).collect();
+        roles.sort();
+        let mut expected = vec![admin.id, reader.id];
+        expected.sort();
+        assert_eq!(roles, expected);
+    }
+}
@@ -0,0 +1,41 @@
+use actix_web::{
+    get, post,
+    web::{scope, to, Data, Json, ServiceConfig},
+    HttpRequest,
+};
+
+use super::models::WebRoutingTable;
+use super::routing::RoutingTable;
+use crate::auth::web::validate_jwt;
+use crate::database::Database;
+use crate::errors::{unknown_resource_error, Result};
+
+// Intermediate function to configure services
+pub fn service_setup(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/cfg/v1/routing-table")
+            .service(get_routing_table)
+            .service(refresh_routing_table)
+            .default_service(to(unknown_resource_error)),
+    );
+}
+
+#[get("/")]
+async fn get_routing_table(
+    req: HttpRequest,
+    routes: Data<RoutingTable>,
+) -> Result<Json<WebRoutingTable>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    Ok(Json(WebRoutingTable::from(routes.get_ref())))
+}
+
+#[post("/refresh")]
+async fn refresh_routing_table(
+    req: HttpRequest,
+    routes: Data<RoutingTable>,
+    repo: Data<Database>,
+) -> Result<Json<WebRoutingTable>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    routes.refresh(&repo).await?;
+    Ok(Json(WebRoutingTable::from(routes.get_ref())))
+}
@@ -0,0 +1,347 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use rand::Rng;
+
+use super::routing::RoutingTable;
+use crate::api_services::models::{DbFullApiService, LoadBalancingStrategy, UpstreamTarget};
+use crate::errors::{GatewayError, Result};
+
+// Consecutive failures (connection errors or 5xx) before a target is ejected
+const EJECTION_FAILURE_THRESHOLD: u32 = 3;
+// How long an ejected target stays out of rotation
+const EJECTION_DURATION: Duration = Duration::from_secs(30);
+
+struct TargetState {
+    target: UpstreamTarget,
+    outstanding: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl TargetState {
+    fn new(target: UpstreamTarget) -> Self {
+        TargetState {
+            target,
+            outstanding: AtomicUsize::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+        }
+    }
+
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| until > now)
+    }
+}
+
+struct ServicePool {
+    targets: Vec<UpstreamTarget>,
+    strategy: LoadBalancingStrategy,
+    states: Vec<Arc<TargetState>>,
+    // Smooth weighted round robin credit of each target, by position in `states`
+    current_weights: Mutex<Vec<i64>>,
+}
+
+impl ServicePool {
+    fn new(targets: Vec<UpstreamTarget>, strategy: LoadBalancingStrategy) -> Self {
+        ServicePool {
+            states: targets
+                .iter()
+                .cloned()
+                .map(|target| Arc::new(TargetState::new(target)))
+                .collect(),
+            current_weights: Mutex::new(vec![0; targets.len()]),
+            targets,
+            strategy,
+        }
+    }
+
+    fn matches(&self, targets: &Vec<UpstreamTarget>, strategy: LoadBalancingStrategy) -> bool {
+        self.targets.eq(targets) && self.strategy == strategy
+    }
+
+    fn select(&self, excluded: &HashSet<String>) -> Option<Arc<TargetState>> {
+        let now = Instant::now();
+        let available: Vec<usize> = (0..self.states.len())
+            .filter(|&i| !excluded.contains(&self.states[i].target.url))
+            .collect();
+        let mut candidates: Vec<usize> = available
+            .iter()
+            .copied()
+            .filter(|&i| !self.states[i].is_ejected(now))
+            .collect();
+        if candidates.is_empty() {
+            // Every target is ejected; try them all rather than failing outright
+            candidates = available;
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+        let weight = |i: usize| self.states[i].target.weight.max(1);
+
+        let selected = match self.strategy {
+            LoadBalancingStrategy::RoundRobin => {
+                // Each candidate earns its weight in credit, and the one with the
+                // most is picked and pays back the total. Targets take turns in
+                // proportion to their weights, without bursts to the heaviest
+                let mut current = self.current_weights.lock().unwrap();
+                let total: i64 = candidates.iter().map(|&i| weight(i) as i64).sum();
+                for &i in &candidates {
+                    current[i] += weight(i) as i64;
+                }
+                let selected = candidates.iter().copied().max_by(|&a, &b| {
+                    // The earlier target wins ties
+                    current[a].cmp(&current[b]).then(b.cmp(&a))
+                });
+                if let Some(i) = selected {
+                    current[i] -= total;
+                }
+                selected
+            }
+            LoadBalancingStrategy::WeightedRandom => {
+                let total: u32 = candidates.iter().map(|&i| weight(i)).sum();
+                let mut pick = rand::thread_rng().gen_range(0..total);
+                candidates.iter().copied().find(|&i| {
+                    if pick < weight(i) {
+                        true
+                    } else {
+                        pick -= weight(i);
+                        false
+                    }
+                })
+            }
+            LoadBalancingStrategy::LeastOutstanding => {
+                candidates.iter().copied().min_by(|&a, &b| {
+                    // Compare outstanding / weight without floating point
+                    let a_load = self.states[a].outstanding.load(Ordering::Relaxed) as u64
+                        * weight(b) as u64;
+                    let b_load = self.states[b].outstanding.load(Ordering::Relaxed) as u64
+                        * weight(a) as u64;
+                    a_load.cmp(&b_load)
+                })
+            }
+        };
+        selected.map(|i| self.states[i].clone())
+    }
+}
+
+/// Handle on the target chosen for a single request.
+///
+/// Counts as an outstanding request until dropped, and carries the passive
+/// health outcome of the request back to the pool.
+pub struct SelectedTarget {
+    state: Arc<TargetState>,
+}
+
+impl SelectedTarget {
+    fn new(state: Arc<TargetState>) -> Self {
+        state.outstanding.fetch_add(1, Ordering::Relaxed);
+        SelectedTarget { state }
+    }
+
+    pub fn url(&self) -> &String {
+        &self.state.target.url
+    }
+
+    pub fn record_success(&self) {
+        self.state.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        let failures = self
+            .state
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if failures >= EJECTION_FAILURE_THRESHOLD {
+            log::warn!(
+                "Ejecting upstream target {} for {}s after {} consecutive failures",
+                self.state.target.url,
+                EJECTION_DURATION.as_secs(),
+                failures
+            );
+            *self.state.ejected_until.lock().unwrap() = Some(Instant::now() + EJECTION_DURATION);
+            self.state.consecutive_failures.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for SelectedTarget {
+    fn drop(&mut self) {
+        self.state.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Load balancing state for every service's upstream pool, keyed on service id.
+pub struct UpstreamPools {
+    pools: RwLock<HashMap<String, Arc<ServicePool>>>,
+}
+
+impl UpstreamPools {
+    pub fn new() -> Self {
+        UpstreamPools {
+            pools: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn pool_for(&self, service: &DbFullApiService) -> Arc<ServicePool> {
+        let service_id = service.id.to_string();
+        let targets = service.upstream_targets();
+        if let Some(pool) = self.pools.read().unwrap().get(&service_id) {
+            if pool.matches(&targets, service.load_balancing) {
+                return pool.clone();
+            }
+        }
+        // First request, or the pool was edited: start over with fresh state
+        let pool = Arc::new(ServicePool::new(targets, service.load_balancing));
+        self.pools.write().unwrap().insert(service_id, pool.clone());
+        pool
+    }
+
+    /// Drops the state of services that are no longer routed, or whose targets
+    /// changed.
+    pub fn retain_routed(&self, services: &[DbFullApiService]) {
+        let routed: HashMap<String, &DbFullApiService> = services
+            .iter()
+            .map(|service| (service.id.to_string(), service))
+            .collect();
+        self.pools.write().unwrap().retain(|service_id, pool| {
+            routed.get(service_id).is_some_and(|service| {
+                pool.matches(&service.upstream_targets(), service.load_balancing)
+            })
+        });
+    }
+
+    /// Picks a target for the next request, skipping any `excluded` target URLs.
+    pub fn select(
+        &self,
+        service: &DbFullApiService,
+        excluded: &HashSet<String>,
+    ) -> Result<SelectedTarget> {
+        self.pool_for(service)
+            .select(excluded)
+            .map(SelectedTarget::new)
+            .ok_or(GatewayError::ServiceUnavailable(format!(
+                "No healthy upstream targets available for {}",
+                service.api_name
+            )))
+    }
+}
+
+/// Evicts the load balancing state of removed services and targets whenever
+/// the routing table is reloaded.
+pub fn spawn_pool_eviction(routes: Data<RoutingTable>, pools: Data<UpstreamPools>) {
+    tokio::spawn(async move {
+        let mut refreshed = routes.refreshed();
+        while refreshed.changed().await.is_ok() {
+            pools.retain_routed(&routes.routes());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(weights: &[u32], strategy: LoadBalancingStrategy) -> ServicePool {
+        let targets = weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| UpstreamTarget {
+                url: format!("http://target-{}", i),
+                weight,
+            })
+            .collect();
+        ServicePool::new(targets, strategy)
+    }
+
+    fn pick(pool: &ServicePool, excluded: &HashSet<String>) -> String {
+        pool.select(excluded).unwrap().target.url.clone()
+    }
+
+    #[test]
+    fn round_robin_interleaves_targets_by_weight() {
+        let pool = pool(&[5, 1, 1], LoadBalancingStrategy::RoundRobin);
+        let picks: Vec<String> = (0..14).map(|_| pick(&pool, &HashSet::new())).collect();
+
+        let expected = ["0", "0", "1", "0", "2", "0", "0"]
+            .iter()
+            .map(|i| format!("http://target-{}", i))
+            .collect::<Vec<_>>();
+        assert_eq!(picks[..7], expected[..]);
+        assert_eq!(picks[7..], expected[..]);
+    }
+
+    #[test]
+    fn round_robin_skips_excluded_targets() {
+        let pool = pool(&[1, 1, 1], LoadBalancingStrategy::RoundRobin);
+        let excluded = HashSet::from([String::from("http://target-1")]);
+        let picks: Vec<String> = (0..4).map(|_| pick(&pool, &excluded)).collect();
+
+        assert_eq!(
+            picks,
+            [
+                "http://target-0",
+                "http://target-2",
+                "http://target-0",
+                "http://target-2"
+            ]
+        );
+    }
+
+    #[test]
+    fn target_is_ejected_after_consecutive_failures() {
+        let pool = pool(&[1, 1], LoadBalancingStrategy::LeastOutstanding);
+        let first = SelectedTarget::new(pool.states[0].clone());
+        for _ in 0..EJECTION_FAILURE_THRESHOLD - 1 {
+            first.record_failure();
+        }
+        assert!(!pool.states[0].is_ejected(Instant::now()));
+
+        first.record_failure();
+        drop(first);
+
+        assert!(pool.states[0].is_ejected(Instant::now()));
+        for _ in 0..3 {
+            assert_eq!(pick(&pool, &HashSet::new()), "http://target-1");
+        }
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let pool = pool(&[1], LoadBalancingStrategy::RoundRobin);
+        let target = SelectedTarget::new(pool.states[0].clone());
+        for _ in 0..EJECTION_FAILURE_THRESHOLD - 1 {
+            target.record_failure();
+        }
+        target.record_success();
+        target.record_failure();
+
+        assert!(!pool.states[0].is_ejected(Instant::now()));
+    }
+
+    #[test]
+    fn ejected_targets_are_tried_when_no_other_remains() {
+        let pool = pool(&[1, 1], LoadBalancingStrategy::RoundRobin);
+        *pool.states[0].ejected_until.lock().unwrap() = Some(Instant::now() + EJECTION_DURATION);
+        let excluded = HashSet::from([String::from("http://target-1")]);
+
+        assert_eq!(pick(&pool, &excluded), "http://target-0");
+    }
+
+    #[test]
+    fn least_outstanding_prefers_the_idlest_target_by_weight() {
+        let pool = pool(&[1, 2], LoadBalancingStrategy::LeastOutstanding);
+        let _busy = SelectedTarget::new(pool.states[0].clone());
+        let _other = SelectedTarget::new(pool.states[1].clone());
+
+        // One outstanding of weight 1 is busier than one of weight 2
+        assert_eq!(pick(&pool, &HashSet::new()), "http://target-1");
+    }
+}
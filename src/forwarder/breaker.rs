@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::api_services::models::{CircuitBreakerConfig, DbFullApiService};
+use crate::errors::{GatewayError, Result};
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    // A trial request is in flight after the cooldown elapsed
+    half_open: bool,
+}
+
+/// Per-service circuit breakers, keyed on service id.
+///
+/// A circuit opens after `failure_threshold` consecutive failures, rejects
+/// requests until `cooldown_secs` pass, then lets a single trial request
+/// through: success closes the circuit, failure opens it again.
+pub struct CircuitBreakers {
+    states: RwLock<HashMap<String, Arc<Mutex<BreakerState>>>>,
+}
+
+impl CircuitBreakers {
+    pub fn new() -> Self {
+        CircuitBreakers {
+            states: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn state_for(&self, service: &DbFullApiService) -> Arc<Mutex<BreakerState>> {
+        let service_id = service.id.to_string();
+        if let Some(state) = self.states.read().unwrap().get(&service_id) {
+            return state.clone();
+        }
+        self.states
+            .write()
+            .unwrap()
+            .entry(service_id)
+            .or_default()
+            .clone()
+    }
+
+    /// Checks whether a request to the service may proceed, returning the
+    /// permit its outcome is recorded with.
+    pub fn acquire(&self, service: &DbFullApiService) -> Result<BreakerPermit> {
+        let config: &CircuitBreakerConfig = match &service.circuit_breaker {
+            Some(config) => config,
+            None => return Ok(BreakerPermit::unguarded()),
+        };
+        let shared = self.state_for(service);
+        let mut state = shared.lock().unwrap();
+        let trial = match state.opened_at {
+            None => false,
+            Some(opened_at)
+                if !state.half_open
+                    && opened_at.elapsed() >= Duration::from_secs(config.cooldown_secs) =>
+            {
+                log::info!(
+                    "Circuit half-open for {}[{}]",
+                    service.api_name,
+                    service.version
+                );
+                state.half_open = true;
+                true
+            }
+            Some(_) => {
+                return Err(GatewayError::ServiceUnavailable(format!(
+                    "Circuit breaker is open for {}",
+                    service.api_name
+                )))
+            }
+        };
+        drop(state);
+        Ok(BreakerPermit {
+            state: Some(shared),
+            failure_threshold: config.failure_threshold,
+            service: format!("{}[{}]", service.api_name, service.version),
+            trial,
+        })
+    }
+}
+
+/// A request let through a service's circuit breaker.
+///
+/// A permit dropped without recording an outcome, such as when the request is
+/// refused before reaching the upstream or the client goes away, hands a
+/// half-open circuit's trial on to the next request.
+pub struct BreakerPermit {
+    // None once the outcome is recorded, or when the service has no breaker
+    state: Option<Arc<Mutex<BreakerState>>>,
+    failure_threshold: u32,
+    service: String,
+    // The trial request of a half-open circuit
+    trial: bool,
+}
+
+impl BreakerPermit {
+    fn unguarded() -> Self {
+        BreakerPermit {
+            state: None,
+            failure_threshold: 0,
+            service: String::new(),
+            trial: false,
+        }
+    }
+
+    pub fn record_success(mut self) {
+        let Some(state) = self.state.take() else {
+            return;
+        };
+        let mut state = state.lock().unwrap();
+        // A request let through before the circuit opened says nothing of
+        // whether the upstream recovered; only the trial closes the circuit
+        if state.opened_at.is_some() && !self.trial {
+            return;
+        }
+        if state.opened_at.is_some() {
+            log::info!("Circuit closed for {}", self.service);
+        }
+        *state = BreakerState::default();
+    }
+
+    pub fn record_failure(mut self) {
+        let Some(state) = self.state.take() else {
+            return;
+        };
+        let mut state = state.lock().unwrap();
+        // Nor does a late failure count against a circuit already open
+        if state.opened_at.is_some() && !self.trial {
+            return;
+        }
+        state.consecutive_failures += 1;
+        if self.trial || state.consecutive_failures >= self.failure_threshold {
+            log::warn!(
+                "Circuit opened for {} after {} consecutive failures",
+                self.service,
+                state.consecutive_failures
+            );
+            state.opened_at = Some(Instant::now());
+            state.half_open = false;
+        }
+    }
+}
+
+impl Drop for BreakerPermit {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.as_ref().filter(|_| self.trial) {
+            state.lock().unwrap().half_open = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn service(cooldown_secs: u64) -> DbFullApiService {
+        serde_json::from_value(json!({
+            "id": { "tb": "api_service", "id": { "String": "shop" } },
+            "api_name": "shop",
+            "forward_url": "http://shop",
+            "active": true,
+            "version": "v1",
+            "environment": "test",
+            "max_body_size": null,
+            "tls": null,
+            "health_check": null,
+            "timeouts": null,
+            "retry": null,
+            "circuit_breaker": { "failure_threshold": 2, "cooldown_secs": cooldown_secs },
+            "websocket": null,
+            "roles": [],
+        }))
+        .unwrap()
+    }
+
+    fn open(breakers: &CircuitBreakers, service: &DbFullApiService) {
+        for _ in 0..2 {
+            breakers.acquire(service).unwrap().record_failure();
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breakers = CircuitBreakers::new();
+        let service = service(60);
+        breakers.acquire(&service).unwrap().record_failure();
+        breakers.acquire(&service).unwrap().record_success();
+        breakers.acquire(&service).unwrap().record_failure();
+        assert!(breakers.acquire(&service).is_ok());
+
+        breakers.acquire(&service).unwrap().record_failure();
+
+        assert!(breakers.acquire(&service).is_err());
+    }
+
+    #[test]
+    fn lets_one_trial_through_after_the_cooldown() {
+        let breakers = CircuitBreakers::new();
+        let service = service(0);
+        open(&breakers, &service);
+
+        let trial = breakers.acquire(&service).unwrap();
+        assert!(trial.trial);
+        assert!(breakers.acquire(&service).is_err());
+
+        trial.record_success();
+        let permit = breakers.acquire(&service).unwrap();
+        assert!(!permit.trial);
+    }
+
+    #[test]
+    fn failed_trial_opens_the_circuit_again() {
+        let breakers = CircuitBreakers::new();
+        let service = service(0);
+        open(&breakers, &service);
+        breakers.acquire(&service).unwrap().record_failure();
+
+        let shared = breakers.state_for(&service);
+        let state = shared.lock().unwrap();
+        assert!(state.opened_at.is_some());
+        assert!(!state.half_open);
+    }
+
+    #[test]
+    fn dropped_trial_hands_the_trial_on() {
+        let breakers = CircuitBreakers::new();
+        let service = service(0);
+        open(&breakers, &service);
+
+        drop(breakers.acquire(&service).unwrap());
+
+        assert!(breakers.acquire(&service).unwrap().trial);
+    }
+
+    #[test]
+    fn late_outcomes_leave_a_half_open_circuit_alone() {
+        let breakers = CircuitBreakers::new();
+        let service = service(0);
+        let late_success = breakers.acquire(&service).unwrap();
+        let late_failure = breakers.acquire(&service).unwrap();
+        open(&breakers, &service);
+        let trial = breakers.acquire(&service).unwrap();
+
+        late_success.record_success();
+        late_failure.record_failure();
+
+        assert!(breakers.acquire(&service).is_err());
+        trial.record_success();
+        assert!(breakers.acquire(&service).is_ok());
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use hyper::client::connect::dns::Name;
+use openssl::x509::X509;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{Certificate, Client, Url};
+
+use crate::api_services::models::ServiceTlsConfig;
+use crate::errors::{GatewayError, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientKey {
+    tls: Option<ServiceTlsConfig>,
+    connect_timeout: Option<Duration>,
+    // Upstream authority pinned by an SNI override, which the client resolves the
+    // override name to
+    pinned_upstream: Option<(String, u16)>,
+}
+
+/// Pooled upstream HTTP clients shared by every forwarder worker.
+///
+/// Services without TLS or connect timeout settings share a single client; each
+/// distinct combination gets its own client (and connection pool), built on first use.
+pub struct UpstreamClients {
+    default_client: Client,
+    configured_clients: RwLock<HashMap<ClientKey, Client>>,
+}
+
+impl UpstreamClients {
+    pub fn new() -> Result<Self> {
+        Ok(UpstreamClients {
+            default_client: Client::builder()
+                .build()
+                .map_err(|e| GatewayError::SystemError(e.to_string()))?,
+            configured_clients: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the client to use for a service and the URL the request should target.
+    ///
+    /// With an SNI override, the URL host is replaced by the override name and the
+    /// client resolves that name to the original upstream address.
+    pub async fn prepare(
+        &self,
+        tls: Option<&ServiceTlsConfig>,
+        connect_timeout: Option<Duration>,
+        forward_url: &str,
+    ) -> Result<(Client, String)> {
+        if tls.is_none() && connect_timeout.is_none() {
+            return Ok((self.default_client.clone(), forward_url.to_string()));
+        }
+
+        let mut url = Url::parse(forward_url)
+            .map_err(|e| GatewayError::BadRequest(format!("Invalid forward URL: {}", e)))?;
+        let pinned_upstream = match tls.and_then(|tls| tls.sni_override.as_ref()) {
+            Some(sni) => {
+                let host = url.host_str().unwrap_or_default().to_string();
+                let port = url.port_or_known_default().unwrap_or(443);
+                url.set_host(Some(sni)).map_err(|e| {
+                    GatewayError::BadRequest(format!("Invalid SNI override: {}", e))
+                })?;
+                Some((host, port))
+            }
+            None => None,
+        };
+
+        let key = ClientKey {
+            tls: tls.cloned(),
+            connect_timeout,
+            pinned_upstream,
+        };
+        if let Some(client) = self.configured_clients.read().unwrap().get(&key) {
+            return Ok((client.clone(), url.to_string()));
+        }
+
+        let client = build_client(&key)?;
+        self.configured_clients
+            .write()
+            .unwrap()
+            .insert(key, client.clone());
+        Ok((client, url.to_string()))
+    }
+}
+
+fn build_client(key: &ClientKey) -> Result<Client> {
+    let mut builder = Client::builder();
+
+    if let Some(connect_timeout) = key.connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+
+    let tls = match &key.tls {
+        Some(tls) => tls,
+        None => {
+            return builder
+                .build()
+                .map_err(|e| GatewayError::SystemError(e.to_string()))
+        }
+    };
+    builder = builder.danger_accept_invalid_certs(!tls.verify);
+
+    if let Some(ca_bundle) = &tls.ca_bundle {
+        for der in ca_certificates(ca_bundle)? {
+            let certificate = Certificate::from_der(&der)
+                .map_err(|e| GatewayError::BadRequest(format!("Invalid CA bundle: {}", e)))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    if let (Some(sni), Some((host, port))) = (&tls.sni_override, &key.pinned_upstream) {
+        builder = builder.dns_resolver(Arc::new(PinnedResolver {
+            sni: sni.clone(),
+            host: host.clone(),
+            port: *port,
+        }));
+    }
+
+    builder
+        .build()
+        .map_err(|e| GatewayError::SystemError(e.to_string()))
+}
+
+/// The DER encoded certificates of a PEM CA bundle, which both the HTTP and
+/// WebSocket connections to upstreams trust.
+pub fn ca_certificates(ca_bundle: &str) -> Result<Vec<Vec<u8>>> {
+    let certificates = X509::stack_from_pem(ca_bundle.as_bytes())
+        .map_err(|e| GatewayError::BadRequest(format!("Invalid CA bundle: {}", e)))?;
+    certificates
+        .iter()
+        .map(|certificate| {
+            certificate
+                .to_der()
+                .map_err(|e| GatewayError::BadRequest(format!("Invalid CA bundle: {}", e)))
+        })
+        .collect()
+}
+
+// Resolves the SNI override name to the upstream's own address, looked up
+// again for every new connection so upstream address changes are followed
+struct PinnedResolver {
+    sni: String,
+    host: String,
+    port: u16,
+}
+
+impl Resolve for PinnedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = if name.as_str() == self.sni {
+            self.host.clone()
+        } else {
+            name.as_str().to_string()
+        };
+        let port = self.port;
+        Box::pin(async move {
+            let addresses: Vec<_> = tokio::net::lookup_host((host.as_str(), port))
+                .await?
+                .collect();
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Maps a transport error from the upstream request onto a gateway error.
+pub fn upstream_error(error: &reqwest::Error) -> GatewayError {
+    if error.is_timeout() {
+        return GatewayError::UpstreamTimeout(format!("Upstream request timed out: {}", error));
+    }
+    // The TLS connector's error, including certificate verification failures,
+    // is kept as the cause of the connect error
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(cause) = source {
+        if let Some(tls_error) = cause.downcast_ref::<native_tls::Error>() {
+            return GatewayError::UpstreamError(format!(
+                "Upstream TLS handshake failed: {}",
+                tls_error
+            ));
+        }
+        source = cause.source();
+    }
+    if error.is_connect() {
+        return GatewayError::UpstreamConnectionError(format!(
+            "Unable to connect to upstream: {}",
+            error
+        ));
+    }
+    GatewayError::UpstreamError(format!("Error forwarding request: {}", error))
+}
@@ -0,0 +1,267 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use surrealdb::sql::Datetime;
+
+use super::client::UpstreamClients;
+use super::routing::RoutingTable;
+use crate::api_services::models::{DbFullApiService, DbServiceHealth, HealthCheckConfig};
+use crate::api_services::repo::ServiceHealthRepository;
+use crate::database::Database;
+
+// Resolution of the health check scheduler; per-service intervals are rounded up to this
+const HEALTH_CHECK_TICK: Duration = Duration::from_secs(1);
+
+/// Latest active health check outcome for each service target.
+pub struct HealthRegistry {
+    // service id -> target URLs that failed their most recent check
+    unhealthy: RwLock<HashMap<String, HashSet<String>>>,
+}
+
+impl HealthRegistry {
+    pub fn new() -> Self {
+        HealthRegistry {
+            unhealthy: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn unhealthy_targets(&self, service_id: &String) -> HashSet<String> {
+        self.unhealthy
+            .read()
+            .unwrap()
+            .get(service_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Forgets the outcomes of targets no longer routed, or no longer health
+    /// checked.
+    pub fn retain_routed(&self, services: &[DbFullApiService]) {
+        let checked: HashMap<String, HashSet<String>> = services
+            .iter()
+            .filter(|service| service.health_check.is_some())
+            .map(|service| {
+                let targets = service
+                    .upstream_targets()
+                    .into_iter()
+                    .map(|target| target.url);
+                (service.id.to_string(), targets.collect())
+            })
+            .collect();
+        self.unhealthy
+            .write()
+            .unwrap()
+            .retain(|service_id, targets| match checked.get(service_id) {
+                Some(routed) => {
+                    targets.retain(|target| routed.contains(target));
+                    !targets.is_empty()
+                }
+                None => false,
+            });
+    }
+
+    fn record(&self, service_id: &String, target: &String, healthy: bool) {
+        let mut unhealthy = self.unhealthy.write().unwrap();
+        let targets = unhealthy.entry(service_id.clone()).or_default();
+        if healthy {
+            targets.remove(target);
+        } else {
+            targets.insert(target.clone());
+        }
+    }
+}
+
+/// Periodically probes the health endpoint of every target of every routed
+/// service that has a health check configured.
+pub fn spawn_health_checks(
+    routes: Data<RoutingTable>,
+    clients: Data<UpstreamClients>,
+    registry: Data<HealthRegistry>,
+    repo: Data<Database>,
+) {
+    tokio::spawn(async move {
+        let mut last_checked: HashMap<(String, String), Instant> = HashMap::new();
+        let mut ticker = tokio::time::interval(HEALTH_CHECK_TICK);
+        loop {
+            ticker.tick().await;
+            let now = Instant::now();
+            let mut health_checked: HashSet<(String, String)> = HashSet::new();
+            for service in routes.routes() {
+                let config = match &service.health_check {
+                    Some(config) => config.clone(),
+                    None => continue,
+                };
+                let interval = Duration::from_secs(config.interval_secs);
+                for target in service.upstream_targets() {
+                    let key = (service.id.to_string(), target.url.clone());
+                    health_checked.insert(key.clone());
+                    if last_checked
+                        .get(&key)
+                        .is_some_and(|checked| now.duration_since(*checked) < interval)
+                    {
+                        continue;
+                    }
+                    last_checked.insert(key, now);
+                    tokio::spawn(check_target(
+                        service.clone(),
+                        config.clone(),
+                        target.url,
+                        clients.clone(),
+                        registry.clone(),
+                        repo.clone(),
+                    ));
+                }
+            }
+            // Forget the targets of services deleted or edited since
+            last_checked.retain(|key, _| health_checked.contains(key));
+        }
+    });
+}
+
+/// Evicts the health check outcomes of removed services and targets whenever
+/// the routing table is reloaded.
+pub fn spawn_health_eviction(routes: Data<RoutingTable>, registry: Data<HealthRegistry>) {
+    tokio::spawn(async move {
+        let mut refreshed = routes.refreshed();
+        while refreshed.changed().await.is_ok() {
+            registry.retain_routed(&routes.routes());
+        }
+    });
+}
+
+async fn check_target(
+    service: DbFullApiService,
+    config: HealthCheckConfig,
+    target: String,
+    clients: Data<UpstreamClients>,
+    registry: Data<HealthRegistry>,
+    repo: Data<Database>,
+) {
+    let check_url = format!(
+        "{}/{}",
+        target.trim_end_matches('/'),
+        config.path.trim_start_matches('/')
+    );
+    let started = Instant::now();
+    let connect_timeout = service
+        .timeouts
+        .as_ref()
+        .and_then(|timeouts| timeouts.connect_ms)
+        .map(Duration::from_millis);
+    let outcome = match clients
+        .prepare(service.tls.as_ref(), connect_timeout, &check_url)
+        .await
+    {
+        Ok((client, url)) => client
+            .get(url)
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .send()
+            .await
+            .map(|response| response.status().as_u16())
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+
+    let (healthy, status_code, error) = match outcome {
+        Ok(status) if status == config.expected_status => (true, Some(status), None),
+        Ok(status) => (
+            false,
+            Some(status),
+            Some(format!(
+                "Expected status {} but received {}",
+                config.expected_status, status
+            )),
+        ),
+        Err(e) => (false, None, Some(e)),
+    };
+    if !healthy {
+        log::warn!(
+            "Health check failed for {}[{}] target {}: {}",
+            service.api_name,
+            service.version,
+            target,
+            error.clone().unwrap_or_default()
+        );
+    }
+
+    let service_id = service.id.to_string();
+    registry.record(&service_id, &target, healthy);
+    let result = DbServiceHealth {
+        id: None,
+        service: service.id.clone(),
+        target,
+        healthy,
+        status_code,
+        error,
+        latency_ms: started.elapsed().as_millis() as u64,
+        checked_at: Datetime::default(),
+    };
+    if let Err(e) = Database::record_health(&repo, &result).await {
+        log::error!("Unable to store health check result: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn service(id: &str, targets: &[&str], health_checked: bool) -> DbFullApiService {
+        serde_json::from_value(json!({
+            "id": { "tb": "service", "id": { "String": id } },
+            "api_name": id,
+            "forward_url": targets[0],
+            "active": true,
+            "version": "v1",
+            "environment": "test",
+            "max_body_size": null,
+            "tls": null,
+            "targets": targets.iter().map(|url| json!({ "url": url })).collect::<Vec<_>>(),
+            "health_check": health_checked.then(|| json!({ "path": "/health" })),
+            "timeouts": null,
+            "retry": null,
+            "circuit_breaker": null,
+            "websocket": null,
+            "roles": [],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn retain_routed_forgets_removed_targets() {
+        let registry = HealthRegistry::new();
+        let shop = service("shop", &["http://a", "http://b"], true);
+        let cart = service("cart", &["http://c"], true);
+        for (service, target) in [
+            (&shop, "http://a"),
+            (&shop, "http://b"),
+            (&cart, "http://c"),
+        ] {
+            registry.record(&service.id.to_string(), &target.to_string(), false);
+        }
+
+        registry.retain_routed(&[service("shop", &["http://b"], true)]);
+
+        let unhealthy = registry.unhealthy.read().unwrap();
+        assert_eq!(unhealthy.len(), 1);
+        assert_eq!(
+            unhealthy.get(&shop.id.to_string()),
+            Some(&HashSet::from(["http://b".to_string()]))
+        );
+    }
+
+    #[test]
+    fn retain_routed_forgets_services_no_longer_checked() {
+        let registry = HealthRegistry::new();
+        let shop = service("shop", &["http://a"], true);
+        registry.record(&shop.id.to_string(), &"http://a".to_string(), false);
+
+        registry.retain_routed(&[service("shop", &["http://a"], false)]);
+
+        assert!(registry.unhealthy_targets(&shop.id.to_string()).is_empty());
+        assert!(registry.unhealthy.read().unwrap().is_empty());
+    }
+}
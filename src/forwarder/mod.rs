@@ -1,23 +1,91 @@
+pub mod admin;
+pub mod balancer;
+pub mod breaker;
+pub mod client;
+pub mod health;
+pub mod models;
+pub mod routing;
+pub mod websocket;
+
 use crate::{
-    api_services::{models::DbApiRole, repo::ApiServiceRepository},
+    api_services::models::{DbApiRole, DbFullApiService},
     auth::web::validate_jwt,
     database::{Database, NAMESPACE_MEMBER_ROLE, ROLE_NAMESPACE_DELIMITER},
     errors::GatewayError,
+    ratelimit::limiter::RateLimiter,
+};
+use actix_web::{
+    http::{header, Method, StatusCode, Version},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use balancer::{SelectedTarget, UpstreamPools};
+use breaker::CircuitBreakers;
+use client::{upstream_error, UpstreamClients};
+use futures::channel::mpsc;
+use futures_util::{stream, SinkExt, Stream, StreamExt};
+use health::HealthRegistry;
+use routing::RoutingTable;
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    io,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+    time::Duration,
 };
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
-use futures_util::stream::TryStreamExt;
-use reqwest::Client;
+use tokio::time::Instant;
 
 const EXCLUDE_HEADERS: &[&str] = &["host"];
+// Connection-specific headers that must not be relayed from the upstream response
+const HOP_BY_HOP_HEADERS: &[&str] = &["connection", "keep-alive", "transfer-encoding"];
+// Response content types that are relayed without the total request timeout
+const STREAMING_CONTENT_TYPES: &[&str] = &[
+    "text/event-stream",
+    "application/x-ndjson",
+    "multipart/x-mixed-replace",
+];
+// Number of request body chunks buffered between the client and the upstream
+const BODY_CHANNEL_CAPACITY: usize = 16;
+// Methods that are safe to send to the upstream more than once
+const IDEMPOTENT_METHODS: &[Method] = &[
+    Method::GET,
+    Method::HEAD,
+    Method::OPTIONS,
+    Method::PUT,
+    Method::DELETE,
+    Method::TRACE,
+];
+// Upstream statuses worth retrying against another attempt
+const RETRYABLE_STATUSES: &[StatusCode] = &[
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+// How the client's request body is handed to the upstream
+enum UpstreamBody {
+    Empty,
+    // Relayed chunk by chunk; can only be sent once
+    Streamed(Option<mpsc::Receiver<io::Result<web::Bytes>>>),
+    // Held in memory so the request can be retried
+    Buffered(web::Bytes),
+}
 
 pub async fn forward(
     req: HttpRequest,
     payload: web::Payload,
-    db: web::Data<Database>,
+    routes: web::Data<RoutingTable>,
+    clients: web::Data<UpstreamClients>,
+    pools: web::Data<UpstreamPools>,
+    health: web::Data<HealthRegistry>,
+    breakers: web::Data<CircuitBreakers>,
+    limiter: web::Data<RateLimiter>,
+    repo: web::Data<Database>,
 ) -> impl Responder {
     log::debug!("Attempting to forward request...");
     let segments: Vec<&str> = req.path().splitn(4, '/').collect();
-    
+
     // Just validate that the token is valid and not expired. aud/roles will be checked later.
     let claims =
         validate_jwt(&req, None).map_err(|e| actix_web::error::ErrorForbidden(e.to_string()))?;
@@ -29,7 +97,7 @@ pub async fn forward(
     let version = String::from(segments[2]);
     let mut endpoint = String::from(segments[3]);
     let query = req.query_string();
-    if !query.is_empty(){
+    if !query.is_empty() {
         endpoint.push_str("?");
         endpoint.push_str(query);
     }
@@ -41,91 +109,371 @@ pub async fn forward(
         endpoint
     );
 
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()
-        .map_err(|err| GatewayError::SystemError(err.to_string()))?;
-
-    if let Ok(service) = Database::get_service_with_roles(&db, &api_name, &version).await {
+    if let Some(service) = routes.lookup(&api_name, &version) {
         // Construct the full URL
         if !check_aud_authorized(&service.roles, &claims.aud) {
             return Err(actix_web::error::ErrorForbidden(
                 "User roles do not authorize access to this service",
             ));
         }
-        log::debug!("Configured Forward URL: {}", service.forward_url);
-        let forward_url = format!("{}/{}", service.forward_url, endpoint);
-
-        log::info!(
-            "{} -> {}[{}] as {} \"{} {}\"",
-            req.peer_addr().unwrap().ip().to_string(),
-            &api_name,
-            &version,
-            &claims.sub_id,
-            &req.method(),
-            &forward_url,
-        );
-
-        // Initialize the client request
-        let mut client_req = client.request(req.method().clone(), &forward_url);
 
-        // Copy the headers
-        for (key, value) in req
-            .headers()
-            .iter()
-            .filter(|(key, _)| !EXCLUDE_HEADERS.contains(&key.as_str()))
+        // Requests the gateway refuses by itself aren't charged to the caller
+        let upgrade = websocket::is_upgrade_request(&req);
+        if upgrade {
+            websocket::idle_timeout(&service)?;
+        } else if let (Some(limit), Some(length)) =
+            (service.max_body_size, request_content_length(&req))
         {
-            log::debug!(
-                "Passing header: {}: {:?}",
-                key,
-                value.clone().to_str().unwrap()
-            );
-            client_req = client_req.header(key.clone(), value.clone());
+            // Reject declared oversize bodies before contacting the upstream
+            if length > limit {
+                return Err(GatewayError::PayloadTooLarge(format!(
+                    "Request body of {} bytes exceeds the {} byte limit for this service",
+                    length, limit
+                ))
+                .into());
+            }
         }
 
-        // Set additional headers for forwarding
-        client_req = client_req
-            .header("X-Real-IP", req.peer_addr().unwrap().ip().to_string())
-            .header(
-                "X-Forwarded-For",
-                req.connection_info().realip_remote_addr().unwrap_or(""),
+        limiter.check(&repo, &service, &claims).await?;
+
+        if upgrade {
+            return websocket::proxy(
+                &req, payload, &service, &endpoint, &pools, &health, &breakers,
             )
-            .header("X-Forwarded-Proto", req.connection_info().scheme())
-            .header("X-Forwarded-Host", req.connection_info().host());
-
-        // Stream the request body
-        let body_stream = payload
-            .try_fold(web::BytesMut::new(), |mut body, chunk| {
-                body.extend_from_slice(&chunk);
-                async move { Ok(body) }
-            })
             .await
-            .map_err(actix_web::error::Error::from)?;
+            .map_err(Into::into);
+        }
 
-        client_req = client_req.body(body_stream.freeze().to_vec());
+        let retries = match &service.retry {
+            Some(retry)
+                if retry.retry_non_idempotent || IDEMPOTENT_METHODS.contains(req.method()) =>
+            {
+                retry.attempts
+            }
+            _ => 0,
+        };
+        let connect_timeout = service
+            .timeouts
+            .as_ref()
+            .and_then(|timeouts| timeouts.connect_ms)
+            .map(Duration::from_millis);
+        let request_timeout = service
+            .timeouts
+            .as_ref()
+            .and_then(|timeouts| timeouts.request_ms)
+            .map(Duration::from_millis);
+        // Streaming services may hold the request open indefinitely (long polling),
+        // so for them the request timeout only limits how long the upstream stays silent
+        let deadline = request_timeout
+            .filter(|_| !service.streaming)
+            .map(|timeout| Instant::now() + timeout);
 
-        // Send the request
-        let response = client_req.send().await.map_err(|e| {
-            eprintln!("Error forwarding request: {}", e);
-            actix_web::error::ErrorInternalServerError("Error forwarding request")
-        })?;
+        // Stream the request body, unless it has to be replayed for retries
+        let oversize = Rc::new(Cell::new(false));
+        let mut body = if !request_has_body(&req) {
+            UpstreamBody::Empty
+        } else if retries > 0 {
+            UpstreamBody::Buffered(buffer_payload(payload, service.max_body_size).await?)
+        } else {
+            let (body_sender, body_receiver) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+            actix_web::rt::spawn(pump_payload(
+                payload,
+                body_sender,
+                service.max_body_size,
+                oversize.clone(),
+            ));
+            UpstreamBody::Streamed(Some(body_receiver))
+        };
+
+        let mut attempt: u32 = 0;
+        let (response, target) = loop {
+            let permit = breakers.acquire(&service)?;
+            // Targets failing their active health check are taken out of rotation
+            let unhealthy = match service.health_check {
+                Some(_) => health.unhealthy_targets(&service.id.to_string()),
+                None => HashSet::new(),
+            };
+            let target = pools.select(&service, &unhealthy)?;
+            log::debug!("Selected upstream target: {}", target.url());
+            let forward_url = format!("{}/{}", target.url(), endpoint);
 
-        // Convert the response into an Actix HttpResponse and return it
+            log::info!(
+                "{} -> {}[{}] as {} \"{} {}\"",
+                req.peer_addr().unwrap().ip().to_string(),
+                &api_name,
+                &version,
+                &claims.sub_id,
+                &req.method(),
+                &forward_url,
+            );
+
+            // Initialize the client request from the shared pool for this service's settings
+            let (client, target_url) = clients
+                .prepare(service.tls.as_ref(), connect_timeout, &forward_url)
+                .await?;
+            let mut client_req = upstream_request(&req, &client, &target_url);
+            client_req = match &mut body {
+                UpstreamBody::Empty => client_req,
+                UpstreamBody::Streamed(receiver) => match receiver.take() {
+                    Some(receiver) => client_req.body(reqwest::Body::wrap_stream(receiver)),
+                    None => client_req,
+                },
+                UpstreamBody::Buffered(bytes) => client_req.body(bytes.clone()),
+            };
+
+            // Send the request. A long poll may hold back its headers until it has
+            // data, so the wait for a streaming service's response is unbounded
+            let outcome = match deadline {
+                Some(at) => match tokio::time::timeout_at(at, client_req.send()).await {
+                    Ok(sent) => sent.map_err(|e| upstream_error(&e)),
+                    Err(_) => Err(GatewayError::UpstreamTimeout(format!(
+                        "No response from {} within the request timeout",
+                        api_name
+                    ))),
+                },
+                None => client_req.send().await.map_err(|e| upstream_error(&e)),
+            };
+            let outcome = outcome.map_err(|e| {
+                if oversize.get() {
+                    GatewayError::PayloadTooLarge(format!(
+                        "Request body exceeds the {} byte limit for this service",
+                        service.max_body_size.unwrap_or_default()
+                    ))
+                } else {
+                    e
+                }
+            });
+
+            match outcome {
+                Ok(response) if response.status().is_server_error() => {
+                    target.record_failure();
+                    permit.record_failure();
+                    if attempt >= retries || !RETRYABLE_STATUSES.contains(&response.status()) {
+                        break (response, target);
+                    }
+                    log::warn!(
+                        "Upstream {} answered {}, retrying",
+                        target.url(),
+                        response.status()
+                    );
+                }
+                Ok(response) => {
+                    target.record_success();
+                    permit.record_success();
+                    break (response, target);
+                }
+                Err(GatewayError::PayloadTooLarge(message)) => {
+                    return Err(GatewayError::PayloadTooLarge(message).into());
+                }
+                Err(e) => {
+                    log::error!("Error forwarding request: {}", e);
+                    target.record_failure();
+                    permit.record_failure();
+                    if attempt >= retries {
+                        return Err(e.into());
+                    }
+                }
+            }
+            tokio::time::sleep(retry_backoff(&service, attempt)).await;
+            attempt += 1;
+        };
+
+        // Convert the response into an Actix HttpResponse and stream the body back
         let mut builder = HttpResponse::build(response.status());
-        for (key, value) in response.headers().iter() {
+        for (key, value) in response
+            .headers()
+            .iter()
+            .filter(|(key, _)| !HOP_BY_HOP_HEADERS.contains(&key.as_str()))
+        {
             builder.insert_header((key.clone(), value.clone()));
         }
 
-        let body = response.bytes().await.map_err(|e| {
-            eprintln!("Error reading response body: {}", e);
-            actix_web::error::ErrorInternalServerError("Error reading response body")
-        })?;
-        Ok(builder.body(body))
+        // Event streams are relayed chunk by chunk for as long as they keep sending
+        let streaming = service.streaming || is_streaming_response(&response);
+
+        // A client hanging up on HTTP/1 drops this handler or this stream, and with
+        // them the upstream request. HTTP/2 resets are only noticed at the next
+        // chunk, so there the request timeout is what bounds the upstream request.
+        let body_stream = TargetBody {
+            body: response
+                .bytes_stream()
+                .map(|chunk| chunk.map_err(io::Error::other)),
+            _target: target,
+        };
+        match (deadline, request_timeout) {
+            (Some(deadline), _) if !streaming => Ok(builder.streaming(with_timeout(
+                body_stream,
+                move || deadline,
+                "Upstream response exceeded the request timeout",
+            ))),
+            (_, Some(timeout)) => Ok(builder.streaming(with_timeout(
+                body_stream,
+                move || Instant::now() + timeout,
+                "Upstream stream stayed silent for longer than the request timeout",
+            ))),
+            _ => Ok(builder.streaming(body_stream)),
+        }
     } else {
         Ok(HttpResponse::NotFound().finish())
     }
 }
 
+fn upstream_request(
+    req: &HttpRequest,
+    client: &reqwest::Client,
+    target_url: &String,
+) -> reqwest::RequestBuilder {
+    let mut client_req = client.request(req.method().clone(), target_url);
+
+    // Copy the headers
+    for (key, value) in req
+        .headers()
+        .iter()
+        .filter(|(key, _)| !EXCLUDE_HEADERS.contains(&key.as_str()))
+    {
+        log::debug!(
+            "Passing header: {}: {:?}",
+            key,
+            value.clone().to_str().unwrap()
+        );
+        client_req = client_req.header(key.clone(), value.clone());
+    }
+
+    // Set additional headers for forwarding
+    client_req
+        .header("X-Real-IP", req.peer_addr().unwrap().ip().to_string())
+        .header(
+            "X-Forwarded-For",
+            req.connection_info().realip_remote_addr().unwrap_or(""),
+        )
+        .header("X-Forwarded-Proto", req.connection_info().scheme())
+        .header("X-Forwarded-Host", req.connection_info().host())
+}
+
+fn retry_backoff(service: &DbFullApiService, attempt: u32) -> Duration {
+    let backoff_ms = service.retry.as_ref().map_or(0, |retry| retry.backoff_ms);
+    Duration::from_millis(backoff_ms.saturating_mul(1 << attempt.min(16)))
+}
+
+// A response body that keeps its upstream target outstanding until the body
+// has been relayed, or dropped because the client went away
+struct TargetBody<S> {
+    body: S,
+    _target: SelectedTarget,
+}
+
+impl<S: Stream + Unpin> Stream for TargetBody<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+// Ends the response body with an error when the next chunk doesn't arrive by
+// the deadline `next_deadline` gives for it.
+fn with_timeout<S, D>(
+    body: S,
+    next_deadline: D,
+    message: &'static str,
+) -> impl Stream<Item = io::Result<web::Bytes>>
+where
+    S: Stream<Item = io::Result<web::Bytes>> + Unpin,
+    D: Fn() -> Instant + Copy,
+{
+    stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout_at(next_deadline(), body.next()).await {
+            Ok(Some(chunk)) => Some((chunk, Some(body))),
+            Ok(None) => None,
+            Err(_) => Some((Err(io::Error::new(io::ErrorKind::TimedOut, message)), None)),
+        }
+    })
+}
+
+fn is_streaming_response(response: &reqwest::Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| {
+            STREAMING_CONTENT_TYPES
+                .iter()
+                .any(|streaming| mime.trim().eq_ignore_ascii_case(streaming))
+        })
+}
+
+fn request_content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+// HTTP/1.x marks a body with Content-Length or Transfer-Encoding, but HTTP/2
+// and later frame it without either, so there a request lacking a length only
+// goes without a body when its method carries none
+fn request_has_body(req: &HttpRequest) -> bool {
+    match request_content_length(req) {
+        Some(length) => length > 0,
+        None if req.version() < Version::HTTP_2 => {
+            req.headers().contains_key(header::TRANSFER_ENCODING)
+        }
+        None => !matches!(*req.method(), Method::GET | Method::HEAD),
+    }
+}
+
+// Relays the client payload into the upstream request body, enforcing the
+// service's body size limit as chunks arrive.
+async fn pump_payload(
+    mut payload: web::Payload,
+    mut sender: mpsc::Sender<io::Result<web::Bytes>>,
+    limit: Option<u64>,
+    oversize: Rc<Cell<bool>>,
+) {
+    let mut received: u64 = 0;
+    while let Some(chunk) = payload.next().await {
+        let item = match chunk {
+            Ok(bytes) => {
+                received += bytes.len() as u64;
+                if limit.is_some_and(|limit| received > limit) {
+                    oversize.set(true);
+                    Err(io::Error::other(
+                        "Request body exceeds the configured maximum size",
+                    ))
+                } else {
+                    Ok(bytes)
+                }
+            }
+            Err(e) => Err(io::Error::other(e.to_string())),
+        };
+        let failed = item.is_err();
+        if sender.send(item).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+// Collects the client payload so the upstream request can be replayed on retry.
+async fn buffer_payload(
+    mut payload: web::Payload,
+    limit: Option<u64>,
+) -> Result<web::Bytes, GatewayError> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+        if limit.is_some_and(|limit| (body.len() + chunk.len()) as u64 > limit) {
+            return Err(GatewayError::PayloadTooLarge(format!(
+                "Request body exceeds the {} byte limit for this service",
+                limit.unwrap_or_default()
+            )));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
 fn check_aud_authorized(service_roles: &Vec<DbApiRole>, claims_aud: &Vec<String>) -> bool {
     // Convert service roles to a HashSet for efficient lookup
     if service_roles
@@ -155,3 +503,174 @@ fn check_aud_authorized(service_roles: &Vec<DbApiRole>, claims_aud: &Vec<String>
     log::debug!("No matching aud found");
     false // No matching scopes found
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::pin::Pin;
+
+    use actix_web::error::PayloadError;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Data;
+    use actix_web::{dev, App, FromRequest, HttpServer};
+    use futures_util::{stream, Stream};
+    use serde_json::json;
+
+    use super::*;
+    use crate::api_services::models::WebRequestApiService;
+    use crate::api_services::repo::ApiServiceRepository;
+    use crate::audit::models::Actor;
+    use crate::auth::keys::{ensure_signing_key, SigningKeys};
+    use crate::auth::models::SigningAlgorithm;
+    use crate::auth::revocation::RevocationList;
+    use crate::config::GatewayConfig;
+    use crate::database::testing::database;
+    use crate::secconf;
+
+    // A client payload arriving in the given chunks
+    async fn payload(chunks: &[&'static [u8]]) -> web::Payload {
+        let chunks: Vec<Result<web::Bytes, PayloadError>> = chunks
+            .iter()
+            .map(|chunk| Ok(web::Bytes::from_static(chunk)))
+            .collect();
+        let stream: Pin<Box<dyn Stream<Item = Result<web::Bytes, PayloadError>>>> =
+            Box::pin(stream::iter(chunks));
+        let req = TestRequest::default().to_http_request();
+        web::Payload::from_request(&req, &mut dev::Payload::from(stream))
+            .await
+            .unwrap()
+    }
+
+    async fn pump(
+        chunks: &[&'static [u8]],
+        limit: Option<u64>,
+    ) -> (Vec<io::Result<web::Bytes>>, bool) {
+        let (sender, receiver) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+        let oversize = Rc::new(Cell::new(false));
+        pump_payload(payload(chunks).await, sender, limit, oversize.clone()).await;
+        (receiver.collect().await, oversize.get())
+    }
+
+    #[actix_web::test]
+    async fn pump_relays_bodies_within_the_limit() {
+        let (relayed, oversize) = pump(&[b"hello ", b"world"], Some(11)).await;
+
+        let relayed: Vec<web::Bytes> = relayed.into_iter().map(Result::unwrap).collect();
+        assert_eq!(relayed, vec!["hello ", "world"]);
+        assert!(!oversize);
+    }
+
+    #[actix_web::test]
+    async fn pump_stops_at_the_chunk_crossing_the_limit() {
+        let (relayed, oversize) = pump(&[b"hello ", b"world", b"!"], Some(8)).await;
+
+        assert_eq!(relayed.len(), 2);
+        assert!(relayed[0].is_ok());
+        assert!(relayed[1].is_err());
+        assert!(oversize);
+    }
+
+    #[actix_web::test]
+    async fn buffer_refuses_bodies_over_the_limit() {
+        let body = buffer_payload(payload(&[b"hello ", b"world"]).await, None)
+            .await
+            .unwrap();
+        assert_eq!(body, "hello world");
+
+        let refused = buffer_payload(payload(&[b"hello ", b"world"]).await, Some(8)).await;
+        assert!(matches!(refused, Err(GatewayError::PayloadTooLarge(_))));
+    }
+
+    // An upstream answering every request with the body it was sent
+    fn echo_upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|body: web::Bytes| async move {
+                HttpResponse::Ok().body(body)
+            }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        url
+    }
+
+    #[actix_web::test]
+    async fn forwards_bodies_sent_without_a_length() {
+        let repo = database().await;
+        let request: WebRequestApiService = serde_json::from_value(json!({
+            "api_name": "echo",
+            "version": "v1",
+            "forward_url": echo_upstream(),
+            "active": true,
+            "environment": "test",
+            "role_namespaces": [],
+            "roles": [{ "namespace": "Shop", "name": "Reader" }],
+        }))
+        .unwrap();
+        Database::add_service(
+            &repo,
+            &(&request).into(),
+            &Vec::from(&request),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let routes = RoutingTable::new();
+        routes.refresh(&repo).await.unwrap();
+
+        let mut jwt_config = secconf::load_jwt_config(&GatewayConfig::default().auth);
+        jwt_config.algorithm = SigningAlgorithm::EdDsa;
+        ensure_signing_key(&repo, jwt_config.algorithm, jwt_config.token_lifetime_secs)
+            .await
+            .unwrap();
+        let signing_keys = SigningKeys::new();
+        signing_keys
+            .refresh(&repo, jwt_config.token_lifetime_secs)
+            .await
+            .unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let token = signing_keys
+            .sign(&json!({
+                "iss": jwt_config.issuer,
+                "sub": "alice",
+                "sub_id": "alice",
+                "aud": ["Shop::Reader"],
+                "exp": now + 60,
+                "iat": now,
+                "nbf": now,
+            }))
+            .unwrap();
+
+        // HTTP/2 frames the body without a Content-Length
+        let req = TestRequest::post()
+            .uri("/echo/v1/orders")
+            .version(Version::HTTP_2)
+            .peer_addr("127.0.0.1:50000".parse().unwrap())
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .app_data(Data::new(jwt_config))
+            .app_data(Data::new(signing_keys))
+            .app_data(Data::new(RevocationList::new()))
+            .to_http_request();
+        let response = forward(
+            req.clone(),
+            payload(&[b"hello ", b"world"]).await,
+            Data::new(routes),
+            Data::new(UpstreamClients::new().unwrap()),
+            Data::new(UpstreamPools::new()),
+            Data::new(HealthRegistry::new()),
+            Data::new(CircuitBreakers::new()),
+            Data::new(RateLimiter::new()),
+            repo,
+        )
+        .await
+        .respond_to(&req);
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = actix_web::body::to_bytes(response.into_body()).await;
+        assert_eq!(body.ok().unwrap(), "hello world");
+    }
+}
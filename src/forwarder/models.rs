@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Datetime;
+
+use super::routing::RoutingTable;
+use crate::api_services::models::WebResponseApiService;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebRoutingTable {
+    pub last_refresh: Option<Datetime>,
+    pub routes: Vec<WebResponseApiService>,
+}
+
+impl From<&RoutingTable> for WebRoutingTable {
+    fn from(table: &RoutingTable) -> Self {
+        Self {
+            last_refresh: table.last_refresh(),
+            routes: table
+                .routes()
+                .iter()
+                .map(WebResponseApiService::from)
+                .collect(),
+        }
+    }
+}
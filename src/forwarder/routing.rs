@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use futures_util::stream::{select_all, StreamExt};
+use surrealdb::sql::{Datetime, Value};
+use tokio::sync::watch;
+
+use crate::api_services::models::DbFullApiService;
+use crate::api_services::repo::ApiServiceRepository;
+use crate::database::{Database, API_ROLE_TABLE, API_SERVICE_TABLE, AUTHORIZATIONS_TABLE};
+use crate::errors::{GatewayError, Result};
+
+// Delay before re-subscribing when a live query stream ends unexpectedly
+const LIVE_QUERY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// In-memory lookup of active API services, keyed on (api_name, version).
+pub struct RoutingTable {
+    routes: RwLock<HashMap<(String, String), DbFullApiService>>,
+    last_refresh: RwLock<Option<Datetime>>,
+    // Counts reloads, so state kept per route can follow them
+    refreshes: watch::Sender<u64>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        RoutingTable {
+            routes: RwLock::new(HashMap::new()),
+            last_refresh: RwLock::new(None),
+            refreshes: watch::channel(0).0,
+        }
+    }
+
+    pub fn lookup(&self, api_name: &String, version: &String) -> Option<DbFullApiService> {
+        self.routes
+            .read()
+            .unwrap()
+            .get(&(api_name.clone(), version.clone()))
+            .cloned()
+    }
+
+    pub fn routes(&self) -> Vec<DbFullApiService> {
+        let mut routes: Vec<DbFullApiService> =
+            self.routes.read().unwrap().values().cloned().collect();
+        routes.sort_by(|a, b| (&a.api_name, &a.version).cmp(&(&b.api_name, &b.version)));
+        routes
+    }
+
+    pub fn last_refresh(&self) -> Option<Datetime> {
+        self.last_refresh.read().unwrap().clone()
+    }
+
+    /// Notified each time the table is reloaded.
+    pub fn refreshed(&self) -> watch::Receiver<u64> {
+        self.refreshes.subscribe()
+    }
+
+    /// Reload every active service (and its authorized roles) from the database.
+    pub async fn refresh(&self, repo: &Data<Database>) -> Result<()> {
+        let services = Database::list_services(repo).await?;
+        let routes: HashMap<(String, String), DbFullApiService> = services
+            .into_iter()
+            .filter(|service| service.active)
+            .map(|service| ((service.api_name.clone(), service.version.clone()), service))
+            .collect();
+        log::debug!("Loaded {} active routes", routes.len());
+        *self.routes.write().unwrap() = routes;
+        *self.last_refresh.write().unwrap() = Some(Datetime::default());
+        self.refreshes.send_modify(|count| *count += 1);
+        Ok(())
+    }
+}
+
+/// Keeps the routing table current by reloading it whenever a `LIVE SELECT`
+/// on the service, role or authorizes tables reports a change.
+pub fn spawn_routing_sync(table: Data<RoutingTable>, repo: Data<Database>) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = watch_route_tables(&table, &repo).await {
+                log::error!("Routing table live query failed: {}", e);
+            }
+            tokio::time::sleep(LIVE_QUERY_RETRY_DELAY).await;
+        }
+    });
+}
+
+async fn watch_route_tables(table: &Data<RoutingTable>, repo: &Data<Database>) -> Result<()> {
+    let mut reconnected = repo.reconnected();
+    let mut streams = Vec::new();
+    for watched_table in [API_SERVICE_TABLE, API_ROLE_TABLE, AUTHORIZATIONS_TABLE] {
+        let stream = repo
+            .db
+            .select::<Vec<Value>>(watched_table)
+            .live()
+            .await
+            .map_err(GatewayError::from)?;
+        streams.push(stream);
+    }
+    // Catch any change made between the last refresh and the subscription
+    table.refresh(repo).await?;
+
+    let mut notifications = select_all(streams);
+    loop {
+        tokio::select! {
+            notification = notifications.next() => match notification {
+                Some(Ok(notification)) => {
+                    log::debug!("Route change detected: {:?}", notification.action);
+                    if let Err(e) = table.refresh(repo).await {
+                        log::error!("Unable to refresh routing table: {}", e);
+                    }
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+            // The live queries ended with the old connection
+            _ = reconnected.changed() => {
+                log::info!("Restarting routing live queries after reconnecting");
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, Session};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::Url;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+
+use super::balancer::{SelectedTarget, UpstreamPools};
+use super::breaker::CircuitBreakers;
+use super::client::ca_certificates;
+use super::health::HealthRegistry;
+use crate::api_services::models::{DbFullApiService, ServiceTlsConfig};
+use crate::errors::{GatewayError, Result};
+
+// Handshake headers generated per connection, which must not be copied to the upstream
+const HANDSHAKE_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+];
+
+type UpstreamSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub fn is_upgrade_request(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// The idle timeout of the service's WebSocket connections, or a bad request
+/// when the service doesn't accept them.
+pub fn idle_timeout(service: &DbFullApiService) -> Result<Duration> {
+    match &service.websocket {
+        Some(websocket) if websocket.enabled => {
+            Ok(Duration::from_secs(websocket.idle_timeout_secs))
+        }
+        _ => Err(GatewayError::BadRequest(format!(
+            "WebSocket connections are not enabled for {}",
+            service.api_name
+        ))),
+    }
+}
+
+/// Accepts the client handshake, opens a WebSocket to one of the service's
+/// upstream targets, and relays frames between the two until either side
+/// closes or the connection sits idle past the service's idle timeout.
+pub async fn proxy(
+    req: &HttpRequest,
+    payload: web::Payload,
+    service: &DbFullApiService,
+    endpoint: &String,
+    pools: &UpstreamPools,
+    health: &HealthRegistry,
+    breakers: &CircuitBreakers,
+) -> Result<HttpResponse> {
+    let idle_timeout = idle_timeout(service)?;
+    // A malformed handshake is the client's fault, so it is refused before an
+    // upstream is dialled or a target or circuit credited with the connection
+    let (mut response, session, client_stream) =
+        actix_ws::handle(req, payload).map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+
+    let permit = breakers.acquire(service)?;
+    let unhealthy = match service.health_check {
+        Some(_) => health.unhealthy_targets(&service.id.to_string()),
+        None => HashSet::new(),
+    };
+    let target = pools.select(service, &unhealthy)?;
+    let forward_url = format!("{}/{}", target.url(), endpoint);
+    log::info!(
+        "{} -> {}[{}] WebSocket \"{}\"",
+        req.peer_addr().unwrap().ip().to_string(),
+        &service.api_name,
+        &service.version,
+        &forward_url,
+    );
+
+    let (upstream, protocol) = match connect_upstream(req, service, &forward_url).await {
+        Ok(connected) => {
+            target.record_success();
+            permit.record_success();
+            connected
+        }
+        Err(e) => {
+            log::error!("Unable to open upstream WebSocket: {}", e);
+            target.record_failure();
+            permit.record_failure();
+            return Err(e);
+        }
+    };
+
+    // Pass along the subprotocol the upstream agreed to
+    if let Some(protocol) = protocol {
+        response
+            .headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+
+    actix_web::rt::spawn(relay(
+        session,
+        client_stream.aggregate_continuations(),
+        upstream,
+        idle_timeout,
+        target,
+    ));
+    Ok(response)
+}
+
+async fn connect_upstream(
+    req: &HttpRequest,
+    service: &DbFullApiService,
+    forward_url: &String,
+) -> Result<(UpstreamSocket, Option<header::HeaderValue>)> {
+    let mut url = Url::parse(forward_url)
+        .map_err(|e| GatewayError::BadRequest(format!("Invalid forward URL: {}", e)))?;
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    url.set_scheme(scheme)
+        .map_err(|_| GatewayError::BadRequest(format!("Invalid forward URL: {}", forward_url)))?;
+    let host = url.host_str().unwrap_or_default().to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    // As with plain requests, an SNI override names the upstream while
+    // the connection still goes to the original address
+    if let Some(sni) = service
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.sni_override.as_ref())
+    {
+        url.set_host(Some(sni))
+            .map_err(|e| GatewayError::BadRequest(format!("Invalid SNI override: {}", e)))?;
+    }
+
+    let mut upstream_req = url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+    for (key, value) in req
+        .headers()
+        .iter()
+        .filter(|(key, _)| !HANDSHAKE_HEADERS.contains(&key.as_str()))
+    {
+        if let (Ok(key), Ok(value)) = (
+            HeaderName::from_bytes(key.as_str().as_bytes()),
+            HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            upstream_req.headers_mut().append(key, value);
+        }
+    }
+    let forwarded_headers = [
+        ("x-real-ip", req.peer_addr().unwrap().ip().to_string()),
+        (
+            "x-forwarded-for",
+            req.connection_info()
+                .realip_remote_addr()
+                .unwrap_or("")
+                .to_string(),
+        ),
+        (
+            "x-forwarded-proto",
+            req.connection_info().scheme().to_string(),
+        ),
+        ("x-forwarded-host", req.connection_info().host().to_string()),
+    ];
+    for (key, value) in forwarded_headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            upstream_req
+                .headers_mut()
+                .insert(HeaderName::from_static(key), value);
+        }
+    }
+
+    let connector = match &service.tls {
+        Some(tls) => Some(tls_connector(tls)?),
+        None => None,
+    };
+    let connect = async {
+        let stream = TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(|e| {
+                GatewayError::UpstreamConnectionError(format!(
+                    "Unable to connect to upstream: {}",
+                    e
+                ))
+            })?;
+        tokio_tungstenite::client_async_tls_with_config(upstream_req, stream, None, connector)
+            .await
+            .map_err(|e| handshake_error(&e))
+    };
+    let connect_timeout = service
+        .timeouts
+        .as_ref()
+        .and_then(|timeouts| timeouts.connect_ms)
+        .map(Duration::from_millis);
+    let (upstream, response) = match connect_timeout {
+        Some(connect_timeout) => tokio::time::timeout(connect_timeout, connect)
+            .await
+            .map_err(|_| {
+                GatewayError::UpstreamTimeout(format!(
+                    "No WebSocket handshake from {} within the connect timeout",
+                    service.api_name
+                ))
+            })??,
+        None => connect.await?,
+    };
+
+    let protocol = response
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|value| header::HeaderValue::from_bytes(value.as_bytes()).ok());
+    Ok((upstream, protocol))
+}
+
+fn tls_connector(tls: &ServiceTlsConfig) -> Result<Connector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.danger_accept_invalid_certs(!tls.verify);
+    if let Some(ca_bundle) = &tls.ca_bundle {
+        for der in ca_certificates(ca_bundle)? {
+            let certificate = native_tls::Certificate::from_der(&der)
+                .map_err(|e| GatewayError::BadRequest(format!("Invalid CA bundle: {}", e)))?;
+            builder.add_root_certificate(certificate);
+        }
+    }
+    builder
+        .build()
+        .map(Connector::NativeTls)
+        .map_err(|e| GatewayError::SystemError(e.to_string()))
+}
+
+fn handshake_error(error: &tungstenite::Error) -> GatewayError {
+    match error {
+        tungstenite::Error::Http(response) => GatewayError::UpstreamError(format!(
+            "Upstream rejected the WebSocket handshake with status {}",
+            response.status()
+        )),
+        tungstenite::Error::Io(e) => {
+            GatewayError::UpstreamConnectionError(format!("Unable to connect to upstream: {}", e))
+        }
+        tungstenite::Error::Tls(e) => GatewayError::UpstreamError(format!(
+            "Upstream TLS certificate could not be verified: {}",
+            e
+        )),
+        e => GatewayError::UpstreamError(format!("Error opening upstream WebSocket: {}", e)),
+    }
+}
+
+// Relays frames until either side closes; the target stays outstanding meanwhile.
+async fn relay(
+    mut session: Session,
+    mut client_stream: actix_ws::AggregatedMessageStream,
+    upstream: UpstreamSocket,
+    idle_timeout: Duration,
+    _target: SelectedTarget,
+) {
+    let (mut upstream_sink, mut upstream_stream) = upstream.split();
+    // Set once the client's close has been passed on to the upstream
+    let mut client_closed = false;
+    loop {
+        tokio::select! {
+            message = client_stream.next(), if !client_closed => {
+                let message = match message {
+                    Some(Ok(AggregatedMessage::Text(text))) => Message::Text(text.to_string()),
+                    Some(Ok(AggregatedMessage::Binary(bytes))) => Message::Binary(bytes.to_vec()),
+                    Some(Ok(AggregatedMessage::Ping(bytes))) => Message::Ping(bytes.to_vec()),
+                    Some(Ok(AggregatedMessage::Pong(bytes))) => Message::Pong(bytes.to_vec()),
+                    Some(Ok(AggregatedMessage::Close(reason))) => {
+                        // Pass the close on once, then wait for the upstream to
+                        // answer it and relay that answer to the client
+                        let _ = upstream_sink.send(Message::Close(reason.map(upstream_close))).await;
+                        client_closed = true;
+                        continue;
+                    }
+                    Some(Err(e)) => {
+                        log::warn!("WebSocket client protocol error: {}", e);
+                        let _ = upstream_sink.send(Message::Close(None)).await;
+                        let _ = session.close(Some(CloseCode::Protocol.into())).await;
+                        break;
+                    }
+                    None => {
+                        let _ = upstream_sink.send(Message::Close(None)).await;
+                        break;
+                    }
+                };
+                if upstream_sink.send(message).await.is_err() {
+                    let _ = session.close(Some(CloseCode::Abnormal.into())).await;
+                    break;
+                }
+            }
+            message = upstream_stream.next() => {
+                let sent = match message {
+                    Some(Ok(Message::Text(text))) => session.text(text).await,
+                    Some(Ok(Message::Binary(bytes))) => session.binary(bytes).await,
+                    Some(Ok(Message::Ping(bytes))) => session.ping(&bytes).await,
+                    Some(Ok(Message::Pong(bytes))) => session.pong(&bytes).await,
+                    Some(Ok(Message::Frame(_))) => Ok(()),
+                    Some(Ok(Message::Close(frame))) => {
+                        let _ = session.close(frame.map(client_close)).await;
+                        break;
+                    }
+                    Some(Err(e)) => {
+                        log::warn!("Upstream WebSocket error: {}", e);
+                        let _ = session.close(Some(CloseCode::Error.into())).await;
+                        break;
+                    }
+                    None => {
+                        let _ = session.close(None).await;
+                        break;
+                    }
+                };
+                if sent.is_err() {
+                    let _ = upstream_sink.send(Message::Close(None)).await;
+                    break;
+                }
+            }
+            _ = tokio::time::sleep(idle_timeout) => {
+                log::info!("Closing WebSocket idle for {}s", idle_timeout.as_secs());
+                let reason = CloseReason {
+                    code: CloseCode::Away,
+                    description: Some("Idle timeout".to_string()),
+                };
+                let _ = upstream_sink
+                    .send(Message::Close(Some(upstream_close(reason.clone()))))
+                    .await;
+                let _ = session.close(Some(reason)).await;
+                break;
+            }
+        }
+    }
+}
+
+fn upstream_close(reason: CloseReason) -> CloseFrame<'static> {
+    CloseFrame {
+        code: u16::from(reason.code).into(),
+        reason: reason.description.unwrap_or_default().into(),
+    }
+}
+
+fn client_close(frame: CloseFrame<'static>) -> CloseReason {
+    CloseReason {
+        code: u16::from(frame.code).into(),
+        description: Some(frame.reason.into_owned()).filter(|reason| !reason.is_empty()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use actix_web::test::TestRequest;
+    use actix_web::FromRequest;
+    use serde_json::json;
+
+    use super::*;
+    use crate::api_services::models::WebRequestApiService;
+    use crate::api_services::repo::ApiServiceRepository;
+    use crate::audit::models::Actor;
+    use crate::database::testing::database;
+    use crate::database::Database;
+    use crate::forwarder::routing::RoutingTable;
+
+    #[actix_web::test]
+    async fn malformed_handshakes_never_reach_the_upstream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        upstream.set_nonblocking(true).unwrap();
+        let repo = database().await;
+        let request: WebRequestApiService = serde_json::from_value(json!({
+            "api_name": "chat",
+            "version": "v1",
+            "forward_url": format!("http://{}", upstream.local_addr().unwrap()),
+            "active": true,
+            "environment": "test",
+            "role_namespaces": [],
+            "roles": [],
+            "websocket": { "enabled": true },
+            "timeouts": { "connect_ms": 200 },
+        }))
+        .unwrap();
+        Database::add_service(
+            &repo,
+            &(&request).into(),
+            &Vec::from(&request),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let routes = RoutingTable::new();
+        routes.refresh(&repo).await.unwrap();
+        let service = routes.lookup(&"chat".into(), &"v1".into()).unwrap();
+
+        // An upgrade without the Sec-WebSocket-Key a handshake needs
+        let (req, mut payload) = TestRequest::get()
+            .uri("/chat/v1/rooms")
+            .peer_addr("127.0.0.1:50000".parse().unwrap())
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "Upgrade"))
+            .to_http_parts();
+        let payload = web::Payload::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        let result = proxy(
+            &req,
+            payload,
+            &service,
+            &"rooms".into(),
+            &UpstreamPools::new(),
+            &HealthRegistry::new(),
+            &CircuitBreakers::new(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(GatewayError::BadRequest(_))));
+        assert!(upstream.accept().is_err());
+    }
+}
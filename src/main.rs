@@ -1,34 +1,125 @@
 use actix_files;
 use actix_web::{middleware, web, App, HttpResponse, HttpServer};
-use env_logger;
+use clap::Parser;
 use serde_json::json;
 
 mod api_services;
+mod audit;
 mod auth;
+mod cli;
+mod config;
 mod database;
+mod declarative;
 mod errors;
+mod federation;
 mod forwarder;
+mod migrations;
+mod oidc;
+mod pagination;
+mod ratelimit;
+mod revisions;
 mod secconf;
 mod users;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    let socket_addr = "127.0.2.1:443";
-    let tls_config = secconf::load_tls_config()?;
-    let jwt_config = web::Data::new(secconf::load_jwt_config()?);
-    let db = database::Database::init("temp.speedb", "api_directory", "services")
+    let cli = config::Cli::parse();
+    let loaded = config::GatewayConfig::load(&cli.overrides).and_then(|config| match cli.command {
+        Some(_) => Ok(config),
+        None => config.validate_serve().map(|_| config),
+    });
+    let config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    config.init_logging();
+
+    let db = database::Database::init(&config.database)
         .await
-        .expect("Error connecting to database");
+        .map_err(|e| std::io::Error::other(format!("Error connecting to database: {}", e)))?;
 
-    api_services::repo::setup_service_table_events(&db).await?;
-    users::repo::setup_user_table(&db).await?;
-    auth::repo::setup_reset_request_table(&db).await?;
+    // `migrate` reports on and applies migrations itself
+    if !matches!(cli.command, Some(cli::Command::Migrate(_))) {
+        migrations::migrate(&db)
+            .await
+            .map_err(|e| std::io::Error::other(format!("Unable to migrate the database: {}", e)))?;
+    }
 
     let db_data = web::Data::new(db);
 
-    log::info!("Starting HTTP server at https://{} ", socket_addr);
-    HttpServer::new(move || {
+    if let Some(command) = cli.command {
+        let status = cli::run(command, &config, &db_data).await;
+        // Close the database before exiting, since `exit` skips destructors
+        drop(db_data);
+        std::process::exit(status);
+    }
+
+    let tls_config = secconf::load_tls_config(&config.tls)?;
+    let jwt_config = web::Data::new(secconf::load_jwt_config(&config.auth));
+
+    database::spawn_connection_monitor(db_data.clone());
+
+    let routing_table = web::Data::new(forwarder::routing::RoutingTable::new());
+    routing_table
+        .refresh(&db_data)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    forwarder::routing::spawn_routing_sync(routing_table.clone(), db_data.clone());
+    let upstream_clients = web::Data::new(
+        forwarder::client::UpstreamClients::new()
+            .map_err(|e| std::io::Error::other(e.to_string()))?,
+    );
+    let upstream_pools = web::Data::new(forwarder::balancer::UpstreamPools::new());
+    forwarder::balancer::spawn_pool_eviction(routing_table.clone(), upstream_pools.clone());
+    let health_registry = web::Data::new(forwarder::health::HealthRegistry::new());
+    forwarder::health::spawn_health_eviction(routing_table.clone(), health_registry.clone());
+    let circuit_breakers = web::Data::new(forwarder::breaker::CircuitBreakers::new());
+    let signing_keys = web::Data::new(auth::keys::SigningKeys::new());
+    auth::keys::ensure_signing_key(
+        &db_data,
+        jwt_config.algorithm,
+        jwt_config.token_lifetime_secs,
+    )
+    .await
+    .map_err(|e| std::io::Error::other(e.to_string()))?;
+    signing_keys
+        .refresh(&db_data, jwt_config.token_lifetime_secs)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    auth::keys::spawn_key_sync(
+        signing_keys.clone(),
+        db_data.clone(),
+        jwt_config.token_lifetime_secs,
+    );
+    let revocation_list = web::Data::new(auth::revocation::RevocationList::new());
+    revocation_list
+        .refresh(&db_data)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    auth::revocation::spawn_revocation_sync(revocation_list.clone(), db_data.clone());
+    let identity_providers = web::Data::new(
+        federation::providers::IdentityProviders::new()
+            .map_err(|e| std::io::Error::other(e.to_string()))?,
+    );
+    let rate_limiter = web::Data::new(ratelimit::limiter::RateLimiter::new());
+    rate_limiter
+        .refresh(&db_data)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    ratelimit::limiter::spawn_limit_sync(rate_limiter.clone(), db_data.clone());
+    forwarder::health::spawn_health_checks(
+        routing_table.clone(),
+        upstream_clients.clone(),
+        health_registry.clone(),
+        db_data.clone(),
+    );
+
+    let listeners = config.server.listen.clone();
+    let config_data = web::Data::new(config);
+    let mut server = HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::new(
                 "%a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T",
@@ -36,11 +127,31 @@ async fn main() -> std::io::Result<()> {
             .wrap(secconf::load_cors_config())
             .app_data(db_data.clone())
             .app_data(jwt_config.clone())
+            .app_data(signing_keys.clone())
+            .app_data(revocation_list.clone())
+            .app_data(identity_providers.clone())
+            .app_data(config_data.clone())
+            .app_data(routing_table.clone())
+            .app_data(upstream_clients.clone())
+            .app_data(upstream_pools.clone())
+            .app_data(health_registry.clone())
+            .app_data(circuit_breakers.clone())
+            .app_data(rate_limiter.clone())
             .configure(api_services::web::service_setup)
+            // Ahead of auth, whose `/auth/v1` scope would otherwise take its paths
+            .configure(federation::web::service_setup)
             .configure(auth::web::service_setup)
             .configure(users::web::service_setup)
+            .configure(forwarder::admin::service_setup)
+            .configure(ratelimit::web::service_setup)
+            .configure(declarative::web::service_setup)
+            .configure(audit::web::service_setup)
+            .configure(oidc::web::service_setup)
             .service(web::scope("/cfg").default_service(web::route().to(not_found)))
-            .service(actix_files::Files::new("/app", "./www").index_file("index.html"))
+            .service(
+                actix_files::Files::new("/app", &config_data.server.ui_dir)
+                    .index_file("index.html"),
+            )
             .service(web::scope("/app").default_service(web::route().to(webui_index)))
             .route("/login", web::get().to(webui_index))
             .route("/reset-password", web::get().to(webui_index))
@@ -51,14 +162,20 @@ async fn main() -> std::io::Result<()> {
                 web::route().to(forwarder::forward),
             )
     })
-    .bind_rustls_0_22(socket_addr, tls_config)?
-    .run()
-    .await
+    // Drop a request's handler, and with it any upstream request in flight, as
+    // soon as an HTTP/1 client hangs up instead of waiting for the upstream to answer
+    .h1_allow_half_closed(false);
+    for address in &listeners {
+        log::info!("Starting HTTP server at https://{} ", address);
+        server = server.bind_rustls_0_22(address, tls_config.clone())?;
+    }
+    server.run().await
 }
 
-async fn webui_index() -> actix_files::NamedFile {
-    actix_files::NamedFile::open("./www/index.html")
-        .unwrap()
+async fn webui_index(
+    config: web::Data<config::GatewayConfig>,
+) -> std::io::Result<actix_files::NamedFile> {
+    actix_files::NamedFile::open(config.server.ui_dir.join("index.html"))
 }
 
 async fn not_found() -> impl actix_web::Responder {
@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surrealdb::sql::Datetime;
+
+use crate::database::{Database, Transaction, MIGRATIONS_TABLE};
+use crate::errors::{GatewayError, Result};
+
+/// A versioned schema change, applied once in its own transaction.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration in the order it is applied. A migration must not change
+/// once released, since its checksum is recorded when it is applied; add a
+/// new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "schemafull_tables",
+        sql: include_str!("../migrations/0001_schemafull_tables.surql"),
+    },
+    Migration {
+        version: 2,
+        name: "record_revisions",
+        sql: include_str!("../migrations/0002_record_revisions.surql"),
+    },
+    Migration {
+        version: 3,
+        name: "service_history",
+        sql: include_str!("../migrations/0003_service_history.surql"),
+    },
+    Migration {
+        version: 4,
+        name: "audit_log",
+        sql: include_str!("../migrations/0004_audit_log.surql"),
+    },
+    Migration {
+        version: 5,
+        name: "sessions",
+        sql: include_str!("../migrations/0005_sessions.surql"),
+    },
+    Migration {
+        version: 6,
+        name: "token_revocation",
+        sql: include_str!("../migrations/0006_token_revocation.surql"),
+    },
+    Migration {
+        version: 7,
+        name: "signing_keys",
+        sql: include_str!("../migrations/0007_signing_keys.surql"),
+    },
+    Migration {
+        version: 8,
+        name: "oidc_provider",
+        sql: include_str!("../migrations/0008_oidc_provider.surql"),
+    },
+    Migration {
+        version: 9,
+        name: "federated_identity",
+        sql: include_str!("../migrations/0009_federated_identity.surql"),
+    },
+    Migration {
+        version: 10,
+        name: "login_keeps_revision",
+        sql: include_str!("../migrations/0010_login_keeps_revision.surql"),
+    },
+    Migration {
+        version: 11,
+        name: "federated_login_browser",
+        sql: include_str!("../migrations/0011_federated_login_browser.surql"),
+    },
+    Migration {
+        version: 12,
+        name: "limits_and_health_schema",
+        sql: include_str!("../migrations/0012_limits_and_health_schema.surql"),
+    },
+];
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppliedMigration {
+    version: i64,
+    name: String,
+    checksum: String,
+    applied_at: Datetime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    // Applied, but the migration has been edited since
+    Modified,
+    // Recorded in the database, but missing from this build
+    Unknown,
+}
+
+impl std::fmt::Display for MigrationState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            MigrationState::Applied => "applied",
+            MigrationState::Pending => "pending",
+            MigrationState::Modified => "modified",
+            MigrationState::Unknown => "unknown",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub state: MigrationState,
+    pub applied_at: Option<Datetime>,
+}
+
+/// Compares the migrations recorded in the database with the ones built
+/// into the gateway, ordered by version.
+pub async fn status(repo: &Database) -> Result<Vec<MigrationStatus>> {
+    let mut applied: BTreeMap<i64, AppliedMigration> = repo
+        .query_list::<AppliedMigration>(
+            format!("SELECT * FROM {}", MIGRATIONS_TABLE),
+            None::<String>,
+        )
+        .await?
+        .into_iter()
+        .map(|migration| (migration.version, migration))
+        .collect();
+
+    let mut statuses: Vec<MigrationStatus> = MIGRATIONS
+        .iter()
+        .map(|migration| match applied.remove(&migration.version) {
+            Some(record) => MigrationStatus {
+                version: migration.version,
+                name: migration.name.to_string(),
+                state: if record.checksum == migration.checksum() {
+                    MigrationState::Applied
+                } else {
+                    MigrationState::Modified
+                },
+                applied_at: Some(record.applied_at),
+            },
+            None => MigrationStatus {
+                version: migration.version,
+                name: migration.name.to_string(),
+                state: MigrationState::Pending,
+                applied_at: None,
+            },
+        })
+        .collect();
+    statuses.extend(applied.into_values().map(|record| MigrationStatus {
+        version: record.version,
+        name: record.name,
+        state: MigrationState::Unknown,
+        applied_at: Some(record.applied_at),
+    }));
+    statuses.sort_by_key(|status| status.version);
+    Ok(statuses)
+}
+
+/// Applies every pending migration in order and returns the versions
+/// applied. Nothing is applied when a recorded migration was edited or is
+/// unknown to this build.
+pub async fn migrate(repo: &Database) -> Result<Vec<i64>> {
+    let statuses = status(repo).await?;
+    for status in &statuses {
+        match status.state {
+            MigrationState::Modified => {
+                return Err(GatewayError::DatabaseError(format!(
+                    "Migration {} ({}) has changed since it was applied",
+                    status.version, status.name
+                )))
+            }
+            MigrationState::Unknown => {
+                return Err(GatewayError::DatabaseError(format!(
+                    "The database has migration {} ({}), which this version of the gateway does not know",
+                    status.version, status.name
+                )))
+            }
+            MigrationState::Applied | MigrationState::Pending => {}
+        }
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS {
+        let pending = statuses.iter().any(|status| {
+            status.version == migration.version && status.state == MigrationState::Pending
+        });
+        if pending {
+            apply(repo, migration).await?;
+            applied.push(migration.version);
+        }
+    }
+    Ok(applied)
+}
+
+async fn apply(repo: &Database, migration: &Migration) -> Result<()> {
+    log::info!(
+        "Applying migration {} ({})",
+        migration.version,
+        migration.name
+    );
+    let record = AppliedMigration {
+        version: migration.version,
+        name: migration.name.to_string(),
+        checksum: migration.checksum(),
+        applied_at: Datetime::default(),
+    };
+    // Recording the migration in the same transaction also stops two
+    // instances from applying it at once
+    let transaction = Transaction::new()
+        .statement(migration.sql)
+        .statement("CREATE type::thing($table, $version) CONTENT $record")
+        .bind("table", MIGRATIONS_TABLE)?
+        .bind("version", migration.version)?
+        .bind("record", record)?;
+    repo.commit(transaction).await.map_err(|e| {
+        GatewayError::DatabaseError(format!(
+            "Migration {} ({}) failed: {}",
+            migration.version, migration.name, e
+        ))
+    })
+}
@@ -0,0 +1,4 @@
+pub mod models;
+pub mod page;
+pub mod repo;
+pub mod web;
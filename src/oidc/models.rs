@@ -0,0 +1,342 @@
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use validator::Validate;
+
+use crate::api_services::models::WebApiRole;
+use crate::errors::{GatewayError, Result};
+
+// Scopes every client may request, which don't grant roles
+pub const OPENID_SCOPE: &str = "openid";
+pub const PROFILE_SCOPE: &str = "profile";
+pub const STANDARD_SCOPES: [&str; 2] = [OPENID_SCOPE, PROFILE_SCOPE];
+
+/// A scope a client application may request, and the gateway roles it grants
+/// when the user holds them.
+#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
+pub struct ClientScope {
+    #[validate(length(min = 1))]
+    pub name: String,
+    // Roles as `Namespace::Name`
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
+pub struct WebOidcClientRequest {
+    #[validate(length(min = 1))]
+    pub name: String,
+
+    // Where users may be sent back to after signing in, compared exactly
+    #[validate(length(min = 1))]
+    pub redirect_uris: Vec<String>,
+
+    #[serde(default)]
+    #[validate]
+    pub scopes: Vec<ClientScope>,
+
+    // Confidential clients authenticate with a secret; public clients, such
+    // as single page apps, can't keep one and rely on PKCE alone
+    #[serde(default)]
+    pub confidential: bool,
+}
+
+impl WebOidcClientRequest {
+    /// Checks what `validate` can't: the redirect URIs, scope names and roles.
+    pub fn check(&self) -> Result<()> {
+        for redirect_uri in &self.redirect_uris {
+            let url = Url::parse(redirect_uri).map_err(|e| {
+                GatewayError::BadRequest(format!(
+                    "redirect_uris: [{}] is not a URL: {}",
+                    redirect_uri, e
+                ))
+            })?;
+            // Plain http is only safe when the redirect never leaves the machine
+            let loopback = matches!(
+                url.host_str(),
+                Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+            );
+            if url.fragment().is_some() || !(url.scheme() == "https" || loopback) {
+                return Err(GatewayError::BadRequest(format!(
+                    "redirect_uris: [{}] must use https, or http on a loopback address, \
+                    and have no fragment",
+                    redirect_uri
+                )));
+            }
+        }
+        for scope in &self.scopes {
+            if STANDARD_SCOPES.contains(&scope.name.as_str())
+                || scope.name.contains(char::is_whitespace)
+            {
+                return Err(GatewayError::BadRequest(format!(
+                    "scopes: [{}] is reserved or contains whitespace",
+                    scope.name
+                )));
+            }
+            for role in &scope.roles {
+                role.parse::<WebApiRole>()
+                    .map_err(|e| GatewayError::BadRequest(format!("scopes: {}", e)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DbOidcClientRequest {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<ClientScope>,
+    pub secret_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbOidcClient {
+    pub id: Thing,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<ClientScope>,
+    pub secret_hash: Option<String>,
+    pub created_at: Datetime,
+}
+
+impl DbOidcClient {
+    /// The roles the scopes grant, among those the user holds.
+    pub fn granted_roles(&self, scope: &[String], user_roles: &[String]) -> Vec<String> {
+        user_roles
+            .iter()
+            .filter(|role| {
+                self.scopes
+                    .iter()
+                    .filter(|client_scope| scope.contains(&client_scope.name))
+                    .any(|client_scope| client_scope.roles.contains(role))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebOidcClient {
+    // The client_id
+    pub id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<ClientScope>,
+    pub confidential: bool,
+    pub created_at: Datetime,
+    // Only returned when the secret is generated, since it isn't stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+impl From<&DbOidcClient> for WebOidcClient {
+    fn from(value: &DbOidcClient) -> Self {
+        Self {
+            id: format!("{}", value.id.id),
+            name: value.name.clone(),
+            redirect_uris: value.redirect_uris.clone(),
+            scopes: value.scopes.clone(),
+            confidential: value.secret_hash.is_some(),
+            created_at: value.created_at.clone(),
+            client_secret: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DbAuthorizationCodeRequest {
+    pub code_hash: String,
+    pub client: Thing,
+    pub user: Thing,
+    pub redirect_uri: String,
+    pub scope: Vec<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub expires_at: Datetime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbAuthorizationCode {
+    pub id: Thing,
+    pub client: Thing,
+    pub user: Thing,
+    pub redirect_uri: String,
+    pub scope: Vec<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub created_at: Datetime,
+    pub expires_at: Datetime,
+    pub used_at: Option<Datetime>,
+    pub session: Option<Thing>,
+}
+
+/// The query string of an authorization request, which the login form
+/// carries through to its submission.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AuthorizationRequest {
+    pub response_type: Option<String>,
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub prompt: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    #[serde(flatten)]
+    pub request: AuthorizationRequest,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OidcTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+    pub refresh_token: String,
+    pub id_token: String,
+    pub scope: String,
+}
+
+/// Claims of an ID token, telling the client who signed in.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    // The user's id
+    pub sub: String,
+    // The client_id
+    pub aud: String,
+    pub exp: u64,
+    pub iat: u64,
+    // When the user entered their password
+    pub auth_time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    pub sid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    // The gateway roles granted to the client
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    pub roles: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_request(redirect_uri: &str, scope: ClientScope) -> WebOidcClientRequest {
+        WebOidcClientRequest {
+            name: "Shop".into(),
+            redirect_uris: vec![redirect_uri.into()],
+            scopes: vec![scope],
+            confidential: false,
+        }
+    }
+
+    fn shop_scope(name: &str, roles: &[&str]) -> ClientScope {
+        ClientScope {
+            name: name.into(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn redirect_uris_need_https_unless_on_loopback() {
+        let scope = shop_scope("shop", &["Shop::Reader"]);
+        for redirect_uri in [
+            "https://shop.example/callback",
+            "http://localhost:8080/callback",
+            "http://127.0.0.1/callback",
+            "http://[::1]:3000/callback",
+        ] {
+            assert!(client_request(redirect_uri, scope.clone()).check().is_ok());
+        }
+        for redirect_uri in [
+            "http://shop.example/callback",
+            "https://shop.example/callback#fragment",
+            "shop.example/callback",
+        ] {
+            assert!(matches!(
+                client_request(redirect_uri, scope.clone()).check(),
+                Err(GatewayError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn scopes_must_not_shadow_standard_scopes_or_name_bad_roles() {
+        let redirect_uri = "https://shop.example/callback";
+        for scope in [
+            shop_scope(OPENID_SCOPE, &[]),
+            shop_scope("shop read", &[]),
+            shop_scope("shop", &["Reader"]),
+        ] {
+            assert!(matches!(
+                client_request(redirect_uri, scope).check(),
+                Err(GatewayError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn scopes_grant_only_requested_roles_the_user_holds() {
+        let client = DbOidcClient {
+            id: Thing::from(("oidc_client", "shop")),
+            name: "Shop".into(),
+            redirect_uris: vec![],
+            scopes: vec![
+                shop_scope("shop.read", &["Shop::Reader"]),
+                shop_scope("shop.write", &["Shop::Writer", "Shop::Admin"]),
+            ],
+            secret_hash: None,
+            created_at: Datetime::default(),
+        };
+        let user_roles = vec![
+            "Shop::Reader".into(),
+            "Shop::Writer".into(),
+            "Other::Role".into(),
+        ];
+
+        let scope = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            client.granted_roles(&scope(&["openid", "shop.read"]), &user_roles),
+            vec!["Shop::Reader"]
+        );
+        assert_eq!(
+            client.granted_roles(&scope(&["openid", "shop.read", "shop.write"]), &user_roles),
+            vec!["Shop::Reader", "Shop::Writer"]
+        );
+        assert!(client
+            .granted_roles(&scope(&["openid"]), &user_roles)
+            .is_empty());
+    }
+}
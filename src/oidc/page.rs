@@ -0,0 +1,102 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::HttpResponse;
+
+use super::models::AuthorizationRequest;
+
+// Inline styles only; the page loads nothing else
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; style-src 'unsafe-inline'; frame-ancestors 'none'";
+
+const STYLE: &str = "body{font-family:sans-serif;background:#f4f5f7;display:flex;\
+justify-content:center;padding-top:10vh}main{background:#fff;padding:2em;border-radius:8px;\
+box-shadow:0 1px 4px rgba(0,0,0,.2);width:20em}label,input,button{display:block;width:100%;\
+box-sizing:border-box}input{margin:.25em 0 1em;padding:.5em}button{padding:.6em}\
+.error{color:#b00020}";
+
+/// The form a user signs in with to approve an authorization request. The
+/// request's parameters are carried through as hidden fields.
+pub fn login_page(
+    client_name: &str,
+    request: &AuthorizationRequest,
+    username: &str,
+    error: Option<&str>,
+    status: StatusCode,
+) -> HttpResponse {
+    let hidden_fields = [
+        ("response_type", &request.response_type),
+        ("client_id", &request.client_id),
+        ("redirect_uri", &request.redirect_uri),
+        ("scope", &request.scope),
+        ("state", &request.state),
+        ("nonce", &request.nonce),
+        ("code_challenge", &request.code_challenge),
+        ("code_challenge_method", &request.code_challenge_method),
+    ]
+    .iter()
+    .filter_map(|(name, value)| {
+        value.as_ref().map(|value| {
+            format!(
+                "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+                name,
+                escape(value)
+            )
+        })
+    })
+    .collect::<String>();
+    let error = error
+        .map(|error| format!("<p class=\"error\">{}</p>", escape(error)))
+        .unwrap_or_default();
+    let body = format!(
+        "<h1>Sign in</h1><p>to continue to <strong>{}</strong></p>{}\
+        <form method=\"post\" action=\"authorize\">{}\
+        <label for=\"username\">Username</label>\
+        <input id=\"username\" name=\"username\" value=\"{}\" autocomplete=\"username\" required autofocus>\
+        <label for=\"password\">Password</label>\
+        <input id=\"password\" name=\"password\" type=\"password\" autocomplete=\"current-password\" required>\
+        <button type=\"submit\">Sign in</button></form>",
+        escape(client_name),
+        error,
+        hidden_fields,
+        escape(username)
+    );
+    page(status, "Sign in", &body)
+}
+
+/// Explains an authorization request that can't be sent back to the client,
+/// because the client or its redirect URI is unknown.
+pub fn error_page(message: &str) -> HttpResponse {
+    let body = format!(
+        "<h1>Unable to sign in</h1><p class=\"error\">{}</p>",
+        escape(message)
+    );
+    page(StatusCode::BAD_REQUEST, "Unable to sign in", &body)
+}
+
+fn page(status: StatusCode, title: &str, body: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header((header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY))
+        .insert_header((header::X_FRAME_OPTIONS, "DENY"))
+        .body(format!(
+            "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"utf-8\">\
+            <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+            <title>{}</title><style>{}</style></head><body><main>{}</main></body></html>",
+            title, STYLE, body
+        ))
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,291 @@
+use actix_web::web::Data;
+use async_trait::async_trait;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use surrealdb::sql::{Duration, Id, Thing};
+
+use super::models::{
+    DbAuthorizationCode, DbAuthorizationCodeRequest, DbOidcClient, DbOidcClientRequest,
+};
+use crate::audit::models::{Actor, AuditAction};
+use crate::audit::repo::audit_within;
+use crate::auth::models::{DbSession, SessionEnd};
+use crate::auth::repo::{add_refresh_token, end_sessions, session_record};
+use crate::database::{
+    Database, Transaction, AUTHORIZATION_CODE_TABLE, OIDC_CLIENT_TABLE, SESSION_TABLE,
+};
+use crate::errors::{GatewayError, Result};
+
+// Characters in client secrets and authorization codes, like refresh tokens
+const SECRET_LENGTH: usize = 48;
+// Thrown when a code turns out to be used by the time it is redeemed
+const AUTHORIZATION_CODE_REUSED: &str = "The authorization code was already used";
+
+#[async_trait]
+pub trait OidcClientRepository {
+    async fn list_oidc_clients(repo: &Data<Database>) -> Result<Vec<DbOidcClient>>;
+    async fn get_oidc_client(repo: &Data<Database>, client_id: &str) -> Result<DbOidcClient>;
+    async fn add_oidc_client(
+        repo: &Data<Database>,
+        client: DbOidcClientRequest,
+        actor: &Actor,
+    ) -> Result<DbOidcClient>;
+    async fn update_oidc_client(
+        repo: &Data<Database>,
+        client_id: &str,
+        client: DbOidcClientRequest,
+        actor: &Actor,
+    ) -> Result<DbOidcClient>;
+    async fn delete_oidc_client(
+        repo: &Data<Database>,
+        client_id: &str,
+        actor: &Actor,
+    ) -> Result<()>;
+}
+
+#[async_trait]
+impl OidcClientRepository for Database {
+    async fn list_oidc_clients(repo: &Data<Database>) -> Result<Vec<DbOidcClient>> {
+        repo.query_list(
+            format!("SELECT * FROM {} ORDER BY name", OIDC_CLIENT_TABLE),
+            None::<String>,
+        )
+        .await
+    }
+
+    async fn get_oidc_client(repo: &Data<Database>, client_id: &str) -> Result<DbOidcClient> {
+        let client: Option<DbOidcClient> = repo
+            .db
+            .select((OIDC_CLIENT_TABLE, client_id))
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        client.ok_or(GatewayError::NotFound(
+            String::from("OIDC Client"),
+            format!("No client with id [{}] found.", client_id),
+        ))
+    }
+
+    async fn add_oidc_client(
+        repo: &Data<Database>,
+        client: DbOidcClientRequest,
+        actor: &Actor,
+    ) -> Result<DbOidcClient> {
+        let client_id = Thing::from((OIDC_CLIENT_TABLE, Id::rand()));
+        let entry = actor.entry(
+            AuditAction::OidcClientCreated,
+            Some(client_id.clone()),
+            json!({ "name": client.name, "confidential": client.secret_hash.is_some() }),
+        );
+        let transaction = Transaction::new()
+            .statement("CREATE $client_id CONTENT $client")
+            .bind("client_id", &client_id)?
+            .bind("client", client)?;
+        repo.commit(audit_within(transaction, vec![entry])?).await?;
+        Database::get_oidc_client(repo, &format!("{}", client_id.id)).await
+    }
+
+    // Replaces the client's settings; its secret is replaced with
+    // `client.secret_hash`
+    async fn update_oidc_client(
+        repo: &Data<Database>,
+        client_id: &str,
+        client: DbOidcClientRequest,
+        actor: &Actor,
+    ) -> Result<DbOidcClient> {
+        let existing = Database::get_oidc_client(repo, client_id).await?;
+        let entry = actor.entry(
+            AuditAction::OidcClientUpdated,
+            Some(existing.id.clone()),
+            json!({
+                "name": client.name,
+                "confidential": client.secret_hash.is_some(),
+                "secret_changed": client.secret_hash != existing.secret_hash,
+            }),
+        );
+        let transaction = Transaction::new()
+            .statement("UPDATE $client_id MERGE $client")
+            .bind("client_id", &existing.id)?
+            .bind("client", client)?;
+        repo.commit(audit_within(transaction, vec![entry])?).await?;
+        Database::get_oidc_client(repo, client_id).await
+    }
+
+    // Also ends the client's sessions, so its refresh tokens stop working
+    async fn delete_oidc_client(
+        repo: &Data<Database>,
+        client_id: &str,
+        actor: &Actor,
+    ) -> Result<()> {
+        let existing = Database::get_oidc_client(repo, client_id).await?;
+        let entry = actor.entry(
+            AuditAction::OidcClientDeleted,
+            Some(existing.id.clone()),
+            json!({ "name": existing.name }),
+        );
+        let transaction = Transaction::new()
+            .statement(format!(
+                "DELETE {} WHERE client = $client_id;\nDELETE $client_id;",
+                AUTHORIZATION_CODE_TABLE
+            ))
+            .bind("client_id", &existing.id)?;
+        let transaction = end_sessions(transaction, "client = $client_id", SessionEnd::Revoked)?;
+        repo.commit(audit_within(transaction, vec![entry])?).await
+    }
+}
+
+#[async_trait]
+pub trait AuthorizationCodeRepository {
+    async fn create_authorization_code(
+        repo: &Data<Database>,
+        code: DbAuthorizationCodeRequest,
+    ) -> Result<()>;
+    async fn find_authorization_code(
+        repo: &Data<Database>,
+        code: &str,
+    ) -> Result<DbAuthorizationCode>;
+    async fn redeem_authorization_code(
+        repo: &Data<Database>,
+        code: &DbAuthorizationCode,
+        user_agent: Option<String>,
+        actor: &Actor,
+        lifetime_secs: u64,
+    ) -> Result<(DbSession, String)>;
+    async fn reject_reused_code(
+        repo: &Data<Database>,
+        code: &DbAuthorizationCode,
+        actor: &Actor,
+    ) -> Result<()>;
+}
+
+#[async_trait]
+impl AuthorizationCodeRepository for Database {
+    async fn create_authorization_code(
+        repo: &Data<Database>,
+        code: DbAuthorizationCodeRequest,
+    ) -> Result<()> {
+        // Expired codes are dropped as new ones are made
+        let transaction = Transaction::new()
+            .statement(format!(
+                "DELETE {table} WHERE expires_at < time::now();\n\
+                CREATE {table} CONTENT $code;",
+                table = AUTHORIZATION_CODE_TABLE
+            ))
+            .bind("code", code)?;
+        repo.commit(transaction).await
+    }
+
+    async fn find_authorization_code(
+        repo: &Data<Database>,
+        code: &str,
+    ) -> Result<DbAuthorizationCode> {
+        let codes: Vec<DbAuthorizationCode> = repo
+            .query_list(
+                format!(
+                    "SELECT * FROM {} WHERE code_hash = $code_hash LIMIT 1",
+                    AUTHORIZATION_CODE_TABLE
+                ),
+                Some(("code_hash", hash_secret(code))),
+            )
+            .await?;
+        codes
+            .into_iter()
+            .next()
+            .ok_or(GatewayError::Unauthorized(String::from(
+                "Invalid authorization code",
+            )))
+    }
+
+    // Starts the session the code was issued for, with its first refresh token
+    async fn redeem_authorization_code(
+        repo: &Data<Database>,
+        code: &DbAuthorizationCode,
+        user_agent: Option<String>,
+        actor: &Actor,
+        lifetime_secs: u64,
+    ) -> Result<(DbSession, String)> {
+        let session_id = Thing::from((SESSION_TABLE, Id::rand()));
+        // Of two requests racing to redeem the code, only the first commits
+        let transaction = Transaction::new()
+            .statement(format!(
+                "IF (SELECT VALUE used_at FROM ONLY $code) != NONE {{ THROW '{}' }};",
+                AUTHORIZATION_CODE_REUSED
+            ))
+            .statement("UPDATE $code SET used_at = time::now(), session = $session_id")
+            .statement(
+                "CREATE $session_id CONTENT { user: $user, user_agent: $user_agent, \
+                source_ip: $source_ip, client: $client, scope: $session_scope, \
+                expires_at: time::now() + $lifetime }",
+            )
+            .bind("code", &code.id)?
+            .bind("session_id", &session_id)?
+            .bind("user", &code.user)?
+            .bind("user_agent", user_agent)?
+            .bind("source_ip", &actor.source_ip)?
+            .bind("client", &code.client)?
+            .bind("session_scope", &code.scope)?
+            .bind("lifetime", Duration::from_secs(lifetime_secs))?;
+        let (transaction, refresh_token) = add_refresh_token(transaction)?;
+        match repo.commit(transaction).await {
+            // The request that won has started its session by now
+            Err(GatewayError::Conflict(message)) if message == AUTHORIZATION_CODE_REUSED => {
+                let used: Option<DbAuthorizationCode> = repo
+                    .db
+                    .select(&code.id)
+                    .await
+                    .map_err(Into::<GatewayError>::into)?;
+                if let Some(used) = used {
+                    Database::reject_reused_code(repo, &used, actor).await?;
+                }
+                return Err(GatewayError::Unauthorized(String::from(
+                    "The authorization code was already used, so its session has been revoked",
+                )));
+            }
+            result => result?,
+        }
+
+        Ok((session_record(repo, &session_id).await?, refresh_token))
+    }
+
+    // A code presented twice may have been intercepted, so the session it
+    // started can't be trusted either
+    async fn reject_reused_code(
+        repo: &Data<Database>,
+        code: &DbAuthorizationCode,
+        actor: &Actor,
+    ) -> Result<()> {
+        let mut transaction = Transaction::new();
+        if let Some(session_id) = &code.session {
+            transaction = end_sessions(
+                transaction.bind("session_id", session_id)?,
+                "id = $session_id",
+                SessionEnd::TokenReuse,
+            )?;
+        }
+        let entry = actor.entry(
+            AuditAction::AuthorizationCodeReused,
+            Some(code.user.clone()),
+            json!({
+                "client": format!("{}", code.client.id),
+                "session": code.session.as_ref().map(|session| format!("{}", session.id)),
+            }),
+        );
+        repo.commit(audit_within(transaction, vec![entry])?).await
+    }
+}
+
+/// A random client secret or authorization code.
+pub fn generate_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Client secrets and authorization codes are random enough that a plain
+/// hash protects them, like refresh tokens.
+pub fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
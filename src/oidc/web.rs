@@ -0,0 +1,1051 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{
+    delete, get, post, put, route,
+    web::{scope, to, Data, Form, Json, Path, Query, ServiceConfig},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use clap::ValueEnum;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use surrealdb::sql::Datetime;
+use thiserror::Error;
+use validator::Validate;
+
+use super::models::{
+    AuthorizationRequest, DbAuthorizationCodeRequest, DbOidcClient, DbOidcClientRequest,
+    IdTokenClaims, LoginForm, OidcTokenResponse, TokenRequest, UserInfo, WebOidcClient,
+    WebOidcClientRequest, OPENID_SCOPE, PROFILE_SCOPE, STANDARD_SCOPES,
+};
+use super::page::{error_page, login_page};
+use super::repo::{
+    generate_secret, hash_secret, AuthorizationCodeRepository, OidcClientRepository,
+};
+use crate::audit;
+use crate::audit::models::{Actor, AuditAction};
+use crate::auth::keys::SigningKeys;
+use crate::auth::models::{JwtConfig, SigningAlgorithm};
+use crate::auth::repo::{SessionRepository, UserAuthRepository};
+use crate::auth::web::{access_token_claims, validate_jwt};
+use crate::config::GatewayConfig;
+use crate::database::Database;
+use crate::errors::{unknown_resource_error, GatewayError, Result};
+use crate::users::repo::UserRepository;
+
+// Codes are exchanged as soon as the client receives them
+const AUTHORIZATION_CODE_LIFETIME_SECS: i64 = 60;
+
+// Intermediate function to configure services
+pub fn service_setup(cfg: &mut ServiceConfig) {
+    cfg.service(discovery)
+        .service(
+            scope("/oidc")
+                .service(authorize)
+                .service(authorize_login)
+                .service(token)
+                .service(userinfo)
+                .default_service(to(unknown_resource_error)),
+        )
+        .service(
+            scope("/cfg/v1/oidc-clients")
+                .service(list_clients)
+                .service(add_client)
+                .service(get_client)
+                .service(update_client)
+                .service(reset_client_secret)
+                .service(delete_client)
+                .default_service(to(unknown_resource_error)),
+        );
+}
+
+#[get("/.well-known/openid-configuration")]
+async fn discovery(config: Data<GatewayConfig>) -> HttpResponse {
+    let issuer = issuer(&config);
+    let algorithms: Vec<String> = SigningAlgorithm::value_variants()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/oidc/authorize", issuer),
+            "token_endpoint": format!("{}/oidc/token", issuer),
+            "userinfo_endpoint": format!("{}/oidc/userinfo", issuer),
+            "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code", "refresh_token"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": algorithms,
+            "scopes_supported": STANDARD_SCOPES,
+            "token_endpoint_auth_methods_supported":
+                ["client_secret_basic", "client_secret_post", "none"],
+            "code_challenge_methods_supported": ["S256"],
+            "claims_supported": [
+                "sub", "iss", "aud", "exp", "iat", "auth_time", "nonce", "sid",
+                "preferred_username", "roles"
+            ],
+            "authorization_response_iss_parameter_supported": true,
+        }))
+}
+
+/**
+ * Authorization code flow
+ */
+
+#[get("/authorize")]
+async fn authorize(
+    repo: Data<Database>,
+    config: Data<GatewayConfig>,
+    request: Query<AuthorizationRequest>,
+) -> HttpResponse {
+    let request = request.into_inner();
+    match check_authorization(&repo, &config, &request).await {
+        Ok(valid) => login_page(&valid.client.name, &request, "", None, StatusCode::OK),
+        Err(response) => response,
+    }
+}
+
+// An authorization request checked against its client's registration
+struct ValidRequest {
+    client: DbOidcClient,
+    // As registered, which the token request must repeat exactly
+    redirect_uri: String,
+    scope: Vec<String>,
+    code_challenge: String,
+}
+
+#[post("/authorize")]
+async fn authorize_login(
+    req: HttpRequest,
+    repo: Data<Database>,
+    config: Data<GatewayConfig>,
+    form: Form<LoginForm>,
+) -> Result<HttpResponse> {
+    let form = form.into_inner();
+    let valid = match check_authorization(&repo, &config, &form.request).await {
+        Ok(valid) => valid,
+        Err(response) => return Ok(response),
+    };
+    let client_id = format!("{}", valid.client.id.id);
+    let authenticated = Database::authenticate_user(&repo, &form.username, &form.password).await;
+    if let Err(GatewayError::InvalidUsernameOrPassword(_)) = &authenticated {
+        audit::repo::record(
+            &repo,
+            Actor::anonymous(&req).entry(
+                AuditAction::LoginFailed,
+                None,
+                json!({ "username": form.username, "client": client_id }),
+            ),
+        )
+        .await;
+        return Ok(login_page(
+            &valid.client.name,
+            &form.request,
+            &form.username,
+            Some("Invalid username or password"),
+            StatusCode::UNAUTHORIZED,
+        ));
+    }
+    let user = authenticated?;
+
+    let code = generate_secret();
+    Database::create_authorization_code(
+        &repo,
+        DbAuthorizationCodeRequest {
+            code_hash: hash_secret(&code),
+            client: valid.client.id.clone(),
+            user: user.id.clone(),
+            redirect_uri: valid.redirect_uri.clone(),
+            scope: valid.scope,
+            nonce: form.request.nonce.clone(),
+            code_challenge: valid.code_challenge,
+            expires_at: Datetime::from(
+                chrono::Utc::now() + chrono::Duration::seconds(AUTHORIZATION_CODE_LIFETIME_SECS),
+            ),
+        },
+    )
+    .await?;
+
+    let user_id = format!("{}", user.id.id);
+    Database::set_last_login(&repo, &user_id).await?;
+    let actor = Actor {
+        user_id: Some(user_id),
+        username: Some(user.username.clone()),
+        ..Actor::anonymous(&req)
+    };
+    audit::repo::record(
+        &repo,
+        actor.entry(
+            AuditAction::LoginSucceeded,
+            Some(user.id),
+            json!({ "client": client_id }),
+        ),
+    )
+    .await;
+    Ok(redirect_to_client(
+        &valid.redirect_uri,
+        &[("code", code.as_str())],
+        &form.request,
+        &config,
+    ))
+}
+
+// Refusals are shown to the user while the client or its redirect URI is
+// unknown, and sent back to the client once they aren't
+async fn check_authorization(
+    repo: &Data<Database>,
+    config: &GatewayConfig,
+    request: &AuthorizationRequest,
+) -> std::result::Result<ValidRequest, HttpResponse> {
+    let client_id = request
+        .client_id
+        .as_deref()
+        .ok_or_else(|| error_page("The request does not name a client application."))?;
+    let client = match Database::get_oidc_client(repo, client_id).await {
+        Ok(client) => client,
+        Err(GatewayError::NotFound(_, _)) => {
+            return Err(error_page(&format!(
+                "Unknown client application [{}].",
+                client_id
+            )))
+        }
+        Err(e) => return Err(e.error_response()),
+    };
+    let redirect_uri = request
+        .redirect_uri
+        .as_ref()
+        .filter(|redirect_uri| client.redirect_uris.contains(redirect_uri))
+        .ok_or_else(|| {
+            error_page("The redirect URI is not registered for this client application.")
+        })?;
+    let refuse = |error: &str, description: &str| {
+        redirect_to_client(
+            redirect_uri,
+            &[("error", error), ("error_description", description)],
+            request,
+            config,
+        )
+    };
+
+    if request.response_type.as_deref() != Some("code") {
+        return Err(refuse(
+            "unsupported_response_type",
+            "Only the code response type is supported",
+        ));
+    }
+    let scope: Vec<String> = request
+        .scope
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    if !scope.iter().any(|scope| scope == OPENID_SCOPE) {
+        return Err(refuse("invalid_scope", "The openid scope is required"));
+    }
+    if let Some(unknown) = scope.iter().find(|scope| {
+        !STANDARD_SCOPES.contains(&scope.as_str())
+            && !client
+                .scopes
+                .iter()
+                .any(|client_scope| &client_scope.name == *scope)
+    }) {
+        return Err(refuse(
+            "invalid_scope",
+            &format!("The client may not request the {} scope", unknown),
+        ));
+    }
+    // A verifier hashes to 43 base64url characters
+    let code_challenge = request
+        .code_challenge
+        .as_ref()
+        .filter(|challenge| {
+            challenge.len() == 43
+                && challenge
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .filter(|_| request.code_challenge_method.as_deref() == Some("S256"))
+        .ok_or_else(|| {
+            refuse(
+                "invalid_request",
+                "PKCE is required, with a code_challenge and code_challenge_method S256",
+            )
+        })?;
+    // Signing in always needs the user, since the gateway keeps no browser session
+    if request
+        .prompt
+        .as_deref()
+        .is_some_and(|prompt| prompt.split_whitespace().any(|prompt| prompt == "none"))
+    {
+        return Err(refuse("login_required", "The user must sign in"));
+    }
+
+    Ok(ValidRequest {
+        redirect_uri: redirect_uri.clone(),
+        client,
+        scope,
+        code_challenge: code_challenge.clone(),
+    })
+}
+
+fn redirect_to_client(
+    redirect_uri: &str,
+    params: &[(&str, &str)],
+    request: &AuthorizationRequest,
+    config: &GatewayConfig,
+) -> HttpResponse {
+    // Registered redirect URIs were checked to be URLs
+    let mut location = Url::parse(redirect_uri).unwrap();
+    {
+        let mut query = location.query_pairs_mut();
+        query.extend_pairs(params);
+        if let Some(state) = &request.state {
+            query.append_pair("state", state);
+        }
+        query.append_pair("iss", &issuer(config));
+    }
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, location.as_str()))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .finish()
+}
+
+/**
+ * Token and userinfo endpoints
+ */
+
+#[post("/token")]
+async fn token(
+    req: HttpRequest,
+    repo: Data<Database>,
+    config: Data<GatewayConfig>,
+    jwt_config: Data<JwtConfig>,
+    signing_keys: Data<SigningKeys>,
+    form: Form<TokenRequest>,
+) -> std::result::Result<HttpResponse, TokenError> {
+    let form = form.into_inner();
+    let client = authenticate_client(&repo, &req, &form).await?;
+    let actor = Actor::anonymous(&req);
+    let (session, refresh_token, nonce) = match form.grant_type.as_str() {
+        "authorization_code" => {
+            let code =
+                Database::find_authorization_code(&repo, required(&form.code, "code")?).await?;
+            if code.used_at.is_some() {
+                Database::reject_reused_code(&repo, &code, &actor).await?;
+                return Err(TokenError::new(
+                    "invalid_grant",
+                    "The authorization code was already used, so its session has been revoked",
+                ));
+            }
+            if code.expires_at.0 <= chrono::Utc::now() {
+                return Err(TokenError::new(
+                    "invalid_grant",
+                    "The authorization code has expired",
+                ));
+            }
+            if code.client != client.id || form.redirect_uri.as_ref() != Some(&code.redirect_uri) {
+                return Err(TokenError::new(
+                    "invalid_grant",
+                    "The authorization code was issued to another client or redirect URI",
+                ));
+            }
+            let verifier = required(&form.code_verifier, "code_verifier")?;
+            if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != code.code_challenge {
+                return Err(TokenError::new(
+                    "invalid_grant",
+                    "The code_verifier does not match the code_challenge",
+                ));
+            }
+            let user_agent = req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from);
+            let (session, refresh_token) = Database::redeem_authorization_code(
+                &repo,
+                &code,
+                user_agent,
+                &actor,
+                jwt_config.refresh_token_lifetime_secs,
+            )
+            .await?;
+            (session, refresh_token, code.nonce)
+        }
+        "refresh_token" => {
+            let (session, refresh_token) = Database::refresh_session(
+                &repo,
+                required(&form.refresh_token, "refresh_token")?,
+                Some(&client.id),
+                &actor,
+                jwt_config.refresh_token_lifetime_secs,
+            )
+            .await?;
+            (session, refresh_token, None)
+        }
+        _ => {
+            return Err(TokenError::new(
+                "unsupported_grant_type",
+                "Only the authorization_code and refresh_token grants are supported",
+            ))
+        }
+    };
+
+    // The tokens carry the roles the user holds now
+    let user = Database::user_detail(&repo, &format!("{}", session.user.id)).await?;
+    if user.disabled {
+        return Err(TokenError::new(
+            "invalid_grant",
+            "The account has been disabled",
+        ));
+    }
+    let scope = session.scope.clone().unwrap_or_default();
+    let user_roles: Vec<String> = user.roles.iter().map(ToString::to_string).collect();
+    let roles = client.granted_roles(&scope, &user_roles);
+    let access_claims = access_token_claims(&jwt_config, &user, &session, roles.clone());
+    let id_claims = IdTokenClaims {
+        iss: issuer(&config),
+        sub: format!("{}", user.id.id),
+        aud: format!("{}", client.id.id),
+        exp: access_claims.exp,
+        iat: access_claims.iat,
+        auth_time: session.created_at.0.timestamp() as u64,
+        nonce,
+        sid: format!("{}", session.id.id),
+        preferred_username: scope
+            .iter()
+            .any(|scope| scope == PROFILE_SCOPE)
+            .then(|| user.username.clone()),
+        roles,
+    };
+    let response = OidcTokenResponse {
+        access_token: signing_keys.sign(&access_claims)?,
+        token_type: String::from("Bearer"),
+        expires_in: jwt_config.token_lifetime_secs,
+        refresh_token,
+        id_token: signing_keys.sign(&id_claims)?,
+        scope: scope.join(" "),
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header((header::PRAGMA, "no-cache"))
+        .json(response))
+}
+
+/// A token request refused in the form RFC 6749 describes.
+#[derive(Debug, Error)]
+#[error("{error}: {description}")]
+struct TokenError {
+    error: &'static str,
+    description: String,
+}
+
+impl TokenError {
+    fn new(error: &'static str, description: &str) -> Self {
+        Self {
+            error,
+            description: description.to_string(),
+        }
+    }
+}
+
+impl ResponseError for TokenError {
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        HttpResponse::build(self.status_code())
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .json(json!({ "error": self.error, "error_description": self.description }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self.error {
+            "invalid_client" => StatusCode::UNAUTHORIZED,
+            "server_error" => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl From<GatewayError> for TokenError {
+    fn from(value: GatewayError) -> Self {
+        match value {
+            GatewayError::Unauthorized(message) | GatewayError::NotFound(_, message) => {
+                TokenError::new("invalid_grant", &message)
+            }
+            GatewayError::BadRequest(message) | GatewayError::MissingData(message) => {
+                TokenError::new("invalid_request", &message)
+            }
+            e => {
+                log::error!("Unable to complete a token request: {}", e);
+                TokenError::new("server_error", "The token request could not be completed")
+            }
+        }
+    }
+}
+
+// The client named by HTTP Basic credentials or the form, which must present
+// its secret if it has one
+async fn authenticate_client(
+    repo: &Data<Database>,
+    req: &HttpRequest,
+    form: &TokenRequest,
+) -> std::result::Result<DbOidcClient, TokenError> {
+    let basic = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|credentials| STANDARD.decode(credentials).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| {
+            credentials
+                .split_once(':')
+                .map(|(client_id, secret)| (client_id.to_string(), Some(secret.to_string())))
+        });
+    let (client_id, secret) = match basic {
+        Some(credentials) => credentials,
+        None => (
+            form.client_id.clone().ok_or(TokenError::new(
+                "invalid_client",
+                "The client was not identified",
+            ))?,
+            form.client_secret.clone(),
+        ),
+    };
+    let client = match Database::get_oidc_client(repo, &client_id).await {
+        Ok(client) => client,
+        Err(GatewayError::NotFound(_, _)) => {
+            return Err(TokenError::new("invalid_client", "Unknown client"))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let secret = secret.filter(|secret| !secret.is_empty());
+    let authenticated = match (&client.secret_hash, &secret) {
+        (Some(secret_hash), Some(secret)) => hash_secret(secret) == *secret_hash,
+        (None, None) => true,
+        _ => false,
+    };
+    if !authenticated {
+        return Err(TokenError::new(
+            "invalid_client",
+            "Client authentication failed",
+        ));
+    }
+    Ok(client)
+}
+
+fn required<'a>(
+    value: &'a Option<String>,
+    parameter: &str,
+) -> std::result::Result<&'a str, TokenError> {
+    value.as_deref().ok_or_else(|| {
+        TokenError::new(
+            "invalid_request",
+            &format!("The {} parameter is required", parameter),
+        )
+    })
+}
+
+#[route("/userinfo", method = "GET", method = "POST")]
+async fn userinfo(req: HttpRequest) -> Result<Json<UserInfo>> {
+    let claims = validate_jwt(&req, None)?;
+    // Tokens from the gateway's own login carry no scope, and see everything
+    let profile = claims
+        .scope
+        .as_ref()
+        .is_none_or(|scope| scope.split_whitespace().any(|scope| scope == PROFILE_SCOPE));
+    Ok(Json(UserInfo {
+        sub: claims.sub_id,
+        preferred_username: profile.then_some(claims.sub),
+        roles: claims.aud,
+    }))
+}
+
+fn issuer(config: &GatewayConfig) -> String {
+    config.server.public_url.trim_end_matches('/').to_string()
+}
+
+/**
+ * Client application management
+ */
+
+#[derive(Deserialize)]
+struct ClientIdPath {
+    pub client_id: String,
+}
+
+#[get("/")]
+async fn list_clients(req: HttpRequest, repo: Data<Database>) -> Result<Json<Vec<WebOidcClient>>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let clients = Database::list_oidc_clients(&repo).await?;
+    Ok(Json(clients.iter().map(Into::into).collect()))
+}
+
+// Confidential clients are given their secret in the response, and only there
+#[post("/")]
+async fn add_client(
+    req: HttpRequest,
+    client: Json<WebOidcClientRequest>,
+    repo: Data<Database>,
+) -> Result<Json<WebOidcClient>> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let client = client.into_inner();
+    client
+        .validate()
+        .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+    client.check()?;
+    let secret = client.confidential.then(generate_secret);
+    let created_client = Database::add_oidc_client(
+        &repo,
+        DbOidcClientRequest {
+            name: client.name,
+            redirect_uris: client.redirect_uris,
+            scopes: client.scopes,
+            secret_hash: secret.as_deref().map(hash_secret),
+        },
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(Json(WebOidcClient {
+        client_secret: secret,
+        ..WebOidcClient::from(&created_client)
+    }))
+}
+
+#[get("/{client_id}")]
+async fn get_client(
+    req: HttpRequest,
+    path_params: Path<ClientIdPath>,
+    repo: Data<Database>,
+) -> Result<Json<WebOidcClient>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let client = Database::get_oidc_client(&repo, &path_params.client_id).await?;
+    Ok(Json(WebOidcClient::from(&client)))
+}
+
+// A client made confidential is given a new secret; one made public loses it
+#[put("/{client_id}")]
+async fn update_client(
+    req: HttpRequest,
+    path_params: Path<ClientIdPath>,
+    client: Json<WebOidcClientRequest>,
+    repo: Data<Database>,
+) -> Result<Json<WebOidcClient>> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let client_id = path_params.into_inner().client_id;
+    let client = client.into_inner();
+    client
+        .validate()
+        .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+    client.check()?;
+    let existing = Database::get_oidc_client(&repo, &client_id).await?;
+    let secret = (client.confidential && existing.secret_hash.is_none()).then(generate_secret);
+    let secret_hash = match &secret {
+        Some(secret) => Some(hash_secret(secret)),
+        None if client.confidential => existing.secret_hash,
+        None => None,
+    };
+    let updated_client = Database::update_oidc_client(
+        &repo,
+        &client_id,
+        DbOidcClientRequest {
+            name: client.name,
+            redirect_uris: client.redirect_uris,
+            scopes: client.scopes,
+            secret_hash,
+        },
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(Json(WebOidcClient {
+        client_secret: secret,
+        ..WebOidcClient::from(&updated_client)
+    }))
+}
+
+#[post("/{client_id}/secret")]
+async fn reset_client_secret(
+    req: HttpRequest,
+    path_params: Path<ClientIdPath>,
+    repo: Data<Database>,
+) -> Result<Json<WebOidcClient>> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let client_id = path_params.into_inner().client_id;
+    let existing = Database::get_oidc_client(&repo, &client_id).await?;
+    if existing.secret_hash.is_none() {
+        return Err(GatewayError::BadRequest(String::from(
+            "Public clients have no secret",
+        )));
+    }
+    let secret = generate_secret();
+    let updated_client = Database::update_oidc_client(
+        &repo,
+        &client_id,
+        DbOidcClientRequest {
+            name: existing.name,
+            redirect_uris: existing.redirect_uris,
+            scopes: existing.scopes,
+            secret_hash: Some(hash_secret(&secret)),
+        },
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(Json(WebOidcClient {
+        client_secret: Some(secret),
+        ..WebOidcClient::from(&updated_client)
+    }))
+}
+
+#[delete("/{client_id}")]
+async fn delete_client(
+    req: HttpRequest,
+    path_params: Path<ClientIdPath>,
+    repo: Data<Database>,
+) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let client_id = path_params.into_inner().client_id;
+    Database::delete_oidc_client(&repo, &client_id, &Actor::from_claims(&req, &claims)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::api_services::models::WebApiRole;
+    use crate::api_services::repo::RoleRepository;
+    use crate::auth::keys::ensure_signing_key;
+    use crate::database::testing::database;
+    use crate::oidc::models::ClientScope;
+    use crate::secconf;
+    use crate::users::models::{DbGatewayUserRequest, InitialCredential};
+
+    const REDIRECT_URI: &str = "https://shop.example/callback";
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+    struct Gateway {
+        repo: Data<Database>,
+        config: Data<GatewayConfig>,
+        jwt_config: Data<JwtConfig>,
+        signing_keys: Data<SigningKeys>,
+        client_id: String,
+    }
+
+    // A user holding two roles, and a public client whose scope grants one
+    async fn gateway() -> Gateway {
+        let repo = database().await;
+        let config = GatewayConfig::default();
+        let mut jwt_config = secconf::load_jwt_config(&config.auth);
+        jwt_config.algorithm = SigningAlgorithm::EdDsa;
+        ensure_signing_key(&repo, jwt_config.algorithm, jwt_config.token_lifetime_secs)
+            .await
+            .unwrap();
+        let signing_keys = SigningKeys::new();
+        signing_keys
+            .refresh(&repo, jwt_config.token_lifetime_secs)
+            .await
+            .unwrap();
+
+        let mut roles = vec![];
+        for name in ["Reader", "Writer"] {
+            let role = WebApiRole {
+                id: None,
+                revision: None,
+                namespace: "Shop".into(),
+                name: name.into(),
+            };
+            roles.push(Database::find_or_add_role(&repo, &role).await.unwrap());
+        }
+        Database::register_user(
+            &repo,
+            DbGatewayUserRequest {
+                username: "alice".into(),
+            },
+            roles,
+            InitialCredential::Password("correct horse".into()),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let client_id = register_client(&repo).await;
+
+        Gateway {
+            repo,
+            config: Data::new(config),
+            jwt_config: Data::new(jwt_config),
+            signing_keys: Data::new(signing_keys),
+            client_id,
+        }
+    }
+
+    async fn register_client(repo: &Data<Database>) -> String {
+        let client = Database::add_oidc_client(
+            repo,
+            DbOidcClientRequest {
+                name: "Shop".into(),
+                redirect_uris: vec![REDIRECT_URI.into()],
+                scopes: vec![ClientScope {
+                    name: "shop.read".into(),
+                    roles: vec!["Shop::Reader".into()],
+                }],
+                secret_hash: None,
+            },
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        format!("{}", client.id.id)
+    }
+
+    macro_rules! app {
+        ($gateway:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data($gateway.repo.clone())
+                    .app_data($gateway.config.clone())
+                    .app_data($gateway.jwt_config.clone())
+                    .app_data($gateway.signing_keys.clone())
+                    .configure(service_setup),
+            )
+            .await
+        };
+    }
+
+    fn login(client_id: &str, scope: &str) -> test::TestRequest {
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(VERIFIER.as_bytes()));
+        test::TestRequest::post().uri("/oidc/authorize").set_form([
+            ("response_type", "code"),
+            ("client_id", client_id),
+            ("redirect_uri", REDIRECT_URI),
+            ("scope", scope),
+            ("state", "xyz"),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+            ("username", "alice"),
+            ("password", "correct horse"),
+        ])
+    }
+
+    fn redeem(
+        client_id: &str,
+        code: &str,
+        redirect_uri: &str,
+        verifier: &str,
+    ) -> test::TestRequest {
+        test::TestRequest::post().uri("/oidc/token").set_form([
+            ("grant_type", "authorization_code"),
+            ("client_id", client_id),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("code_verifier", verifier),
+        ])
+    }
+
+    fn location(response: &actix_web::dev::ServiceResponse) -> Url {
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let location = response.headers().get(header::LOCATION).unwrap();
+        Url::parse(location.to_str().unwrap()).unwrap()
+    }
+
+    fn query_param(url: &Url, name: &str) -> Option<String> {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    fn code(response: &actix_web::dev::ServiceResponse) -> String {
+        query_param(&location(response), "code").unwrap()
+    }
+
+    fn id_token_claims(id_token: &str) -> IdTokenClaims {
+        let payload = id_token.split('.').nth(1).unwrap();
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn token_requires_the_matching_code_verifier() {
+        let gateway = gateway().await;
+        let app = app!(gateway);
+        let response =
+            test::call_service(&app, login(&gateway.client_id, "openid").to_request()).await;
+        let code = code(&response);
+
+        let wrong_verifier = "x".repeat(43);
+        let request = redeem(&gateway.client_id, &code, REDIRECT_URI, &wrong_verifier);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["error"], "invalid_grant");
+
+        // A failed check does not use up the code
+        let request = redeem(&gateway.client_id, &code, REDIRECT_URI, VERIFIER);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn code_is_bound_to_its_client_and_redirect_uri() {
+        let gateway = gateway().await;
+        let other_client_id = register_client(&gateway.repo).await;
+        let app = app!(gateway);
+        let response =
+            test::call_service(&app, login(&gateway.client_id, "openid").to_request()).await;
+        let code = code(&response);
+
+        for request in [
+            redeem(&other_client_id, &code, REDIRECT_URI, VERIFIER),
+            redeem(
+                &gateway.client_id,
+                &code,
+                "https://shop.example/other",
+                VERIFIER,
+            ),
+        ] {
+            let response = test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(body["error"], "invalid_grant");
+        }
+    }
+
+    #[actix_web::test]
+    async fn reused_code_revokes_its_session() {
+        let gateway = gateway().await;
+        let app = app!(gateway);
+        let response =
+            test::call_service(&app, login(&gateway.client_id, "openid").to_request()).await;
+        let code = code(&response);
+        let request = redeem(&gateway.client_id, &code, REDIRECT_URI, VERIFIER);
+        let tokens: serde_json::Value =
+            test::call_and_read_body_json(&app, request.to_request()).await;
+
+        let request = redeem(&gateway.client_id, &code, REDIRECT_URI, VERIFIER);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["error"], "invalid_grant");
+
+        let refresh = test::TestRequest::post().uri("/oidc/token").set_form([
+            ("grant_type", "refresh_token"),
+            ("client_id", gateway.client_id.as_str()),
+            ("refresh_token", tokens["refresh_token"].as_str().unwrap()),
+        ]);
+        let response = test::call_service(&app, refresh.to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn racing_redemptions_revoke_the_session() {
+        let gateway = gateway().await;
+        let app = app!(gateway);
+        let response =
+            test::call_service(&app, login(&gateway.client_id, "openid").to_request()).await;
+        // Both requests read the code before either redeems it
+        let code = Database::find_authorization_code(&gateway.repo, &code(&response))
+            .await
+            .unwrap();
+        let actor = Actor::default();
+        let (session, _) =
+            Database::redeem_authorization_code(&gateway.repo, &code, None, &actor, 3600)
+                .await
+                .unwrap();
+
+        let raced =
+            Database::redeem_authorization_code(&gateway.repo, &code, None, &actor, 3600).await;
+        assert_eq!(TokenError::from(raced.unwrap_err()).error, "invalid_grant");
+        let sessions = Database::list_sessions(&gateway.repo, &format!("{}", session.user.id))
+            .await
+            .unwrap();
+        assert!(sessions.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn scopes_map_to_the_roles_they_grant() {
+        let gateway = gateway().await;
+        let app = app!(gateway);
+
+        for (scope, roles) in [
+            ("openid", vec![]),
+            ("openid shop.read", vec!["Shop::Reader"]),
+        ] {
+            let response =
+                test::call_service(&app, login(&gateway.client_id, scope).to_request()).await;
+            let request = redeem(&gateway.client_id, &code(&response), REDIRECT_URI, VERIFIER);
+            let tokens: serde_json::Value =
+                test::call_and_read_body_json(&app, request.to_request()).await;
+            let claims = id_token_claims(tokens["id_token"].as_str().unwrap());
+            assert_eq!(claims.aud, gateway.client_id);
+            assert_eq!(claims.roles, roles);
+        }
+    }
+
+    #[actix_web::test]
+    async fn authorization_requests_are_checked_against_the_client() {
+        let gateway = gateway().await;
+        let app = app!(gateway);
+        let authorization = |query: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/oidc/authorize?{}", query))
+                .to_request()
+        };
+        let client = format!("client_id={}", gateway.client_id);
+        let redirect = format!("redirect_uri={}", REDIRECT_URI);
+        let challenge = format!(
+            "code_challenge={}&code_challenge_method=S256",
+            URL_SAFE_NO_PAD.encode(Sha256::digest(VERIFIER.as_bytes()))
+        );
+
+        // Unregistered redirect URIs are never sent to
+        let query = format!(
+            "response_type=code&{}&redirect_uri=https://evil.example/&scope=openid&{}",
+            client, challenge
+        );
+        let response = test::call_service(&app, authorization(&query)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.headers().get(header::LOCATION).is_none());
+
+        for (query, error) in [
+            (
+                format!("response_type=code&{}&{}&scope=openid", client, redirect),
+                "invalid_request",
+            ),
+            (
+                format!(
+                    "response_type=code&{}&{}&scope=shop.read&{}",
+                    client, redirect, challenge
+                ),
+                "invalid_scope",
+            ),
+            (
+                format!(
+                    "response_type=code&{}&{}&scope=openid%20shop.write&{}",
+                    client, redirect, challenge
+                ),
+                "invalid_scope",
+            ),
+            (
+                format!(
+                    "response_type=token&{}&{}&scope=openid&{}",
+                    client, redirect, challenge
+                ),
+                "unsupported_response_type",
+            ),
+        ] {
+            let response = test::call_service(&app, authorization(&query)).await;
+            let location = location(&response);
+            assert!(location.as_str().starts_with(REDIRECT_URI));
+            assert_eq!(query_param(&location, "error").as_deref(), Some(error));
+        }
+
+        let query = format!(
+            "response_type=code&{}&{}&scope=openid&{}",
+            client, redirect, challenge
+        );
+        let response = test::call_service(&app, authorization(&query)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Value;
+
+use crate::database::Database;
+use crate::errors::{GatewayError, Result};
+
+pub const DEFAULT_PAGE_SIZE: u64 = 50;
+pub const MAX_PAGE_SIZE: u64 = 500;
+pub const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+pub fn default_page_size() -> u64 {
+    DEFAULT_PAGE_SIZE
+}
+
+/// One page of a listing, along with how many records match across every page.
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: u64,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            total: self.total,
+            offset: self.offset,
+            limit: self.limit,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Count {
+    count: u64,
+}
+
+/// Selects one page of a table, filtered by every added condition.
+pub struct PageQuery {
+    fields: String,
+    table: String,
+    conditions: Vec<String>,
+    params: BTreeMap<String, Value>,
+    order: String,
+    offset: u64,
+    limit: u64,
+}
+
+impl PageQuery {
+    /// `sort` is a comma separated list of fields, each descending when
+    /// prefixed with `-`. Only `sortable` fields are accepted, and
+    /// `default_sort` applies when no sort is given.
+    pub fn new(
+        fields: impl std::fmt::Display,
+        table: &str,
+        offset: u64,
+        limit: u64,
+        sort: Option<&str>,
+        sortable: &[&str],
+        default_sort: &str,
+    ) -> Result<Self> {
+        if limit == 0 || limit > MAX_PAGE_SIZE {
+            return Err(GatewayError::BadRequest(format!(
+                "limit must be between 1 and {}",
+                MAX_PAGE_SIZE
+            )));
+        }
+        let mut order: Vec<String> = Vec::new();
+        for key in sort.unwrap_or(default_sort).split(',') {
+            let (field, direction) = match key.trim().strip_prefix('-') {
+                Some(field) => (field, "DESC"),
+                None => (key.trim(), "ASC"),
+            };
+            if !sortable.contains(&field) {
+                return Err(GatewayError::BadRequest(format!(
+                    "Cannot sort by [{}]; expected one of {}",
+                    field,
+                    sortable.join(", ")
+                )));
+            }
+            order.push(format!("{} {}", field, direction));
+        }
+        // Ties are broken by id so that pages don't overlap
+        order.push(String::from("id ASC"));
+        Ok(Self {
+            fields: fields.to_string(),
+            table: table.to_string(),
+            conditions: Vec::new(),
+            params: BTreeMap::new(),
+            order: order.join(", "),
+            offset,
+            limit,
+        })
+    }
+
+    pub fn condition(mut self, condition: impl std::fmt::Display) -> Self {
+        self.conditions.push(condition.to_string());
+        self
+    }
+
+    pub fn bind(mut self, name: &str, value: impl Serialize) -> Result<Self> {
+        let value = surrealdb::sql::to_value(value)
+            .map_err(|e| GatewayError::DatabaseError(e.to_string()))?;
+        self.params.insert(name.to_string(), value);
+        Ok(self)
+    }
+
+    fn filter(&self) -> String {
+        if self.conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", self.conditions.join(" AND "))
+        }
+    }
+
+    pub async fn fetch<T>(self, repo: &Database) -> Result<Page<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let filter = self.filter();
+        let mut response = repo
+            .db
+            .query(format!(
+                "SELECT {} FROM {} {} ORDER BY {} LIMIT $limit START $offset;\n\
+                SELECT count() FROM {} {} GROUP ALL",
+                self.fields, self.table, filter, self.order, self.table, filter
+            ))
+            .bind(self.params)
+            .bind(("limit", self.limit))
+            .bind(("offset", self.offset))
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        let items: Vec<T> = response.take(0).map_err(Into::<GatewayError>::into)?;
+        let count: Option<Count> = response.take(1).map_err(Into::<GatewayError>::into)?;
+        Ok(Page {
+            items,
+            total: count.map(|count| count.count).unwrap_or(0),
+            offset: self.offset,
+            limit: self.limit,
+        })
+    }
+
+    /// The page's items alone, without counting every matching record.
+    pub async fn fetch_items<T>(self, repo: &Database) -> Result<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        repo.db
+            .query(format!(
+                "SELECT {} FROM {} {} ORDER BY {} LIMIT $limit START $offset",
+                self.fields,
+                self.table,
+                self.filter(),
+                self.order
+            ))
+            .bind(self.params)
+            .bind(("limit", self.limit))
+            .bind(("offset", self.offset))
+            .await
+            .map_err(Into::<GatewayError>::into)?
+            .take(0)
+            .map_err(Into::<GatewayError>::into)
+    }
+}
+
+/// Responds with the page's items, its total in `X-Total-Count` and links to
+/// the pages either side of it in `Link`.
+pub fn page_response<T: Serialize>(req: &HttpRequest, page: Page<T>) -> HttpResponse {
+    let mut links: Vec<String> = Vec::new();
+    if page.offset + page.limit < page.total {
+        links.push(page_link(req, page.offset + page.limit, page.limit, "next"));
+    }
+    if page.offset > 0 {
+        links.push(page_link(
+            req,
+            page.offset.saturating_sub(page.limit),
+            page.limit,
+            "prev",
+        ));
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((TOTAL_COUNT_HEADER, page.total.to_string()));
+    if !links.is_empty() {
+        response.insert_header((header::LINK, links.join(", ")));
+    }
+    response.json(page.items)
+}
+
+// Keeps the request's filters, replacing only its position
+fn page_link(req: &HttpRequest, offset: u64, limit: u64, rel: &str) -> String {
+    let position = format!("offset={}&limit={}", offset, limit);
+    let mut query: Vec<&str> = req
+        .query_string()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| !pair.starts_with("offset=") && !pair.starts_with("limit="))
+        .collect();
+    query.push(&position);
+    format!("<{}?{}>; rel=\"{}\"", req.path(), query.join("&"), rel)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::database::testing::database;
+
+    #[derive(Debug, Deserialize)]
+    struct Widget {
+        name: String,
+    }
+
+    fn widgets(sort: Option<&str>, offset: u64, limit: u64) -> Result<PageQuery> {
+        PageQuery::new(
+            "*",
+            "widget",
+            offset,
+            limit,
+            sort,
+            &["name", "size"],
+            "name",
+        )
+    }
+
+    #[test]
+    fn sorts_only_by_sortable_fields() {
+        assert_eq!(widgets(None, 0, 10).unwrap().order, "name ASC, id ASC");
+        assert_eq!(
+            widgets(Some("-size, name"), 0, 10).unwrap().order,
+            "size DESC, name ASC, id ASC"
+        );
+        for sort in ["password_hash", "-name,", "name; DELETE widget"] {
+            assert!(matches!(
+                widgets(Some(sort), 0, 10),
+                Err(GatewayError::BadRequest(_))
+            ));
+        }
+        for limit in [0, MAX_PAGE_SIZE + 1] {
+            assert!(matches!(
+                widgets(None, 0, limit),
+                Err(GatewayError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[actix_web::test]
+    async fn fetches_one_filtered_page_and_counts_every_match() {
+        let repo = database().await;
+        repo.db
+            .query(
+                "CREATE widget:a SET name = 'a', size = 3;\
+                CREATE widget:b SET name = 'b', size = 1;\
+                CREATE widget:c SET name = 'c', size = 2;\
+                CREATE widget:d SET name = 'd', size = 9",
+            )
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+
+        let page: Page<Widget> = widgets(Some("-size"), 1, 2)
+            .unwrap()
+            .condition("size < $largest")
+            .bind("largest", 5)
+            .unwrap()
+            .fetch(&repo)
+            .await
+            .unwrap();
+
+        let names: Vec<&str> = page.items.iter().map(|item| item.name.as_str()).collect();
+        assert_eq!(names, vec!["c", "b"]);
+        assert_eq!(page.total, 3);
+        let rest: Vec<Widget> = widgets(None, 3, 2)
+            .unwrap()
+            .fetch_items(&repo)
+            .await
+            .unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].name, "d");
+    }
+
+    #[test]
+    fn links_to_the_pages_either_side_keeping_the_filters() {
+        let req = TestRequest::get()
+            .uri("/cfg/v1/services?environment=test&offset=10&limit=10")
+            .to_http_request();
+        let page = Page {
+            items: Vec::<u64>::new(),
+            total: 35,
+            offset: 10,
+            limit: 10,
+        };
+
+        let response = page_response(&req, page);
+
+        assert_eq!(response.headers().get(TOTAL_COUNT_HEADER).unwrap(), "35");
+        assert_eq!(
+            response.headers().get(header::LINK).unwrap(),
+            "</cfg/v1/services?environment=test&offset=20&limit=10>; rel=\"next\", \
+            </cfg/v1/services?environment=test&offset=0&limit=10>; rel=\"prev\""
+        );
+    }
+
+    #[test]
+    fn first_and_last_pages_link_one_way() {
+        let req = TestRequest::get().uri("/cfg/v1/services").to_http_request();
+        let link = |offset: u64| {
+            let page = Page {
+                items: Vec::<u64>::new(),
+                total: 20,
+                offset,
+                limit: 10,
+            };
+            page_response(&req, page)
+                .headers()
+                .get(header::LINK)
+                .map(|link| link.to_str().unwrap().to_string())
+        };
+
+        assert_eq!(
+            link(0).as_deref(),
+            Some("</cfg/v1/services?offset=10&limit=10>; rel=\"next\"")
+        );
+        assert_eq!(
+            link(10).as_deref(),
+            Some("</cfg/v1/services?offset=0&limit=10>; rel=\"prev\"")
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use futures_util::stream::{select_all, StreamExt};
+use surrealdb::sql::{Datetime, Id, Thing, Value};
+
+use super::models::{DbQuota, DbQuotaCharge, DbRateLimit, LimitScope, QuotaWindow};
+use super::repo::{QuotaRepository, RateLimitRepository};
+use crate::api_services::models::DbFullApiService;
+use crate::auth::models::GatewayUserClaims;
+use crate::database::{Database, QUOTA_TABLE, QUOTA_USAGE_TABLE, RATE_LIMIT_TABLE};
+use crate::errors::{GatewayError, Result};
+
+// Delay before re-subscribing when a live query stream ends unexpectedly
+const LIVE_QUERY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &DbRateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * refill_rate(limit)).min(limit.capacity() as f64);
+        self.updated = now;
+    }
+}
+
+// Tokens added to a bucket per second
+fn refill_rate(limit: &DbRateLimit) -> f64 {
+    limit.requests as f64 / limit.period_secs.max(1) as f64
+}
+
+/// Token bucket rate limits and long-window quotas applied by the forwarder.
+///
+/// Limit and quota definitions are cached from the database; buckets live in
+/// memory, keyed on (limit id, subject), while quota counters are persisted.
+pub struct RateLimiter {
+    limits: RwLock<Vec<DbRateLimit>>,
+    quotas: RwLock<Vec<DbQuota>>,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter {
+            limits: RwLock::new(Vec::new()),
+            quotas: RwLock::new(Vec::new()),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reload every rate limit and quota definition from the database.
+    pub async fn refresh(&self, repo: &Data<Database>) -> Result<()> {
+        let limits = Database::list_rate_limits(repo).await?;
+        let quotas = Database::list_quotas(repo).await?;
+        log::debug!(
+            "Loaded {} rate limits and {} quotas",
+            limits.len(),
+            quotas.len()
+        );
+        // Drop the buckets of limits that no longer exist
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|(limit_id, _), _| limits.iter().any(|l| &l.id.to_string() == limit_id));
+        *self.limits.write().unwrap() = limits;
+        *self.quotas.write().unwrap() = quotas;
+        Ok(())
+    }
+
+    /// Charges a request against every rate limit and quota that applies to
+    /// it, or against none of them when any turns it away.
+    pub async fn check(
+        &self,
+        repo: &Data<Database>,
+        service: &DbFullApiService,
+        claims: &GatewayUserClaims,
+    ) -> Result<()> {
+        let charged = self.take_tokens(service, claims)?;
+        if let Err(e) = self.count_quotas(repo, service, claims).await {
+            self.return_tokens(charged);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Takes a token from every bucket the request falls in, returning their keys
+    fn take_tokens(
+        &self,
+        service: &DbFullApiService,
+        claims: &GatewayUserClaims,
+    ) -> Result<Vec<(String, String)>> {
+        let limits = self.limits.read().unwrap();
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+
+        // Only take tokens once every bucket has been checked,
+        // so a rejected request doesn't drain the others
+        let mut charged = Vec::new();
+        for limit in limits.iter() {
+            for subject in matching_subjects(limit.scope, &limit.subject, service, claims) {
+                let key = (limit.id.to_string(), subject);
+                let bucket = buckets.entry(key.clone()).or_insert_with(|| Bucket {
+                    tokens: limit.capacity() as f64,
+                    updated: now,
+                });
+                bucket.refill(limit, now);
+                if bucket.tokens < 1.0 {
+                    let retry_after = ((1.0 - bucket.tokens) / refill_rate(limit)).ceil() as u64;
+                    return Err(GatewayError::RateLimited {
+                        message: format!("Rate limit exceeded for {}", key.1),
+                        limit: limit.capacity() as u64,
+                        retry_after: retry_after.max(1),
+                    });
+                }
+                charged.push(key);
+            }
+        }
+        for key in &charged {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(charged)
+    }
+
+    // Puts back the tokens of a request that was turned away after all
+    fn return_tokens(&self, charged: Vec<(String, String)>) {
+        let limits = self.limits.read().unwrap();
+        let mut buckets = self.buckets.lock().unwrap();
+        for key in charged {
+            let limit = limits.iter().find(|limit| limit.id.to_string() == key.0);
+            if let (Some(limit), Some(bucket)) = (limit, buckets.get_mut(&key)) {
+                bucket.tokens = (bucket.tokens + 1.0).min(limit.capacity() as f64);
+            }
+        }
+    }
+
+    async fn count_quotas(
+        &self,
+        repo: &Data<Database>,
+        service: &DbFullApiService,
+        claims: &GatewayUserClaims,
+    ) -> Result<()> {
+        let quotas = self.quotas.read().unwrap().clone();
+        let now = Utc::now();
+        let mut charges: Vec<(DbQuotaCharge, DateTime<Utc>)> = Vec::new();
+        for quota in quotas {
+            let (window_start, window_end) = quota_window(quota.window, now);
+            let window_start: Datetime = window_start.into();
+            for subject in matching_subjects(quota.scope, &quota.subject, service, claims) {
+                let counter = Id::Array(
+                    vec![
+                        Value::Thing(quota.id.clone()),
+                        subject.clone().into(),
+                        Value::Datetime(window_start.clone()),
+                    ]
+                    .into(),
+                );
+                let charge = DbQuotaCharge {
+                    id: Thing::from((QUOTA_USAGE_TABLE, counter)),
+                    quota: quota.id.clone(),
+                    subject,
+                    window_start: window_start.clone(),
+                    quota_limit: quota.limit,
+                    position: charges.len(),
+                };
+                charges.push((charge, window_end));
+            }
+        }
+        if charges.is_empty() {
+            return Ok(());
+        }
+
+        let to_charge: Vec<DbQuotaCharge> =
+            charges.iter().map(|(charge, _)| charge.clone()).collect();
+        let exhausted = Database::charge_quotas(repo, &to_charge).await?;
+        match exhausted.and_then(|position| charges.get(position)) {
+            Some((charge, window_end)) => Err(GatewayError::RateLimited {
+                message: format!("Quota exhausted for {}", charge.subject),
+                limit: charge.quota_limit,
+                retry_after: (*window_end - now).num_seconds().max(1) as u64,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+// The subjects of a request within a scope, narrowed to a limit's subject if it names one
+fn matching_subjects(
+    scope: LimitScope,
+    limit_subject: &Option<String>,
+    service: &DbFullApiService,
+    claims: &GatewayUserClaims,
+) -> Vec<String> {
+    let subjects = match scope {
+        LimitScope::User => vec![claims.sub_id.clone()],
+        LimitScope::Role => claims.aud.clone(),
+        LimitScope::Service => vec![format!("{}/{}", service.api_name, service.version)],
+    };
+    subjects
+        .into_iter()
+        .filter(|subject| limit_subject.as_ref().map_or(true, |s| s == subject))
+        .collect()
+}
+
+// Start and end of the calendar window (UTC) containing `now`
+fn quota_window(window: QuotaWindow, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start_date = match window {
+        QuotaWindow::Day => now.date_naive(),
+        QuotaWindow::Month => NaiveDate::from_ymd_opt(now.year(), now.month(), 1).unwrap(),
+    };
+    let end_date = match window {
+        QuotaWindow::Day => start_date.succ_opt().unwrap(),
+        QuotaWindow::Month => start_date.checked_add_months(Months::new(1)).unwrap(),
+    };
+    (
+        start_date.and_hms_opt(0, 0, 0).unwrap().and_utc(),
+        end_date.and_hms_opt(0, 0, 0).unwrap().and_utc(),
+    )
+}
+
+/// Keeps the rate limiter current by reloading it whenever a `LIVE SELECT`
+/// on the rate limit or quota tables reports a change.
+pub fn spawn_limit_sync(limiter: Data<RateLimiter>, repo: Data<Database>) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = watch_limit_tables(&limiter, &repo).await {
+                log::error!("Rate limit live query failed: {}", e);
+            }
+            tokio::time::sleep(LIVE_QUERY_RETRY_DELAY).await;
+        }
+    });
+}
+
+async fn watch_limit_tables(limiter: &Data<RateLimiter>, repo: &Data<Database>) -> Result<()> {
+    let mut reconnected = repo.reconnected();
+    let mut streams = Vec::new();
+    for watched_table in [RATE_LIMIT_TABLE, QUOTA_TABLE] {
+        let stream = repo
+            .db
+            .select::<Vec<Value>>(watched_table)
+            .live()
+            .await
+            .map_err(GatewayError::from)?;
+        streams.push(stream);
+    }
+    // Catch any change made between the last refresh and the subscription
+    limiter.refresh(repo).await?;
+
+    let mut notifications = select_all(streams);
+    loop {
+        tokio::select! {
+            notification = notifications.next() => match notification {
+                Some(Ok(notification)) => {
+                    log::debug!("Rate limit change detected: {:?}", notification.action);
+                    if let Err(e) = limiter.refresh(repo).await {
+                        log::error!("Unable to refresh rate limits: {}", e);
+                    }
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+            // The live queries ended with the old connection
+            _ = reconnected.changed() => {
+                log::info!("Restarting rate limit live queries after reconnecting");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::{count, database};
+    use crate::ratelimit::models::WebQuotaRequest;
+    use serde_json::json;
+
+    fn service() -> DbFullApiService {
+        serde_json::from_value(json!({
+            "id": { "tb": "api_service", "id": { "String": "shop" } },
+            "api_name": "shop",
+            "forward_url": "http://shop",
+            "active": true,
+            "version": "v1",
+            "environment": "test",
+            "max_body_size": null,
+            "tls": null,
+            "health_check": null,
+            "timeouts": null,
+            "retry": null,
+            "circuit_breaker": null,
+            "websocket": null,
+            "roles": [],
+        }))
+        .unwrap()
+    }
+
+    fn claims() -> GatewayUserClaims {
+        serde_json::from_value(json!({
+            "iss": "test",
+            "sub": "alice",
+            "sub_id": "gateway_user:alice",
+            "aud": ["Shop::Reader"],
+            "exp": 0,
+            "iat": 0,
+            "nbf": 0,
+        }))
+        .unwrap()
+    }
+
+    fn rate_limit(id: &str, scope: LimitScope, requests: u32, burst: Option<u32>) -> DbRateLimit {
+        DbRateLimit {
+            id: Thing::from((RATE_LIMIT_TABLE, id)),
+            scope,
+            subject: None,
+            requests,
+            period_secs: 10,
+            burst,
+        }
+    }
+
+    #[test]
+    fn bucket_refills_at_the_limit_rate_up_to_its_capacity() {
+        let limit = rate_limit("a", LimitScope::User, 10, Some(3));
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            updated: start,
+        };
+
+        bucket.refill(&limit, start + Duration::from_millis(1500));
+        assert!((bucket.tokens - 1.5).abs() < 1e-9);
+
+        bucket.refill(&limit, start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn requests_past_the_burst_are_rate_limited() {
+        let limiter = RateLimiter::new();
+        *limiter.limits.write().unwrap() = vec![rate_limit("a", LimitScope::User, 1, Some(2))];
+
+        assert!(limiter.take_tokens(&service(), &claims()).is_ok());
+        assert!(limiter.take_tokens(&service(), &claims()).is_ok());
+        match limiter.take_tokens(&service(), &claims()) {
+            Err(GatewayError::RateLimited {
+                limit, retry_after, ..
+            }) => {
+                assert_eq!(limit, 2);
+                assert_eq!(retry_after, 10);
+            }
+            other => panic!("Expected a rate limit, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejected_request_leaves_the_other_buckets_alone() {
+        let limiter = RateLimiter::new();
+        *limiter.limits.write().unwrap() = vec![
+            rate_limit("service", LimitScope::Service, 5, None),
+            rate_limit("user", LimitScope::User, 1, None),
+        ];
+        limiter.take_tokens(&service(), &claims()).unwrap();
+
+        assert!(limiter.take_tokens(&service(), &claims()).is_err());
+
+        let buckets = limiter.buckets.lock().unwrap();
+        let service_bucket = &buckets[&(
+            Thing::from((RATE_LIMIT_TABLE, "service")).to_string(),
+            String::from("shop/v1"),
+        )];
+        assert!((service_bucket.tokens - 4.0).abs() < 0.01);
+    }
+
+    #[actix_web::test]
+    async fn exhausted_quota_charges_nothing_and_returns_the_tokens() {
+        let repo = database().await;
+        for (scope, limit) in [(LimitScope::Service, 5), (LimitScope::User, 1)] {
+            Database::add_quota(
+                &repo,
+                &WebQuotaRequest {
+                    scope,
+                    subject: None,
+                    limit,
+                    window: QuotaWindow::Day,
+                },
+            )
+            .await
+            .unwrap();
+        }
+        let limiter = RateLimiter::new();
+        limiter.refresh(&repo).await.unwrap();
+        *limiter.limits.write().unwrap() = vec![rate_limit("a", LimitScope::User, 10, None)];
+
+        limiter.check(&repo, &service(), &claims()).await.unwrap();
+        let rejected = limiter.check(&repo, &service(), &claims()).await;
+
+        match rejected {
+            Err(GatewayError::RateLimited { message, .. }) => {
+                assert_eq!(message, "Quota exhausted for gateway_user:alice")
+            }
+            other => panic!("Expected an exhausted quota, got {:?}", other),
+        }
+        let usage: Vec<u64> = repo
+            .db
+            .query(format!("SELECT VALUE count FROM {}", QUOTA_USAGE_TABLE))
+            .await
+            .unwrap()
+            .take(0)
+            .unwrap();
+        assert_eq!(usage, [1, 1]);
+        assert_eq!(count(&repo, QUOTA_USAGE_TABLE).await, 2);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.values().all(|bucket| bucket.tokens > 8.9));
+    }
+}
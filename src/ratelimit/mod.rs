@@ -0,0 +1,4 @@
+pub mod limiter;
+pub mod models;
+pub mod repo;
+pub mod web;
@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use validator::Validate;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitScope {
+    // Keyed on the caller's `sub_id`
+    User,
+    // Keyed on each role (`Namespace::Name`) in the caller's token
+    Role,
+    // Keyed on `api_name/version`
+    Service,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaWindow {
+    Day,
+    Month,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
+pub struct WebRateLimitRequest {
+    pub scope: LimitScope,
+
+    // Limit a single subject; when omitted every subject in the scope gets its own bucket
+    #[validate(length(min = 1))]
+    pub subject: Option<String>,
+
+    // Requests refilled into the bucket over `period_secs`
+    #[validate(range(min = 1))]
+    pub requests: u32,
+
+    #[validate(range(min = 1))]
+    pub period_secs: u64,
+
+    // Bucket capacity; defaults to `requests`
+    #[validate(range(min = 1))]
+    pub burst: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbRateLimit {
+    pub id: Thing,
+    pub scope: LimitScope,
+    pub subject: Option<String>,
+    pub requests: u32,
+    pub period_secs: u64,
+    pub burst: Option<u32>,
+}
+
+impl DbRateLimit {
+    pub fn capacity(&self) -> u32 {
+        self.burst.unwrap_or(self.requests)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebRateLimit {
+    pub id: String,
+    pub scope: LimitScope,
+    pub subject: Option<String>,
+    pub requests: u32,
+    pub period_secs: u64,
+    pub burst: Option<u32>,
+}
+
+impl From<&DbRateLimit> for WebRateLimit {
+    fn from(value: &DbRateLimit) -> Self {
+        Self {
+            id: format!("{}", value.id.id),
+            scope: value.scope,
+            subject: value.subject.clone(),
+            requests: value.requests,
+            period_secs: value.period_secs,
+            burst: value.burst,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
+pub struct WebQuotaRequest {
+    pub scope: LimitScope,
+
+    // Limit a single subject; when omitted every subject in the scope is counted separately
+    #[validate(length(min = 1))]
+    pub subject: Option<String>,
+
+    #[validate(range(min = 1))]
+    pub limit: u64,
+
+    pub window: QuotaWindow,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbQuota {
+    pub id: Thing,
+    pub scope: LimitScope,
+    pub subject: Option<String>,
+    pub limit: u64,
+    pub window: QuotaWindow,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebQuota {
+    pub id: String,
+    pub scope: LimitScope,
+    pub subject: Option<String>,
+    pub limit: u64,
+    pub window: QuotaWindow,
+}
+
+impl From<&DbQuota> for WebQuota {
+    fn from(value: &DbQuota) -> Self {
+        Self {
+            id: format!("{}", value.id.id),
+            scope: value.scope,
+            subject: value.subject.clone(),
+            limit: value.limit,
+            window: value.window,
+        }
+    }
+}
+
+/// One request counted against a quota's counter for a subject and window.
+#[derive(Debug, Serialize, Clone)]
+pub struct DbQuotaCharge {
+    // The counter, keyed on [quota, subject, window_start]
+    pub id: Thing,
+    pub quota: Thing,
+    pub subject: String,
+    pub window_start: Datetime,
+    pub quota_limit: u64,
+    // Position among the charges made for the request
+    pub position: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DbQuotaUsage {
+    pub quota: Thing,
+    pub subject: String,
+    pub window_start: Datetime,
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebQuotaUsage {
+    pub subject: String,
+    pub window_start: Datetime,
+    pub count: u64,
+}
+
+impl From<&DbQuotaUsage> for WebQuotaUsage {
+    fn from(value: &DbQuotaUsage) -> Self {
+        Self {
+            subject: value.subject.clone(),
+            window_start: value.window_start.clone(),
+            count: value.count,
+        }
+    }
+}
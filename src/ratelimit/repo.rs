@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+
+use actix_web::web::Data;
+use async_trait::async_trait;
+use surrealdb::sql::{Thing, Value};
+
+use super::models::{
+    DbQuota, DbQuotaCharge, DbQuotaUsage, DbRateLimit, WebQuotaRequest, WebRateLimitRequest,
+};
+use crate::database::{Database, Transaction, QUOTA_TABLE, QUOTA_USAGE_TABLE, RATE_LIMIT_TABLE};
+use crate::errors::{GatewayError, Result};
+
+#[async_trait]
+pub trait RateLimitRepository {
+    async fn list_rate_limits(repo: &Data<Database>) -> Result<Vec<DbRateLimit>>;
+    async fn add_rate_limit(
+        repo: &Data<Database>,
+        new_limit: &WebRateLimitRequest,
+    ) -> Result<DbRateLimit>;
+    async fn update_rate_limit(
+        repo: &Data<Database>,
+        limit_id: &String,
+        limit: &WebRateLimitRequest,
+    ) -> Result<DbRateLimit>;
+    async fn delete_rate_limit(repo: &Data<Database>, limit_id: &str) -> Result<()>;
+}
+
+#[async_trait]
+impl RateLimitRepository for Database {
+    async fn list_rate_limits(repo: &Data<Database>) -> Result<Vec<DbRateLimit>> {
+        repo.db
+            .select(RATE_LIMIT_TABLE)
+            .await
+            .map_err(Into::<GatewayError>::into)
+    }
+
+    async fn add_rate_limit(
+        repo: &Data<Database>,
+        new_limit: &WebRateLimitRequest,
+    ) -> Result<DbRateLimit> {
+        let created: Vec<DbRateLimit> = repo
+            .db
+            .create(RATE_LIMIT_TABLE)
+            .content(new_limit)
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        created
+            .into_iter()
+            .next()
+            .ok_or(GatewayError::DatabaseError(
+                "Unable to insert rate limit.".to_string(),
+            ))
+    }
+
+    async fn update_rate_limit(
+        repo: &Data<Database>,
+        limit_id: &String,
+        limit: &WebRateLimitRequest,
+    ) -> Result<DbRateLimit> {
+        let updated: Option<DbRateLimit> = repo
+            .db
+            .update((RATE_LIMIT_TABLE, limit_id))
+            .content(limit)
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        updated.ok_or(GatewayError::NotFound(
+            "Rate Limit".to_string(),
+            format!("No rate limit with id [{}] found.", limit_id),
+        ))
+    }
+
+    async fn delete_rate_limit(repo: &Data<Database>, limit_id: &str) -> Result<()> {
+        let deleted: Option<DbRateLimit> = repo
+            .db
+            .delete((RATE_LIMIT_TABLE, limit_id))
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        deleted.map(|_| ()).ok_or(GatewayError::NotFound(
+            "Rate Limit".to_string(),
+            format!("No rate limit with id [{}] found.", limit_id),
+        ))
+    }
+}
+
+#[async_trait]
+pub trait QuotaRepository {
+    async fn list_quotas(repo: &Data<Database>) -> Result<Vec<DbQuota>>;
+    async fn add_quota(repo: &Data<Database>, new_quota: &WebQuotaRequest) -> Result<DbQuota>;
+    async fn update_quota(
+        repo: &Data<Database>,
+        quota_id: &String,
+        quota: &WebQuotaRequest,
+    ) -> Result<DbQuota>;
+    async fn delete_quota(repo: &Data<Database>, quota_id: &str) -> Result<()>;
+    async fn quota_usage(repo: &Data<Database>, quota_id: &String) -> Result<Vec<DbQuotaUsage>>;
+    async fn charge_quotas(
+        repo: &Data<Database>,
+        charges: &[DbQuotaCharge],
+    ) -> Result<Option<usize>>;
+}
+
+#[async_trait]
+impl QuotaRepository for Database {
+    async fn list_quotas(repo: &Data<Database>) -> Result<Vec<DbQuota>> {
+        repo.db
+            .select(QUOTA_TABLE)
+            .await
+            .map_err(Into::<GatewayError>::into)
+    }
+
+    async fn add_quota(repo: &Data<Database>, new_quota: &WebQuotaRequest) -> Result<DbQuota> {
+        let created: Vec<DbQuota> = repo
+            .db
+            .create(QUOTA_TABLE)
+            .content(new_quota)
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        created
+            .into_iter()
+            .next()
+            .ok_or(GatewayError::DatabaseError(
+                "Unable to insert quota.".to_string(),
+            ))
+    }
+
+    async fn update_quota(
+        repo: &Data<Database>,
+        quota_id: &String,
+        quota: &WebQuotaRequest,
+    ) -> Result<DbQuota> {
+        let updated: Option<DbQuota> = repo
+            .db
+            .update((QUOTA_TABLE, quota_id))
+            .content(quota)
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        updated.ok_or(GatewayError::NotFound(
+            "Quota".to_string(),
+            format!("No quota with id [{}] found.", quota_id),
+        ))
+    }
+
+    async fn delete_quota(repo: &Data<Database>, quota_id: &str) -> Result<()> {
+        repo.db
+            .query("DELETE type::table($usage_table) WHERE quota = $quota_id")
+            .bind(("usage_table", QUOTA_USAGE_TABLE))
+            .bind((
+                "quota_id",
+                Thing::from((QUOTA_TABLE.to_string(), quota_id.to_string())),
+            ))
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        let deleted: Option<DbQuota> = repo
+            .db
+            .delete((QUOTA_TABLE, quota_id))
+            .await
+            .map_err(Into::<GatewayError>::into)?;
+        deleted.map(|_| ()).ok_or(GatewayError::NotFound(
+            "Quota".to_string(),
+            format!("No quota with id [{}] found.", quota_id),
+        ))
+    }
+
+    async fn quota_usage(repo: &Data<Database>, quota_id: &String) -> Result<Vec<DbQuotaUsage>> {
+        let bind_params: BTreeMap<String, Value> = [
+            ("table".to_string(), QUOTA_USAGE_TABLE.into()),
+            (
+                "quota_id".to_string(),
+                Value::Thing(Thing::from((QUOTA_TABLE.to_string(), quota_id.clone()))),
+            ),
+        ]
+        .into();
+        repo.query_list(
+            "SELECT * FROM type::table($table) WHERE quota = $quota_id \
+            ORDER BY window_start DESC, subject",
+            Some(bind_params),
+        )
+        .await
+    }
+
+    // Counts the request against every counter, or against none of them when
+    // any has reached its quota's limit, returning the position of the first
+    // such charge
+    async fn charge_quotas(
+        repo: &Data<Database>,
+        charges: &[DbQuotaCharge],
+    ) -> Result<Option<usize>> {
+        let transaction = Transaction::new()
+            .statement(
+                "LET $exhausted = array::first((SELECT VALUE position FROM $charges \
+                WHERE ((SELECT VALUE count FROM $parent.id)[0] ?? 0) >= quota_limit));\n\
+                IF $exhausted = NONE {\n\
+                    FOR $charge IN $charges {\n\
+                        UPDATE $charge.id SET quota = $charge.quota, subject = $charge.subject, \
+                        window_start = $charge.window_start, count += 1;\n\
+                    };\n\
+                };\n\
+                RETURN $exhausted",
+            )
+            .bind("charges", charges)?;
+        repo.commit_returning(transaction).await
+    }
+}
@@ -0,0 +1,165 @@
+use actix_web::{
+    delete, get, post, put,
+    web::{scope, to, Data, Json, Path, ServiceConfig},
+    HttpRequest, HttpResponse,
+};
+use serde::Deserialize;
+use validator::Validate;
+
+use super::models::{WebQuota, WebQuotaRequest, WebQuotaUsage, WebRateLimit, WebRateLimitRequest};
+use super::repo::{QuotaRepository, RateLimitRepository};
+use crate::auth::web::validate_jwt;
+use crate::database::Database;
+use crate::errors::{unknown_resource_error, GatewayError, Result};
+
+// Intermediate function to configure services
+pub fn service_setup(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/cfg/v1/rate-limits")
+            .service(list_rate_limits)
+            .service(add_rate_limit)
+            .service(update_rate_limit)
+            .service(delete_rate_limit)
+            .default_service(to(unknown_resource_error)),
+    )
+    .service(
+        scope("/cfg/v1/quotas")
+            .service(list_quotas)
+            .service(add_quota)
+            .service(update_quota)
+            .service(quota_usage)
+            .service(delete_quota)
+            .default_service(to(unknown_resource_error)),
+    );
+}
+
+#[get("/")]
+async fn list_rate_limits(
+    req: HttpRequest,
+    repo: Data<Database>,
+) -> Result<Json<Vec<WebRateLimit>>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let limits = Database::list_rate_limits(&repo).await?;
+    Ok(Json(limits.iter().map(Into::into).collect()))
+}
+
+#[post("/")]
+async fn add_rate_limit(
+    req: HttpRequest,
+    limit: Json<WebRateLimitRequest>,
+    repo: Data<Database>,
+) -> Result<Json<WebRateLimit>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let limit = limit.into_inner();
+    limit
+        .validate()
+        .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+    let created_limit = Database::add_rate_limit(&repo, &limit).await?;
+    Ok(Json(WebRateLimit::from(&created_limit)))
+}
+
+#[derive(Deserialize)]
+struct RateLimitIdPath {
+    pub limit_id: String,
+}
+
+#[put("/{limit_id}")]
+async fn update_rate_limit(
+    req: HttpRequest,
+    path_params: Path<RateLimitIdPath>,
+    limit: Json<WebRateLimitRequest>,
+    repo: Data<Database>,
+) -> Result<Json<WebRateLimit>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let limit_id = path_params.into_inner().limit_id;
+    let limit = limit.into_inner();
+    limit
+        .validate()
+        .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+    let updated_limit = Database::update_rate_limit(&repo, &limit_id, &limit).await?;
+    Ok(Json(WebRateLimit::from(&updated_limit)))
+}
+
+#[delete("/{limit_id}")]
+async fn delete_rate_limit(
+    req: HttpRequest,
+    path_params: Path<RateLimitIdPath>,
+    repo: Data<Database>,
+) -> Result<HttpResponse> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let limit_id = path_params.into_inner().limit_id;
+    Database::delete_rate_limit(&repo, limit_id.as_str()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/**
+ * Quota management
+ */
+
+#[get("/")]
+async fn list_quotas(req: HttpRequest, repo: Data<Database>) -> Result<Json<Vec<WebQuota>>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let quotas = Database::list_quotas(&repo).await?;
+    Ok(Json(quotas.iter().map(Into::into).collect()))
+}
+
+#[post("/")]
+async fn add_quota(
+    req: HttpRequest,
+    quota: Json<WebQuotaRequest>,
+    repo: Data<Database>,
+) -> Result<Json<WebQuota>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let quota = quota.into_inner();
+    quota
+        .validate()
+        .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+    let created_quota = Database::add_quota(&repo, &quota).await?;
+    Ok(Json(WebQuota::from(&created_quota)))
+}
+
+#[derive(Deserialize)]
+struct QuotaIdPath {
+    pub quota_id: String,
+}
+
+#[put("/{quota_id}")]
+async fn update_quota(
+    req: HttpRequest,
+    path_params: Path<QuotaIdPath>,
+    quota: Json<WebQuotaRequest>,
+    repo: Data<Database>,
+) -> Result<Json<WebQuota>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let quota_id = path_params.into_inner().quota_id;
+    let quota = quota.into_inner();
+    quota
+        .validate()
+        .map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+    let updated_quota = Database::update_quota(&repo, &quota_id, &quota).await?;
+    Ok(Json(WebQuota::from(&updated_quota)))
+}
+
+#[get("/{quota_id}/usage")]
+async fn quota_usage(
+    req: HttpRequest,
+    path_params: Path<QuotaIdPath>,
+    repo: Data<Database>,
+) -> Result<Json<Vec<WebQuotaUsage>>> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let quota_id = path_params.into_inner().quota_id;
+    let usage = Database::quota_usage(&repo, &quota_id).await?;
+    Ok(Json(usage.iter().map(Into::into).collect()))
+}
+
+#[delete("/{quota_id}")]
+async fn delete_quota(
+    req: HttpRequest,
+    path_params: Path<QuotaIdPath>,
+    repo: Data<Database>,
+) -> Result<HttpResponse> {
+    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let quota_id = path_params.into_inner().quota_id;
+    Database::delete_quota(&repo, quota_id.as_str()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,178 @@
+use actix_web::http::header::{self, ETag, EntityTag, Header, IfMatch, IfNoneMatch};
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Serialize;
+
+use crate::database::Transaction;
+use crate::errors::{GatewayError, Result};
+
+/// The revisions of a record an update may be applied to, taken from the
+/// request's `If-Match` header.
+#[derive(Debug, Clone, Default)]
+pub enum Precondition {
+    // No `If-Match`, or `If-Match: *`
+    #[default]
+    Any,
+    Revisions(Vec<u64>),
+}
+
+impl Precondition {
+    pub fn from_request(req: &HttpRequest) -> Result<Self> {
+        if !req.headers().contains_key(header::IF_MATCH) {
+            return Ok(Precondition::Any);
+        }
+        match IfMatch::parse(req) {
+            Ok(IfMatch::Any) => Ok(Precondition::Any),
+            // If-Match uses the strong comparison, so weak tags never match
+            Ok(IfMatch::Items(tags)) => Ok(Precondition::Revisions(
+                tags.iter()
+                    .filter(|tag| !tag.weak)
+                    .filter_map(|tag| tag.tag().parse().ok())
+                    .collect(),
+            )),
+            Err(_) => Err(GatewayError::BadRequest(String::from(
+                "If-Match must be * or a list of entity tags",
+            ))),
+        }
+    }
+
+    /// Fails early when the record's current revision doesn't match.
+    pub fn check(&self, revision: u64) -> Result<()> {
+        match self {
+            Precondition::Revisions(revisions) if !revisions.contains(&revision) => {
+                Err(precondition_failed())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds a statement that aborts the transaction when the record bound to
+    /// `param` has been written since. It must come before the record's
+    /// own updates, which move its revision on.
+    pub fn guard(&self, transaction: Transaction, param: &str) -> Result<Transaction> {
+        match self {
+            Precondition::Any => Ok(transaction),
+            Precondition::Revisions(revisions) => transaction
+                .statement(format!(
+                    "IF ${}.revision NOTINSIDE $if_match {{ THROW \"{}\" }}",
+                    param, PRECONDITION_FAILED
+                ))
+                .bind("if_match", revisions),
+        }
+    }
+}
+
+pub const PRECONDITION_FAILED: &str = "The record has changed since it was read";
+
+fn precondition_failed() -> GatewayError {
+    GatewayError::PreconditionFailed(PRECONDITION_FAILED.to_string())
+}
+
+/// The `ETag` header for a record at the given revision.
+pub fn etag(revision: u64) -> ETag {
+    ETag(EntityTag::new_strong(revision.to_string()))
+}
+
+/// Responds with the body and the `ETag` of its revision, or with 304 Not
+/// Modified when the request's `If-None-Match` already names that revision.
+pub fn tagged_response<T: Serialize>(req: &HttpRequest, revision: u64, body: &T) -> HttpResponse {
+    let tag = etag(revision);
+    // A missing or malformed header never matches
+    let not_modified = match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|other| other.weak_eq(&tag.0)),
+        Err(_) => false,
+    };
+    if not_modified {
+        HttpResponse::NotModified().insert_header(tag).finish()
+    } else {
+        HttpResponse::Ok().insert_header(tag).json(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use surrealdb::sql::Thing;
+
+    use super::*;
+    use crate::database::testing::database;
+
+    fn if_match(value: &str) -> Result<Precondition> {
+        Precondition::from_request(
+            &TestRequest::default()
+                .insert_header((header::IF_MATCH, value))
+                .to_http_request(),
+        )
+    }
+
+    #[test]
+    fn if_match_names_strong_revisions() {
+        assert!(matches!(if_match("*"), Ok(Precondition::Any)));
+        assert!(matches!(
+            if_match("\"3\", \"4\""),
+            Ok(Precondition::Revisions(revisions)) if revisions == vec![3, 4]
+        ));
+        assert!(matches!(
+            if_match("W/\"3\""),
+            Ok(Precondition::Revisions(revisions)) if revisions.is_empty()
+        ));
+        assert!(matches!(
+            Precondition::from_request(&TestRequest::default().to_http_request()),
+            Ok(Precondition::Any)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn guard_refuses_a_stale_revision() {
+        let repo = database().await;
+        repo.db
+            .query("CREATE widget:one SET revision = 3")
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+        let update = |precondition: Precondition| {
+            let transaction = Transaction::new()
+                .bind("widget", Thing::from(("widget", "one")))
+                .unwrap();
+            precondition
+                .guard(transaction, "widget")
+                .unwrap()
+                .statement("UPDATE $widget SET revision += 1")
+        };
+
+        let stale = repo.commit(update(Precondition::Revisions(vec![2]))).await;
+        assert!(matches!(stale, Err(GatewayError::PreconditionFailed(_))));
+        repo.commit(update(Precondition::Revisions(vec![3])))
+            .await
+            .unwrap();
+        repo.commit(update(Precondition::Any)).await.unwrap();
+        assert!(matches!(
+            Precondition::Revisions(vec![3]).check(5),
+            Err(GatewayError::PreconditionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn matching_if_none_match_is_not_modified() {
+        let response = |if_none_match: Option<&str>| {
+            let mut req = TestRequest::default();
+            if let Some(value) = if_none_match {
+                req = req.insert_header((header::IF_NONE_MATCH, value));
+            }
+            tagged_response(&req.to_http_request(), 3, &"body")
+        };
+
+        for value in ["\"3\"", "W/\"3\"", "\"2\", \"3\"", "*"] {
+            let response = response(Some(value));
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(response.headers().get(header::ETAG).unwrap(), "\"3\"");
+        }
+        for value in [None, Some("\"2\""), Some("3")] {
+            let response = response(value);
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers().get(header::ETAG).unwrap(), "\"3\"");
+        }
+    }
+}
@@ -1,17 +1,26 @@
+use std::path::Path;
 use std::{fs::File, io::BufReader};
 
 use rustls::pki_types::PrivateKeyDer;
 use rustls_pemfile::{certs, pkcs8_private_keys};
 
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
-
 use crate::auth::models::JwtConfig;
+use crate::config::{AuthConfig, TlsConfig};
+
+fn open_pem(path: &Path) -> std::io::Result<BufReader<File>> {
+    File::open(path).map(BufReader::new).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Unable to open {}: {}", path.display(), e),
+        )
+    })
+}
 
-pub fn load_tls_config() -> std::io::Result<rustls::ServerConfig> {
+pub fn load_tls_config(tls: &TlsConfig) -> std::io::Result<rustls::ServerConfig> {
     let config = rustls::ServerConfig::builder().with_no_client_auth();
 
-    let certificate_file = &mut BufReader::new(File::open(".ssl.dev/snakeoil.pem")?);
-    let key_file = &mut BufReader::new(File::open(".ssl.dev/snakeoil.key")?);
+    let certificate_file = &mut open_pem(&tls.certificate)?;
+    let key_file = &mut open_pem(&tls.private_key)?;
 
     let cert_chain = certs(certificate_file).filter_map(Result::ok).collect();
 
@@ -21,22 +30,24 @@ pub fn load_tls_config() -> std::io::Result<rustls::ServerConfig> {
         .collect();
 
     if keys.is_empty() {
-        eprintln!("Could not locate PKCS 8 private keys.");
-        std::process::exit(1);
+        return Err(std::io::Error::other(format!(
+            "Could not locate PKCS 8 private keys in {}",
+            tls.private_key.display()
+        )));
     }
 
-    Ok(config.with_single_cert(cert_chain, keys.remove(0)).unwrap())
+    config
+        .with_single_cert(cert_chain, keys.remove(0))
+        .map_err(|e| std::io::Error::other(format!("Invalid TLS certificate or key: {}", e)))
 }
 
-pub fn load_jwt_config() -> std::io::Result<JwtConfig> {
-    Ok(JwtConfig {
-        algorithm: Algorithm::RS512,
-        decoding_key: DecodingKey::from_rsa_pem(&std::fs::read(".ssl.dev/snakeoil.pem")?)
-            .map_err(|e| std::io::Error::other(e))?,
-        encoding_key: EncodingKey::from_rsa_pem(&std::fs::read(".ssl.dev/snakeoil.key")?)
-            .map_err(|e| std::io::Error::other(e))?,
-        issuer: String::from("apigateway.local"),
-    })
+pub fn load_jwt_config(auth: &AuthConfig) -> JwtConfig {
+    JwtConfig {
+        issuer: auth.issuer.clone(),
+        algorithm: auth.jwt_algorithm,
+        token_lifetime_secs: auth.token_lifetime_secs,
+        refresh_token_lifetime_secs: auth.refresh_token_lifetime_secs,
+    }
 }
 
 pub fn load_cors_config() -> actix_cors::Cors {
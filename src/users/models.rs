@@ -1,29 +1,29 @@
 use crate::api_services::models::{DbApiRole, WebApiRole};
+use crate::pagination::default_page_size;
 use serde::{Deserialize, Serialize};
 use surrealdb::sql::{Datetime, Thing};
 use validator::Validate;
 
-#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
-
-pub struct DbRegisteredUser {
-    pub id: Thing,
-    pub username: String,
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
 pub struct DbGatewayUserRecord {
     pub id: Thing,
+    #[serde(default)]
+    pub revision: u64,
     #[validate(length(min = 4))]
     pub username: String,
     pub created_date: Datetime,
     pub last_modified_date: Datetime,
     pub last_login: Option<Datetime>,
     pub password_reset_at: Option<Datetime>,
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
 pub struct DbGatewayUserResponse {
     pub id: Thing,
+    #[serde(default)]
+    pub revision: u64,
     #[validate(length(min = 4))]
     pub username: String,
     pub roles: Vec<DbApiRole>,
@@ -31,11 +31,16 @@ pub struct DbGatewayUserResponse {
     pub last_modified_date: Datetime,
     pub last_login: Option<Datetime>,
     pub password_reset_at: Option<Datetime>, // Field to store the datetime of the last password reset
+    // Disabled users can't log in
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
 pub struct WebGatewayUserResponse {
     pub id: String,
+    // Counts the writes to the user, logins included; also sent as its ETag
+    pub revision: u64,
     #[validate(length(min = 4))]
     pub username: String,
     pub roles: Vec<WebApiRole>,
@@ -43,6 +48,7 @@ pub struct WebGatewayUserResponse {
     pub last_modified_date: Datetime,
     pub last_login: Option<Datetime>,
     pub password_reset_at: Option<Datetime>, // Field to store the datetime of the last password reset
+    pub disabled: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
@@ -50,28 +56,30 @@ pub struct WebPartialGatewayUserUpdate {
     #[validate(length(min = 4))]
     pub username: Option<String>,
     pub roles: Option<Vec<WebApiRole>>,
+    pub disabled: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Validate)]
 pub struct DbPartialGatewayUserUpdate {
     #[validate(length(min = 4))]
     pub username: Option<String>,
+    pub disabled: Option<bool>,
 }
 
 impl From<&WebPartialGatewayUserUpdate> for DbPartialGatewayUserUpdate {
     fn from(value: &WebPartialGatewayUserUpdate) -> Self {
         Self {
             username: value.username.clone(),
+            disabled: value.disabled,
         }
     }
 }
 
+// Over the web API, a user update always carries the user's full set of roles,
+// so leaving them out revokes every membership
 impl From<&WebPartialGatewayUserUpdate> for Option<Vec<DbApiRole>> {
     fn from(value: &WebPartialGatewayUserUpdate) -> Self {
-        match &value.roles {
-            Some(roles) => Some(roles.iter().map(DbApiRole::from).collect()),
-            None => None,
-        }
+        Some(value.roles.iter().flatten().map(DbApiRole::from).collect())
     }
 }
 
@@ -96,6 +104,30 @@ impl From<&WebGatewayUserRequest> for DbGatewayUserRequest {
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct UserListParams {
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default = "default_page_size")]
+    pub limit: u64,
+    // Matched case-insensitively against the username
+    pub q: Option<String>,
+    // A `Namespace::Name` role the users must be members of
+    pub role: Option<String>,
+    pub disabled: Option<bool>,
+    pub sort: Option<String>,
+}
+
+/// How a newly registered user gets their first password.
+#[derive(Clone, Debug)]
+pub enum InitialCredential {
+    Password(String),
+    // A reset request the user completes themselves, valid for this many seconds
+    PasswordReset(u64),
+    // None; the user signs in through an identity provider
+    External,
+}
+
 impl From<&WebGatewayUserRequest> for Vec<DbApiRole> {
     fn from(value: &WebGatewayUserRequest) -> Self {
         value.roles.iter().map(|role| role.into()).collect()
@@ -106,12 +138,14 @@ impl From<&DbGatewayUserResponse> for WebGatewayUserResponse {
     fn from(value: &DbGatewayUserResponse) -> Self {
         Self {
             id: format!("{}", value.id.id),
+            revision: value.revision,
             username: value.username.clone(),
             roles: value.roles.iter().map(|role| role.into()).collect(),
             created_date: value.created_date.clone(),
             last_modified_date: value.last_modified_date.clone(),
             last_login: value.last_login.clone(),
             password_reset_at: value.password_reset_at.clone(),
+            disabled: value.disabled,
         }
     }
 }
@@ -120,12 +154,14 @@ impl From<(DbGatewayUserRecord, Vec<DbApiRole>)> for DbGatewayUserResponse {
     fn from((user, roles): (DbGatewayUserRecord, Vec<DbApiRole>)) -> Self {
         Self {
             id: user.id,
+            revision: user.revision,
             username: user.username,
             roles,
             created_date: user.created_date,
             last_modified_date: user.last_modified_date,
             last_login: user.last_login,
             password_reset_at: user.password_reset_at,
+            disabled: user.disabled,
         }
     }
 }
@@ -1,24 +1,24 @@
-use std::collections::{BTreeMap, HashSet};
-use std::time::SystemTime;
+use std::time;
 
 use actix_web::web::Data;
 use async_trait::async_trait;
 use serde::Serialize;
-use serde_json::{to_value, Value};
-use surrealdb::opt::PatchOp;
-use surrealdb::sql::{Datetime, Thing};
+use serde_json::{json, to_value, Value};
+use surrealdb::sql::{Datetime, Id, Thing};
 
 use super::models::{
     DbGatewayUserRecord, DbGatewayUserRequest, DbGatewayUserResponse, DbPartialGatewayUserUpdate,
-    DbRegisteredUser,
+    InitialCredential, UserListParams,
 };
-use crate::api_services::models::DbApiRole;
+use crate::api_services::models::{DbApiRole, WebApiRole};
 use crate::api_services::repo::RoleRepository;
+use crate::audit::models::{Actor, AuditAction, DbAuditEntryRequest};
+use crate::audit::repo::audit_within;
 use crate::auth::models::PasswordResetRequest;
-use crate::database::{Database, ROLE_MEMBER_TABLE, USER_TABLE};
+use crate::database::{Database, Transaction, PASSWORD_RESET_TABLE, ROLE_MEMBER_TABLE, USER_TABLE};
 use crate::errors::{GatewayError, Result};
-
-const PASSWORD_RESET_TABLE: &str = "password_reset_request";
+use crate::pagination::{Page, PageQuery};
+use crate::revisions::Precondition;
 
 #[async_trait]
 pub trait UserRepository {
@@ -26,7 +26,9 @@ pub trait UserRepository {
         repo: &Data<Database>,
         user: DbGatewayUserRequest,
         roles: Vec<DbApiRole>,
-    ) -> Result<DbGatewayUserResponse>;
+        credential: InitialCredential,
+        actor: &Actor,
+    ) -> Result<(DbGatewayUserResponse, Option<PasswordResetRequest>)>;
 
     async fn user_detail(repo: &Data<Database>, user_id: &String) -> Result<DbGatewayUserResponse>;
 
@@ -35,11 +37,16 @@ pub trait UserRepository {
         user_id: &String,
         user: DbPartialGatewayUserUpdate,
         roles: Option<Vec<DbApiRole>>,
+        precondition: &Precondition,
+        actor: &Actor,
     ) -> Result<DbGatewayUserResponse>;
 
     async fn list_users(repo: &Data<Database>) -> Result<Vec<DbGatewayUserResponse>>;
 
-    async fn user_roles(repo: &Data<Database>, user_id: &Thing) -> Result<Vec<DbApiRole>>;
+    async fn search_users(
+        repo: &Data<Database>,
+        params: &UserListParams,
+    ) -> Result<Page<DbGatewayUserResponse>>;
 }
 
 #[derive(Serialize)]
@@ -48,58 +55,26 @@ struct _UserIdQueryParams<'_a, '_b> {
     pub user_id: &'_b String,
 }
 
-pub async fn setup_user_table(repo: &Database) -> std::io::Result<()> {
-    repo.define_index(
-        USER_TABLE,
-        "usernameIndex",
-        vec!["username"],
-        Some("UNIQUE"),
-    )
-    .await?;
-    repo.automate_created_date(USER_TABLE).await?;
-
-    repo.define_index(
-        ROLE_MEMBER_TABLE,
-        "roleMembershipIndex",
-        vec!["in", "out"],
-        Some("UNIQUE"),
-    )
-    .await?;
-    repo.automate_created_date(ROLE_MEMBER_TABLE).await?;
-    Ok(())
-}
-
 #[async_trait]
 impl UserRepository for Database {
     async fn register_user(
         repo: &Data<Database>,
         new_user: DbGatewayUserRequest,
         roles: Vec<DbApiRole>,
-    ) -> Result<DbGatewayUserResponse> {
-        // First, validate the GatewayUser
-        // new_user.validate().map_err(|e| GatewayError::ValidationError(e.to_string()))?;
-
-        // Insert the GatewayUser into the database
-        let inserted_user: DbRegisteredUser = repo
-            .db
-            .create(USER_TABLE)
-            .content(new_user)
-            .await
-            .map_err(GatewayError::from)?
-            .remove(0);
-
-        let inserted_user: DbGatewayUserRecord = repo
-            .db
-            .select(inserted_user.id)
-            .await
-            .map_err(GatewayError::from)?
-            .ok_or(GatewayError::DatabaseError(
-                "Failed to fetch inserted user".to_string(),
-            ))?;
-
+        credential: InitialCredential,
+        actor: &Actor,
+    ) -> Result<(DbGatewayUserResponse, Option<PasswordResetRequest>)> {
+        let user_id = Thing::from((USER_TABLE, Id::rand()));
+        let mut audit_entries = vec![actor.entry(
+            AuditAction::UserCreated,
+            Some(user_id.clone()),
+            json!({ "username": new_user.username }),
+        )];
+        let mut role_ids: Vec<Thing> = Vec::new();
         for role in roles.iter() {
             if let Some(role_id) = &role.id {
-                repo.db
+                let _: DbApiRole = repo
+                    .db
                     .select(role_id)
                     .await
                     .map_err(GatewayError::from)?
@@ -107,51 +82,95 @@ impl UserRepository for Database {
                         "Role".to_string(),
                         format!("{} could not be found", role_id),
                     ))?;
-                repo.relate(role_id, &inserted_user.id, ROLE_MEMBER_TABLE, None)
-                    .await?;
+                role_ids.push(role_id.clone());
+                audit_entries.push(role_entry(actor, AuditAction::RoleGranted, &user_id, role));
             }
         }
 
-        // Generate a PasswordResetRequest for the user
-        let now_ts = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        let user_id = format!("{}", inserted_user.id);
-
-        let password_reset_request = PasswordResetRequest {
-            id: None,
-            user_id: user_id.clone(), // Assuming `id` is some form of unique identifier
-            used: false,
-            expires_at: now_ts + (24 * 60 * 60), // expires in 1 day
-            last_modified: Datetime::default(),
+        // The user, their memberships and their first credential are created together
+        let transaction = Transaction::new()
+            .statement("CREATE $user CONTENT $content")
+            .statement(format!(
+                "FOR $role IN $roles {{ RELATE $user->{}->$role; }}",
+                ROLE_MEMBER_TABLE
+            ))
+            .bind("user", &user_id)?
+            .bind("content", new_user)?
+            .bind("roles", role_ids)?;
+        let external = matches!(credential, InitialCredential::External);
+        let (transaction, password_reset) = match credential {
+            InitialCredential::Password(password) => (
+                transaction
+                    .statement(
+                        "UPDATE $user SET password_hash = crypto::argon2::generate($password), \
+                        password_reset_at = time::now()",
+                    )
+                    .bind("password", password)?,
+                None,
+            ),
+            InitialCredential::PasswordReset(lifetime_secs) => {
+                let now = time::SystemTime::now()
+                    .duration_since(time::UNIX_EPOCH)
+                    .map_err(|e| GatewayError::SystemError(e.to_string()))?
+                    .as_secs();
+                let password_reset = PasswordResetRequest {
+                    id: Some(Thing::from((PASSWORD_RESET_TABLE, Id::rand()))),
+                    user_id: user_id.id.to_string(),
+                    used: false,
+                    expires_at: now + lifetime_secs,
+                    last_modified: Datetime::default(),
+                };
+                (
+                    transaction
+                        .statement("CREATE $reset_id CONTENT $reset")
+                        .bind("reset_id", &password_reset.id)?
+                        .bind("reset", &password_reset)?,
+                    Some(password_reset),
+                )
+            }
+            InitialCredential::External => (transaction, None),
         };
+        if !external {
+            audit_entries.push(actor.entry(
+                match password_reset {
+                    Some(_) => AuditAction::PasswordResetRequested,
+                    None => AuditAction::PasswordChanged,
+                },
+                Some(user_id.clone()),
+                json!({}),
+            ));
+        }
+        let transaction = audit_within(transaction, audit_entries)?;
+        repo.commit(transaction).await?;
 
-        let password_reset: PasswordResetRequest = repo
+        let inserted_user: DbGatewayUserRecord = repo
             .db
-            .create(PASSWORD_RESET_TABLE)
-            .content(password_reset_request)
+            .select(&user_id)
             .await
-            .map_err(Into::<GatewayError>::into)?
-            .remove(0);
-        log::info!(
-            "Created Password Reset request {} for user {}",
-            password_reset.id.unwrap().id,
-            user_id.clone()
-        );
-        Ok((inserted_user, roles).into())
+            .map_err(GatewayError::from)?
+            .ok_or(GatewayError::DatabaseError(
+                "Failed to fetch inserted user".to_string(),
+            ))?;
+
+        Ok(((inserted_user, roles).into(), password_reset))
     }
 
     async fn user_detail(repo: &Data<Database>, user_id: &String) -> Result<DbGatewayUserResponse> {
-        repo.db
-            .select((USER_TABLE, user_id))
-            .await
-            .map_err(Into::<GatewayError>::into)?
-            .ok_or(GatewayError::NotFound(
-                "User".to_string(),
-                "Could not find a user with the specified ID".to_string(),
-            ))
+        repo.query_record(
+            format!(
+                "SELECT *, ->{}->role.* as roles FROM $user_id",
+                ROLE_MEMBER_TABLE
+            ),
+            Some((
+                "user_id".to_string(),
+                surrealdb::sql::Value::Thing((USER_TABLE, user_id.as_str()).into()),
+            )),
+        )
+        .await?
+        .ok_or(GatewayError::NotFound(
+            "User".to_string(),
+            "Could not find a user with the specified ID".to_string(),
+        ))
     }
 
     async fn update_user(
@@ -159,92 +178,136 @@ impl UserRepository for Database {
         user_id: &String,
         user: DbPartialGatewayUserUpdate,
         roles: Option<Vec<DbApiRole>>,
+        precondition: &Precondition,
+        actor: &Actor,
     ) -> Result<DbGatewayUserResponse> {
-        // Serialize the DbPartialGatewayUserUpdate struct to a serde_json Value
+        // UPDATE would otherwise create a missing user
+        let existing_user = Database::user_detail(repo, user_id).await?;
         let user_id: Thing = ((USER_TABLE.to_string(), user_id.clone())).into();
-        let mut intended_roles: Vec<DbApiRole> = Vec::new();
+        precondition.check(existing_user.revision)?;
+        let mut audit_entries = Vec::new();
+        let mut refuse_tokens = false;
+
+        // Memberships are only replaced when roles are given
+        let transaction = Transaction::new().bind("user", &user_id)?;
+        let mut transaction = precondition.guard(transaction, "user")?;
         if let Some(new_roles) = roles {
+            let mut found_roles: Vec<DbApiRole> = Vec::new();
             for role in new_roles {
-                if let Some(_) = role.id {
-                    intended_roles.push(role.clone());
-                } else {
-                    intended_roles
-                        .push(Database::find_role(repo, &role.namespace, &role.name).await?);
+                found_roles.push(match &role.id {
+                    Some(role_id) => repo
+                        .db
+                        .select(role_id)
+                        .await
+                        .map_err(GatewayError::from)?
+                        .ok_or(GatewayError::NotFound(
+                            "Role".to_string(),
+                            format!("{} could not be found", role_id),
+                        ))?,
+                    None => Database::find_role(repo, &role.namespace, &role.name).await?,
+                });
+            }
+            let role_ids: Vec<Thing> = found_roles
+                .iter()
+                .filter_map(|role| role.id.clone())
+                .collect();
+            let held_ids: Vec<Thing> = existing_user
+                .roles
+                .iter()
+                .filter_map(|role| role.id.clone())
+                .collect();
+            for role in &found_roles {
+                if !role.id.as_ref().is_some_and(|id| held_ids.contains(id)) {
+                    audit_entries.push(role_entry(actor, AuditAction::RoleGranted, &user_id, role));
+                }
+            }
+            for role in &existing_user.roles {
+                if !role.id.as_ref().is_some_and(|id| role_ids.contains(id)) {
+                    audit_entries.push(role_entry(actor, AuditAction::RoleRevoked, &user_id, role));
                 }
             }
+            // Tokens carry the roles they were issued with
+            refuse_tokens = !audit_entries.is_empty();
+            transaction = transaction
+                .statement(format!(
+                    "DELETE {table} WHERE in = $user AND out NOTINSIDE $roles;\n\
+                    FOR $role IN $roles {{\n\
+                        IF array::len(SELECT id FROM {table} WHERE in = $user AND out = $role) = 0 {{\n\
+                            RELATE $user->{table}->$role;\n\
+                        }};\n\
+                    }}",
+                    table = ROLE_MEMBER_TABLE
+                ))
+                .bind("roles", role_ids)?;
         }
 
-        let mut new_role_ids: HashSet<Thing> = HashSet::new();
-        for role in &intended_roles {
-            new_role_ids.insert(role.id.clone().unwrap());
+        let mut changes = serde_json::Map::new();
+        if let Some(username) = user
+            .username
+            .as_ref()
+            .filter(|u| **u != existing_user.username)
+        {
+            changes.insert("username".to_string(), json!(username));
         }
-        let existing_roles = Database::user_roles(repo, &user_id).await?;
-        let mut existing_role_ids: HashSet<Thing> = HashSet::new();
-        for role in existing_roles {
-            existing_role_ids.insert(role.id.unwrap());
+        if let Some(disabled) = user.disabled.filter(|d| *d != existing_user.disabled) {
+            changes.insert("disabled".to_string(), json!(disabled));
+            // A disabled user's tokens stop working now, not when they expire
+            refuse_tokens |= disabled;
         }
-        for role_to_remove in existing_role_ids.difference(&new_role_ids) {
-            repo.unrelate(&user_id, role_to_remove, &ROLE_MEMBER_TABLE.to_string())
-                .await?;
-        }
-        for role_to_add in new_role_ids.difference(&existing_role_ids) {
-            repo.relate(&user_id, role_to_add, &ROLE_MEMBER_TABLE.to_string(), None)
-                .await?;
+        if !changes.is_empty() {
+            audit_entries.push(actor.entry(
+                AuditAction::UserUpdated,
+                Some(user_id.clone()),
+                Value::Object(changes),
+            ));
         }
 
+        // Serialize the DbPartialGatewayUserUpdate struct to a serde_json Value
         let update_data: Value =
-            to_value(user).map_err(|e| GatewayError::MissingData(e.to_string()))?; // Handle this unwrap more gracefully in production code
-
-        if let Value::Object(fields) = update_data {
-            // Start constructing the update query for the specific service ID
-            let mut patch_request = repo
-                .db
-                .update(&user_id)
-                .patch(PatchOp::replace("/last_modified", Datetime::default()));
-
-            // Iterate over the fields in the JSON object
-            for (key, value) in fields {
-                // Skip fields that are null or not provided in the partial update
-                if !value.is_null() {
-                    // Construct the JSON Pointer string
-                    let prop_path = format!("/{}", key);
-
-                    // Apply a patch operation for the current field
-                    patch_request = patch_request.patch(PatchOp::replace(&prop_path, value));
-                }
-            }
+            to_value(user).map_err(|e| GatewayError::MissingData(e.to_string()))?;
+        let Value::Object(fields) = update_data else {
+            // The serialized update data is not an object, which shouldn't happen in correct implementations
+            return Err(GatewayError::MissingData(String::from(
+                "Didn't understand the input data",
+            )));
+        };
+        // Fields that are null or not provided in the partial update are left as they are
+        let patch: Vec<Value> = fields
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| json!({"op": "replace", "path": format!("/{}", key), "value": value}))
+            .collect();
+        // The watermark moves in the same write so the revision only goes up once
+        let statement = if refuse_tokens {
+            "UPDATE $user PATCH array::append($patch, \
+                { op: 'replace', path: '/tokens_valid_after', value: time::now() })"
+        } else {
+            "UPDATE $user PATCH $patch"
+        };
+        let transaction = transaction.statement(statement).bind("patch", patch)?;
+        let transaction = audit_within(transaction, audit_entries)?;
+        repo.commit(transaction).await?;
 
-            // Execute the update query
-            let _update_result: DbGatewayUserRecord =
-                patch_request.await.map_err(GatewayError::from)?.ok_or(
-                    GatewayError::DatabaseError("Unable to update record".to_string()),
-                )?;
-
-            let result: Option<DbGatewayUserResponse> = repo
-                .query_record(
-                    format!(
-                        "SELECT *, ->{}->role.* as roles FROM $user_id",
-                        ROLE_MEMBER_TABLE
-                    ),
-                    Some::<(String, surrealdb::sql::Value)>(
-                        (
-                            "user_id".to_string(),
-                            surrealdb::sql::Value::Thing(user_id.clone()),
-                        )
-                            .into(),
-                    ),
-                )
-                .await?;
+        let result: Option<DbGatewayUserResponse> = repo
+            .query_record(
+                format!(
+                    "SELECT *, ->{}->role.* as roles FROM $user_id",
+                    ROLE_MEMBER_TABLE
+                ),
+                Some::<(String, surrealdb::sql::Value)>(
+                    (
+                        "user_id".to_string(),
+                        surrealdb::sql::Value::Thing(user_id.clone()),
+                    )
+                        .into(),
+                ),
+            )
+            .await?;
 
-            result.ok_or(GatewayError::NotFound(
-                String::from("API Service"),
-                format!("An API Service could not be found at id {}", user_id),
-            ))
-        } else {
-            Err(GatewayError::MissingData(String::from(
-                "Didn't understand the input data",
-            ))) // The serialized update data is not an object, which shouldn't happen in correct implementations
-        }
+        result.ok_or(GatewayError::NotFound(
+            String::from("User"),
+            format!("Could not find a user with id {}", user_id),
+        ))
     }
 
     async fn list_users(repo: &Data<Database>) -> Result<Vec<DbGatewayUserResponse>> {
@@ -259,21 +322,171 @@ impl UserRepository for Database {
             .map_err(Into::<GatewayError>::into)
     }
 
-    async fn user_roles(repo: &Data<Database>, user_id: &Thing) -> Result<Vec<DbApiRole>> {
-        let bind_params: BTreeMap<String, surrealdb::sql::Value> = [(
-            "user_id".to_string(),
-            surrealdb::sql::Value::Thing(user_id.clone()),
-        )]
-        .into();
-        let result: Option<Vec<DbApiRole>> = repo
-            .query_record(
-                format!(
-                    "SELECT VALUE roles FROM (SELECT ->{}->role.* AS roles FROM $user_id)",
+    async fn search_users(
+        repo: &Data<Database>,
+        params: &UserListParams,
+    ) -> Result<Page<DbGatewayUserResponse>> {
+        let mut query = PageQuery::new(
+            format!("*, ->{}->role.* as roles", ROLE_MEMBER_TABLE),
+            USER_TABLE,
+            params.offset,
+            params.limit,
+            params.sort.as_deref(),
+            &[
+                "username",
+                "created_date",
+                "last_modified_date",
+                "last_login",
+            ],
+            "username",
+        )?;
+        if let Some(search) = &params.q {
+            query = query
+                .condition("string::contains(string::lowercase(username), $search)")
+                .bind("search", search.to_lowercase())?;
+        }
+        if let Some(role) = &params.role {
+            let role: WebApiRole = role.parse().map_err(GatewayError::BadRequest)?;
+            query = query
+                .condition(format!(
+                    "array::len(->{}->role[WHERE namespace = $role_namespace AND name = $role_name]) > 0",
                     ROLE_MEMBER_TABLE
-                ),
-                Some(bind_params),
-            )
-            .await?;
-        Ok(result.unwrap_or(Vec::new()))
+                ))
+                .bind("role_namespace", role.namespace)?
+                .bind("role_name", role.name)?;
+        }
+        if let Some(disabled) = params.disabled {
+            query = query
+                .condition("disabled = $disabled")
+                .bind("disabled", disabled)?;
+        }
+        query.fetch(repo).await
+    }
+}
+
+fn role_entry(
+    actor: &Actor,
+    action: AuditAction,
+    user_id: &Thing,
+    role: &DbApiRole,
+) -> DbAuditEntryRequest {
+    actor.entry(
+        action,
+        Some(user_id.clone()),
+        json!({ "role": role.to_string() }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::repo::{TokenRevocationRepository, UserAuthRepository};
+    use crate::database::testing::{count, database, fail_creating};
+    use crate::database::AUDIT_TABLE;
+
+    #[actix_web::test]
+    async fn register_user_leaves_nothing_behind_when_it_fails() {
+        let repo = database().await;
+        let role = Database::find_or_add_role(
+            &repo,
+            &WebApiRole {
+                id: None,
+                revision: None,
+                namespace: "Shop".into(),
+                name: "Reader".into(),
+            },
+        )
+        .await
+        .unwrap();
+        fail_creating(&repo, AUDIT_TABLE).await;
+
+        let registered = Database::register_user(
+            &repo,
+            DbGatewayUserRequest {
+                username: "alice".into(),
+            },
+            vec![role],
+            InitialCredential::PasswordReset(3600),
+            &Actor::default(),
+        )
+        .await;
+
+        assert!(registered.is_err());
+        assert_eq!(count(&repo, USER_TABLE).await, 0);
+        assert_eq!(count(&repo, ROLE_MEMBER_TABLE).await, 0);
+        assert_eq!(count(&repo, PASSWORD_RESET_TABLE).await, 0);
+    }
+
+    #[actix_web::test]
+    async fn login_leaves_the_revision_alone() {
+        let repo = database().await;
+        let (user, _) = Database::register_user(
+            &repo,
+            DbGatewayUserRequest {
+                username: "alice".into(),
+            },
+            vec![],
+            InitialCredential::PasswordReset(3600),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let user_id = user.id.id.to_string();
+
+        Database::set_last_login(&repo, &user_id).await.unwrap();
+        let logged_in = Database::user_detail(&repo, &user_id).await.unwrap();
+        assert!(logged_in.last_login.is_some());
+        assert_eq!(logged_in.revision, user.revision);
+
+        let updated = Database::update_user(
+            &repo,
+            &user_id,
+            DbPartialGatewayUserUpdate {
+                username: None,
+                disabled: Some(true),
+            },
+            None,
+            &Precondition::Revisions(vec![user.revision]),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(updated.revision, user.revision + 1);
+    }
+
+    #[actix_web::test]
+    async fn disabling_a_user_refuses_their_tokens() {
+        let repo = database().await;
+        let (user, _) = Database::register_user(
+            &repo,
+            DbGatewayUserRequest {
+                username: "alice".into(),
+            },
+            vec![],
+            InitialCredential::PasswordReset(3600),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+        let user_id = user.id.id.to_string();
+
+        let updated = Database::update_user(
+            &repo,
+            &user_id,
+            DbPartialGatewayUserUpdate {
+                username: None,
+                disabled: Some(true),
+            },
+            None,
+            &Precondition::Revisions(vec![user.revision]),
+            &Actor::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(updated.revision, user.revision + 1);
+        let refused = Database::list_token_watermarks(&repo).await.unwrap();
+        assert_eq!(refused.len(), 1);
+        assert_eq!(refused[0].id, user.id);
     }
 }
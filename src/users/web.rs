@@ -1,18 +1,26 @@
 use actix_web::{
-    get, patch, post,
-    web::{scope, to, Data, Json, Path, ServiceConfig},
-    HttpRequest,
+    delete, get, patch, post,
+    web::{scope, to, Data, Json, Path, Query, ServiceConfig},
+    HttpRequest, HttpResponse,
 };
 use serde::Deserialize;
 
 use super::{
-    models::{WebGatewayUserRequest, WebGatewayUserResponse, WebPartialGatewayUserUpdate},
+    models::{
+        InitialCredential, UserListParams, WebGatewayUserRequest, WebGatewayUserResponse,
+        WebPartialGatewayUserUpdate,
+    },
     repo::UserRepository,
 };
 
+use crate::audit::models::Actor;
+use crate::auth::repo::SessionRepository;
 use crate::auth::web::{validate_jwt, validate_jwt_prefix};
+use crate::config::GatewayConfig;
 use crate::database::Database;
 use crate::errors::{unknown_resource_error, Result};
+use crate::pagination::page_response;
+use crate::revisions::{etag, tagged_response, Precondition};
 
 // Intermediate function to configure services
 pub fn service_setup(cfg: &mut ServiceConfig) {
@@ -25,6 +33,7 @@ pub fn service_setup(cfg: &mut ServiceConfig) {
             .service(current_user)
             .service(user_detail)
             .service(update_user)
+            .service(revoke_user_sessions)
             .default_service(to(unknown_resource_error)),
     );
 }
@@ -32,34 +41,57 @@ pub fn service_setup(cfg: &mut ServiceConfig) {
 #[get("/")]
 async fn list_users(
     req: HttpRequest,
+    params: Query<UserListParams>,
     repo: Data<Database>,
-) -> Result<Json<Vec<WebGatewayUserResponse>>> {
+) -> Result<HttpResponse> {
     validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
-    let user_list = Database::list_users(&repo).await?;
-    Ok(Json(user_list.iter().map(|db_rec| db_rec.into()).collect()))
+    let user_list = Database::search_users(&repo, &params).await?;
+    Ok(page_response(
+        &req,
+        user_list.map(|db_rec| WebGatewayUserResponse::from(&db_rec)),
+    ))
 }
 
 #[post("/")]
 async fn register_user(
     req: HttpRequest,
     repo: Data<Database>,
+    config: Data<GatewayConfig>,
     user_json: Json<WebGatewayUserRequest>,
-) -> Result<Json<WebGatewayUserResponse>> {
-    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
     let user_data = user_json.into_inner();
-    let registered_user =
-        Database::register_user(&repo, (&user_data).into(), (&user_data).into()).await?;
-    Ok(Json((&registered_user).into()))
+    // New users set their first password through a reset request
+    let (registered_user, password_reset) = Database::register_user(
+        &repo,
+        (&user_data).into(),
+        (&user_data).into(),
+        InitialCredential::PasswordReset(config.auth.password_reset_lifetime_secs),
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    let registered_user: WebGatewayUserResponse = (&registered_user).into();
+    if let Some(password_reset) = password_reset {
+        log::info!(
+            "Created Password Reset request {} for user {}",
+            password_reset.id.unwrap().id,
+            registered_user.id
+        );
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(registered_user.revision))
+        .json(registered_user))
 }
 
 #[get("/current")]
-async fn current_user(
-    req: HttpRequest,
-    repo: Data<Database>,
-) -> Result<Json<WebGatewayUserResponse>> {
+async fn current_user(req: HttpRequest, repo: Data<Database>) -> Result<HttpResponse> {
     let claims = validate_jwt(&req, None)?;
     let user = Database::user_detail(&repo, &claims.sub_id).await?;
-    Ok(Json((&user).into()))
+    Ok(tagged_response(
+        &req,
+        user.revision,
+        &WebGatewayUserResponse::from(&user),
+    ))
 }
 
 #[derive(Deserialize)]
@@ -72,11 +104,15 @@ async fn user_detail(
     req: HttpRequest,
     repo: Data<Database>,
     path_params: Path<UserIdPathParams>,
-) -> Result<Json<WebGatewayUserResponse>> {
+) -> Result<HttpResponse> {
     validate_jwt_prefix(&req, &vec!["Gateway"])?;
     let user_id = path_params.into_inner().user_id;
     let user = Database::user_detail(&repo, &user_id).await?;
-    Ok(Json((&user).into()))
+    Ok(tagged_response(
+        &req,
+        user.revision,
+        &WebGatewayUserResponse::from(&user),
+    ))
 }
 
 #[patch("/{user_id}")]
@@ -85,11 +121,34 @@ async fn update_user(
     repo: Data<Database>,
     path_params: Path<UserIdPathParams>,
     user_form: Json<WebPartialGatewayUserUpdate>,
-) -> Result<Json<WebGatewayUserResponse>> {
-    validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let precondition = Precondition::from_request(&req)?;
     let user_id = path_params.into_inner().user_id;
     let user = user_form.into_inner();
-    let updated_user =
-        Database::update_user(&repo, &user_id, (&user).into(), (&user).into()).await?;
-    Ok(Json((&updated_user).into()))
+    let updated_user = Database::update_user(
+        &repo,
+        &user_id,
+        (&user).into(),
+        (&user).into(),
+        &precondition,
+        &Actor::from_claims(&req, &claims),
+    )
+    .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(updated_user.revision))
+        .json(WebGatewayUserResponse::from(&updated_user)))
+}
+
+// Signs the user out everywhere and refuses every access token issued so far
+#[delete("/{user_id}/sessions")]
+async fn revoke_user_sessions(
+    req: HttpRequest,
+    repo: Data<Database>,
+    path_params: Path<UserIdPathParams>,
+) -> Result<HttpResponse> {
+    let claims = validate_jwt(&req, Some(&vec!["Gateway::Admin"]))?;
+    let user_id = path_params.into_inner().user_id;
+    Database::revoke_user_sessions(&repo, &user_id, &Actor::from_claims(&req, &claims)).await?;
+    Ok(HttpResponse::NoContent().finish())
 }